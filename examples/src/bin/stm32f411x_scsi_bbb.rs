@@ -1,6 +1,7 @@
 #![no_std]
 #![no_main]
 
+use core::cmp::min;
 use core::mem::MaybeUninit;
 use core::ptr::addr_of_mut;
 use defmt_rtt as _;
@@ -12,27 +13,24 @@ use stm32f4xx_hal::pac;
 use stm32f4xx_hal::prelude::*;
 use stm32f4xx_hal::rcc::RccExt;
 use usb_device::prelude::*;
-use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+use usbd_storage::subclass::scsi::block::{BlockDevice, BlockDriver};
+use usbd_storage::subclass::scsi::capacity::{read_capacity_10, read_capacity_16, BlockSize};
+use usbd_storage::subclass::scsi::sense::Sense;
+use usbd_storage::subclass::scsi::{required_buffer_len, Scsi, ScsiCommand};
 use usbd_storage::subclass::Command;
 use usbd_storage::transport::bbb::{BulkOnly, BulkOnlyError};
 use usbd_storage::transport::TransportError;
 
 static mut USB_EP_MEMORY: [u32; 1024] = [0u32; 1024];
 /// Not necessarily `'static`. May reside in some special memory location
-static mut USB_TRANSPORT_BUF: MaybeUninit<[u8; 512]> = MaybeUninit::uninit();
+static mut USB_TRANSPORT_BUF: MaybeUninit<[u8; USB_TRANSPORT_BUF_LEN]> = MaybeUninit::uninit();
 static mut STORAGE: [u8; (BLOCKS * BLOCK_SIZE) as usize] = [0u8; (BLOCK_SIZE * BLOCKS) as usize];
 
-static mut STATE: State = State {
-    storage_offset: 0,
-    sense_key: None,
-    sense_key_code: None,
-    sense_qualifier: None,
-};
-
 const BLOCK_SIZE: u32 = 512;
 const BLOCKS: u32 = 200;
 const USB_PACKET_SIZE: u16 = 64; // 8,16,32,64
 const MAX_LUN: u8 = 0; // max 0x0F
+const USB_TRANSPORT_BUF_LEN: usize = required_buffer_len(USB_PACKET_SIZE, BlockSize::B512);
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
@@ -40,20 +38,38 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     loop {}
 }
 
-#[derive(Default)]
-struct State {
-    storage_offset: usize,
-    sense_key: Option<u8>,
-    sense_key_code: Option<u8>,
-    sense_qualifier: Option<u8>,
-}
+/// The medium, served with [BlockDriver]
+struct Storage(&'static mut [u8]);
+
+impl BlockDevice for Storage {
+    fn block_size(&self) -> BlockSize {
+        BlockSize::B512
+    }
+
+    fn num_blocks(&self) -> u64 {
+        BLOCKS as u64
+    }
 
-impl State {
-    fn reset(&mut self) {
-        self.storage_offset = 0;
-        self.sense_key = None;
-        self.sense_key_code = None;
-        self.sense_qualifier = None;
+    fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), Sense> {
+        let start = lba as usize * BLOCK_SIZE as usize;
+        defmt::info!(
+            "Data transfer >>>>>>>> [{}..{}]",
+            start,
+            start + block.len()
+        );
+        block.copy_from_slice(&self.0[start..start + block.len()]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), Sense> {
+        let start = lba as usize * BLOCK_SIZE as usize;
+        defmt::info!(
+            "Data transfer <<<<<<<< [{}..{}]",
+            start,
+            start + block.len()
+        );
+        self.0[start..start + block.len()].copy_from_slice(block);
+        Ok(())
     }
 }
 
@@ -115,6 +131,10 @@ fn main() -> ! {
         .self_powered(false)
         .build();
 
+    let mut storage = Storage(unsafe { &mut *addr_of_mut!(STORAGE) });
+    // the block size matches the default of the device type, the capacity is reported below
+    let mut driver = BlockDriver::new([0u8; 2 * BLOCK_SIZE as usize]);
+
     loop {
         led.set_high();
 
@@ -122,15 +142,14 @@ fn main() -> ! {
             continue;
         }
 
-        // clear state if just configured or reset
-        if matches!(usb_device.state(), UsbDeviceState::Default) {
-            unsafe {
-                STATE.reset();
-            };
-        }
+        // Read and Write resume where the transfer is, nothing to clear on a reset
+        let _ = scsi.take_reset();
 
         let _ = scsi.poll(|command| {
             led.set_low();
+            let Some(command) = driver.handle(&mut storage, command) else {
+                return;
+            };
             if let Err(err) = process_command(command) {
                 defmt::error!("{}", err);
             }
@@ -164,42 +183,20 @@ fn process_command(
             ])?;
             command.pass();
         }
-        ScsiCommand::RequestSense { .. } => unsafe {
-            command.try_write_data_all(&[
-                0x70,                         // RESPONSE CODE. Set to 70h for information on current errors
-                0x00,                         // obsolete
-                STATE.sense_key.unwrap_or(0), // Bits 3..0: SENSE KEY. Contains information describing the error.
-                0x00,
-                0x00,
-                0x00,
-                0x00, // INFORMATION. Device-specific or command-specific information.
-                0x00, // ADDITIONAL SENSE LENGTH.
-                0x00,
-                0x00,
-                0x00,
-                0x00,                               // COMMAND-SPECIFIC INFORMATION
-                STATE.sense_key_code.unwrap_or(0),  // ASC
-                STATE.sense_qualifier.unwrap_or(0), // ASCQ
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-            ])?;
-            STATE.reset();
+        ScsiCommand::RequestSense { .. } => {
+            // pending sense is answered by the subclass, nothing to report otherwise
+            command.try_write_data_all(&Sense::NO_SENSE.to_fixed_bytes())?;
             command.pass();
-        },
+        }
         ScsiCommand::ReadCapacity10 { .. } => {
-            let mut data = [0u8; 8];
-            let _ = &mut data[0..4].copy_from_slice(&u32::to_be_bytes(BLOCKS - 1));
-            let _ = &mut data[4..8].copy_from_slice(&u32::to_be_bytes(BLOCK_SIZE));
-            command.try_write_data_all(&data)?;
+            // a non-zero LBA without PMI is rejected by the subclass. with PMI, the last LBA of
+            // the medium is a valid answer
+            command.try_write_data_all(&read_capacity_10(BLOCKS as u64, BlockSize::B512))?;
             command.pass();
         }
-        ScsiCommand::ReadCapacity16 { .. } => {
-            let mut data = [0u8; 16];
-            let _ = &mut data[0..8].copy_from_slice(&u32::to_be_bytes(BLOCKS - 1));
-            let _ = &mut data[8..12].copy_from_slice(&u32::to_be_bytes(BLOCK_SIZE));
-            command.try_write_data_all(&data)?;
+        ScsiCommand::ReadCapacity16 { alloc_len, .. } => {
+            let data = read_capacity_16(BLOCKS as u64, BlockSize::B512);
+            command.try_write_data_all(&data[..min(alloc_len as usize, data.len())])?;
             command.pass();
         }
         ScsiCommand::ReadFormatCapacities { .. } => {
@@ -217,43 +214,6 @@ fn process_command(
             command.try_write_data_all(&data)?;
             command.pass();
         }
-        ScsiCommand::Read { lba, len } => unsafe {
-            let lba = lba as u32;
-            let len = len as u32;
-            if STATE.storage_offset != (len * BLOCK_SIZE) as usize {
-                let start = (BLOCK_SIZE * lba) as usize + STATE.storage_offset;
-                let end = (BLOCK_SIZE * lba) as usize + (BLOCK_SIZE * len) as usize;
-
-                // Uncomment this in order to push data in chunks smaller than a USB packet.
-                // let end = min(start + USB_PACKET_SIZE as usize - 1, end);
-
-                defmt::info!("Data transfer >>>>>>>> [{}..{}]", start, end);
-                let count = command.write_data(&mut STORAGE[start..end])?;
-                STATE.storage_offset += count;
-            } else {
-                command.pass();
-                STATE.storage_offset = 0;
-            }
-        },
-        ScsiCommand::Write { lba, len } => unsafe {
-            let lba = lba as u32;
-            let len = len as u32;
-            if STATE.storage_offset != (len * BLOCK_SIZE) as usize {
-                let start = (BLOCK_SIZE * lba) as usize + STATE.storage_offset;
-                let end = (BLOCK_SIZE * lba) as usize + (BLOCK_SIZE * len) as usize;
-                defmt::info!("Data transfer <<<<<<<< [{}..{}]", start, end);
-                let count = command.read_data(&mut STORAGE[start..end])?;
-                STATE.storage_offset += count;
-
-                if STATE.storage_offset == (len * BLOCK_SIZE) as usize {
-                    command.pass();
-                    STATE.storage_offset = 0;
-                }
-            } else {
-                command.pass();
-                STATE.storage_offset = 0;
-            }
-        },
         ScsiCommand::ModeSense6 { .. } => {
             command.try_write_data_all(&[
                 0x03, // number of bytes that follow
@@ -269,12 +229,8 @@ fn process_command(
         }
         ref unknown_scsi_kind => {
             defmt::error!("Unknown SCSI command: {}", unknown_scsi_kind);
-            unsafe {
-                STATE.sense_key.replace(0x05); // illegal request Sense Key
-                STATE.sense_key_code.replace(0x20); // Invalid command operation ASC
-                STATE.sense_qualifier.replace(0x00); // Invalid command operation ASCQ
-            }
-            command.fail();
+            // queued and reported by the subclass on the next REQUEST SENSE
+            command.fail_with_sense(Sense::INVALID_COMMAND_OPERATION_CODE);
         }
     }
 
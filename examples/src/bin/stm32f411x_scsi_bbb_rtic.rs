@@ -0,0 +1,223 @@
+#![no_std]
+#![no_main]
+
+use defmt_rtt as _;
+use usbd_storage::subclass::scsi::inquiry::DeviceIdentity;
+
+const BLOCK_SIZE: u32 = 512;
+const BLOCKS: u32 = 200;
+const USB_PACKET_SIZE: u16 = 64; // 8,16,32,64
+const MAX_LUN: u8 = 0; // max 0x0F
+/// Reported both in INQUIRY data and in the USB string descriptors
+const IDENTITY: DeviceIdentity = DeviceIdentity {
+    vendor: "UNKNOWN",
+    product: "STM32 USB Flash",
+    revision: "1.23",
+    serial: "FOOBAR1234567890ABCDEF",
+};
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    defmt::error!("{}", defmt::Display2Format(info));
+    loop {}
+}
+
+/// The USB interrupt drives the transport, while the idle task handles the commands.
+/// The subclass is shared between both of them
+#[rtic::app(device = stm32f4xx_hal::pac, peripherals = true)]
+mod app {
+    use crate::{BLOCKS, BLOCK_SIZE, IDENTITY, MAX_LUN, USB_PACKET_SIZE};
+    use stm32f4xx_hal::gpio::alt::otg_fs::{Dm, Dp};
+    use stm32f4xx_hal::otg_fs::{UsbBus, USB};
+    use stm32f4xx_hal::prelude::*;
+    use usb_device::bus::UsbBusAllocator;
+    use usb_device::prelude::*;
+    use usbd_storage::subclass::scsi::inquiry::InquiryData;
+    use usbd_storage::subclass::scsi::sense::Sense;
+    use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+    use usbd_storage::subclass::Command;
+    use usbd_storage::transport::bbb::{BulkOnly, BulkOnlyError};
+    use usbd_storage::transport::TransportError;
+
+    type Storage = Scsi<BulkOnly<'static, UsbBus<USB>, &'static mut [u8]>>;
+
+    #[shared]
+    struct Shared {
+        scsi: Storage,
+    }
+
+    #[local]
+    struct Local {
+        usb_device: UsbDevice<'static, UsbBus<USB>>,
+        disk: RamDisk,
+    }
+
+    struct RamDisk {
+        storage: &'static mut [u8],
+        offset: usize,
+    }
+
+    #[init(local = [
+        usb_ep_memory: [u32; 1024] = [0u32; 1024],
+        usb_bus: Option<UsbBusAllocator<UsbBus<USB>>> = None,
+        // Not necessarily `'static`. May reside in some special memory location
+        usb_transport_buf: [u8; 512] = [0u8; 512],
+        storage: [u8; (BLOCKS * BLOCK_SIZE) as usize] = [0u8; (BLOCKS * BLOCK_SIZE) as usize],
+    ])]
+    fn init(cx: init::Context) -> (Shared, Local) {
+        defmt::info!("Started...");
+
+        let dp = cx.device;
+
+        // setup clocks
+        let rcc = dp.RCC.constrain();
+        let clocks = rcc
+            .cfgr
+            .use_hse(25.MHz()) // 25Mhz HSE is present on the board
+            .sysclk(48.MHz())
+            .require_pll48clk()
+            .freeze();
+
+        // setup GPIO
+        let gpioa = dp.GPIOA.split();
+        // USB
+        let mut pin_usb_dm = gpioa.pa11.into_push_pull_output();
+        let mut pin_usb_dp = gpioa.pa12.into_push_pull_output();
+
+        // force D+ for 100ms
+        // this forces the host to enumerate devices
+        pin_usb_dm.set_low();
+        pin_usb_dp.set_low();
+        cx.core.SYST.delay(&clocks).delay_ms(100u32);
+
+        let usb_peripheral = USB {
+            usb_global: dp.OTG_FS_GLOBAL,
+            usb_device: dp.OTG_FS_DEVICE,
+            usb_pwrclk: dp.OTG_FS_PWRCLK,
+            pin_dm: Dm::from(pin_usb_dm.into_alternate()),
+            pin_dp: Dp::from(pin_usb_dp.into_alternate()),
+            hclk: clocks.hclk(),
+        };
+
+        let usb_bus = cx
+            .local
+            .usb_bus
+            .insert(UsbBus::new(usb_peripheral, cx.local.usb_ep_memory));
+        let mut scsi = Scsi::new(
+            usb_bus,
+            USB_PACKET_SIZE,
+            MAX_LUN,
+            cx.local.usb_transport_buf.as_mut_slice(),
+        )
+        .unwrap();
+        // INQUIRY, READ CAPACITY, MODE SENSE etc. are answered by the subclass
+        scsi.set_capacity(0, BLOCKS as u64);
+        scsi.set_identity(&IDENTITY);
+        scsi.set_inquiry(InquiryData {
+            removable: true,
+            ..IDENTITY.inquiry_data()
+        });
+
+        let usb_device = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0xabcd, 0xabcd))
+            .strings(&[IDENTITY.string_descriptors(LangID::EN)])
+            .unwrap()
+            .self_powered(false)
+            .build();
+
+        (
+            Shared { scsi },
+            Local {
+                usb_device,
+                disk: RamDisk {
+                    storage: cx.local.storage.as_mut_slice(),
+                    offset: 0,
+                },
+            },
+        )
+    }
+
+    /// Drives the transport only. Never blocks on a command being handled
+    #[task(binds = OTG_FS, priority = 2, shared = [scsi], local = [usb_device])]
+    fn usb_irq(mut cx: usb_irq::Context) {
+        let usb_device = cx.local.usb_device;
+        cx.shared.scsi.lock(|scsi| {
+            usb_device.poll(&mut [scsi]);
+            if let Err(err) = scsi.drive_transport() {
+                defmt::error!("{}", err);
+            }
+        });
+    }
+
+    /// Handles the commands. Preempted by the USB interrupt outside of the lock
+    #[idle(shared = [scsi], local = [disk])]
+    fn idle(mut cx: idle::Context) -> ! {
+        let disk = cx.local.disk;
+        loop {
+            cx.shared.scsi.lock(|scsi| {
+                // clear state on a bus or a class reset
+                if scsi.take_reset().is_some() {
+                    disk.offset = 0;
+                }
+
+                let res = scsi.handle_command(|command| {
+                    if let Err(err) = process_command(disk, command) {
+                        defmt::error!("{}", err);
+                    }
+                });
+                if let Err(err) = res {
+                    defmt::error!("{}", err);
+                }
+            });
+        }
+    }
+
+    fn process_command(
+        disk: &mut RamDisk,
+        mut command: Command<ScsiCommand, Storage>,
+    ) -> Result<(), TransportError<BulkOnlyError>> {
+        defmt::info!("Handling: {}", command.kind);
+
+        match command.kind {
+            ScsiCommand::TestUnitReady { .. } => {
+                command.pass();
+            }
+            ScsiCommand::Read { lba, len } => {
+                let start = (BLOCK_SIZE * lba as u32) as usize;
+                let total = (BLOCK_SIZE * len as u32) as usize;
+                if disk.offset != total {
+                    let from = start + disk.offset;
+                    defmt::info!("Data transfer >>>>>>>> [{}..{}]", from, start + total);
+                    let count = command.write_data(&disk.storage[from..start + total])?;
+                    disk.offset += count;
+                } else {
+                    command.pass();
+                    disk.offset = 0;
+                }
+            }
+            ScsiCommand::Write { lba, len } => {
+                let start = (BLOCK_SIZE * lba as u32) as usize;
+                let total = (BLOCK_SIZE * len as u32) as usize;
+                if disk.offset != total {
+                    let from = start + disk.offset;
+                    defmt::info!("Data transfer <<<<<<<< [{}..{}]", from, start + total);
+                    let count = command.read_data(&mut disk.storage[from..start + total])?;
+                    disk.offset += count;
+
+                    if disk.offset == total {
+                        command.pass();
+                        disk.offset = 0;
+                    }
+                } else {
+                    command.pass();
+                    disk.offset = 0;
+                }
+            }
+            ref unknown_scsi_kind => {
+                defmt::error!("Unknown SCSI command: {}", unknown_scsi_kind);
+                command.fail_with_sense(Sense::INVALID_COMMAND_OPERATION_CODE);
+            }
+        }
+
+        Ok(())
+    }
+}
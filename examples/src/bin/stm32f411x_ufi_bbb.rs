@@ -13,6 +13,7 @@ use stm32f4xx_hal::prelude::*;
 use stm32f4xx_hal::rcc::RccExt;
 
 use usb_device::prelude::*;
+use usbd_storage::quirks::Quirks;
 use usbd_storage::subclass::ufi::{Ufi, UfiCommand};
 use usbd_storage::subclass::Command;
 use usbd_storage::transport::bbb::{BulkOnly, BulkOnlyError};
@@ -102,6 +103,11 @@ fn main() -> ! {
         USB_TRANSPORT_BUF.assume_init_mut().as_mut_slice()
     })
     .unwrap();
+    let mut quirks = Quirks::default();
+    quirks.fill_short_in = Some(0xF6);
+    ufi.set_quirks(quirks);
+    // MODE SENSE is answered by the subclass, writes are failed. `false` for Read Write
+    ufi.set_write_protected(true);
 
     let mut usb_device = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd))
         .strings(&[StringDescriptors::new(LangID::EN)
@@ -119,8 +125,8 @@ fn main() -> ! {
             continue;
         }
 
-        // clear state if just configured or reset
-        if matches!(usb_device.state(), UsbDeviceState::Default) {
+        // clear state on a bus or a class reset
+        if ufi.take_reset().is_some() {
             unsafe {
                 STATE.reset();
             };
@@ -182,15 +188,6 @@ fn process_command(
             STATE.reset();
             command.pass();
         },
-        UfiCommand::ModeSense { .. } => {
-            /* Read Only */
-            command.try_write_data_all(&[0x00, 0x46, 0x02, 0x80, 0x00, 0x00, 0x00, 0x00])?;
-
-            /* Read Write */
-            // command.try_write_data_all(&[0x00, 0x46, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00])?;
-
-            command.pass();
-        }
         UfiCommand::Write { .. } => {
             command.pass();
         }
@@ -207,14 +204,9 @@ fn process_command(
                     let count = command.write_data(&FAT[start..end])?;
                     STATE.storage_offset += count;
                 } else {
-                    /* fill with 0xF6 */
-                    loop {
-                        let count = command.write_data(&[0xF6; BLOCK_SIZE as usize])?;
-                        STATE.storage_offset += count;
-                        if count == 0 {
-                            break;
-                        }
-                    }
+                    /* filled with 0xF6 by the transport */
+                    command.pass();
+                    STATE.storage_offset = 0;
                 }
             } else {
                 command.pass();
@@ -0,0 +1,738 @@
+//! Exposes a RAM disk through a virtual USB/IP device, so that a real Linux host enumerates and
+//! drives it with its `usb-storage` driver. Unlike the dummy bus of the tests, this catches the
+//! issues only a real host runs into, e.g. mount failures or residue handling.
+//!
+//! ```shell
+//! cargo run -p usbd-storage --example usbip --features scsi,bbb
+//! sudo modprobe vhci-hcd
+//! sudo usbip attach -r 127.0.0.1 -b 1-1
+//! ```
+//!
+//! The device shows up as a SCSI disk, e.g. `/dev/sdX`, which can be formatted and mounted.
+//! `sudo usbip detach -p 0` disconnects it. The contents are lost once the example exits.
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, Thread};
+use std::time::Duration;
+use usb_device::bus::{PollResult, UsbBus, UsbBusAllocator};
+use usb_device::class_prelude::{EndpointAddress, EndpointType};
+use usb_device::device::{StringDescriptors, UsbDeviceBuilder, UsbVidPid};
+use usb_device::LangID;
+use usb_device::{UsbDirection, UsbError};
+use usbd_storage::subclass::scsi::inquiry::InquiryData;
+use usbd_storage::subclass::scsi::sense::Sense;
+use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+use usbd_storage::subclass::Command;
+use usbd_storage::transport::bbb::BulkOnly;
+
+const USBIP_PORT: u16 = 3240;
+const USBIP_VERSION: u16 = 0x0111;
+const BUS_ID: &str = "1-1";
+const BUS_NUM: u32 = 1;
+const DEV_NUM: u32 = 1;
+const USB_SPEED_FULL: u32 = 2;
+
+const OP_REQ_DEVLIST: u16 = 0x8005;
+const OP_REP_DEVLIST: u16 = 0x0005;
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+
+const USBIP_CMD_SUBMIT: u32 = 0x0001;
+const USBIP_CMD_UNLINK: u32 = 0x0002;
+const USBIP_RET_SUBMIT: u32 = 0x0003;
+const USBIP_RET_UNLINK: u32 = 0x0004;
+const USBIP_HEADER_LEN: usize = 48;
+
+/// URB status of a stalled endpoint
+const EPIPE: i32 = -32;
+/// URB status of an unlinked URB
+const ECONNRESET: i32 = -104;
+
+const VID_PID: UsbVidPid = UsbVidPid(0xabcd, 0xabcd);
+const DEVICE_RELEASE: u16 = 0x0100;
+const USB_PACKET_SIZE: u16 = 64;
+const BLOCK_SIZE: usize = 512;
+const BLOCKS: usize = 8 * 1024; // 4 MiB
+
+fn main() {
+    let bus = UsbipBus::default();
+    let usb_bus = UsbBusAllocator::new(bus.clone());
+    let mut io_buf = [0u8; BLOCK_SIZE];
+    let mut scsi = Scsi::new(&usb_bus, USB_PACKET_SIZE, 0, io_buf.as_mut_slice()).unwrap();
+    let mut usb_device = UsbDeviceBuilder::new(&usb_bus, VID_PID)
+        .strings(&[StringDescriptors::new(LangID::EN)
+            .manufacturer("Foo Bar")
+            .product("USB/IP Flash")
+            .serial_number("FOOBAR1234567890ABCDEF")])
+        .unwrap()
+        .max_packet_size_0(64)
+        .unwrap()
+        .device_release(DEVICE_RELEASE)
+        .build();
+
+    let mut disk = RamDisk::new();
+    scsi.set_capacity(0, BLOCKS as u64);
+    scsi.set_inquiry(InquiryData {
+        removable: true,
+        ..InquiryData::new("USBDSTOR", "USB/IP RAM DISK", "1.00")
+    });
+
+    let device = thread::current();
+    thread::spawn(move || {
+        if let Err(err) = serve(bus, device) {
+            eprintln!("usbip: server failed: {err}");
+            std::process::exit(1);
+        }
+    });
+
+    loop {
+        let polled = usb_device.poll(&mut [&mut scsi]);
+
+        // clear state on a bus or a class reset
+        if scsi.take_reset().is_some() {
+            disk.offset = 0;
+        }
+
+        // the class is driven even without new packets, as a command may need more than a single
+        // poll to complete once the last packet of its data has been read
+        if let Err(err) = scsi.poll(|command| disk.handle(command)) {
+            eprintln!("usbip: poll failed: {err:?}");
+        }
+
+        if !polled {
+            // woken up by the server as soon as the host submits a transfer
+            thread::park_timeout(Duration::from_millis(1));
+        }
+    }
+}
+
+/// A RAM backed SCSI block device. The capacity related commands are handled by [Scsi] itself
+struct RamDisk {
+    data: Vec<u8>,
+    /// bytes transferred so far by the current Read/Write command
+    offset: usize,
+}
+
+impl RamDisk {
+    fn new() -> Self {
+        Self {
+            data: vec![0u8; BLOCK_SIZE * BLOCKS],
+            offset: 0,
+        }
+    }
+
+    fn handle<Bus: UsbBus>(
+        &mut self,
+        mut cmd: Command<ScsiCommand, Scsi<BulkOnly<Bus, &mut [u8]>>>,
+    ) {
+        match cmd.kind {
+            ScsiCommand::TestUnitReady => {
+                cmd.pass();
+            }
+            ScsiCommand::Read { lba, len } => {
+                let start = lba as usize * BLOCK_SIZE;
+                let total = len as usize * BLOCK_SIZE;
+                if self.offset < total {
+                    let from = start + self.offset;
+                    if let Ok(count) = cmd.write_data(&self.data[from..start + total]) {
+                        self.offset += count;
+                    }
+                }
+                if self.offset == total {
+                    self.offset = 0;
+                    cmd.pass();
+                }
+            }
+            ScsiCommand::Write { lba, len } => {
+                let start = lba as usize * BLOCK_SIZE;
+                let total = len as usize * BLOCK_SIZE;
+                if self.offset < total {
+                    let from = start + self.offset;
+                    if let Ok(count) = cmd.read_data(&mut self.data[from..start + total]) {
+                        self.offset += count;
+                    }
+                }
+                if self.offset == total {
+                    self.offset = 0;
+                    cmd.pass();
+                }
+            }
+            ScsiCommand::Inquiry { .. } => {
+                cmd.fail_with_sense(Sense::INVALID_FIELD_IN_CDB);
+            }
+            _ => {
+                cmd.fail_with_sense(Sense::INVALID_COMMAND_OPERATION_CODE);
+            }
+        }
+    }
+}
+
+/// Accepts USB/IP connections. A host lists the only device available and then imports it, after
+/// which the connection carries the URBs until the host detaches
+fn serve(bus: UsbipBus, device: Thread) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", USBIP_PORT))?;
+    println!("usbip: listening on port {USBIP_PORT}, bus id {BUS_ID}");
+
+    for stream in listener.incoming() {
+        if let Err(err) = handle_connection(&bus, &device, stream?) {
+            eprintln!("usbip: connection closed: {err}");
+        }
+        bus.lock().detach();
+        device.unpark();
+    }
+
+    Ok(())
+}
+
+fn handle_connection(bus: &UsbipBus, device: &Thread, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+
+    let mut op = [0u8; 8];
+    stream.read_exact(&mut op)?;
+    match u16::from_be_bytes([op[2], op[3]]) {
+        OP_REQ_DEVLIST => {
+            let mut reply = op_header(OP_REP_DEVLIST, 0);
+            reply.extend_from_slice(&1u32.to_be_bytes()); // number of devices
+            reply.extend_from_slice(&device_description());
+            reply.extend_from_slice(&[0x08, 0x06, 0x50, 0x00]); // Mass Storage, SCSI, Bulk-Only
+            stream.write_all(&reply)
+        }
+        OP_REQ_IMPORT => {
+            let mut bus_id = [0u8; 32];
+            stream.read_exact(&mut bus_id)?;
+            if !bus_id.starts_with(BUS_ID.as_bytes()) || bus_id[BUS_ID.len()] != 0 {
+                return stream.write_all(&op_header(OP_REP_IMPORT, 1));
+            }
+
+            let mut reply = op_header(OP_REP_IMPORT, 0);
+            reply.extend_from_slice(&device_description());
+            stream.write_all(&reply)?;
+
+            println!("usbip: attached");
+            bus.lock().attach(stream.try_clone()?);
+            device.unpark();
+            handle_urbs(bus, device, &mut stream)
+        }
+        code => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unexpected operation {code:#06x}"),
+        )),
+    }
+}
+
+fn handle_urbs(bus: &UsbipBus, device: &Thread, stream: &mut TcpStream) -> io::Result<()> {
+    loop {
+        let mut header = [0u8; USBIP_HEADER_LEN];
+        match stream.read_exact(&mut header) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                println!("usbip: detached");
+                return Ok(());
+            }
+            res => res?,
+        }
+        let word = |i: usize| u32::from_be_bytes(header[i * 4..i * 4 + 4].try_into().unwrap());
+
+        match word(0) {
+            USBIP_CMD_SUBMIT => {
+                let dir = if word(3) == 1 {
+                    UsbDirection::In
+                } else {
+                    UsbDirection::Out
+                };
+                let len = word(6) as usize;
+                let mut data = vec![];
+                if dir == UsbDirection::Out {
+                    data.resize(len, 0);
+                    stream.read_exact(&mut data)?;
+                }
+                bus.lock().submit(Urb {
+                    seqnum: word(1),
+                    ep: word(4) as usize,
+                    dir,
+                    len,
+                    setup: header[40..48].try_into().unwrap(),
+                    data,
+                    offset: 0,
+                });
+            }
+            USBIP_CMD_UNLINK => {
+                let mut inner = bus.lock();
+                let status = if inner.unlink(word(5)) { ECONNRESET } else { 0 };
+                inner.send(USBIP_RET_UNLINK, word(1), status, 0, &[]);
+            }
+            command => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unexpected command {command:#x}"),
+                ));
+            }
+        }
+        device.unpark();
+    }
+}
+
+fn op_header(code: u16, status: u32) -> Vec<u8> {
+    let mut header = vec![];
+    header.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+    header.extend_from_slice(&code.to_be_bytes());
+    header.extend_from_slice(&status.to_be_bytes());
+    header
+}
+
+/// `usbip_usb_device` without the interfaces
+fn device_description() -> Vec<u8> {
+    let mut path = [0u8; 256];
+    let sys_path = b"/sys/devices/usbd-storage/usb1/1-1";
+    path[..sys_path.len()].copy_from_slice(sys_path);
+    let mut bus_id = [0u8; 32];
+    bus_id[..BUS_ID.len()].copy_from_slice(BUS_ID.as_bytes());
+
+    let mut desc = vec![];
+    desc.extend_from_slice(&path);
+    desc.extend_from_slice(&bus_id);
+    desc.extend_from_slice(&BUS_NUM.to_be_bytes());
+    desc.extend_from_slice(&DEV_NUM.to_be_bytes());
+    desc.extend_from_slice(&USB_SPEED_FULL.to_be_bytes());
+    desc.extend_from_slice(&VID_PID.0.to_be_bytes());
+    desc.extend_from_slice(&VID_PID.1.to_be_bytes());
+    desc.extend_from_slice(&DEVICE_RELEASE.to_be_bytes());
+    // class, subclass, protocol are defined by the interface, single configuration and interface
+    desc.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x01, 0x01]);
+    desc
+}
+
+/// USB Request Block submitted by the host
+struct Urb {
+    seqnum: u32,
+    /// endpoint number
+    ep: usize,
+    dir: UsbDirection,
+    /// transfer buffer length
+    len: usize,
+    setup: [u8; 8],
+    /// data received from the host during OUT transfers, or collected from the device during IN
+    data: Vec<u8>,
+    /// bytes of `data` read by the device so far during OUT transfers
+    offset: usize,
+}
+
+impl Urb {
+    fn is_out_data_pending(&self) -> bool {
+        self.dir == UsbDirection::Out && self.offset < self.len
+    }
+}
+
+/// Control transfer in progress
+struct Control {
+    urb: Urb,
+    /// SETUP packet hasn't been read by the device yet
+    setup_pending: bool,
+}
+
+#[derive(Default)]
+struct BulkIn {
+    addr: Option<EndpointAddress>,
+    packet_size: usize,
+    stalled: bool,
+    /// a single packet written by the device, but not yet delivered to the host
+    fifo: Option<Vec<u8>>,
+    urbs: VecDeque<Urb>,
+    /// a packet has been delivered since the last poll
+    complete: bool,
+}
+
+#[derive(Default)]
+struct BulkOut {
+    addr: Option<EndpointAddress>,
+    packet_size: usize,
+    stalled: bool,
+    urbs: VecDeque<Urb>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// connection of the attached host, which RET_* are sent to
+    conn: Option<TcpStream>,
+    reset_pending: bool,
+    ep0_packet_size: usize,
+    control_urbs: VecDeque<Urb>,
+    control: Option<Control>,
+    /// a control packet has been written since the last poll
+    ep0_in_complete: bool,
+    bulk_in: BulkIn,
+    bulk_out: BulkOut,
+}
+
+impl Inner {
+    fn attach(&mut self, conn: TcpStream) {
+        self.conn = Some(conn);
+        self.reset_pending = true;
+    }
+
+    /// Drops all URBs and anything the device has written as a disconnect would
+    fn detach(&mut self) {
+        self.conn = None;
+        self.control_urbs.clear();
+        self.control = None;
+        self.bulk_in.urbs.clear();
+        self.bulk_in.fifo = None;
+        self.bulk_in.stalled = false;
+        self.bulk_out.urbs.clear();
+        self.bulk_out.stalled = false;
+        self.reset_pending = true;
+    }
+
+    fn submit(&mut self, urb: Urb) {
+        let bulk_in = self.bulk_in.addr.map(|addr| addr.index());
+        let bulk_out = self.bulk_out.addr.map(|addr| addr.index());
+        match urb.dir {
+            _ if urb.ep == 0 => self.control_urbs.push_back(urb),
+            UsbDirection::In if Some(urb.ep) == bulk_in && !self.bulk_in.stalled => {
+                self.bulk_in.urbs.push_back(urb);
+                self.deliver_in();
+            }
+            UsbDirection::Out if Some(urb.ep) == bulk_out && !self.bulk_out.stalled => {
+                self.bulk_out.urbs.push_back(urb);
+            }
+            _ => self.complete(urb, EPIPE),
+        }
+    }
+
+    /// Returns `false` if the URB has been completed already
+    fn unlink(&mut self, seqnum: u32) -> bool {
+        if self
+            .control
+            .as_ref()
+            .is_some_and(|control| control.urb.seqnum == seqnum)
+        {
+            self.control = None;
+            return true;
+        }
+
+        let mut unlinked = false;
+        for urbs in [
+            &mut self.control_urbs,
+            &mut self.bulk_in.urbs,
+            &mut self.bulk_out.urbs,
+        ] {
+            let len = urbs.len();
+            urbs.retain(|urb| urb.seqnum != seqnum);
+            unlinked |= urbs.len() != len;
+        }
+        unlinked
+    }
+
+    /// Moves the packet written by the device into the IN URB in progress
+    fn deliver_in(&mut self) {
+        if self.bulk_in.stalled {
+            return;
+        }
+        let Some(urb) = self.bulk_in.urbs.front_mut() else {
+            return;
+        };
+        let Some(packet) = self.bulk_in.fifo.take() else {
+            return;
+        };
+
+        urb.data.extend_from_slice(&packet);
+        self.bulk_in.complete = true;
+
+        // a short packet terminates a transfer
+        if packet.len() < self.bulk_in.packet_size || urb.data.len() >= urb.len {
+            let urb = self.bulk_in.urbs.pop_front().unwrap();
+            self.complete(urb, 0);
+        }
+    }
+
+    fn write_control(&mut self, buf: &[u8]) {
+        self.ep0_in_complete = true;
+
+        let Some(control) = self.control.as_mut().filter(|c| !c.setup_pending) else {
+            return; // status stage or an extra ZLP of a transfer completed already
+        };
+        let urb = &mut control.urb;
+        let done = match urb.dir {
+            UsbDirection::In => {
+                urb.data.extend_from_slice(buf);
+                buf.len() < self.ep0_packet_size || urb.data.len() >= urb.len
+            }
+            // status stage
+            UsbDirection::Out => buf.is_empty() && !urb.is_out_data_pending(),
+        };
+
+        if done {
+            let mut urb = self.control.take().unwrap().urb;
+            urb.data.truncate(urb.len);
+            self.complete(urb, 0);
+        }
+    }
+
+    fn read_control(&mut self, buf: &mut [u8]) -> usb_device::Result<usize> {
+        let Some(control) = self.control.as_mut() else {
+            return Err(UsbError::WouldBlock);
+        };
+
+        if control.setup_pending {
+            let setup = &control.urb.setup;
+            if buf.len() < setup.len() {
+                return Err(UsbError::BufferOverflow);
+            }
+            control.setup_pending = false;
+            buf[..setup.len()].copy_from_slice(setup);
+            return Ok(setup.len());
+        }
+
+        let urb = &mut control.urb;
+        if !urb.is_out_data_pending() {
+            return Err(UsbError::WouldBlock);
+        }
+        let count = self.ep0_packet_size.min(urb.len - urb.offset);
+        if buf.len() < count {
+            return Err(UsbError::BufferOverflow);
+        }
+        buf[..count].copy_from_slice(&urb.data[urb.offset..urb.offset + count]);
+        urb.offset += count;
+        Ok(count)
+    }
+
+    fn read_bulk(&mut self, buf: &mut [u8]) -> usb_device::Result<usize> {
+        if self.bulk_out.stalled {
+            return Err(UsbError::WouldBlock); // the host gets STALL handshake
+        }
+        let Some(urb) = self.bulk_out.urbs.front_mut() else {
+            return Err(UsbError::WouldBlock);
+        };
+
+        let count = self.bulk_out.packet_size.min(urb.len - urb.offset);
+        if buf.len() < count {
+            return Err(UsbError::BufferOverflow);
+        }
+        buf[..count].copy_from_slice(&urb.data[urb.offset..urb.offset + count]);
+        urb.offset += count;
+
+        if !urb.is_out_data_pending() {
+            let urb = self.bulk_out.urbs.pop_front().unwrap();
+            self.complete(urb, 0);
+        }
+        Ok(count)
+    }
+
+    fn set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
+        if ep_addr.index() == 0 {
+            // a STALL handshake ends a control transfer. The next SETUP clears it
+            if stalled && self.control.as_ref().is_some_and(|c| !c.setup_pending) {
+                let urb = self.control.take().unwrap().urb;
+                self.complete(urb, EPIPE);
+            }
+        } else if Some(ep_addr) == self.bulk_in.addr {
+            self.bulk_in.stalled = stalled;
+            if stalled {
+                // the host gets STALL handshake instead of a packet not delivered yet
+                self.bulk_in.fifo = None;
+                while let Some(urb) = self.bulk_in.urbs.pop_front() {
+                    self.complete(urb, EPIPE);
+                }
+            } else {
+                self.deliver_in();
+            }
+        } else if Some(ep_addr) == self.bulk_out.addr {
+            self.bulk_out.stalled = stalled;
+            if stalled {
+                while let Some(urb) = self.bulk_out.urbs.pop_front() {
+                    self.complete(urb, EPIPE);
+                }
+            }
+        }
+    }
+
+    fn complete(&mut self, urb: Urb, status: i32) {
+        let (actual_len, data) = match urb.dir {
+            UsbDirection::In => (urb.data.len(), urb.data.as_slice()),
+            UsbDirection::Out => (urb.offset, [].as_slice()),
+        };
+        self.send(USBIP_RET_SUBMIT, urb.seqnum, status, actual_len, data);
+    }
+
+    /// Sends RET_SUBMIT or RET_UNLINK to the attached host
+    fn send(&mut self, command: u32, seqnum: u32, status: i32, actual_len: usize, data: &[u8]) {
+        let Some(conn) = self.conn.as_mut() else {
+            return;
+        };
+
+        let mut reply = Vec::with_capacity(USBIP_HEADER_LEN + data.len());
+        reply.extend_from_slice(&command.to_be_bytes());
+        reply.extend_from_slice(&seqnum.to_be_bytes());
+        reply.extend_from_slice(&[0u8; 12]); // devid, direction and ep are not used
+        reply.extend_from_slice(&status.to_be_bytes());
+        if command == USBIP_RET_SUBMIT {
+            reply.extend_from_slice(&(actual_len as u32).to_be_bytes());
+        }
+        reply.resize(USBIP_HEADER_LEN, 0); // start frame, number of packets, error count
+        reply.extend_from_slice(data);
+
+        if let Err(err) = conn.write_all(&reply) {
+            eprintln!("usbip: failed to send a reply: {err}");
+            self.conn = None;
+        }
+    }
+}
+
+/// [UsbBus] turning packets into URBs of a USB/IP host and back. Full speed, with a single pair of
+/// bulk endpoints which is what the Bulk Only Transport needs
+#[derive(Clone, Default)]
+struct UsbipBus {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl UsbipBus {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+}
+
+impl UsbBus for UsbipBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        _ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> usb_device::Result<EndpointAddress> {
+        let mut inner = self.lock();
+        let packet_size = max_packet_size as usize;
+        match (ep_type, ep_dir) {
+            (EndpointType::Control, _) => {
+                inner.ep0_packet_size = packet_size;
+                Ok(EndpointAddress::from_parts(0, ep_dir))
+            }
+            (EndpointType::Bulk, UsbDirection::In) if inner.bulk_in.addr.is_none() => {
+                let addr = EndpointAddress::from_parts(1, ep_dir);
+                inner.bulk_in.addr = Some(addr);
+                inner.bulk_in.packet_size = packet_size;
+                Ok(addr)
+            }
+            (EndpointType::Bulk, UsbDirection::Out) if inner.bulk_out.addr.is_none() => {
+                let addr = EndpointAddress::from_parts(2, ep_dir);
+                inner.bulk_out.addr = Some(addr);
+                inner.bulk_out.packet_size = packet_size;
+                Ok(addr)
+            }
+            _ => Err(UsbError::EndpointOverflow),
+        }
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+        let mut inner = self.lock();
+
+        if ep_addr.index() == 0 {
+            inner.write_control(buf);
+            return Ok(buf.len());
+        }
+
+        if Some(ep_addr) != inner.bulk_in.addr {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        if buf.len() > inner.bulk_in.packet_size {
+            return Err(UsbError::BufferOverflow);
+        }
+        if inner.bulk_in.fifo.is_some() {
+            return Err(UsbError::WouldBlock);
+        }
+
+        inner.bulk_in.fifo = Some(buf.to_vec());
+        inner.deliver_in();
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
+        let mut inner = self.lock();
+
+        if ep_addr.index() == 0 {
+            inner.read_control(buf)
+        } else if Some(ep_addr) == inner.bulk_out.addr {
+            inner.read_bulk(buf)
+        } else {
+            Err(UsbError::InvalidEndpoint)
+        }
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        self.lock().set_stalled(ep_addr, stalled);
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        let inner = self.lock();
+        if Some(ep_addr) == inner.bulk_in.addr {
+            inner.bulk_in.stalled
+        } else if Some(ep_addr) == inner.bulk_out.addr {
+            inner.bulk_out.stalled
+        } else {
+            false
+        }
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        let mut inner = self.lock();
+
+        if inner.reset_pending {
+            inner.reset_pending = false;
+            return PollResult::Reset;
+        }
+
+        if inner.control.is_none() {
+            inner.control = inner.control_urbs.pop_front().map(|urb| Control {
+                urb,
+                setup_pending: true,
+            });
+        }
+
+        let mut ep_out = 0;
+        let mut ep_in_complete = 0;
+        let mut ep_setup = 0;
+
+        match &inner.control {
+            Some(control) if control.setup_pending => ep_setup |= 1,
+            Some(control) if control.urb.is_out_data_pending() => ep_out |= 1,
+            _ => {}
+        }
+        if inner.ep0_in_complete {
+            inner.ep0_in_complete = false;
+            ep_in_complete |= 1;
+        }
+        if let Some(addr) = inner.bulk_out.addr {
+            if !inner.bulk_out.stalled && !inner.bulk_out.urbs.is_empty() {
+                ep_out |= 1 << addr.index();
+            }
+        }
+        if let Some(addr) = inner.bulk_in.addr {
+            if inner.bulk_in.complete {
+                inner.bulk_in.complete = false;
+                ep_in_complete |= 1 << addr.index();
+            }
+        }
+
+        if ep_out | ep_in_complete | ep_setup == 0 {
+            PollResult::None
+        } else {
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        }
+    }
+}
@@ -16,6 +16,10 @@ impl<T: BorrowMut<[u8]>> Buffer<T> {
         }
     }
 
+    pub fn capacity(&self) -> usize {
+        self.inner.borrow().len()
+    }
+
     pub fn available_read(&self) -> usize {
         self.wpos - self.rpos
     }
@@ -24,6 +28,11 @@ impl<T: BorrowMut<[u8]>> Buffer<T> {
         self.inner.borrow().len() - self.wpos
     }
 
+    /// Space available for writing once the unread data is shifted to the beginning
+    pub fn free_space(&self) -> usize {
+        self.inner.borrow().len() - self.available_read()
+    }
+
     /// Returns number of bytes actually written
     pub fn write(&mut self, data: &[u8]) -> usize {
         if self.available_write() < data.len() {
@@ -71,20 +80,21 @@ impl<T: BorrowMut<[u8]>> Buffer<T> {
         })
     }
 
+    /// Discards up to `count` most recently written bytes that haven't been read yet
+    pub fn discard_last(&mut self, count: usize) {
+        self.wpos -= min(count, self.available_read());
+    }
+
     pub fn clean(&mut self) {
         self.rpos = 0;
         self.wpos = 0;
     }
 
+    /// Moves the unread data to the beginning
     fn shift(&mut self) {
         if self.rpos != self.wpos {
-            unsafe {
-                core::ptr::copy(
-                    &self.inner.borrow()[self.rpos] as *const u8,
-                    &mut self.inner.borrow_mut()[0] as *mut u8,
-                    self.available_read(),
-                )
-            }
+            // the ranges may overlap, which copy_within handles
+            self.inner.borrow_mut().copy_within(self.rpos..self.wpos, 0);
             self.wpos -= self.rpos;
             self.rpos = 0;
         } else {
@@ -170,4 +180,104 @@ mod tests {
         assert_eq!(10, buf.available_read());
         assert_eq!(0, buf.available_write());
     }
+
+    #[test]
+    fn discard_last_written() {
+        let mut buf = Buffer::new([0u8; 10]);
+        assert_eq!(8, buf.write(&DATA[..8]));
+        buf.discard_last(3);
+        assert_eq!(5, buf.available_read());
+
+        // discard no more than available
+        buf.discard_last(10);
+        assert_eq!(0, buf.available_read());
+    }
+
+    #[test]
+    fn shift_overlapping() {
+        let mut buf = Buffer::new([0u8; 10]);
+        assert_eq!(10, buf.write(&DATA));
+        assert_eq!(Ok::<usize, ()>(3), buf.read(|_buf| Ok(3)));
+
+        // 7 unread bytes are moved by 3, overlapping themselves
+        assert_eq!(3, buf.write(&DATA[..3]));
+        assert_eq!(
+            Ok::<usize, ()>(10),
+            buf.read(|buf| {
+                assert_eq!([3, 4, 5, 6, 7, 8, 9, 0, 1, 2], buf);
+                Ok(10)
+            })
+        );
+    }
+
+    #[test]
+    fn shift_rpos_near_end() {
+        let mut buf = Buffer::new([0u8; 10]);
+        assert_eq!(10, buf.write(&DATA));
+        assert_eq!(Ok::<usize, ()>(9), buf.read(|_buf| Ok(9)));
+
+        assert_eq!(
+            Ok(9),
+            buf.write_all(9, (), |buf| {
+                buf.copy_from_slice(&DATA[..9]);
+                Ok(9)
+            })
+        );
+        assert_eq!(0, buf.available_write());
+        assert_eq!(
+            Ok::<usize, ()>(10),
+            buf.read(|buf| {
+                assert_eq!(9, buf[0]);
+                assert_eq!(DATA[..9], buf[1..]);
+                Ok(10)
+            })
+        );
+    }
+
+    #[test]
+    fn shift_all_read() {
+        let mut buf = Buffer::new([0u8; 10]);
+        assert_eq!(10, buf.write(&DATA));
+        assert_eq!(Ok::<usize, ()>(10), buf.read(|_buf| Ok(10)));
+
+        // nothing to move, the positions are reset
+        assert_eq!(5, buf.write(&DATA[..5]));
+        assert_eq!(0, buf.rpos);
+        assert_eq!(5, buf.wpos);
+    }
+
+    #[test]
+    fn zero_length_read_and_write() {
+        let mut buf = Buffer::new([0u8; 10]);
+        assert_eq!(
+            Ok::<usize, ()>(0),
+            buf.read(|buf| {
+                assert!(buf.is_empty());
+                Ok(0)
+            })
+        );
+        assert_eq!(0, buf.write(&[]));
+        assert_eq!(Ok(0), buf.write_all(0, (), |_buf| Ok(0)));
+
+        assert_eq!(4, buf.write(&DATA[..4]));
+        assert_eq!(Ok::<usize, ()>(0), buf.read(|_buf| Ok(0)));
+        assert_eq!(4, buf.available_read());
+    }
+
+    #[test]
+    fn write_all_overflow() {
+        let mut buf = Buffer::new([0u8; 10]);
+        assert_eq!(8, buf.write(&DATA[..8]));
+        assert_eq!(Err(()), buf.write_all(3, (), |_buf| Ok(3)));
+        assert_eq!(8, buf.available_read());
+    }
+
+    #[test]
+    fn free_space_after_read() {
+        let mut buf = Buffer::new([0u8; 10]);
+        assert_eq!(8, buf.write(&DATA[..8]));
+        assert_eq!(Ok::<usize, ()>(6), buf.read(|_buf| Ok(6)));
+        assert_eq!(2, buf.available_write());
+        assert_eq!(8, buf.free_space());
+    }
 }
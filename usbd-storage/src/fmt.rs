@@ -1,12 +1,21 @@
 #![allow(unused_macros)]
 #![allow(unused_imports)]
 
+// `log-*` features prune the levels below the selected one at compile time.
+// If several are enabled, the most restrictive one wins
+
 macro_rules! trace {
     ($s:literal $(, $x:expr)* $(,)?) => {
         {
-            #[cfg(feature = "defmt")]
+            #[cfg(all(
+                feature = "defmt",
+                not(any(feature = "log-debug", feature = "log-info", feature = "log-off"))
+            ))]
             ::defmt::trace!($s $(, $x)*);
-            #[cfg(not(feature="defmt"))]
+            #[cfg(not(all(
+                feature = "defmt",
+                not(any(feature = "log-debug", feature = "log-info", feature = "log-off"))
+            )))]
             let _ = ($( & $x ),*);
         }
     };
@@ -15,9 +24,9 @@ macro_rules! trace {
 macro_rules! info {
     ($s:literal $(, $x:expr)* $(,)?) => {
         {
-            #[cfg(feature = "defmt")]
+            #[cfg(all(feature = "defmt", not(feature = "log-off")))]
             ::defmt::info!($s $(, $x)*);
-            #[cfg(not(feature="defmt"))]
+            #[cfg(not(all(feature = "defmt", not(feature = "log-off"))))]
             let _ = ($( & $x ),*);
         }
     };
@@ -26,9 +35,15 @@ macro_rules! info {
 macro_rules! debug {
     ($s:literal $(, $x:expr)* $(,)?) => {
         {
-            #[cfg(feature = "defmt")]
+            #[cfg(all(
+                feature = "defmt",
+                not(any(feature = "log-info", feature = "log-off"))
+            ))]
             ::defmt::debug!($s $(, $x)*);
-            #[cfg(not(feature="defmt"))]
+            #[cfg(not(all(
+                feature = "defmt",
+                not(any(feature = "log-info", feature = "log-off"))
+            )))]
             let _ = ($( & $x ),*);
         }
     };
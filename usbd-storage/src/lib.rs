@@ -3,11 +3,11 @@
 //! # Subclasses:
 //! * [SCSI] - SCSI device
 //! * [UFI] - USB Floppy Interface
-//! * [Vendor Specific subclass] - implement [Transport] trait
+//! * [Vendor Specific subclass] - over any [Transport]
 //!
 //! # Transports:
 //! * [Bulk Only]
-//! * [Vendor Specific Transport]
+//! * [Vendor Specific Transport] - implement [Transport] trait, e.g. after [Simple Vendor Transport]
 //!
 //! # Features
 //! | Feature | Description                           |
@@ -15,21 +15,38 @@
 //! | `bbb` | Include Bulk Only Transport           |
 //! | `scsi` | Include SCSI subclass                 |
 //! | `ufi` | Include USB Floppy Interface sublcass |
+//! | `vendor` | Include Simple Vendor Transport and Vendor Specific subclass |
+//! | `history` | Keep a ring of the last completed SCSI commands |
+//! | `names` | Spec names of SCSI commands, also used in logs |
+//! | `metrics` | Histograms of transfer lengths and gaps between commands |
 //! | `defmt` | Enable logging via [defmt](https://crates.io/crates/defmt) crate |
+//! | `test-util` | Include command block serializers symmetric with the parsers |
+//! | `csw-faults` | Allow injecting invalid CSWs to exercise the recovery of host drivers |
+//! | `embedded-io` | Include `embedded-io` streams over a SCSI block device and a block device over a stream |
+//! | `embedded-io-async` | Implement the `embedded-io-async` traits of the streams over a SCSI block device |
+//! | `std` | Implement `std::error::Error` and include `Vec` based helpers of `test-util` |
+//! | `log-trace` | Keep all logging. The default |
+//! | `log-debug` | Prune `trace` logging at compile time |
+//! | `log-info` | Prune `trace` and `debug` logging at compile time |
+//! | `log-off` | Prune all logging at compile time |
 //!
 //! [usb-device]: https://crates.io/crates/usb-device
 //! [SCSI]: crate::subclass::scsi
 //! [UFI]: crate::subclass::ufi
 //! [Bulk Only]: crate::transport::bbb
-//! [Vendor Specific subclass]: crate::subclass
+//! [Vendor Specific subclass]: crate::subclass::vendor
 //! [Vendor Specific Transport]: crate::transport
+//! [Simple Vendor Transport]: crate::transport::vendor
 //! [Transport]: crate::transport::Transport
 
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+#![forbid(unsafe_code)]
 
 #[cfg(feature = "bbb")]
 pub(crate) mod buffer;
 pub(crate) mod fmt;
+pub mod quirks;
+pub mod reenumerate;
 pub mod subclass;
 pub mod transport;
 
@@ -0,0 +1,85 @@
+//! Host-specific workarounds
+//!
+//! All the quirks are disabled by default, which is the behavior described by the specifications.
+
+/// Toggles for host-specific workarounds
+///
+/// ```
+/// use usbd_storage::quirks::Quirks;
+///
+/// let mut quirks = Quirks::default();
+/// quirks.zlp_on_short_in = true;
+/// ```
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Quirks {
+    /// How to respond to GET MAX LUN. See [GetMaxLun]
+    pub get_max_lun: GetMaxLun,
+    /// End IN data shorter than expected by the host with a short packet, or a zero length packet
+    /// if the last one was full, instead of stalling the IN endpoint
+    pub zlp_on_short_in: bool,
+    /// Report the Caching mode page in response to MODE SENSE for all pages (page code `0x3F`)
+    pub mode_sense_all_pages: bool,
+    /// Pad IN data of a passed command shorter than expected by the host with the byte, e.g.
+    /// `0x00` or the `0xF6` format filler, instead of ending it short. Takes precedence over
+    /// [zlp_on_short_in]. Spares the hosts resetting a device on short reads of sparse media
+    ///
+    /// [zlp_on_short_in]: Quirks::zlp_on_short_in
+    pub fill_short_in: Option<u8>,
+    /// Answer the Supported VPD Pages and the Device Identification pages of INQUIRY once
+    /// standard INQUIRY data is registered, instead of passing them to the user. Asked for by
+    /// macOS on attach
+    pub vpd_pages: bool,
+    /// How the bulk endpoints stalled by the transport get unstalled. See [Unstall]
+    pub unstall: Unstall,
+}
+
+impl Quirks {
+    /// The workarounds of a device meant for macOS hosts: [vpd_pages]. INQUIRY of the 4096
+    /// bytes macOS allocates needs none, the data is ended short as with any other host
+    ///
+    /// [vpd_pages]: Quirks::vpd_pages
+    pub fn macos() -> Self {
+        Self {
+            vpd_pages: true,
+            ..Self::default()
+        }
+    }
+}
+
+/// Response to the GET MAX LUN request of the Bulk Only Transport
+///
+/// Requests with `wValue` other than zero are stalled regardless of the policy. The response
+/// is truncated to `wLength`, e.g. a request of zero `wLength` gets an empty data stage.
+/// BOT spec. section 3.2
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum GetMaxLun {
+    /// Respond with the max index of the Logical Unit
+    #[default]
+    Respond,
+    /// Respond with zero regardless of the number of Logical Units. The host addresses
+    /// the first Logical Unit only
+    RespondZero,
+    /// Stall if there is a single Logical Unit, respond with the max index otherwise.
+    /// Allowed by the BOT spec. section 3.2
+    StallSingleLun,
+}
+
+/// Unstalling of the bulk endpoints of the Bulk Only Transport
+///
+/// A USB bus reset unstalls both endpoints regardless of the policy. So does the host clearing
+/// halt of an endpoint stalled outside of Reset Recovery, e.g. after IN data ended short.
+/// BOT spec. section 5.3.4 and 6.6
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Unstall {
+    /// The endpoints stay stalled over Bulk-Only Mass Storage Reset until the host clears
+    /// their halt. Clearing halt before the reset, e.g. after an invalid CBW, keeps them stalled
+    #[default]
+    ClearFeature,
+    /// Bulk-Only Mass Storage Reset also unstalls both endpoints, completing Reset Recovery
+    /// right away. Spares the hosts not clearing halt after the reset
+    Reset,
+}
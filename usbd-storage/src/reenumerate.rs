@@ -0,0 +1,32 @@
+//! Forcing the host to re-enumerate the device
+//!
+//! The host caches what it has learned about the device on enumeration. Changing the number of
+//! Logical Units, switching a unit to read-only or completing a firmware update is only picked
+//! up reliably once the device is enumerated again.
+
+use usb_device::bus::UsbBus;
+use usb_device::device::UsbDevice;
+use usb_device::UsbError;
+
+/// Forces the host to reset and re-enumerate the device
+///
+/// Simulates a disconnect with [UsbDevice::force_reset] if the USB peripheral supports it.
+/// Otherwise, `detach` is called, which is expected to disconnect the device from the bus and
+/// connect it back, e.g. by driving D+ low for at least 10 ms as the examples do on start.
+///
+/// The subclasses report the following bus reset via `take_reset`.
+///
+/// # Errors
+/// Errors of [UsbDevice::force_reset] other than [UsbError::Unsupported]
+pub fn force_reenumeration<B: UsbBus>(
+    device: &mut UsbDevice<'_, B>,
+    detach: impl FnOnce(),
+) -> Result<(), UsbError> {
+    match device.force_reset() {
+        Err(UsbError::Unsupported) => {
+            detach();
+            Ok(())
+        }
+        res => res,
+    }
+}
@@ -1,21 +1,33 @@
 //! USB Mass Storage subclasses
 
-#[cfg(all(feature = "bbb", feature = "scsi"))]
-use crate::subclass::scsi::{Scsi, ScsiCommand};
 #[cfg(all(feature = "bbb", feature = "ufi"))]
 use crate::subclass::ufi::{Ufi, UfiCommand};
+#[cfg(all(feature = "bbb", feature = "scsi"))]
+use {
+    crate::subclass::scsi::{implied_data, sense::Sense, Scsi, ScsiCommand, WriteChunk},
+    core::cmp::min,
+};
 #[cfg(all(any(feature = "scsi", feature = "ufi"), feature = "bbb"))]
 use {
-    crate::transport::bbb::{BulkOnly, BulkOnlyError},
+    crate::transport::bbb::{BulkOnly, BulkOnlyError, CommandPhase, DataInChunks, WriteHint},
     crate::transport::{CommandStatus, TransportError},
     core::borrow::BorrowMut,
     usb_device::bus::UsbBus,
+    usb_device::{UsbDirection, UsbError},
+};
+
+#[cfg(any(feature = "scsi", feature = "ufi", feature = "vendor"))]
+use usb_device::{
+    bus::InterfaceNumber,
+    control::{Recipient, Request},
 };
 
 #[cfg(feature = "scsi")]
 pub mod scsi;
 #[cfg(feature = "ufi")]
 pub mod ufi;
+#[cfg(feature = "vendor")]
+pub mod vendor;
 
 /// The subclass' command and a LUN it is addressed to
 pub struct Command<'a, Kind, Class> {
@@ -25,6 +37,26 @@ pub struct Command<'a, Kind, Class> {
     pub lun: u8,
 }
 
+/// The subclass' command dropped by a reset or abandoned by the host before its status has been set
+///
+/// Any resources acquired to serve the command, e.g. a DMA transfer, are expected to be released.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Aborted<Kind> {
+    pub kind: Kind,
+    pub lun: u8,
+    /// `dCBWTag` of the command
+    pub tag: u32,
+    /// Operation code of the command block
+    pub opcode: u8,
+    /// Address of the first block of a command transferring blocks
+    pub lba: Option<u64>,
+    /// Number of bytes of the data transfer sent or received over the bus before the command
+    /// was dropped. E.g. a partially received Write has to be rolled back past the block
+    /// `lba + transferred / block size`
+    pub transferred: u32,
+}
+
 /// [UFI] over [Bulk Only Transport] command
 ///
 /// [UFI]: crate::subclass::ufi::Ufi
@@ -33,6 +65,14 @@ pub struct Command<'a, Kind, Class> {
 impl<'a, 'alloc, Bus: UsbBus + 'alloc, Buf: BorrowMut<[u8]>>
     Command<'a, UfiCommand, Ufi<BulkOnly<'alloc, Bus, Buf>>>
 {
+    /// Returns the raw command block as declared by `bCBWCBLength` of the CBW
+    pub fn command_block(&self) -> &[u8] {
+        self.class
+            .transport
+            .get_command()
+            .map_or(&[], |cb| cb.bytes)
+    }
+
     /// [crate::transport::bbb::BulkOnly::read_data]
     pub fn read_data(&mut self, dst: &mut [u8]) -> Result<usize, TransportError<BulkOnlyError>> {
         self.class.transport.read_data(dst)
@@ -43,21 +83,66 @@ impl<'a, 'alloc, Bus: UsbBus + 'alloc, Buf: BorrowMut<[u8]>>
         self.class.transport.write_data(src)
     }
 
+    /// [crate::transport::bbb::BulkOnly::write_data_hinted]
+    pub fn write_data_hinted(
+        &mut self,
+        src: &[u8],
+    ) -> Result<WriteHint, TransportError<BulkOnlyError>> {
+        self.class.transport.write_data_hinted(src)
+    }
+
     /// [crate::transport::bbb::BulkOnly::try_write_data_all]
     pub fn try_write_data_all(&mut self, src: &[u8]) -> Result<(), TransportError<BulkOnlyError>> {
         self.class.transport.try_write_data_all(src)
     }
 
+    /// [crate::transport::bbb::BulkOnly::data_in_chunks]
+    pub fn data_in_chunks(
+        &mut self,
+        max_len: usize,
+    ) -> Result<DataInChunks<'_, 'alloc, Bus, Buf>, TransportError<BulkOnlyError>> {
+        self.class.transport.data_in_chunks(max_len)
+    }
+
+    /// [crate::transport::bbb::BulkOnly::data_residue]
+    pub fn data_residue(&self) -> u32 {
+        self.class.transport.data_residue()
+    }
+
+    /// Direction and number of bytes of the data the host expects, as declared by the CBW.
+    /// `None` if the host expects no data
+    pub fn host_expects(&self) -> Option<(UsbDirection, u32)> {
+        let transport = &self.class.transport;
+        transport
+            .data_direction()
+            .map(|direction| (direction, transport.data_transfer_len()))
+    }
+
+    /// Returns how far the command has got, so that a callback called again resumes where
+    /// the previous call has stopped. See [crate::transport::bbb::BulkOnly::phase]
+    pub fn phase(&self) -> CommandPhase {
+        self.class
+            .transport
+            .phase()
+            .unwrap_or(CommandPhase::CommandReceived)
+    }
+
     pub fn pass(self) {
-        self.class.transport.set_status(CommandStatus::Passed);
+        let _ = self.class.transport.send_status(CommandStatus::Passed);
     }
 
     pub fn fail(self) {
-        self.class.transport.set_status(CommandStatus::Failed);
+        let _ = self.class.transport.send_status(CommandStatus::Failed);
+    }
+
+    /// Tells that the IN data isn't ready yet, so the command is handed over again on the next
+    /// poll instead of right away. See [crate::transport::bbb::BulkOnly::set_data_pending]
+    pub fn pending(self) {
+        self.class.transport.set_data_pending();
     }
 
     pub fn fail_phase(self) {
-        self.class.transport.set_status(CommandStatus::PhaseError);
+        let _ = self.class.transport.send_status(CommandStatus::PhaseError);
     }
 }
 
@@ -69,6 +154,14 @@ impl<'a, 'alloc, Bus: UsbBus + 'alloc, Buf: BorrowMut<[u8]>>
 impl<'a, 'alloc, Bus: UsbBus + 'alloc, Buf: BorrowMut<[u8]>>
     Command<'a, ScsiCommand, Scsi<BulkOnly<'alloc, Bus, Buf>>>
 {
+    /// Returns the raw command block as declared by `bCBWCBLength` of the CBW
+    pub fn command_block(&self) -> &[u8] {
+        self.class
+            .transport
+            .get_command()
+            .map_or(&[], |cb| cb.bytes)
+    }
+
     /// [crate::transport::bbb::BulkOnly::read_data]
     pub fn read_data(&mut self, dst: &mut [u8]) -> Result<usize, TransportError<BulkOnlyError>> {
         self.class.transport.read_data(dst)
@@ -79,20 +172,214 @@ impl<'a, 'alloc, Bus: UsbBus + 'alloc, Buf: BorrowMut<[u8]>>
         self.class.transport.write_data(src)
     }
 
+    /// [crate::transport::bbb::BulkOnly::write_data_hinted]
+    pub fn write_data_hinted(
+        &mut self,
+        src: &[u8],
+    ) -> Result<WriteHint, TransportError<BulkOnlyError>> {
+        self.class.transport.write_data_hinted(src)
+    }
+
     /// [crate::transport::bbb::BulkOnly::try_write_data_all]
     pub fn try_write_data_all(&mut self, src: &[u8]) -> Result<(), TransportError<BulkOnlyError>> {
         self.class.transport.try_write_data_all(src)
     }
 
+    /// [crate::transport::bbb::BulkOnly::data_in_chunks]
+    pub fn data_in_chunks(
+        &mut self,
+        max_len: usize,
+    ) -> Result<DataInChunks<'_, 'alloc, Bus, Buf>, TransportError<BulkOnlyError>> {
+        self.class.transport.data_in_chunks(max_len)
+    }
+
+    /// [crate::transport::bbb::BulkOnly::data_residue]
+    pub fn data_residue(&self) -> u32 {
+        self.class.transport.data_residue()
+    }
+
+    /// Returns how far the command has got, so that a callback called again resumes where
+    /// the previous call has stopped. See [crate::transport::bbb::BulkOnly::phase]
+    pub fn phase(&self) -> CommandPhase {
+        self.class
+            .transport
+            .phase()
+            .unwrap_or(CommandPhase::CommandReceived)
+    }
+
+    /// Direction and number of bytes of the data the host expects, as declared by the CBW.
+    /// `None` if the host expects no data. Compared with [cdb_implies] tells which of the cases
+    /// of BBB 6.7 the command is: the device transfers no more than the least of the two
+    /// lengths, reports the rest of the host's one as residue, and fails with Phase Error
+    /// if the directions differ or the device has to transfer more than the host expects.
+    /// The subclass checks the direction, and the length of READ and WRITE commands, before
+    /// handing a command over
+    ///
+    /// [cdb_implies]: Self::cdb_implies
+    pub fn host_expects(&self) -> Option<(UsbDirection, u32)> {
+        let transport = &self.class.transport;
+        transport
+            .data_direction()
+            .map(|direction| (direction, transport.data_transfer_len()))
+    }
+
+    /// Direction and number of bytes of the data the command block implies, `None` if it implies
+    /// none or the command is not known. For a command with an allocation length, e.g. INQUIRY,
+    /// it's the most the device may return. Block counts are converted with the block size of
+    /// the Logical Unit. See [host_expects]
+    ///
+    /// [host_expects]: Self::host_expects
+    pub fn cdb_implies(&self) -> Option<(UsbDirection, u64)> {
+        implied_data(self.kind, self.class.block_size(self.lun))
+    }
+
+    /// Hands the [Write] command data received so far to `f` piece by piece, along with the
+    /// position of each piece on the medium. The pieces are split at block boundaries, so a block
+    /// is assembled by the handler out of as many pieces as it takes. Allows serving the command
+    /// with an IO buffer as small as a single packet.
+    ///
+    /// Returns `true` once all the blocks of the command have been handed over. Any data sent by
+    /// the host past the last block is left in the IO buffer.
+    ///
+    /// # Errors
+    /// Returns [BulkOnlyError::InvalidState] if the command is neither a [Write], a
+    /// [WriteAndVerify] nor a [Verify] with `byte_check` or if called during any but OUT Data
+    /// Transfer state.
+    ///
+    /// [Write]: crate::subclass::scsi::ScsiCommand::Write
+    /// [WriteAndVerify]: crate::subclass::scsi::ScsiCommand::WriteAndVerify
+    /// [Verify]: crate::subclass::scsi::ScsiCommand::Verify
+    /// [BulkOnlyError::InvalidState]: crate::transport::bbb::BulkOnlyError::InvalidState
+    pub fn read_write_chunks(
+        &mut self,
+        mut f: impl FnMut(WriteChunk),
+    ) -> Result<bool, TransportError<BulkOnlyError>> {
+        self.read_write_chunks_while(|chunk| {
+            f(chunk);
+            true
+        })
+    }
+
+    /// Same as [read_write_chunks], but stops at the first piece `f` returns `false` for, which
+    /// is left in the IO buffer
+    ///
+    /// [read_write_chunks]: Self::read_write_chunks
+    pub(crate) fn read_write_chunks_while(
+        &mut self,
+        mut f: impl FnMut(WriteChunk) -> bool,
+    ) -> Result<bool, TransportError<BulkOnlyError>> {
+        let (ScsiCommand::Write { lba, len }
+        | ScsiCommand::WriteAndVerify { lba, len, .. }
+        | ScsiCommand::Verify {
+            lba,
+            len,
+            byte_check: true,
+        }) = self.kind
+        else {
+            return Err(TransportError::Error(BulkOnlyError::InvalidState));
+        };
+        let block_size = self.class.block_size(self.lun).get() as u64;
+        let total = len.saturating_mul(block_size);
+
+        loop {
+            let pos = self.class.transport.data_consumed() as u64;
+            let count = self.class.transport.read_data_with(|bytes| {
+                let offset_in_block = (pos % block_size) as usize;
+                let count = min(
+                    min(bytes.len() as u64, block_size - offset_in_block as u64),
+                    total.saturating_sub(pos),
+                ) as usize;
+                let chunk = WriteChunk {
+                    lba: lba + pos / block_size,
+                    offset_in_block,
+                    bytes: &bytes[..count],
+                };
+                if count > 0 && f(chunk) {
+                    count
+                } else {
+                    0
+                }
+            })?;
+            if count == 0 {
+                break;
+            }
+        }
+        Ok(self.class.transport.data_consumed() as u64 >= total)
+    }
+
+    /// Whether the command is a [Read] starting at the block that follows the last [Read]
+    /// passed for the same Logical Unit. Allows a backend to keep a multi-block read of the
+    /// medium open across commands instead of starting a new one for each.
+    ///
+    /// The continuity is broken by any other command passed to the user, a [Read] that hasn't
+    /// passed and a bus reset. A Bulk-Only Mass Storage Reset is reported by [Scsi::take_reset]
+    /// and is expected to be checked by the backend itself.
+    ///
+    /// [Read]: crate::subclass::scsi::ScsiCommand::Read
+    pub fn continues_previous(&self) -> bool {
+        self.class.continues_read(self.kind, self.lun)
+    }
+
+    /// Whether the command is a [Write] with FUA (Force Unit Access) set: the blocks are
+    /// expected to be on the medium, not only in a write cache, once the command passes
+    ///
+    /// [Write]: crate::subclass::scsi::ScsiCommand::Write
+    pub fn fua(&self) -> bool {
+        matches!(self.kind, ScsiCommand::Write { .. })
+            && self
+                .command_block()
+                .get(1)
+                .is_some_and(|b| b & 0b00001000 != 0)
+    }
+
     pub fn pass(self) {
-        self.class.transport.set_status(CommandStatus::Passed);
+        self.finish(CommandStatus::Passed);
     }
 
     pub fn fail(self) {
-        self.class.transport.set_status(CommandStatus::Failed);
+        self.finish(CommandStatus::Failed);
+    }
+
+    /// Fails the command setting sense data to be reported with the next REQUEST SENSE.
+    /// See [Scsi::set_sense]
+    pub fn fail_with_sense(self, sense: Sense) {
+        self.class.set_sense(self.lun, sense);
+        self.finish(CommandStatus::Failed);
+    }
+
+    /// Tells that the IN data isn't ready yet, so the command is handed over again on the next
+    /// poll instead of right away. See [crate::transport::bbb::BulkOnly::set_data_pending]
+    pub fn pending(self) {
+        self.class.transport.set_data_pending();
     }
 
     pub fn fail_phase(self) {
-        self.class.transport.set_status(CommandStatus::PhaseError);
+        self.finish(CommandStatus::PhaseError);
+    }
+
+    fn finish(self, status: CommandStatus) {
+        self.class.track_completed(self.kind, self.lun, status);
+        #[cfg(feature = "history")]
+        self.class.record(self.kind, self.lun, status);
+        let _ = self.class.transport.send_status(status);
+    }
+}
+
+/// Whether `req` is addressed to an interface other than the subclass' one, e.g. a class request
+/// of another function of a composite device. Such requests are not forwarded to the transport
+#[cfg(any(feature = "scsi", feature = "ufi", feature = "vendor"))]
+fn addressed_elsewhere(req: &Request, interface: InterfaceNumber) -> bool {
+    req.recipient == Recipient::Interface && req.index as u8 != u8::from(interface)
+}
+
+/// Maps a result of driving a transport to the result of a subclass' poll.
+/// `WouldBlock` and the errors of the transport itself are not reported
+#[cfg(all(any(feature = "scsi", feature = "ufi"), feature = "bbb"))]
+fn map_ignore<T>(res: Result<T, TransportError<BulkOnlyError>>) -> Result<(), UsbError> {
+    match res {
+        Ok(_) | Err(TransportError::Usb(UsbError::WouldBlock)) | Err(TransportError::Error(_)) => {
+            Ok(())
+        }
+        Err(TransportError::Usb(err)) => Err(err),
     }
 }
@@ -0,0 +1,488 @@
+//! Block devices completing their transfers later
+//!
+//! [AsyncBlockDevice] is a [BlockDevice] for slow media, e.g. SPI flash or an SD card over SPI
+//! with DMA: a block is read or written over several calls, each returning [Poll::Pending] until
+//! the transfer completes. [AsyncBlockDriver] serves commands with it without blocking the poll
+//! loop. A command waiting for the device is left without a status, so the subclass hands it over
+//! again on the next poll and the driver resumes it where it has stopped.
+//!
+//! The waker of the [Context] is woken by the device once it makes progress, e.g. from the DMA
+//! interrupt, to schedule the task handling the commands with an async executor. A plain poll
+//! loop needs no executor: it passes a context of [Waker::noop] and the driver polls the device
+//! on each call.
+//!
+//! [BlockDevice]: crate::subclass::scsi::block::BlockDevice
+//! [Waker::noop]: core::task::Waker::noop
+
+use crate::subclass::scsi::capacity::BlockSize;
+use crate::subclass::scsi::inquiry::InquiryData;
+use crate::subclass::scsi::sense::Sense;
+use core::borrow::BorrowMut;
+use core::task::{Context, Poll};
+#[cfg(feature = "bbb")]
+use {
+    crate::subclass::scsi::block::{check_blocks, inquiry},
+    crate::subclass::scsi::{Scsi, ScsiCommand},
+    crate::subclass::Command,
+    crate::transport::bbb::{BulkOnly, CommandPhase},
+    usb_device::bus::UsbBus,
+};
+
+/// A medium read and written one block at a time, each block over as many calls as it takes
+///
+/// A transfer is started by the first call for a block and polled by the next calls for the same
+/// block, each with the same buffer, until it returns [Poll::Ready]. A call for another block or
+/// another transfer abandons the previous one, e.g. after the host has reset the command. Once
+/// returning [Poll::Pending], the device is expected to wake the waker of `cx` as soon as
+/// calling it again makes progress
+pub trait AsyncBlockDevice {
+    /// Size of a block. Expected not to change while the device is attached
+    fn block_size(&self) -> BlockSize;
+
+    /// Number of blocks of the medium
+    fn num_blocks(&self) -> u64;
+
+    /// Reads the block `lba` into `block`, exactly [block_size] long
+    ///
+    /// [block_size]: AsyncBlockDevice::block_size
+    fn poll_read_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        lba: u64,
+        block: &mut [u8],
+    ) -> Poll<Result<(), Sense>>;
+
+    /// Writes `block`, exactly [block_size] long, to the block `lba`
+    ///
+    /// [block_size]: AsyncBlockDevice::block_size
+    fn poll_write_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        lba: u64,
+        block: &[u8],
+    ) -> Poll<Result<(), Sense>>;
+
+    /// Puts the blocks written so far on the medium, e.g. of a write cache. Called before
+    /// a Write with [fua] passes. Completes right away by default
+    ///
+    /// [fua]: crate::subclass::Command::fua
+    fn poll_flush(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Sense>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Standard INQUIRY data of the Logical Unit, see [BlockDevice::inquiry_data]
+    ///
+    /// [BlockDevice::inquiry_data]: crate::subclass::scsi::block::BlockDevice::inquiry_data
+    fn inquiry_data(&self) -> Option<InquiryData> {
+        None
+    }
+}
+
+impl<D: AsyncBlockDevice + ?Sized> AsyncBlockDevice for &mut D {
+    fn block_size(&self) -> BlockSize {
+        (**self).block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (**self).num_blocks()
+    }
+
+    fn poll_read_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        lba: u64,
+        block: &mut [u8],
+    ) -> Poll<Result<(), Sense>> {
+        (**self).poll_read_block(cx, lba, block)
+    }
+
+    fn poll_write_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        lba: u64,
+        block: &[u8],
+    ) -> Poll<Result<(), Sense>> {
+        (**self).poll_write_block(cx, lba, block)
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Sense>> {
+        (**self).poll_flush(cx)
+    }
+
+    fn inquiry_data(&self) -> Option<InquiryData> {
+        (**self).inquiry_data()
+    }
+}
+
+/// The transfer an [AsyncBlockDriver] waits for the device to complete
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+enum Transfer {
+    /// The block received last is being written
+    Write(u64),
+    /// The block written last, or received last by a Verify, is being read back
+    ReadBack(u64),
+    /// The device is being flushed once all the blocks of a Write with FUA are written
+    Flush,
+    /// The block is being read by a Verify without a data transfer
+    Verify(u64),
+}
+
+/// Serves Read, Write, WriteAndVerify and Verify commands with an [AsyncBlockDevice]
+///
+/// Same as [BlockDriver], the data goes through `buf`, a buffer of two blocks. Register the
+/// device with [Scsi::set_async_block_device], so that the subclass splits the data at the blocks
+/// of the device and answers the capacity commands.
+///
+/// ```
+/// use core::task::{Context, Poll, Waker};
+/// use usbd_storage::subclass::scsi::async_block::{AsyncBlockDevice, AsyncBlockDriver};
+/// use usbd_storage::subclass::scsi::capacity::BlockSize;
+/// use usbd_storage::subclass::scsi::sense::Sense;
+///
+/// /// Completes each transfer on the second call
+/// struct Flash {
+///     busy: bool,
+/// }
+///
+/// impl AsyncBlockDevice for Flash {
+///     fn block_size(&self) -> BlockSize {
+///         BlockSize::B512
+///     }
+///
+///     fn num_blocks(&self) -> u64 {
+///         8
+///     }
+///
+///     fn poll_read_block(
+///         &mut self,
+///         cx: &mut Context<'_>,
+///         lba: u64,
+///         block: &mut [u8],
+///     ) -> Poll<Result<(), Sense>> {
+///         self.busy = !self.busy;
+///         if self.busy {
+///             cx.waker().wake_by_ref();
+///             return Poll::Pending;
+///         }
+///         block.fill(lba as u8);
+///         Poll::Ready(Ok(()))
+///     }
+///
+///     fn poll_write_block(
+///         &mut self,
+///         _: &mut Context<'_>,
+///         _: u64,
+///         _: &[u8],
+///     ) -> Poll<Result<(), Sense>> {
+///         Poll::Ready(Err(Sense::WRITE_PROTECTED))
+///     }
+/// }
+///
+/// let mut driver = AsyncBlockDriver::new([0u8; 2 * 512]);
+/// let mut flash = Flash { busy: false };
+/// let mut cx = Context::from_waker(Waker::noop());
+/// // scsi.set_async_block_device(0, &flash);
+/// // scsi.poll(|cmd| {
+/// //     if let Some(cmd) = driver.poll_handle(&mut cx, &mut flash, cmd) {
+/// //         /* the rest of the commands */
+/// //     }
+/// // });
+/// ```
+///
+/// [BlockDriver]: crate::subclass::scsi::block::BlockDriver
+pub struct AsyncBlockDriver<Buf: BorrowMut<[u8]>> {
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    buf: Buf,
+    /// The LUN and the block read into the buffer by the current Read. Dropped once the buffer
+    /// holds anything else and before a new Read
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    cached: Option<(u8, u64)>,
+    /// The tag and the LUN of the command waiting for `Transfer` to complete
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    pending: Option<(u32, u8, Transfer)>,
+}
+
+impl<Buf: BorrowMut<[u8]>> AsyncBlockDriver<Buf> {
+    /// Creates a driver transferring the data through `buf`
+    ///
+    /// # Panics
+    /// Panics if `buf` doesn't fit two blocks of the smallest size, 512 bytes
+    pub fn new(mut buf: Buf) -> Self {
+        assert!(
+            buf.borrow_mut().len() >= 2 * BlockSize::B512.get() as usize,
+            "the buffer is expected to fit two blocks"
+        );
+        Self {
+            buf,
+            cached: None,
+            pending: None,
+        }
+    }
+}
+
+#[cfg(feature = "bbb")]
+impl<Buf: BorrowMut<[u8]>> AsyncBlockDriver<Buf> {
+    /// Serves `command` if it's a Read, a Write, a WriteAndVerify or a Verify, returns it back
+    /// otherwise
+    ///
+    /// Serves the commands as [BlockDriver::handle] does, but leaves a command working with the
+    /// device without a status until the device completes, IN data reported pending. Called
+    /// again with the same command, the driver polls the device again and resumes the transfer.
+    /// The data of the host is left in the IO buffer meanwhile, so the host is NAKed once it's
+    /// full. A transfer of a command dropped by a reset is abandoned once a new command comes.
+    ///
+    /// # Panics
+    /// Panics if the buffer doesn't fit two blocks of `device`
+    ///
+    /// [BlockDriver::handle]: crate::subclass::scsi::block::BlockDriver::handle
+    pub fn poll_handle<'a, 'alloc, D, Bus, IoBuf>(
+        &mut self,
+        cx: &mut Context<'_>,
+        device: &mut D,
+        command: Command<'a, ScsiCommand, Scsi<BulkOnly<'alloc, Bus, IoBuf>>>,
+    ) -> Option<Command<'a, ScsiCommand, Scsi<BulkOnly<'alloc, Bus, IoBuf>>>>
+    where
+        D: AsyncBlockDevice,
+        Bus: UsbBus + 'alloc,
+        IoBuf: BorrowMut<[u8]>,
+    {
+        let (lba, len) = match command.kind {
+            ScsiCommand::Inquiry {
+                evpd: false,
+                page_code,
+                alloc_len,
+            } => match device.inquiry_data() {
+                Some(data) => {
+                    inquiry(command, &data, page_code, alloc_len);
+                    return None;
+                }
+                None => return Some(command),
+            },
+            ScsiCommand::Read { lba, len }
+            | ScsiCommand::Write { lba, len }
+            | ScsiCommand::WriteAndVerify { lba, len, .. }
+            | ScsiCommand::Verify { lba, len, .. } => (lba, len),
+            _ => return Some(command),
+        };
+        assert!(
+            self.buf.borrow_mut().len() >= 2 * device.block_size().get() as usize,
+            "the buffer is expected to fit two blocks of the device"
+        );
+        // the transfer of another command, e.g. one dropped by a reset, is abandoned
+        let tag = command.class.transport.get_command().map(|cb| cb.tag);
+        let pending = self
+            .pending
+            .take()
+            .filter(|(pending_tag, lun, _)| Some(*pending_tag) == tag && *lun == command.lun)
+            .map(|(_, _, transfer)| transfer);
+        let command = check_blocks(command, device.block_size(), device.num_blocks())?;
+        let (tag, lun) = (tag.unwrap_or_default(), command.lun);
+        let pending = match command.kind {
+            ScsiCommand::Read { .. } => {
+                self.read(cx, device, command, lba, len);
+                None
+            }
+            ScsiCommand::Verify {
+                byte_check: false, ..
+            } => self.verify_medium(cx, device, command, lba, len, pending),
+            _ => self.write(cx, device, command, lba, pending),
+        };
+        self.pending = pending.map(|transfer| (tag, lun, transfer));
+        None
+    }
+
+    fn read<D: AsyncBlockDevice, Bus: UsbBus, IoBuf: BorrowMut<[u8]>>(
+        &mut self,
+        cx: &mut Context<'_>,
+        device: &mut D,
+        mut command: Command<ScsiCommand, Scsi<BulkOnly<Bus, IoBuf>>>,
+        lba: u64,
+        len: u64,
+    ) {
+        let block_size = device.block_size().get() as u64;
+        let block = &mut self.buf.borrow_mut()[..block_size as usize];
+        let total = len * block_size;
+        // a new command: the medium may have changed since, e.g. after an aborted Read
+        if matches!(command.phase(), CommandPhase::DataIn { sent: 0, .. }) {
+            self.cached = None;
+        }
+        loop {
+            let sent = match command.phase() {
+                CommandPhase::DataIn { sent, .. } => sent as u64,
+                _ => return,
+            };
+            if sent >= total {
+                self.cached = None;
+                command.pass();
+                return;
+            }
+            let current = (command.lun, lba + sent / block_size);
+            if self.cached != Some(current) {
+                self.cached = None;
+                match device.poll_read_block(cx, current.1, block) {
+                    Poll::Pending => {
+                        command.pending();
+                        return;
+                    }
+                    Poll::Ready(Err(sense)) => {
+                        command.fail_with_sense(sense);
+                        return;
+                    }
+                    Poll::Ready(Ok(())) => self.cached = Some(current),
+                }
+            }
+            match command.write_data(&block[(sent % block_size) as usize..]) {
+                Ok(count) if count > 0 => {}
+                // the IO buffer is full, the rest is written on the next call
+                _ => return,
+            }
+        }
+    }
+
+    /// Serves a Verify without a data transfer: the blocks are only checked for being readable.
+    /// Returns the transfer left pending, if any
+    fn verify_medium<D: AsyncBlockDevice, Bus: UsbBus, IoBuf: BorrowMut<[u8]>>(
+        &mut self,
+        cx: &mut Context<'_>,
+        device: &mut D,
+        command: Command<ScsiCommand, Scsi<BulkOnly<Bus, IoBuf>>>,
+        lba: u64,
+        len: u64,
+        pending: Option<Transfer>,
+    ) -> Option<Transfer> {
+        let block_size = device.block_size().get() as usize;
+        let block = &mut self.buf.borrow_mut()[..block_size];
+        self.cached = None;
+        let mut next = match pending {
+            Some(Transfer::Verify(next)) => next,
+            _ => lba,
+        };
+        while next < lba + len {
+            match device.poll_read_block(cx, next, block) {
+                Poll::Pending => return Some(Transfer::Verify(next)),
+                Poll::Ready(Err(sense)) => {
+                    command.fail_with_sense(sense);
+                    return None;
+                }
+                Poll::Ready(Ok(())) => next += 1,
+            }
+        }
+        command.pass();
+        None
+    }
+
+    /// Serves a Write, a WriteAndVerify or a Verify with a byte check. Returns the transfer
+    /// left pending, if any
+    fn write<D: AsyncBlockDevice, Bus: UsbBus, IoBuf: BorrowMut<[u8]>>(
+        &mut self,
+        cx: &mut Context<'_>,
+        device: &mut D,
+        mut command: Command<ScsiCommand, Scsi<BulkOnly<Bus, IoBuf>>>,
+        lba: u64,
+        pending: Option<Transfer>,
+    ) -> Option<Transfer> {
+        let fua = command.fua();
+        // whether the blocks are written, and compared with what is read back if verified
+        let (write, verify) = match command.kind {
+            ScsiCommand::WriteAndVerify { byte_check, .. } => (true, Some(byte_check)),
+            ScsiCommand::Verify { .. } => (false, Some(true)),
+            _ => (true, None),
+        };
+        let block_size = device.block_size().get() as usize;
+        let (block, read_back) = self.buf.borrow_mut().split_at_mut(block_size);
+        let read_back = &mut read_back[..block_size];
+        self.cached = None;
+
+        // the data of the pending block has been consumed, the one of a new command hasn't
+        let consumed = command.class.transport.data_consumed() as u64;
+        let mut pending = pending.filter(|transfer| match *transfer {
+            Transfer::Write(pending) | Transfer::ReadBack(pending) => {
+                pending >= lba && consumed == (pending - lba + 1) * block_size as u64
+            }
+            Transfer::Flush => true,
+            Transfer::Verify(_) => false,
+        });
+        if pending == Some(Transfer::Flush) {
+            return match Self::complete(cx, device, &mut pending, block, read_back, verify) {
+                Err(sense) => {
+                    command.fail_with_sense(sense);
+                    None
+                }
+                Ok(()) if pending.is_none() => {
+                    command.pass();
+                    None
+                }
+                Ok(()) => pending,
+            };
+        }
+
+        let mut error = Self::complete(cx, device, &mut pending, block, read_back, verify).err();
+        let done = command.read_write_chunks_while(|chunk| {
+            // the rest of the data waits for the device
+            if pending.is_some() || error.is_some() {
+                return false;
+            }
+            let end = chunk.offset_in_block + chunk.bytes.len();
+            block[chunk.offset_in_block..end].copy_from_slice(chunk.bytes);
+            if end == block_size {
+                pending = Some(if write {
+                    Transfer::Write(chunk.lba)
+                } else {
+                    Transfer::ReadBack(chunk.lba)
+                });
+                error = Self::complete(cx, device, &mut pending, block, read_back, verify).err();
+            }
+            true
+        });
+        match (error, done) {
+            (Some(sense), _) => command.fail_with_sense(sense),
+            (None, Ok(true)) if pending.is_none() && fua => {
+                pending = Some(Transfer::Flush);
+                match Self::complete(cx, device, &mut pending, block, read_back, verify) {
+                    Err(sense) => command.fail_with_sense(sense),
+                    Ok(()) if pending.is_none() => command.pass(),
+                    Ok(()) => {}
+                }
+            }
+            (None, Ok(true)) if pending.is_none() => command.pass(),
+            // the rest of the data hasn't been received or written yet
+            (None, _) => {}
+        }
+        pending
+    }
+
+    /// Polls the device for the `pending` transfer and the ones following it for the block, until
+    /// the device hasn't completed one. Leaves that one in `pending`
+    fn complete<D: AsyncBlockDevice>(
+        cx: &mut Context<'_>,
+        device: &mut D,
+        pending: &mut Option<Transfer>,
+        block: &[u8],
+        read_back: &mut [u8],
+        verify: Option<bool>,
+    ) -> Result<(), Sense> {
+        while let Some(transfer) = *pending {
+            let poll = match transfer {
+                Transfer::Write(lba) => device.poll_write_block(cx, lba, block),
+                Transfer::ReadBack(lba) | Transfer::Verify(lba) => {
+                    device.poll_read_block(cx, lba, read_back)
+                }
+                Transfer::Flush => device.poll_flush(cx),
+            };
+            let Poll::Ready(result) = poll else {
+                return Ok(());
+            };
+            *pending = None;
+            result?;
+            match (transfer, verify) {
+                (Transfer::Write(lba), Some(_)) => *pending = Some(Transfer::ReadBack(lba)),
+                (Transfer::ReadBack(_), Some(true)) if read_back != block => {
+                    return Err(Sense::MISCOMPARE_DURING_VERIFY);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
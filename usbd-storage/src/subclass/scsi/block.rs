@@ -0,0 +1,646 @@
+//! Block devices served by the subclass
+//!
+//! [BlockDriver] takes Read, Write, WriteAndVerify and Verify commands off the callback and serves
+//! them with a [BlockDevice] one block at a time: it splits the data transfer at block boundaries,
+//! resumes it where the previous call has stopped and passes or fails the command. Devices capable
+//! of multi-block transfers, e.g. SD cards or QSPI flash, are handed as many blocks at once as the
+//! buffer of the driver fits with [BlockDevice::read_blocks] and [BlockDevice::write_blocks]. The rest of
+//! the commands are handed back to the callback. [LunTable] routes the commands of several
+//! Logical Units to their devices.
+
+use crate::subclass::scsi::capacity::BlockSize;
+use crate::subclass::scsi::inquiry::InquiryData;
+use crate::subclass::scsi::sense::Sense;
+use crate::subclass::scsi::Scsi;
+use crate::transport::Transport;
+use core::borrow::BorrowMut;
+#[cfg(feature = "bbb")]
+use {
+    crate::fmt::debug,
+    crate::subclass::scsi::inquiry::{write_standard_inquiry, EXTENDED_INQUIRY_DATA_LEN},
+    crate::subclass::scsi::ScsiCommand,
+    crate::subclass::Command,
+    crate::transport::bbb::{BulkOnly, CommandPhase},
+    core::ops::Range,
+    usb_device::bus::UsbBus,
+};
+
+/// A medium read and written one block at a time, or several consecutive blocks at once
+///
+/// An error is reported to the host as the sense of the failed command, e.g.
+/// [UNRECOVERED_READ_ERROR] of a read. The blocks read before the failing one are still sent to
+/// the host, the blocks of a failing [write_blocks] may be left unwritten
+///
+/// [write_blocks]: BlockDevice::write_blocks
+///
+/// [UNRECOVERED_READ_ERROR]: Sense::UNRECOVERED_READ_ERROR
+pub trait BlockDevice {
+    /// Size of a block. Expected not to change while the device is attached
+    fn block_size(&self) -> BlockSize;
+
+    /// Number of blocks of the medium
+    fn num_blocks(&self) -> u64;
+
+    /// Reads the block `lba` into `block`, exactly [block_size] long
+    ///
+    /// [block_size]: BlockDevice::block_size
+    fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), Sense>;
+
+    /// Writes `block`, exactly [block_size] long, to the block `lba`
+    ///
+    /// [block_size]: BlockDevice::block_size
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), Sense>;
+
+    /// Reads the blocks from `lba` on into `blocks`, a multiple of [block_size] long. Reads one
+    /// block at a time with [read_block] by default
+    ///
+    /// Overridden by devices reading consecutive blocks faster at once, e.g. with READ MULTIPLE
+    /// BLOCK of an SD card. Once it fails, the blocks are read again one at a time, so the ones
+    /// before the failing block are still sent to the host
+    ///
+    /// [block_size]: BlockDevice::block_size
+    /// [read_block]: BlockDevice::read_block
+    fn read_blocks(&mut self, lba: u64, blocks: &mut [u8]) -> Result<(), Sense> {
+        let block_size = self.block_size().get() as usize;
+        blocks
+            .chunks_exact_mut(block_size)
+            .zip(lba..)
+            .try_for_each(|(block, lba)| self.read_block(lba, block))
+    }
+
+    /// Writes `blocks`, a multiple of [block_size] long, to the blocks from `lba` on. Writes one
+    /// block at a time with [write_block] by default
+    ///
+    /// Overridden by devices writing consecutive blocks faster at once, e.g. with WRITE MULTIPLE
+    /// BLOCK of an SD card
+    ///
+    /// [block_size]: BlockDevice::block_size
+    /// [write_block]: BlockDevice::write_block
+    fn write_blocks(&mut self, lba: u64, blocks: &[u8]) -> Result<(), Sense> {
+        let block_size = self.block_size().get() as usize;
+        blocks
+            .chunks_exact(block_size)
+            .zip(lba..)
+            .try_for_each(|(block, lba)| self.write_block(lba, block))
+    }
+
+    /// Number of blocks of a page, the unit the medium is programmed in, e.g. of a flash erased
+    /// a page at a time. 1 by default
+    ///
+    /// [BlockDriver] hands [write_blocks] whole pages only: the blocks of a partial page not
+    /// written by the host are read first, so they are written back as they are
+    ///
+    /// [write_blocks]: BlockDevice::write_blocks
+    fn page_blocks(&self) -> u32 {
+        1
+    }
+
+    /// Puts the blocks written so far on the medium, e.g. of a write cache. Called before
+    /// a Write with [fua] passes. Does nothing by default
+    ///
+    /// [fua]: crate::subclass::Command::fua
+    fn flush(&mut self) -> Result<(), Sense> {
+        Ok(())
+    }
+
+    /// Standard INQUIRY data of the Logical Unit, answered by [BlockDriver] as long as the device
+    /// is registered with [Scsi::set_block_device]. `None` by default, which leaves the unit with
+    /// the data shared by all the units
+    fn inquiry_data(&self) -> Option<InquiryData> {
+        None
+    }
+}
+
+/// Lets devices of different types share a [LunTable] as `&mut dyn BlockDevice`
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+    fn block_size(&self) -> BlockSize {
+        (**self).block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (**self).num_blocks()
+    }
+
+    fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), Sense> {
+        (**self).read_block(lba, block)
+    }
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), Sense> {
+        (**self).write_block(lba, block)
+    }
+
+    fn read_blocks(&mut self, lba: u64, blocks: &mut [u8]) -> Result<(), Sense> {
+        (**self).read_blocks(lba, blocks)
+    }
+
+    fn write_blocks(&mut self, lba: u64, blocks: &[u8]) -> Result<(), Sense> {
+        (**self).write_blocks(lba, blocks)
+    }
+
+    fn page_blocks(&self) -> u32 {
+        (**self).page_blocks()
+    }
+
+    fn flush(&mut self) -> Result<(), Sense> {
+        (**self).flush()
+    }
+
+    fn inquiry_data(&self) -> Option<InquiryData> {
+        (**self).inquiry_data()
+    }
+}
+
+/// Serves Read, Write, WriteAndVerify and Verify commands with a [BlockDevice]
+///
+/// The data goes through `buf`, a buffer of at least two pages, see [page_blocks]: verified
+/// blocks are compared with the ones read back into its second half. Reads and Writes move as
+/// many consecutive blocks at once as the buffer fits. Several devices, e.g. of different Logical
+/// Units, may share a driver, as long as the buffer fits two of the largest pages of them.
+/// Register each device with [Scsi::set_block_device], so that the subclass splits the data
+/// at the blocks of the device and answers the capacity commands.
+///
+/// ```
+/// use usbd_storage::subclass::scsi::block::{BlockDevice, BlockDriver};
+/// use usbd_storage::subclass::scsi::capacity::BlockSize;
+/// use usbd_storage::subclass::scsi::sense::Sense;
+///
+/// struct Ram([u8; 4096]);
+///
+/// impl BlockDevice for Ram {
+///     fn block_size(&self) -> BlockSize {
+///         BlockSize::B512
+///     }
+///
+///     fn num_blocks(&self) -> u64 {
+///         8
+///     }
+///
+///     fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), Sense> {
+///         let start = lba as usize * 512;
+///         block.copy_from_slice(&self.0[start..start + 512]);
+///         Ok(())
+///     }
+///
+///     fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), Sense> {
+///         let start = lba as usize * 512;
+///         self.0[start..start + 512].copy_from_slice(block);
+///         Ok(())
+///     }
+/// }
+///
+/// let mut driver = BlockDriver::new([0u8; 2 * 512]);
+/// let mut ram = Ram([0u8; 4096]);
+/// // scsi.set_block_device(0, &ram);
+/// // scsi.poll(|cmd| {
+/// //     if let Some(cmd) = driver.handle(&mut ram, cmd) {
+/// //         /* the rest of the commands */
+/// //     }
+/// // });
+/// ```
+///
+/// [page_blocks]: BlockDevice::page_blocks
+pub struct BlockDriver<Buf: BorrowMut<[u8]>> {
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    buf: Buf,
+    /// The LUN, the first block and the number of blocks read into the buffer by the current
+    /// Read. Dropped once the buffer holds anything else and before a new Read
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    cached: Option<(u8, u64, u64)>,
+}
+
+impl<Buf: BorrowMut<[u8]>> BlockDriver<Buf> {
+    /// Creates a driver transferring the data through `buf`
+    ///
+    /// # Panics
+    /// Panics if `buf` doesn't fit two blocks of the smallest size, 512 bytes
+    pub fn new(mut buf: Buf) -> Self {
+        assert!(
+            buf.borrow_mut().len() >= 2 * BlockSize::B512.get() as usize,
+            "the buffer is expected to fit two blocks"
+        );
+        Self { buf, cached: None }
+    }
+}
+
+#[cfg(feature = "bbb")]
+impl<Buf: BorrowMut<[u8]>> BlockDriver<Buf> {
+    /// Serves `command` if it's a Read, a Write, a WriteAndVerify or a Verify, returns it back
+    /// otherwise
+    ///
+    /// Called again with the same command, e.g. once the IO buffer has room for more data,
+    /// the driver resumes the transfer where it has stopped. The blocks of a Write are gathered
+    /// in the buffer and written with [write_blocks] once it's full or the last block is received.
+    /// The command is passed once all
+    /// its blocks have been transferred, or failed with the sense of the device error. A command
+    /// addressing blocks beyond [num_blocks] is failed with [LBA_OUT_OF_RANGE] and one of zero
+    /// blocks is passed right away. A Write with [fua] is passed once the device has been
+    /// flushed. A WriteAndVerify reads each block back once written and a Verify reads each block
+    /// of the medium. With [byte_check], either is failed with [MISCOMPARE_DURING_VERIFY] if
+    /// a block doesn't match the data sent by the host. If the block size of
+    /// the Logical Unit differs from the one of `device`, e.g. the device hasn't been registered
+    /// with [Scsi::set_block_device], the command is failed with [LOGICAL_UNIT_NOT_CONFIGURED].
+    /// Standard INQUIRY is answered with the [inquiry_data] of `device`, if any.
+    ///
+    /// # Panics
+    /// Panics if the buffer doesn't fit two pages of `device`, see [page_blocks]
+    ///
+    /// [num_blocks]: BlockDevice::num_blocks
+    /// [write_blocks]: BlockDevice::write_blocks
+    /// [page_blocks]: BlockDevice::page_blocks
+    /// [inquiry_data]: BlockDevice::inquiry_data
+    /// [fua]: Command::fua
+    /// [byte_check]: ScsiCommand::WriteAndVerify::byte_check
+    /// [MISCOMPARE_DURING_VERIFY]: Sense::MISCOMPARE_DURING_VERIFY
+    /// [LBA_OUT_OF_RANGE]: Sense::LBA_OUT_OF_RANGE
+    /// [LOGICAL_UNIT_NOT_CONFIGURED]: Sense::LOGICAL_UNIT_NOT_CONFIGURED
+    pub fn handle<'a, 'alloc, D, Bus, IoBuf>(
+        &mut self,
+        device: &mut D,
+        command: Command<'a, ScsiCommand, Scsi<BulkOnly<'alloc, Bus, IoBuf>>>,
+    ) -> Option<Command<'a, ScsiCommand, Scsi<BulkOnly<'alloc, Bus, IoBuf>>>>
+    where
+        D: BlockDevice,
+        Bus: UsbBus + 'alloc,
+        IoBuf: BorrowMut<[u8]>,
+    {
+        let (lba, len) = match command.kind {
+            ScsiCommand::Inquiry {
+                evpd: false,
+                page_code,
+                alloc_len,
+            } => match device.inquiry_data() {
+                Some(data) => {
+                    inquiry(command, &data, page_code, alloc_len);
+                    return None;
+                }
+                None => return Some(command),
+            },
+            ScsiCommand::Read { lba, len }
+            | ScsiCommand::Write { lba, len }
+            | ScsiCommand::WriteAndVerify { lba, len, .. }
+            | ScsiCommand::Verify { lba, len, .. } => (lba, len),
+            _ => return Some(command),
+        };
+        let page = device.page_blocks().max(1) as usize * device.block_size().get() as usize;
+        assert!(
+            self.buf.borrow_mut().len() >= 2 * page,
+            "the buffer is expected to fit two pages of the device"
+        );
+        if let Some(command) = check_blocks(command, device.block_size(), device.num_blocks()) {
+            match command.kind {
+                ScsiCommand::Read { .. } => self.read(device, command, lba, len),
+                ScsiCommand::Verify {
+                    byte_check: false, ..
+                } => self.verify_medium(device, command, lba, len),
+                _ => self.write(device, command, lba, len),
+            }
+        }
+        None
+    }
+
+    fn read<D: BlockDevice, Bus: UsbBus, IoBuf: BorrowMut<[u8]>>(
+        &mut self,
+        device: &mut D,
+        mut command: Command<ScsiCommand, Scsi<BulkOnly<Bus, IoBuf>>>,
+        lba: u64,
+        len: u64,
+    ) {
+        let block_size = device.block_size().get() as u64;
+        let buf = self.buf.borrow_mut();
+        let capacity = buf.len() as u64 / block_size;
+        let total = len * block_size;
+        // a new command: the medium may have changed since, e.g. after an aborted Read
+        if matches!(command.phase(), CommandPhase::DataIn { sent: 0, .. }) {
+            self.cached = None;
+        }
+        loop {
+            let sent = match command.phase() {
+                CommandPhase::DataIn { sent, .. } => sent as u64,
+                _ => return,
+            };
+            if sent >= total {
+                self.cached = None;
+                command.pass();
+                return;
+            }
+            let current = lba + sent / block_size;
+            let (first, count) = match self.cached {
+                Some((lun, first, count))
+                    if lun == command.lun && (first..first + count).contains(&current) =>
+                {
+                    (first, count)
+                }
+                _ => {
+                    self.cached = None;
+                    let mut count = capacity.min(lba + len - current);
+                    let extent = &mut buf[..(count * block_size) as usize];
+                    if let Err(sense) = device.read_blocks(current, extent) {
+                        // narrows the error down to a block, the ones before it are still sent
+                        count = match count {
+                            1 => 0,
+                            _ => extent
+                                .chunks_exact_mut(block_size as usize)
+                                .zip(current..)
+                                .map(|(block, lba)| device.read_block(lba, block))
+                                .take_while(Result::is_ok)
+                                .count() as u64,
+                        };
+                        if count == 0 {
+                            command.fail_with_sense(sense);
+                            return;
+                        }
+                    }
+                    self.cached = Some((command.lun, current, count));
+                    (current, count)
+                }
+            };
+            let from = (current - first) * block_size + sent % block_size;
+            let to = count * block_size;
+            match command.write_data(&buf[from as usize..to as usize]) {
+                Ok(count) if count > 0 => {}
+                // the IO buffer is full, the rest is written on the next call
+                _ => return,
+            }
+        }
+    }
+
+    /// Serves a Verify without a data transfer: the blocks are only checked for being readable
+    fn verify_medium<D: BlockDevice, Bus: UsbBus, IoBuf: BorrowMut<[u8]>>(
+        &mut self,
+        device: &mut D,
+        command: Command<ScsiCommand, Scsi<BulkOnly<Bus, IoBuf>>>,
+        lba: u64,
+        len: u64,
+    ) {
+        let block_size = device.block_size().get() as u64;
+        let buf = self.buf.borrow_mut();
+        let capacity = buf.len() as u64 / block_size;
+        self.cached = None;
+        let extents = (lba..lba + len).step_by(capacity as usize);
+        match extents
+            .map(|first| (first, capacity.min(lba + len - first)))
+            .try_for_each(|(first, count)| {
+                device.read_blocks(first, &mut buf[..(count * block_size) as usize])
+            }) {
+            Ok(()) => command.pass(),
+            Err(sense) => command.fail_with_sense(sense),
+        }
+    }
+
+    /// Serves a Write, a WriteAndVerify or a Verify with a byte check
+    fn write<D: BlockDevice, Bus: UsbBus, IoBuf: BorrowMut<[u8]>>(
+        &mut self,
+        device: &mut D,
+        mut command: Command<ScsiCommand, Scsi<BulkOnly<Bus, IoBuf>>>,
+        lba: u64,
+        len: u64,
+    ) {
+        let fua = command.fua();
+        // whether the blocks are written, and compared with what is read back if verified
+        let (write, verify) = match command.kind {
+            ScsiCommand::WriteAndVerify { byte_check, .. } => (true, Some(byte_check)),
+            ScsiCommand::Verify { .. } => (false, Some(true)),
+            _ => (true, None),
+        };
+        let block_size = device.block_size().get() as usize;
+        let page = device.page_blocks().max(1) as u64;
+        let buf = self.buf.borrow_mut();
+        // the buffer holds the blocks of an extent aligned to its capacity, each at its own slot,
+        // followed by the blocks read back if verified
+        let blocks = (buf.len() / block_size) as u64;
+        let capacity = match verify {
+            Some(_) => blocks / 2,
+            None => blocks,
+        } / page
+            * page;
+        let (staged, read_back) = buf.split_at_mut(capacity as usize * block_size);
+        let last = lba + len - 1;
+        self.cached = None;
+        let mut error = None;
+        let done = command.read_write_chunks(|chunk| {
+            let slot = (chunk.lba % capacity) as usize * block_size;
+            let end = chunk.offset_in_block + chunk.bytes.len();
+            staged[slot + chunk.offset_in_block..slot + end].copy_from_slice(chunk.bytes);
+            let extent_ends = chunk.lba % capacity == capacity - 1 || chunk.lba == last;
+            if end == block_size && extent_ends && error.is_none() {
+                let start = chunk.lba - chunk.lba % capacity;
+                let extent = Extent {
+                    start,
+                    blocks: start.max(lba)..chunk.lba + 1,
+                    page,
+                };
+                error = extent.write(device, staged, read_back, write, verify).err();
+            }
+        });
+        match (error, done) {
+            (Some(sense), _) => command.fail_with_sense(sense),
+            (None, Ok(true)) if fua => match device.flush() {
+                Ok(()) => command.pass(),
+                Err(sense) => command.fail_with_sense(sense),
+            },
+            (None, Ok(true)) => command.pass(),
+            // the rest of the data hasn't been received yet
+            (None, _) => {}
+        }
+    }
+}
+
+/// Blocks of a Write staged in the buffer of [BlockDriver]
+#[cfg(feature = "bbb")]
+struct Extent {
+    /// The block at the start of the buffer
+    start: u64,
+    /// The blocks received from the host
+    blocks: Range<u64>,
+    /// Number of blocks of a page of the device
+    page: u64,
+}
+
+#[cfg(feature = "bbb")]
+impl Extent {
+    /// Writes the blocks, if `write` is set, and reads them back into `read_back` if `verify` is
+    /// set, comparing them with what's been written if it's `true`
+    ///
+    /// The blocks are written in whole pages: the blocks of the first and the last pages not
+    /// received from the host are read into `staged` first, so they are written back as they are
+    fn write<D: BlockDevice>(
+        &self,
+        device: &mut D,
+        staged: &mut [u8],
+        read_back: &mut [u8],
+        write: bool,
+        verify: Option<bool>,
+    ) -> Result<(), Sense> {
+        let block_size = device.block_size().get() as usize;
+        let at = |lba: u64| (lba - self.start) as usize * block_size;
+        let Range { start: first, end } = self.blocks;
+        if write {
+            let head = first - first % self.page;
+            let tail = end.next_multiple_of(self.page).min(device.num_blocks());
+            if head < first {
+                device.read_blocks(head, &mut staged[at(head)..at(first)])?;
+            }
+            if end < tail {
+                device.read_blocks(end, &mut staged[at(end)..at(tail)])?;
+            }
+            device.write_blocks(head, &staged[at(head)..at(tail)])?;
+        }
+        if let Some(byte_check) = verify {
+            let read_back = &mut read_back[..at(end) - at(first)];
+            device.read_blocks(first, read_back)?;
+            if byte_check && read_back != &staged[at(first)..at(end)] {
+                return Err(Sense::MISCOMPARE_DURING_VERIFY);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Answers standard INQUIRY with the `data` of a device
+#[cfg(feature = "bbb")]
+pub(super) fn inquiry<Bus: UsbBus, IoBuf: BorrowMut<[u8]>>(
+    mut command: Command<ScsiCommand, Scsi<BulkOnly<Bus, IoBuf>>>,
+    data: &InquiryData,
+    page_code: u8,
+    alloc_len: u16,
+) {
+    if page_code != 0 {
+        command.fail_with_sense(Sense::INVALID_FIELD_IN_CDB);
+        return;
+    }
+    let mut buf = [0u8; EXTENDED_INQUIRY_DATA_LEN];
+    let len = write_standard_inquiry(&mut buf, command.class.device_type(), data);
+    // the IO buffer of the subclass fits the whole data
+    let _ = command.write_data(&buf[..len.min(alloc_len as usize)]);
+    command.pass();
+}
+
+/// Fails a Read, a Write, a WriteAndVerify or a Verify the device can't serve and passes one of
+/// zero blocks. Returns `command` back if it's left to the device
+#[cfg(feature = "bbb")]
+pub(super) fn check_blocks<'a, 'alloc, Bus: UsbBus, IoBuf: BorrowMut<[u8]>>(
+    command: Command<'a, ScsiCommand, Scsi<BulkOnly<'alloc, Bus, IoBuf>>>,
+    block_size: BlockSize,
+    num_blocks: u64,
+) -> Option<Command<'a, ScsiCommand, Scsi<BulkOnly<'alloc, Bus, IoBuf>>>> {
+    let (ScsiCommand::Read { lba, len }
+    | ScsiCommand::Write { lba, len }
+    | ScsiCommand::WriteAndVerify { lba, len, .. }
+    | ScsiCommand::Verify { lba, len, .. }) = command.kind
+    else {
+        return Some(command);
+    };
+    // the subclass splits the data at the blocks of the unit, not of the device
+    if command.class.block_size(command.lun) != block_size {
+        debug!("usb: scsi: Block size mismatch of LUN {}", command.lun);
+        command.fail_with_sense(Sense::LOGICAL_UNIT_NOT_CONFIGURED);
+    } else if lba.saturating_add(len) > num_blocks {
+        command.fail_with_sense(Sense::LBA_OUT_OF_RANGE);
+    } else if len == 0 {
+        command.pass();
+    } else {
+        return Some(command);
+    }
+    None
+}
+
+/// Devices of Logical Units `0..N`, one per LUN
+///
+/// Devices of different types are held as `&mut dyn BlockDevice`, or as an enum implementing
+/// [BlockDevice]:
+///
+/// ```
+/// # use usbd_storage::subclass::scsi::block::{BlockDevice, LunTable};
+/// # use usbd_storage::subclass::scsi::capacity::BlockSize;
+/// # use usbd_storage::subclass::scsi::sense::Sense;
+/// # struct Flash;
+/// # struct SdCard;
+/// # impl BlockDevice for Flash {
+/// #     fn block_size(&self) -> BlockSize { BlockSize::B512 }
+/// #     fn num_blocks(&self) -> u64 { 256 }
+/// #     fn read_block(&mut self, _: u64, _: &mut [u8]) -> Result<(), Sense> { Ok(()) }
+/// #     fn write_block(&mut self, _: u64, _: &[u8]) -> Result<(), Sense> { Ok(()) }
+/// # }
+/// # impl BlockDevice for SdCard {
+/// #     fn block_size(&self) -> BlockSize { BlockSize::B512 }
+/// #     fn num_blocks(&self) -> u64 { 1 << 21 }
+/// #     fn read_block(&mut self, _: u64, _: &mut [u8]) -> Result<(), Sense> { Ok(()) }
+/// #     fn write_block(&mut self, _: u64, _: &[u8]) -> Result<(), Sense> { Ok(()) }
+/// # }
+/// let (mut flash, mut sd_card) = (Flash, SdCard);
+/// let mut luns: LunTable<2, &mut dyn BlockDevice> = LunTable::new([&mut flash, &mut sd_card]);
+/// assert_eq!(1, luns.max_lun());
+/// // let mut scsi = Scsi::new(&usb_bus, 64, luns.max_lun(), io_buf)?;
+/// // luns.register(&mut scsi);
+/// // scsi.poll(|cmd| {
+/// //     if let Some(cmd) = luns.handle(&mut driver, cmd) {
+/// //         /* the rest of the commands */
+/// //     }
+/// // });
+/// ```
+pub struct LunTable<const N: usize, D: BlockDevice> {
+    devices: [D; N],
+}
+
+impl<const N: usize, D: BlockDevice> LunTable<N, D> {
+    /// Fails the build of a table of no devices or of more than 16 of them
+    const VALID_LEN: () = assert!(N > 0 && N <= 16, "1 to 16 Logical Units are supported");
+
+    /// Creates a table of `devices`, the device of LUN `i` at index `i`. A table of no devices
+    /// or of more than 16 of them doesn't compile
+    ///
+    /// ```compile_fail
+    /// # use usbd_storage::subclass::scsi::block::{BlockDevice, LunTable};
+    /// let luns: LunTable<0, &mut dyn BlockDevice> = LunTable::new([]);
+    /// ```
+    pub fn new(devices: [D; N]) -> Self {
+        let () = Self::VALID_LEN;
+        Self { devices }
+    }
+
+    /// Returns the greatest LUN of the table, to create the subclass with
+    pub fn max_lun(&self) -> u8 {
+        (N - 1) as u8
+    }
+
+    /// Returns the device of `lun`, `None` if not in the table
+    pub fn get(&self, lun: u8) -> Option<&D> {
+        self.devices.get(lun as usize)
+    }
+
+    /// Returns the device of `lun`, `None` if not in the table
+    pub fn get_mut(&mut self, lun: u8) -> Option<&mut D> {
+        self.devices.get_mut(lun as usize)
+    }
+
+    /// Registers the capacity and the block size of each device with [Scsi::set_block_device].
+    /// Called again once a device changes its medium, e.g. an SD card is swapped
+    pub fn register<T: Transport>(&self, scsi: &mut Scsi<T>) {
+        for (lun, device) in self.devices.iter().enumerate() {
+            scsi.set_block_device(lun as u8, device);
+        }
+    }
+}
+
+#[cfg(feature = "bbb")]
+impl<const N: usize, D: BlockDevice> LunTable<N, D> {
+    /// Serves `command` with `driver` and the device of its LUN, see [BlockDriver::handle]
+    ///
+    /// Returns back the commands the driver doesn't serve, as well as the ones addressed to
+    /// a LUN beyond the table
+    pub fn handle<'a, 'alloc, Buf, Bus, IoBuf>(
+        &mut self,
+        driver: &mut BlockDriver<Buf>,
+        command: Command<'a, ScsiCommand, Scsi<BulkOnly<'alloc, Bus, IoBuf>>>,
+    ) -> Option<Command<'a, ScsiCommand, Scsi<BulkOnly<'alloc, Bus, IoBuf>>>>
+    where
+        Buf: BorrowMut<[u8]>,
+        Bus: UsbBus + 'alloc,
+        IoBuf: BorrowMut<[u8]>,
+    {
+        match self.devices.get_mut(command.lun as usize) {
+            Some(device) => driver.handle(device, command),
+            None => Some(command),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Logical block size and capacity data
+
+/// Length of the READ CAPACITY(10) parameter data
+pub const READ_CAPACITY_10_DATA_LEN: usize = 8;
+/// Length of the READ CAPACITY(16) parameter data
+pub const READ_CAPACITY_16_DATA_LEN: usize = 32;
+/// Length of the READ FORMAT CAPACITIES data with a single capacity descriptor
+pub const READ_FORMAT_CAPACITIES_DATA_LEN: usize = 12;
+/// Length of the short LBA mode parameter block descriptor
+pub const BLOCK_DESCRIPTOR_LEN: usize = 8;
+
+/// Logical block size in bytes
+///
+/// Always a power of two and not less than 512.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BlockSize(u32);
+
+impl BlockSize {
+    /// 512 bytes
+    pub const B512: BlockSize = BlockSize(512);
+    /// 2048 bytes. Usual for optical media
+    pub const B2048: BlockSize = BlockSize(2048);
+    /// 4096 bytes
+    pub const B4096: BlockSize = BlockSize(4096);
+
+    /// Returns `None` if `size` is not a power of two or less than 512
+    pub const fn new(size: u32) -> Option<Self> {
+        if size >= 512 && size.is_power_of_two() {
+            Some(BlockSize(size))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the size in bytes
+    pub const fn get(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for BlockSize {
+    fn default() -> Self {
+        BlockSize::B512
+    }
+}
+
+/// Builds READ CAPACITY(10) parameter data.
+///
+/// The returned LBA is `0xFFFFFFFF` if the last LBA doesn't fit 32 bits, which tells a host to
+/// issue READ CAPACITY(16).
+pub fn read_capacity_10(num_blocks: u64, block_size: BlockSize) -> [u8; READ_CAPACITY_10_DATA_LEN] {
+    let last_lba = u32::try_from(num_blocks.saturating_sub(1)).unwrap_or(u32::MAX);
+    let mut data = [0u8; READ_CAPACITY_10_DATA_LEN];
+    data[..4].copy_from_slice(&last_lba.to_be_bytes());
+    data[4..].copy_from_slice(&block_size.get().to_be_bytes());
+    data
+}
+
+/// Builds READ CAPACITY(16) parameter data
+pub fn read_capacity_16(num_blocks: u64, block_size: BlockSize) -> [u8; READ_CAPACITY_16_DATA_LEN] {
+    let mut data = [0u8; READ_CAPACITY_16_DATA_LEN];
+    data[..8].copy_from_slice(&num_blocks.saturating_sub(1).to_be_bytes());
+    data[8..12].copy_from_slice(&block_size.get().to_be_bytes());
+    data
+}
+
+/// Builds READ FORMAT CAPACITIES data with a single descriptor of formatted media
+pub fn read_format_capacities(
+    num_blocks: u64,
+    block_size: BlockSize,
+) -> [u8; READ_FORMAT_CAPACITIES_DATA_LEN] {
+    const FORMATTED_MEDIA: u8 = 0b10;
+
+    let mut data = [0u8; READ_FORMAT_CAPACITIES_DATA_LEN];
+    data[3] = 8; // capacity list length
+    data[4..8].copy_from_slice(&u32::try_from(num_blocks).unwrap_or(u32::MAX).to_be_bytes());
+    data[8] = FORMATTED_MEDIA;
+    data[9..].copy_from_slice(&block_size.get().to_be_bytes()[1..]);
+    data
+}
+
+/// Builds a short LBA mode parameter block descriptor
+pub fn block_descriptor(num_blocks: u64, block_size: BlockSize) -> [u8; BLOCK_DESCRIPTOR_LEN] {
+    let mut data = [0u8; BLOCK_DESCRIPTOR_LEN];
+    data[..4].copy_from_slice(&u32::try_from(num_blocks).unwrap_or(u32::MAX).to_be_bytes());
+    data[5..].copy_from_slice(&block_size.get().to_be_bytes()[1..]);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subclass::scsi::capacity::{
+        block_descriptor, read_capacity_10, read_capacity_16, read_format_capacities, BlockSize,
+    };
+
+    #[test]
+    fn should_validate_block_size() {
+        assert_eq!(None, BlockSize::new(0));
+        assert_eq!(None, BlockSize::new(256));
+        assert_eq!(None, BlockSize::new(520));
+        assert_eq!(None, BlockSize::new(3072));
+        assert_eq!(Some(BlockSize::B512), BlockSize::new(512));
+        assert_eq!(Some(BlockSize::B2048), BlockSize::new(2048));
+        assert_eq!(Some(BlockSize::B4096), BlockSize::new(4096));
+        assert_eq!(BlockSize::B512, BlockSize::default());
+    }
+
+    #[test]
+    fn should_build_capacity_data_with_2048_blocks() {
+        let data = read_capacity_10(1000, BlockSize::B2048);
+        assert_eq!([0x00, 0x00, 0x03, 0xE7, 0x00, 0x00, 0x08, 0x00], data);
+
+        let data = read_capacity_16(1000, BlockSize::B2048);
+        assert_eq!([0, 0, 0, 0, 0, 0, 0x03, 0xE7], data[..8]);
+        assert_eq!([0x00, 0x00, 0x08, 0x00], data[8..12]);
+
+        let data = read_format_capacities(1000, BlockSize::B2048);
+        assert_eq!(
+            [0, 0, 0, 8, 0x00, 0x00, 0x03, 0xE8, 0b10, 0x00, 0x08, 0x00],
+            data
+        );
+    }
+
+    #[test]
+    fn should_build_capacity_data_with_4096_blocks() {
+        let data = read_capacity_10(0x100, BlockSize::B4096);
+        assert_eq!([0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x10, 0x00], data);
+
+        let data = block_descriptor(0x100, BlockSize::B4096);
+        assert_eq!([0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x10, 0x00], data);
+    }
+}
@@ -0,0 +1,122 @@
+//! Best-effort guess of the host OS
+//!
+//! Hosts probe a new device with slightly different sets of commands. A few of them are
+//! distinctive enough to tell the OS apart, so that the device can apply per-OS workarounds
+//! without user interaction. The guess is based on the first [FINGERPRINT_COMMANDS] commands
+//! after a reset and is a heuristic only: a host tool or a virtual machine may look like
+//! any OS.
+
+use crate::subclass::scsi::ScsiCommand;
+
+/// Number of the first commands after a reset the guess is based on
+pub const FINGERPRINT_COMMANDS: u8 = 32;
+
+const MODE_PAGE_INFORMATIONAL_EXCEPTIONS: u8 = 0x1C;
+const MODE_PAGE_ALL: u8 = 0x3F;
+
+const SIGNAL_READ_FORMAT_CAPACITIES: u8 = 1 << 0;
+const SIGNAL_MODE_SENSE_ALL_PAGES_192: u8 = 1 << 1;
+const SIGNAL_MODE_SENSE_INFORMATIONAL_EXCEPTIONS: u8 = 1 << 2;
+
+/// The host OS as guessed from the commands it issues
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HostOs {
+    /// None of the distinctive commands has been seen (yet)
+    #[default]
+    Unknown,
+    /// Asks for the Informational Exceptions Control mode page
+    MacOs,
+    /// Asks for all the mode pages with MODE SENSE(6) of exactly 192 bytes, as usb-storage does
+    Linux,
+    /// Issues READ FORMAT CAPACITIES
+    Windows,
+}
+
+/// Signals collected from the early commands
+#[derive(Copy, Clone, Default)]
+pub(crate) struct Fingerprint {
+    commands: u8,
+    signals: u8,
+}
+
+impl Fingerprint {
+    /// Takes a completed command into account, if still early enough
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    pub(crate) fn observe(&mut self, kind: ScsiCommand) {
+        if self.commands >= FINGERPRINT_COMMANDS {
+            return;
+        }
+        self.commands += 1;
+        self.signals |= match kind {
+            ScsiCommand::ReadFormatCapacities { .. } => SIGNAL_READ_FORMAT_CAPACITIES,
+            ScsiCommand::ModeSense6 {
+                page_code: MODE_PAGE_ALL,
+                alloc_len: 192,
+                ..
+            } => SIGNAL_MODE_SENSE_ALL_PAGES_192,
+            ScsiCommand::ModeSense6 {
+                page_code: MODE_PAGE_INFORMATIONAL_EXCEPTIONS,
+                ..
+            }
+            | ScsiCommand::ModeSense10 {
+                page_code: MODE_PAGE_INFORMATIONAL_EXCEPTIONS,
+                ..
+            } => SIGNAL_MODE_SENSE_INFORMATIONAL_EXCEPTIONS,
+            _ => 0,
+        };
+    }
+
+    /// The most specific signal wins
+    pub(crate) fn host_os(&self) -> HostOs {
+        if self.signals & SIGNAL_MODE_SENSE_INFORMATIONAL_EXCEPTIONS != 0 {
+            HostOs::MacOs
+        } else if self.signals & SIGNAL_MODE_SENSE_ALL_PAGES_192 != 0 {
+            HostOs::Linux
+        } else if self.signals & SIGNAL_READ_FORMAT_CAPACITIES != 0 {
+            HostOs::Windows
+        } else {
+            HostOs::Unknown
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subclass::scsi::fingerprint::{Fingerprint, HostOs, FINGERPRINT_COMMANDS};
+    use crate::subclass::scsi::{PageControl, ScsiCommand};
+
+    fn mode_sense_6(page_code: u8, alloc_len: u8) -> ScsiCommand {
+        ScsiCommand::ModeSense6 {
+            dbd: false,
+            page_control: PageControl::CurrentValues,
+            page_code,
+            subpage_code: 0,
+            alloc_len,
+        }
+    }
+
+    #[test]
+    fn should_guess_host_os() {
+        let mut fingerprint = Fingerprint::default();
+        fingerprint.observe(ScsiCommand::TestUnitReady);
+        assert_eq!(HostOs::Unknown, fingerprint.host_os());
+        fingerprint.observe(ScsiCommand::ReadFormatCapacities { alloc_len: 0xFC });
+        assert_eq!(HostOs::Windows, fingerprint.host_os());
+        fingerprint.observe(mode_sense_6(0x3F, 192));
+        assert_eq!(HostOs::Linux, fingerprint.host_os());
+        fingerprint.observe(mode_sense_6(0x1C, 192));
+        assert_eq!(HostOs::MacOs, fingerprint.host_os());
+    }
+
+    #[test]
+    fn should_ignore_late_commands() {
+        let mut fingerprint = Fingerprint::default();
+        fingerprint.observe(mode_sense_6(0x3F, 4));
+        for _ in 1..FINGERPRINT_COMMANDS {
+            fingerprint.observe(ScsiCommand::TestUnitReady);
+        }
+        fingerprint.observe(mode_sense_6(0x3F, 192));
+        assert_eq!(HostOs::Unknown, fingerprint.host_os());
+    }
+}
@@ -0,0 +1,109 @@
+//! History of completed commands
+//!
+//! Kept for post-mortem debugging where live logging isn't available, e.g. to tell which command
+//! the host has reset the device after.
+
+use crate::subclass::scsi::ScsiCommand;
+use crate::transport::CommandStatus;
+
+/// Number of the most recent commands kept
+pub const HISTORY_LEN: usize = 16;
+
+/// A command completed by the device
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HistoryEntry {
+    pub opcode: u8,
+    pub lun: u8,
+    /// The first block addressed, zero if not applicable
+    pub lba: u64,
+    /// Transfer length as declared by the command block, zero if not applicable
+    pub len: u64,
+    pub status: CommandStatus,
+}
+
+impl HistoryEntry {
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    pub(crate) fn new(opcode: u8, lun: u8, kind: ScsiCommand, status: CommandStatus) -> Self {
+        let (lba, len) = match kind {
+            ScsiCommand::Read { lba, len }
+            | ScsiCommand::Write { lba, len }
+            | ScsiCommand::WriteAndVerify { lba, len, .. }
+            | ScsiCommand::Verify { lba, len, .. } => (lba, len),
+            ScsiCommand::ReadSequential { len, .. } | ScsiCommand::WriteSequential { len, .. } => {
+                (0, len as u64)
+            }
+            ScsiCommand::ReadCd { lba, len, .. } => (lba as u64, len as u64),
+            _ => (0, 0),
+        };
+        Self {
+            opcode,
+            lun,
+            lba,
+            len,
+            status,
+        }
+    }
+}
+
+/// Ring of the last [HISTORY_LEN] completed commands
+#[derive(Default, Copy, Clone)]
+pub(crate) struct History {
+    entries: [Option<HistoryEntry>; HISTORY_LEN],
+    /// Index to write the next entry at
+    next: usize,
+}
+
+impl History {
+    /// Records `entry` overwriting the oldest one if full
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    pub(crate) fn push(&mut self, entry: HistoryEntry) {
+        self.entries[self.next] = Some(entry);
+        self.next = (self.next + 1) % HISTORY_LEN;
+    }
+
+    /// Returns the entries from the oldest to the most recent
+    pub(crate) fn iter(&self) -> impl Iterator<Item = HistoryEntry> + '_ {
+        let (newer, older) = self.entries.split_at(self.next);
+        older.iter().chain(newer).flatten().copied()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = Default::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subclass::scsi::history::{History, HistoryEntry, HISTORY_LEN};
+    use crate::subclass::scsi::ScsiCommand;
+    use crate::transport::CommandStatus;
+
+    fn entry(lba: u64) -> HistoryEntry {
+        HistoryEntry::new(
+            0x28,
+            0,
+            ScsiCommand::Read { lba, len: 1 },
+            CommandStatus::Passed,
+        )
+    }
+
+    #[test]
+    fn should_keep_most_recent_entries() {
+        let mut history = History::default();
+        assert_eq!(0, history.iter().count());
+        history.push(entry(0));
+        history.push(entry(1));
+        let lbas: Vec<_> = history.iter().map(|entry| entry.lba).collect();
+        assert_eq!(vec![0, 1], lbas);
+
+        for lba in 2..HISTORY_LEN as u64 + 5 {
+            history.push(entry(lba));
+        }
+        let lbas: Vec<_> = history.iter().map(|entry| entry.lba).collect();
+        assert_eq!((5..HISTORY_LEN as u64 + 5).collect::<Vec<_>>(), lbas);
+
+        history.clear();
+        assert_eq!(0, history.iter().count());
+    }
+}
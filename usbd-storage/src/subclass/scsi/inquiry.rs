@@ -0,0 +1,383 @@
+//! Standard INQUIRY data and the Vital Product Data pages
+
+use crate::subclass::scsi::PeripheralDeviceType;
+use usb_device::device::StringDescriptors;
+use usb_device::LangID;
+
+/// Length of the standard INQUIRY data without version descriptors
+pub const STANDARD_INQUIRY_DATA_LEN: usize = 36;
+/// Max number of version descriptors
+pub const VERSION_DESCRIPTORS_MAX: usize = 8;
+/// Length of the standard INQUIRY data including version descriptors
+pub const EXTENDED_INQUIRY_DATA_LEN: usize =
+    VERSION_DESCRIPTORS_START + 2 * VERSION_DESCRIPTORS_MAX;
+
+const VERSION_DESCRIPTORS_START: usize = 58;
+
+/// Max length of a serial number reported in the Unit Serial Number page
+pub const SERIAL_NUMBER_MAX_LEN: usize = 32;
+/// Length of the Unit Serial Number page with a serial number of [SERIAL_NUMBER_MAX_LEN]
+pub const UNIT_SERIAL_NUMBER_PAGE_MAX_LEN: usize =
+    UNIT_SERIAL_NUMBER_HEADER_LEN + SERIAL_NUMBER_MAX_LEN;
+/// Unit Serial Number VPD page code
+pub const UNIT_SERIAL_NUMBER_PAGE: u8 = 0x80;
+/// Supported VPD Pages page code
+pub const SUPPORTED_VPD_PAGES: u8 = 0x00;
+/// Device Identification VPD page code
+pub const DEVICE_IDENTIFICATION_PAGE: u8 = 0x83;
+/// Length of the Device Identification page with a serial number of [SERIAL_NUMBER_MAX_LEN]
+pub const DEVICE_IDENTIFICATION_PAGE_MAX_LEN: usize =
+    VPD_PAGE_HEADER_LEN + DESIGNATOR_HEADER_LEN + 8 + 16 + SERIAL_NUMBER_MAX_LEN;
+
+const UNIT_SERIAL_NUMBER_HEADER_LEN: usize = 4;
+const VPD_PAGE_HEADER_LEN: usize = 4;
+const DESIGNATOR_HEADER_LEN: usize = 4;
+/// CODE SET of a designator of ASCII characters
+const CODE_SET_ASCII: u8 = 0x02;
+/// DESIGNATOR TYPE of a T10 vendor ID based designator
+const DESIGNATOR_T10_VENDOR_ID: u8 = 0x01;
+
+/// RMB bit
+const REMOVABLE: u8 = 0b10000000;
+/// Response data format required by SPC
+const RESPONSE_DATA_FORMAT: u8 = 0x02;
+
+/// Standard INQUIRY data describing a device
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InquiryData {
+    /// Whether the medium is removable
+    pub removable: bool,
+    /// Version of the SPC standard claimed, e.g. `0x04` for SPC-2
+    pub version: u8,
+    /// T10 vendor identification
+    pub vendor: [u8; 8],
+    /// Product identification
+    pub product: [u8; 16],
+    /// Product revision level
+    pub revision: [u8; 4],
+    /// Version descriptors of the standards claimed, zero if unused. Reported only if any is set
+    pub version_descriptors: [u16; VERSION_DESCRIPTORS_MAX],
+}
+
+impl InquiryData {
+    /// Claims SPC-2 for a non-removable medium. `vendor`, `product` and `revision` are truncated
+    /// or padded with spaces to their fixed lengths
+    pub const fn new(vendor: &str, product: &str, revision: &str) -> Self {
+        Self {
+            removable: false,
+            version: 0x04,
+            vendor: pad(vendor),
+            product: pad(product),
+            revision: pad(revision),
+            version_descriptors: [0; VERSION_DESCRIPTORS_MAX],
+        }
+    }
+
+    /// Returns the length of the data. Version descriptors extend it past
+    /// [STANDARD_INQUIRY_DATA_LEN]
+    pub const fn data_len(&self) -> usize {
+        let mut i = 0;
+        while i < VERSION_DESCRIPTORS_MAX {
+            if self.version_descriptors[i] != 0 {
+                return EXTENDED_INQUIRY_DATA_LEN;
+            }
+            i += 1;
+        }
+        STANDARD_INQUIRY_DATA_LEN
+    }
+}
+
+/// Identity of the device reported both over SCSI and in the USB string descriptors
+///
+/// Keeps INQUIRY data, the Unit Serial Number page and the USB strings from drifting apart.
+/// Register it with [Scsi::set_identity] and build the string descriptors of the device with
+/// [string_descriptors].
+///
+/// [Scsi::set_identity]: crate::subclass::scsi::Scsi::set_identity
+/// [string_descriptors]: DeviceIdentity::string_descriptors
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceIdentity<'a> {
+    /// T10 vendor identification and the manufacturer string. Up to 8 characters are reported
+    /// in INQUIRY data
+    pub vendor: &'a str,
+    /// Product identification and the product string. Up to 16 characters are reported in
+    /// INQUIRY data
+    pub product: &'a str,
+    /// Product revision level. Up to 4 characters are reported in INQUIRY data
+    pub revision: &'a str,
+    /// Serial number string. Up to [SERIAL_NUMBER_MAX_LEN] characters are reported in the Unit
+    /// Serial Number page
+    pub serial: &'a str,
+}
+
+impl<'a> DeviceIdentity<'a> {
+    /// Returns the USB string descriptors of `lang_id` with the manufacturer, the product and
+    /// the serial number of the identity
+    pub fn string_descriptors(&self, lang_id: LangID) -> StringDescriptors<'a> {
+        StringDescriptors::new(lang_id)
+            .manufacturer(self.vendor)
+            .product(self.product)
+            .serial_number(self.serial)
+    }
+
+    /// Returns standard INQUIRY data of the identity. See [InquiryData::new]
+    pub const fn inquiry_data(&self) -> InquiryData {
+        InquiryData::new(self.vendor, self.product, self.revision)
+    }
+}
+
+/// Serial number reported in the Unit Serial Number page
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SerialNumber {
+    bytes: [u8; SERIAL_NUMBER_MAX_LEN],
+    len: u8,
+}
+
+impl SerialNumber {
+    /// `serial` is truncated to [SERIAL_NUMBER_MAX_LEN] bytes
+    pub const fn new(serial: &str) -> Self {
+        let src = serial.as_bytes();
+        let mut bytes = [0u8; SERIAL_NUMBER_MAX_LEN];
+        let mut i = 0;
+        while i < SERIAL_NUMBER_MAX_LEN && i < src.len() {
+            bytes[i] = src[i];
+            i += 1;
+        }
+        Self {
+            bytes,
+            len: i as u8,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+/// Writes the Unit Serial Number page into `dst` returning the number of bytes written. Spec.
+/// SPC-3 7.6.10
+///
+/// # Panics
+/// Panics if `dst` doesn't fit the page
+pub fn write_unit_serial_number_page(
+    dst: &mut [u8],
+    device_type: PeripheralDeviceType,
+    serial: &SerialNumber,
+) -> usize {
+    let serial = serial.as_bytes();
+    let len = UNIT_SERIAL_NUMBER_HEADER_LEN + serial.len();
+    let dst = &mut dst[..len];
+    dst[0] = device_type as u8; // peripheral qualifier: connected
+    dst[1] = UNIT_SERIAL_NUMBER_PAGE;
+    dst[2] = 0;
+    dst[3] = serial.len() as u8; // page length
+    dst[UNIT_SERIAL_NUMBER_HEADER_LEN..].copy_from_slice(serial);
+    len
+}
+
+/// Writes the Supported VPD Pages page into `dst` returning the number of bytes written.
+/// `pages` are the codes of the pages supported, in ascending order. Spec. SPC-3 7.6.11
+///
+/// # Panics
+/// Panics if `dst` doesn't fit the page
+pub fn write_supported_vpd_pages(
+    dst: &mut [u8],
+    device_type: PeripheralDeviceType,
+    pages: &[u8],
+) -> usize {
+    let len = VPD_PAGE_HEADER_LEN + pages.len();
+    let dst = &mut dst[..len];
+    dst[0] = device_type as u8; // peripheral qualifier: connected
+    dst[1] = SUPPORTED_VPD_PAGES;
+    dst[2] = 0;
+    dst[3] = pages.len() as u8; // page length
+    dst[VPD_PAGE_HEADER_LEN..].copy_from_slice(pages);
+    len
+}
+
+/// Writes the Device Identification page into `dst` returning the number of bytes written.
+/// The page holds a single T10 vendor ID based designator: the vendor and the product of
+/// `data` followed by the serial number, if any. Spec. SPC-3 7.6.3
+///
+/// # Panics
+/// Panics if `dst` doesn't fit the page
+pub fn write_device_identification_page(
+    dst: &mut [u8],
+    device_type: PeripheralDeviceType,
+    data: &InquiryData,
+    serial: Option<&SerialNumber>,
+) -> usize {
+    let serial = serial.map_or(&[][..], |serial| serial.as_bytes());
+    let designator_len = data.vendor.len() + data.product.len() + serial.len();
+    let len = VPD_PAGE_HEADER_LEN + DESIGNATOR_HEADER_LEN + designator_len;
+    let dst = &mut dst[..len];
+    dst[0] = device_type as u8; // peripheral qualifier: connected
+    dst[1] = DEVICE_IDENTIFICATION_PAGE;
+    dst[2..4].copy_from_slice(&((len - VPD_PAGE_HEADER_LEN) as u16).to_be_bytes()); // page length
+
+    let designator = &mut dst[VPD_PAGE_HEADER_LEN..];
+    designator[0] = CODE_SET_ASCII; // protocol identifier: none
+    designator[1] = DESIGNATOR_T10_VENDOR_ID; // association: the addressed logical unit
+    designator[2] = 0;
+    designator[3] = designator_len as u8;
+    let (vendor, rest) = designator[DESIGNATOR_HEADER_LEN..].split_at_mut(data.vendor.len());
+    let (product, rest) = rest.split_at_mut(data.product.len());
+    vendor.copy_from_slice(&data.vendor);
+    product.copy_from_slice(&data.product);
+    rest.copy_from_slice(serial);
+    len
+}
+
+/// Writes standard INQUIRY data into `dst` returning the number of bytes written.
+/// ADDITIONAL LENGTH follows the actual length of the data
+///
+/// # Panics
+/// Panics if `dst` doesn't fit [InquiryData::data_len] bytes
+pub fn write_standard_inquiry(
+    dst: &mut [u8],
+    device_type: PeripheralDeviceType,
+    data: &InquiryData,
+) -> usize {
+    let len = data.data_len();
+    let dst = &mut dst[..len];
+    dst.fill(0);
+
+    dst[0] = device_type as u8; // peripheral qualifier: connected
+    dst[1] = if data.removable { REMOVABLE } else { 0 };
+    dst[2] = data.version;
+    dst[3] = RESPONSE_DATA_FORMAT;
+    dst[4] = (len - 5) as u8; // additional length
+    dst[8..16].copy_from_slice(&data.vendor);
+    dst[16..32].copy_from_slice(&data.product);
+    dst[32..36].copy_from_slice(&data.revision);
+    if len > STANDARD_INQUIRY_DATA_LEN {
+        let descriptors = &mut dst[VERSION_DESCRIPTORS_START..];
+        for (i, descriptor) in data.version_descriptors.iter().enumerate() {
+            descriptors[2 * i..2 * i + 2].copy_from_slice(&descriptor.to_be_bytes());
+        }
+    }
+    len
+}
+
+const fn pad<const N: usize>(s: &str) -> [u8; N] {
+    let bytes = s.as_bytes();
+    let mut padded = [b' '; N];
+    let mut i = 0;
+    while i < N && i < bytes.len() {
+        padded[i] = bytes[i];
+        i += 1;
+    }
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subclass::scsi::inquiry::{
+        write_device_identification_page, write_standard_inquiry, write_supported_vpd_pages,
+        write_unit_serial_number_page, DeviceIdentity, InquiryData, SerialNumber,
+        DEVICE_IDENTIFICATION_PAGE_MAX_LEN, EXTENDED_INQUIRY_DATA_LEN,
+        UNIT_SERIAL_NUMBER_PAGE_MAX_LEN,
+    };
+    use crate::subclass::scsi::PeripheralDeviceType;
+
+    #[test]
+    fn should_write_standard_inquiry() {
+        let data = InquiryData {
+            removable: true,
+            ..InquiryData::new("UNKNOWN", "STM32 USB Flash Drive", "1.23")
+        };
+        let mut buf = [0xFFu8; EXTENDED_INQUIRY_DATA_LEN];
+        assert_eq!(
+            36,
+            write_standard_inquiry(&mut buf, PeripheralDeviceType::DirectAccess, &data)
+        );
+        assert_eq!([0x00, 0x80, 0x04, 0x02, 0x1F, 0x00, 0x00, 0x00], buf[..8]);
+        assert_eq!(b"UNKNOWN ", &buf[8..16]);
+        assert_eq!(b"STM32 USB Flash ", &buf[16..32]);
+        assert_eq!(b"1.23", &buf[32..36]);
+        assert_eq!([0xFFu8; 38], buf[36..]);
+    }
+
+    #[test]
+    fn should_write_version_descriptors() {
+        let mut data = InquiryData::new("", "", "");
+        data.version_descriptors[0] = 0x1728; // USB
+        data.version_descriptors[1] = 0x0460; // SPC-4
+        let mut buf = [0xFFu8; EXTENDED_INQUIRY_DATA_LEN];
+        assert_eq!(
+            74,
+            write_standard_inquiry(&mut buf, PeripheralDeviceType::SequentialAccess, &data)
+        );
+        assert_eq!([0x01, 0x00, 0x04, 0x02, 0x45], buf[..5]);
+        assert_eq!([b' '; 28], buf[8..36]);
+        assert_eq!([0u8; 22], buf[36..58]);
+        assert_eq!([0x17, 0x28, 0x04, 0x60], buf[58..62]);
+        assert_eq!([0u8; 12], buf[62..]);
+    }
+
+    #[test]
+    fn should_write_unit_serial_number_page() {
+        let identity = DeviceIdentity {
+            vendor: "ACME",
+            product: "Flash Drive",
+            revision: "1.0",
+            serial: "0123456789ABCDEF0123456789ABCDEF0123",
+        };
+        assert_eq!(b"ACME    ", &identity.inquiry_data().vendor);
+
+        let serial = SerialNumber::new(identity.serial);
+        let mut buf = [0xFFu8; UNIT_SERIAL_NUMBER_PAGE_MAX_LEN + 1];
+        assert_eq!(
+            36,
+            write_unit_serial_number_page(&mut buf, PeripheralDeviceType::DirectAccess, &serial)
+        );
+        assert_eq!([0x00, 0x80, 0x00, 0x20], buf[..4]);
+        assert_eq!(identity.serial.as_bytes()[..32], buf[4..36]);
+        assert_eq!(0xFF, buf[36]);
+        assert_eq!(b"12", SerialNumber::new("12").as_bytes());
+    }
+
+    #[test]
+    fn should_write_vpd_pages() {
+        let mut buf = [0xFFu8; DEVICE_IDENTIFICATION_PAGE_MAX_LEN + 1];
+        assert_eq!(
+            7,
+            write_supported_vpd_pages(
+                &mut buf,
+                PeripheralDeviceType::DirectAccess,
+                &[0x00, 0x80, 0x83]
+            )
+        );
+        assert_eq!([0x00, 0x00, 0x00, 0x03, 0x00, 0x80, 0x83], buf[..7]);
+
+        let data = InquiryData::new("ACME", "Flash Drive", "1.0");
+        let serial = SerialNumber::new("0123456789ABCDEF0123456789ABCDEF");
+        assert_eq!(
+            DEVICE_IDENTIFICATION_PAGE_MAX_LEN,
+            write_device_identification_page(
+                &mut buf,
+                PeripheralDeviceType::DirectAccess,
+                &data,
+                Some(&serial)
+            )
+        );
+        assert_eq!([0x00, 0x83, 0x00, 0x3C, 0x02, 0x01, 0x00, 0x38], buf[..8]);
+        assert_eq!(b"ACME    Flash Drive     ", &buf[8..32]);
+        assert_eq!(serial.as_bytes(), &buf[32..64]);
+        assert_eq!(0xFF, buf[64]);
+
+        assert_eq!(
+            32,
+            write_device_identification_page(
+                &mut buf,
+                PeripheralDeviceType::DirectAccess,
+                &data,
+                None
+            )
+        );
+        assert_eq!([0x00, 0x83, 0x00, 0x1C, 0x02, 0x01, 0x00, 0x18], buf[..8]);
+    }
+}
@@ -0,0 +1,266 @@
+//! SCSI log pages
+
+use crate::subclass::scsi::sense::Sense;
+use crate::subclass::scsi::PageControl;
+
+/// Length of the log page header
+pub const LOG_PAGE_HEADER_LEN: usize = 4;
+/// Length of the log parameter header
+pub const LOG_PARAMETER_HEADER_LEN: usize = 4;
+
+/// Page code of the Supported Log Pages page (SPC)
+pub const SUPPORTED_LOG_PAGES_CODE: u8 = 0x00;
+/// Page code of the Write Error Counter log page (SPC)
+pub const WRITE_ERROR_COUNTER_PAGE_CODE: u8 = 0x02;
+/// Page code of the Read Error Counter log page (SPC)
+pub const READ_ERROR_COUNTER_PAGE_CODE: u8 = 0x03;
+/// Page code of the Temperature log page (SPC)
+pub const TEMPERATURE_PAGE_CODE: u8 = 0x0D;
+
+/// SPF bit of the page code byte
+const SUB_PAGE_FORMAT: u8 = 0b01000000;
+
+/// Writes a log page into `dst` returning the number of bytes written. `parameters` are
+/// the raw log parameters following the header, e.g. written with [write_log_parameter]
+///
+/// # Panics
+/// Panics if `dst` doesn't fit the header and the parameters
+pub fn write_log_page(
+    dst: &mut [u8],
+    page_code: u8,
+    subpage_code: Option<u8>,
+    parameters: &[u8],
+) -> usize {
+    let len = LOG_PAGE_HEADER_LEN + parameters.len();
+    dst[0] = page_code & 0b00111111;
+    if subpage_code.is_some() {
+        dst[0] |= SUB_PAGE_FORMAT;
+    }
+    dst[1] = subpage_code.unwrap_or(0);
+    dst[2..4].copy_from_slice(&(parameters.len() as u16).to_be_bytes()); // page length
+    dst[LOG_PAGE_HEADER_LEN..len].copy_from_slice(parameters);
+    len
+}
+
+/// Writes a log parameter into `dst` returning the number of bytes written. `control` is
+/// the control byte, e.g. zero for a bounded data counter
+///
+/// # Panics
+/// Panics if `dst` doesn't fit the parameter, or if `value` is longer than 255 bytes
+pub fn write_log_parameter(dst: &mut [u8], code: u16, control: u8, value: &[u8]) -> usize {
+    let len = LOG_PARAMETER_HEADER_LEN + value.len();
+    dst[..2].copy_from_slice(&code.to_be_bytes());
+    dst[2] = control;
+    dst[3] = u8::try_from(value.len()).unwrap(); // parameter length
+    dst[LOG_PARAMETER_HEADER_LEN..len].copy_from_slice(value);
+    len
+}
+
+/// The request of LOG SELECT without a parameter list. See [Scsi::set_on_log_reset]
+///
+/// [Scsi::set_on_log_reset]: crate::subclass::scsi::Scsi::set_on_log_reset
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LogReset {
+    /// Whether the cumulative parameters are to be reset to zero. Otherwise, the parameters
+    /// of `page_control` are to be set to their defaults
+    pub pcr: bool,
+    pub page_control: PageControl,
+    /// The page to reset, zero for all of them
+    pub page_code: u8,
+    pub subpage_code: u8,
+}
+
+/// Log page selected by the host
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LogPage<'a> {
+    pub page_code: u8,
+    /// Set for a page of the sub-page format only
+    pub subpage_code: Option<u8>,
+    /// Raw log parameters following the page header. See [LogPage::parameters]
+    pub parameters: &'a [u8],
+}
+
+impl<'a> LogPage<'a> {
+    /// Returns an iterator over the log parameters. Yields an error and stops at a malformed
+    /// parameter
+    pub fn parameters(&self) -> LogParameters<'a> {
+        LogParameters {
+            rest: self.parameters,
+        }
+    }
+}
+
+/// Log parameter selected by the host
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LogParameter<'a> {
+    pub code: u16,
+    /// The control byte: DU, TSD, ETC, TMC and FORMAT AND LINKING
+    pub control: u8,
+    pub value: &'a [u8],
+}
+
+/// Iterator over log pages. See [decode_log_select]
+#[derive(Clone, Debug)]
+pub struct LogPages<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for LogPages<'a> {
+    type Item = Result<LogPage<'a>, Sense>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let page = decode_page(self.rest);
+        match page {
+            Ok((_, len)) => self.rest = &self.rest[len..],
+            Err(_) => self.rest = &[],
+        }
+        Some(page.map(|(page, _)| page))
+    }
+}
+
+/// Iterator over log parameters. See [LogPage::parameters]
+#[derive(Clone, Debug)]
+pub struct LogParameters<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for LogParameters<'a> {
+    type Item = Result<LogParameter<'a>, Sense>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let data = self.rest;
+        let len = if data.len() >= LOG_PARAMETER_HEADER_LEN {
+            LOG_PARAMETER_HEADER_LEN + data[3] as usize
+        } else {
+            usize::MAX
+        };
+        if data.len() < len {
+            self.rest = &[];
+            return Some(Err(Sense::PARAMETER_LIST_LENGTH_ERROR));
+        }
+        self.rest = &data[len..];
+        Some(Ok(LogParameter {
+            code: u16::from_be_bytes([data[0], data[1]]),
+            control: data[2],
+            value: &data[LOG_PARAMETER_HEADER_LEN..len],
+        }))
+    }
+}
+
+/// Decodes a LOG SELECT parameter list. `data` is the parameter list as received, i.e.
+/// as long as the parameter list length of the command block. The pages are validated while
+/// iterating, a page that doesn't fit is reported as [PARAMETER_LIST_LENGTH_ERROR]
+///
+/// [PARAMETER_LIST_LENGTH_ERROR]: Sense::PARAMETER_LIST_LENGTH_ERROR
+pub fn decode_log_select(data: &[u8]) -> LogPages<'_> {
+    LogPages { rest: data }
+}
+
+/// Decodes the first page of `data` returning it along with its length including the header
+fn decode_page(data: &[u8]) -> Result<(LogPage<'_>, usize), Sense> {
+    if data.len() < LOG_PAGE_HEADER_LEN {
+        return Err(Sense::PARAMETER_LIST_LENGTH_ERROR);
+    }
+    let len = LOG_PAGE_HEADER_LEN + u16::from_be_bytes([data[2], data[3]]) as usize;
+    if data.len() < len {
+        return Err(Sense::PARAMETER_LIST_LENGTH_ERROR);
+    }
+    let sub_page = (data[0] & SUB_PAGE_FORMAT) != 0;
+    let page = LogPage {
+        page_code: data[0] & 0b00111111,
+        subpage_code: sub_page.then_some(data[1]),
+        parameters: &data[LOG_PAGE_HEADER_LEN..len],
+    };
+    Ok((page, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subclass::scsi::log::{
+        decode_log_select, write_log_page, write_log_parameter, LogPage, LogParameter,
+        READ_ERROR_COUNTER_PAGE_CODE,
+    };
+    use crate::subclass::scsi::sense::Sense;
+
+    #[test]
+    fn should_write_log_page() {
+        let mut parameters = [0u8; 16];
+        let mut len = write_log_parameter(&mut parameters, 0x0003, 0x00, &[0x12, 0x34]);
+        len += write_log_parameter(&mut parameters[len..], 0x0006, 0x00, &[0x01]);
+        assert_eq!(11, len);
+
+        let mut buf = [0xFFu8; 16];
+        assert_eq!(
+            15,
+            write_log_page(
+                &mut buf,
+                READ_ERROR_COUNTER_PAGE_CODE,
+                None,
+                &parameters[..len]
+            )
+        );
+        assert_eq!(
+            [0x03, 0x00, 0x00, 0x0B, 0x00, 0x03, 0x00, 0x02, 0x12, 0x34],
+            buf[..10]
+        );
+        assert_eq!([0x00, 0x06, 0x00, 0x01, 0x01, 0xFF], buf[10..]);
+
+        assert_eq!(4, write_log_page(&mut buf, 0x0D, Some(0x01), &[]));
+        assert_eq!([0x4D, 0x01, 0x00, 0x00], buf[..4]);
+    }
+
+    #[test]
+    fn should_decode_log_select() {
+        let data = [
+            0x02, 0x00, 0x00, 0x05, // Write Error Counter page
+            0x00, 0x01, 0x00, 0x01, 0xAA, // parameter 1
+            0x43, 0x07, 0x00, 0x00, // empty sub-page
+        ];
+        let mut pages = decode_log_select(&data);
+        let page = pages.next().unwrap().unwrap();
+        assert_eq!((0x02, None), (page.page_code, page.subpage_code));
+        let parameters: Vec<_> = page.parameters().collect();
+        assert_eq!(
+            vec![Ok(LogParameter {
+                code: 1,
+                control: 0,
+                value: &[0xAA],
+            })],
+            parameters
+        );
+        assert_eq!(
+            Some(Ok(LogPage {
+                page_code: 0x03,
+                subpage_code: Some(0x07),
+                parameters: &[],
+            })),
+            pages.next()
+        );
+        assert_eq!(None, pages.next());
+
+        let mut pages = decode_log_select(&data[..7]);
+        assert_eq!(Some(Err(Sense::PARAMETER_LIST_LENGTH_ERROR)), pages.next());
+        assert_eq!(None, pages.next());
+
+        let page = LogPage {
+            page_code: 0x02,
+            subpage_code: None,
+            parameters: &data[4..8],
+        };
+        let mut parameters = page.parameters();
+        assert_eq!(
+            Some(Err(Sense::PARAMETER_LIST_LENGTH_ERROR)),
+            parameters.next()
+        );
+        assert_eq!(None, parameters.next());
+    }
+}
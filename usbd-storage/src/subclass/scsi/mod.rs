@@ -0,0 +1,2128 @@
+//! USB SCSI
+
+use crate::quirks::Quirks;
+use crate::subclass::addressed_elsewhere;
+use crate::subclass::scsi::async_block::AsyncBlockDevice;
+use crate::subclass::scsi::block::BlockDevice;
+use crate::subclass::scsi::capacity::BlockSize;
+use crate::subclass::scsi::fingerprint::{Fingerprint, HostOs};
+#[cfg(feature = "history")]
+use crate::subclass::scsi::history::{History, HistoryEntry};
+use crate::subclass::scsi::inquiry::{DeviceIdentity, InquiryData, SerialNumber};
+use crate::subclass::scsi::log::LogReset;
+use crate::subclass::scsi::mode::{is_valid_mode_pages, MODE_PAGES_MAX_LEN};
+use crate::subclass::scsi::sense::{Sense, SenseQueue};
+use crate::transport::Transport;
+use crate::CLASS_MASS_STORAGE;
+use num_enum::TryFromPrimitive;
+use usb_device::bus::InterfaceNumber;
+use usb_device::bus::UsbBus;
+use usb_device::class::{ControlIn, ControlOut, UsbClass};
+use usb_device::descriptor::{BosWriter, DescriptorWriter};
+#[cfg(feature = "bbb")]
+use {
+    crate::fmt::debug,
+    crate::subclass::scsi::capacity::{
+        block_descriptor, read_capacity_10, read_capacity_16, read_format_capacities,
+        BLOCK_DESCRIPTOR_LEN,
+    },
+    crate::subclass::scsi::inquiry::{
+        write_device_identification_page, write_standard_inquiry, write_supported_vpd_pages,
+        write_unit_serial_number_page, DEVICE_IDENTIFICATION_PAGE,
+        DEVICE_IDENTIFICATION_PAGE_MAX_LEN, EXTENDED_INQUIRY_DATA_LEN, SUPPORTED_VPD_PAGES,
+        UNIT_SERIAL_NUMBER_PAGE, UNIT_SERIAL_NUMBER_PAGE_MAX_LEN,
+    },
+    crate::subclass::scsi::mode::{
+        caching_mode_page, select_mode_pages, write_mode_sense_10, write_mode_sense_6, ALL_PAGES,
+        MODE_PARAMETER_HEADER_10_LEN, MODE_PARAMETER_HEADER_6_LEN,
+    },
+    crate::subclass::scsi::sense::DESCRIPTOR_SENSE_DATA_MAX_LEN,
+    crate::subclass::{map_ignore, Aborted, Command},
+    crate::transport::bbb::{BulkOnly, BulkOnlyError, CommandPhase, CBW_LEN},
+    crate::transport::{CommandStatus, Reset, TransportError},
+    core::borrow::BorrowMut,
+    core::cmp::min,
+    usb_device::bus::UsbBusAllocator,
+    usb_device::{UsbDirection, UsbError},
+};
+
+pub mod async_block;
+pub mod block;
+pub mod capacity;
+pub mod fingerprint;
+#[cfg(feature = "history")]
+pub mod history;
+pub mod inquiry;
+pub mod log;
+pub mod mode;
+#[cfg(feature = "names")]
+pub mod names;
+pub mod sense;
+#[cfg(any(feature = "test-util", test))]
+pub mod serialize;
+#[cfg(feature = "embedded-io")]
+pub mod stream;
+
+/// SCSI device subclass code
+pub const SUBCLASS_SCSI: u8 = 0x06; // SCSI Transparent command set
+
+/* SCSI codes */
+
+/* SPC */
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1A;
+const MODE_SENSE_10: u8 = 0x5A;
+const MODE_SELECT_6: u8 = 0x15;
+const MODE_SELECT_10: u8 = 0x55;
+const RESERVE_6: u8 = 0x16;
+const RELEASE_6: u8 = 0x17;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1E;
+const LOG_SELECT: u8 = 0x4C;
+const LOG_SENSE: u8 = 0x4D;
+const READ_ATTRIBUTE: u8 = 0x8C;
+const WRITE_ATTRIBUTE: u8 = 0x8D;
+
+/* SBC */
+const READ_10: u8 = 0x28;
+const READ_16: u8 = 0x88;
+const READ_CAPACITY_10: u8 = 0x25;
+const START_STOP_UNIT: u8 = 0x1B;
+const READ_CAPACITY_16: u8 = 0x9E;
+const WRITE_10: u8 = 0x2A;
+const WRITE_16: u8 = 0x8A;
+const WRITE_AND_VERIFY_10: u8 = 0x2E;
+const WRITE_AND_VERIFY_12: u8 = 0xAE;
+const WRITE_AND_VERIFY_16: u8 = 0x8E;
+const VERIFY_10: u8 = 0x2F;
+const VERIFY_12: u8 = 0xAF;
+const VERIFY_16: u8 = 0x8F;
+const READ_DEFECT_DATA_10: u8 = 0x37;
+const READ_DEFECT_DATA_12: u8 = 0xB7;
+
+/* SSC */
+const REWIND: u8 = 0x01;
+const READ_BLOCK_LIMITS: u8 = 0x05;
+const READ_6: u8 = 0x08;
+const WRITE_6: u8 = 0x0A;
+const WRITE_FILEMARKS_6: u8 = 0x10;
+const SPACE_6: u8 = 0x11;
+
+/* MMC */
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_HEADER: u8 = 0x44;
+const READ_CD: u8 = 0xBE;
+
+/// SCSI command
+///
+/// Refer to specifications (SPC,SAM,SBC,MMC,etc.)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum ScsiCommand {
+    Unknown,
+    /// The command block is shorter than its opcode implies, or is of a variable-length or
+    /// extended format that doesn't fit a CBW. Expected to be failed with
+    /// [INVALID_FIELD_IN_CDB] or [INVALID_COMMAND_OPERATION_CODE] respectively
+    ///
+    /// [INVALID_FIELD_IN_CDB]: sense::Sense::INVALID_FIELD_IN_CDB
+    /// [INVALID_COMMAND_OPERATION_CODE]: sense::Sense::INVALID_COMMAND_OPERATION_CODE
+    UnsupportedCdbFormat {
+        opcode: u8,
+        /// `bCBWCBLength` declared by the host
+        len: u8,
+    },
+
+    /* SPC */
+    Inquiry {
+        evpd: bool,
+        page_code: u8,
+        alloc_len: u16,
+    },
+    TestUnitReady,
+    RequestSense {
+        desc: bool,
+        alloc_len: u8,
+    },
+    ModeSense6 {
+        dbd: bool,
+        page_control: PageControl,
+        page_code: u8,
+        subpage_code: u8,
+        alloc_len: u8,
+    },
+    ModeSense10 {
+        dbd: bool,
+        page_control: PageControl,
+        page_code: u8,
+        subpage_code: u8,
+        alloc_len: u16,
+    },
+    /// MODE SELECT(6). The parameter list is expected to be decoded with
+    /// [decode_mode_select_6](mode::decode_mode_select_6)
+    ModeSelect6 {
+        /// Whether the mode pages conform to the standard rather than being vendor specific
+        pf: bool,
+        /// Whether the host asks to save the pages
+        sp: bool,
+        parameter_list_len: u8,
+    },
+    /// MODE SELECT(10). The parameter list is expected to be decoded with
+    /// [decode_mode_select_10](mode::decode_mode_select_10)
+    ModeSelect10 {
+        /// Whether the mode pages conform to the standard rather than being vendor specific
+        pf: bool,
+        /// Whether the host asks to save the pages
+        sp: bool,
+        parameter_list_len: u16,
+    },
+    Reserve6,
+    Release6,
+    /// PREVENT ALLOW MEDIUM REMOVAL. `prevent` is set if the host prevents the removal
+    PreventAllowMediumRemoval {
+        prevent: bool,
+    },
+    /// LOG SELECT. Without a parameter list, asks to reset the parameters of the page, or of
+    /// all the pages if `page_code` is zero. The parameter list is expected to be decoded with
+    /// [decode_log_select](log::decode_log_select)
+    LogSelect {
+        /// Parameter code reset: whether the cumulative parameters are to be reset
+        pcr: bool,
+        /// Whether the host asks to save the parameters
+        sp: bool,
+        page_control: PageControl,
+        page_code: u8,
+        subpage_code: u8,
+        parameter_list_len: u16,
+    },
+    /// LOG SENSE. The answer is expected to be built with [write_log_page](log::write_log_page)
+    LogSense {
+        /// Whether the host asks to save the parameters
+        sp: bool,
+        page_control: PageControl,
+        page_code: u8,
+        subpage_code: u8,
+        /// The first parameter code to report
+        parameter_pointer: u16,
+        alloc_len: u16,
+    },
+    /// READ ATTRIBUTE of the Medium Auxiliary Memory. `service_action` selects what is
+    /// reported: `0x00` - attribute values, `0x01` - attribute list, `0x02` - logical volume
+    /// list, `0x03` - partition list, `0x05` - supported attributes
+    ReadAttribute {
+        service_action: u8,
+        logical_volume: u8,
+        partition: u8,
+        /// The first attribute identifier to report
+        first_attribute: u16,
+        alloc_len: u32,
+        /// Whether the host asks for the cached attributes of a medium no longer loaded
+        cache: bool,
+    },
+    /// WRITE ATTRIBUTE of the Medium Auxiliary Memory. The parameter list carries the
+    /// attributes to write
+    WriteAttribute {
+        /// Write-through cache: whether the attributes are to be written to the medium
+        /// before the status is reported
+        wtc: bool,
+        logical_volume: u8,
+        partition: u8,
+        parameter_list_len: u32,
+    },
+
+    /* SBC */
+    /// READ CAPACITY(10). `lba` is meaningful only if the Partial Medium Indicator `pmi` is set,
+    /// and must be zero otherwise
+    ReadCapacity10 {
+        lba: u32,
+        pmi: bool,
+    },
+    /// READ CAPACITY(16). See [ReadCapacity10](ScsiCommand::ReadCapacity10)
+    ReadCapacity16 {
+        lba: u64,
+        pmi: bool,
+        alloc_len: u32,
+    },
+    Read {
+        lba: u64,
+        len: u64,
+    },
+    Write {
+        lba: u64,
+        len: u64,
+    },
+    /// START STOP UNIT. `start` is ignored unless `power_condition` is zero. `load_eject` along
+    /// with `start` cleared requests ejecting the medium
+    StartStopUnit {
+        immed: bool,
+        power_condition: u8,
+        load_eject: bool,
+        start: bool,
+    },
+    /// WRITE AND VERIFY(10/12/16). The written blocks are expected to be verified.
+    /// `byte_check` is set if the host expects them to be compared with the written data
+    /// rather than only checked for being readable
+    WriteAndVerify {
+        lba: u64,
+        len: u64,
+        byte_check: bool,
+    },
+    /// VERIFY(10/12/16). With `byte_check`, the host sends the data of the blocks to compare
+    /// them with. Otherwise there is no data transfer and the blocks are only checked for being
+    /// readable
+    Verify {
+        lba: u64,
+        len: u64,
+        byte_check: bool,
+    },
+    ReadDefectData {
+        req_plist: bool,
+        req_glist: bool,
+        defect_list_format: u8,
+        alloc_len: u32,
+    },
+
+    /* SSC */
+    Rewind {
+        immed: bool,
+    },
+    ReadBlockLimits {
+        mloc: bool,
+    },
+    ReadSequential {
+        sili: bool,
+        fixed: bool,
+        /// Number of blocks if `fixed` is set, number of bytes otherwise
+        len: u32,
+    },
+    WriteSequential {
+        fixed: bool,
+        /// Number of blocks if `fixed` is set, number of bytes otherwise
+        len: u32,
+    },
+    WriteFilemarks {
+        wsmk: bool,
+        immed: bool,
+        count: u32,
+    },
+    Space {
+        code: u8,
+        /// Negative values mean spacing towards the beginning of medium
+        count: i32,
+    },
+
+    /* MMC */
+    ReadFormatCapacities {
+        alloc_len: u16,
+    },
+    ReadHeader {
+        msf: bool,
+        lba: u32,
+        alloc_len: u16,
+    },
+    ReadCd {
+        /// Expected Sector Type. `0` - any type
+        expected_sector_type: u8,
+        dap: bool,
+        lba: u32,
+        len: u32,
+        sync: bool,
+        header_codes: u8,
+        user_data: bool,
+        edc_ecc: bool,
+        c2_error_info: u8,
+        subchannel: u8,
+    },
+}
+
+/// A piece of [Write] command data along with its position on the medium.
+/// Never crosses a block boundary
+///
+/// [Write]: ScsiCommand::Write
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WriteChunk<'a> {
+    /// The block the data belongs to
+    pub lba: u64,
+    /// Offset of the data within the block
+    pub offset_in_block: usize,
+    pub bytes: &'a [u8],
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, TryFromPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PageControl {
+    CurrentValues = 0b00,
+    ChangeableValues = 0b01,
+    DefaultValues = 0b10,
+    SavedValues = 0b11,
+}
+
+/// SCSI peripheral device type
+///
+/// Selects the command set used to parse command blocks, the type reported in INQUIRY data and
+/// the default logical block size. Refer to SPC
+#[repr(u8)]
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum PeripheralDeviceType {
+    /// Direct access block device (SBC), e.g. a disk or a flash drive
+    #[default]
+    DirectAccess = 0x00,
+    /// Sequential access device (SSC), e.g. a tape drive
+    SequentialAccess = 0x01,
+    /// CD/DVD device (MMC)
+    CdDvd = 0x05,
+    /// Simplified direct access device (RBC)
+    SimplifiedDirectAccess = 0x0E,
+}
+
+impl PeripheralDeviceType {
+    /// Logical block size assumed unless set via [Scsi::set_block_size]: 2048 bytes for
+    /// [CdDvd], 512 bytes otherwise
+    ///
+    /// [CdDvd]: PeripheralDeviceType::CdDvd
+    pub const fn default_block_size(&self) -> BlockSize {
+        match self {
+            PeripheralDeviceType::CdDvd => BlockSize::B2048,
+            _ => BlockSize::B512,
+        }
+    }
+}
+
+/// What the host is doing with a Logical Unit. See [Scsi::host_activity]
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct HostActivity {
+    /// Whether any Read or Write has been handed to the user since the previous
+    /// [Scsi::host_activity]
+    pub accessed: bool,
+    /// Whether the host prevents medium removal with PREVENT ALLOW MEDIUM REMOVAL
+    pub removal_prevented: bool,
+    /// Whether the host has stopped the unit with START STOP UNIT, e.g. on eject
+    pub stopped: bool,
+}
+
+impl HostActivity {
+    /// Whether taking the medium back is likely to disturb the host: it has accessed the medium
+    /// recently, or holds it and hasn't stopped the unit
+    pub fn is_active(&self) -> bool {
+        self.accessed || (self.removal_prevented && !self.stopped)
+    }
+}
+
+/// Logical Unit readiness
+///
+/// While a Logical Unit is not ready, the subclass fails media access commands with
+/// NOT READY sense. Useful when the media takes a while to initialize.
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Readiness {
+    /// The medium is not present, reported as MEDIUM NOT PRESENT
+    NotReady,
+    /// The medium is being initialized, reported as LOGICAL UNIT IS IN PROCESS OF BECOMING READY.
+    /// `progress` is the fraction of the work done, where `0xFFFF` is complete
+    BecomingReady { progress: Option<u16> },
+    #[default]
+    Ready,
+}
+
+impl Readiness {
+    /// Sense data reported while the Logical Unit is in this state
+    #[allow(dead_code)]
+    fn sense(&self) -> Option<Sense> {
+        match *self {
+            Readiness::NotReady => Some(Sense::MEDIUM_NOT_PRESENT),
+            Readiness::BecomingReady { progress } => Some(Sense {
+                progress,
+                ..Sense::BECOMING_READY
+            }),
+            Readiness::Ready => None,
+        }
+    }
+}
+
+/// Checks the length of the command block against the one implied by the group code of its
+/// opcode (SAM). Vendor specific groups are not checked
+fn unsupported_cdb_format(cb: &[u8]) -> Option<ScsiCommand> {
+    let required = match cb[0] >> 5 {
+        0b000 => 6,
+        0b001 | 0b010 => 10,
+        0b011 => usize::MAX, // variable-length and extended CDBs
+        0b100 => 16,
+        0b101 => 12,
+        _ => 1,
+    };
+    (cb.len() < required).then_some(ScsiCommand::UnsupportedCdbFormat {
+        opcode: cb[0],
+        len: cb.len() as u8,
+    })
+}
+
+#[allow(dead_code)]
+fn parse_cb(cb: &[u8]) -> ScsiCommand {
+    if let Some(unsupported) = unsupported_cdb_format(cb) {
+        return unsupported;
+    }
+    match cb[0] {
+        TEST_UNIT_READY => ScsiCommand::TestUnitReady,
+        INQUIRY => ScsiCommand::Inquiry {
+            evpd: (cb[1] & 0b00000001) != 0,
+            page_code: cb[2],
+            alloc_len: u16::from_be_bytes([cb[3], cb[4]]),
+        },
+        REQUEST_SENSE => ScsiCommand::RequestSense {
+            desc: (cb[1] & 0b00000001) != 0,
+            alloc_len: cb[4],
+        },
+        RESERVE_6 => ScsiCommand::Reserve6,
+        RELEASE_6 => ScsiCommand::Release6,
+        PREVENT_ALLOW_MEDIUM_REMOVAL => ScsiCommand::PreventAllowMediumRemoval {
+            prevent: (cb[4] & 0b00000001) != 0,
+        },
+        START_STOP_UNIT => ScsiCommand::StartStopUnit {
+            immed: (cb[1] & 0b00000001) != 0,
+            power_condition: cb[4] >> 4,
+            load_eject: (cb[4] & 0b00000010) != 0,
+            start: (cb[4] & 0b00000001) != 0,
+        },
+        READ_CAPACITY_10 => ScsiCommand::ReadCapacity10 {
+            lba: u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]),
+            pmi: (cb[8] & 0b00000001) != 0,
+        },
+        READ_CAPACITY_16 => ScsiCommand::ReadCapacity16 {
+            lba: u64::from_be_bytes((&cb[2..10]).try_into().unwrap()),
+            pmi: (cb[14] & 0b00000001) != 0,
+            alloc_len: u32::from_be_bytes([cb[10], cb[11], cb[12], cb[13]]),
+        },
+        READ_10 => ScsiCommand::Read {
+            lba: u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as u64,
+            len: u16::from_be_bytes([cb[7], cb[8]]) as u64,
+        },
+        READ_16 => ScsiCommand::Read {
+            lba: u64::from_be_bytes((&cb[2..10]).try_into().unwrap()),
+            len: u32::from_be_bytes((&cb[10..14]).try_into().unwrap()) as u64,
+        },
+        WRITE_10 => ScsiCommand::Write {
+            lba: u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as u64,
+            len: u16::from_be_bytes([cb[7], cb[8]]) as u64,
+        },
+        WRITE_AND_VERIFY_10 => ScsiCommand::WriteAndVerify {
+            lba: u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as u64,
+            len: u16::from_be_bytes([cb[7], cb[8]]) as u64,
+            byte_check: (cb[1] & 0b00000110) != 0,
+        },
+        WRITE_AND_VERIFY_12 => ScsiCommand::WriteAndVerify {
+            lba: u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as u64,
+            len: u32::from_be_bytes([cb[6], cb[7], cb[8], cb[9]]) as u64,
+            byte_check: (cb[1] & 0b00000110) != 0,
+        },
+        WRITE_AND_VERIFY_16 => ScsiCommand::WriteAndVerify {
+            lba: u64::from_be_bytes((&cb[2..10]).try_into().unwrap()),
+            len: u32::from_be_bytes((&cb[10..14]).try_into().unwrap()) as u64,
+            byte_check: (cb[1] & 0b00000110) != 0,
+        },
+        VERIFY_10 => ScsiCommand::Verify {
+            lba: u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as u64,
+            len: u16::from_be_bytes([cb[7], cb[8]]) as u64,
+            byte_check: (cb[1] & 0b00000110) != 0,
+        },
+        VERIFY_12 => ScsiCommand::Verify {
+            lba: u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as u64,
+            len: u32::from_be_bytes([cb[6], cb[7], cb[8], cb[9]]) as u64,
+            byte_check: (cb[1] & 0b00000110) != 0,
+        },
+        VERIFY_16 => ScsiCommand::Verify {
+            lba: u64::from_be_bytes((&cb[2..10]).try_into().unwrap()),
+            len: u32::from_be_bytes((&cb[10..14]).try_into().unwrap()) as u64,
+            byte_check: (cb[1] & 0b00000110) != 0,
+        },
+        READ_DEFECT_DATA_10 => ScsiCommand::ReadDefectData {
+            req_plist: (cb[2] & 0b00010000) != 0,
+            req_glist: (cb[2] & 0b00001000) != 0,
+            defect_list_format: cb[2] & 0b00000111,
+            alloc_len: u16::from_be_bytes([cb[7], cb[8]]) as u32,
+        },
+        READ_DEFECT_DATA_12 => ScsiCommand::ReadDefectData {
+            req_plist: (cb[1] & 0b00010000) != 0,
+            req_glist: (cb[1] & 0b00001000) != 0,
+            defect_list_format: cb[1] & 0b00000111,
+            alloc_len: u32::from_be_bytes((&cb[6..10]).try_into().unwrap()),
+        },
+        WRITE_16 => ScsiCommand::Write {
+            lba: u64::from_be_bytes((&cb[2..10]).try_into().unwrap()),
+            len: u32::from_be_bytes((&cb[10..14]).try_into().unwrap()) as u64,
+        },
+        MODE_SENSE_6 => ScsiCommand::ModeSense6 {
+            dbd: (cb[1] & 0b00001000) != 0,
+            page_control: PageControl::try_from_primitive(cb[2] >> 6).unwrap(),
+            page_code: cb[2] & 0b00111111,
+            subpage_code: cb[3],
+            alloc_len: cb[4],
+        },
+        MODE_SENSE_10 => ScsiCommand::ModeSense10 {
+            dbd: (cb[1] & 0b00001000) != 0,
+            page_control: PageControl::try_from_primitive(cb[2] >> 6).unwrap(),
+            page_code: cb[2] & 0b00111111,
+            subpage_code: cb[3],
+            alloc_len: u16::from_be_bytes([cb[7], cb[8]]),
+        },
+        MODE_SELECT_6 => ScsiCommand::ModeSelect6 {
+            pf: (cb[1] & 0b00010000) != 0,
+            sp: (cb[1] & 0b00000001) != 0,
+            parameter_list_len: cb[4],
+        },
+        MODE_SELECT_10 => ScsiCommand::ModeSelect10 {
+            pf: (cb[1] & 0b00010000) != 0,
+            sp: (cb[1] & 0b00000001) != 0,
+            parameter_list_len: u16::from_be_bytes([cb[7], cb[8]]),
+        },
+        LOG_SELECT => ScsiCommand::LogSelect {
+            pcr: (cb[1] & 0b00000010) != 0,
+            sp: (cb[1] & 0b00000001) != 0,
+            page_control: PageControl::try_from_primitive(cb[2] >> 6).unwrap(),
+            page_code: cb[2] & 0b00111111,
+            subpage_code: cb[3],
+            parameter_list_len: u16::from_be_bytes([cb[7], cb[8]]),
+        },
+        LOG_SENSE => ScsiCommand::LogSense {
+            sp: (cb[1] & 0b00000001) != 0,
+            page_control: PageControl::try_from_primitive(cb[2] >> 6).unwrap(),
+            page_code: cb[2] & 0b00111111,
+            subpage_code: cb[3],
+            parameter_pointer: u16::from_be_bytes([cb[5], cb[6]]),
+            alloc_len: u16::from_be_bytes([cb[7], cb[8]]),
+        },
+        READ_ATTRIBUTE => ScsiCommand::ReadAttribute {
+            service_action: cb[1] & 0b00011111,
+            logical_volume: cb[5],
+            partition: cb[7],
+            first_attribute: u16::from_be_bytes([cb[8], cb[9]]),
+            alloc_len: u32::from_be_bytes([cb[10], cb[11], cb[12], cb[13]]),
+            cache: (cb[14] & 0b00000001) != 0,
+        },
+        WRITE_ATTRIBUTE => ScsiCommand::WriteAttribute {
+            wtc: (cb[1] & 0b00000001) != 0,
+            logical_volume: cb[5],
+            partition: cb[7],
+            parameter_list_len: u32::from_be_bytes([cb[10], cb[11], cb[12], cb[13]]),
+        },
+        READ_FORMAT_CAPACITIES => ScsiCommand::ReadFormatCapacities {
+            alloc_len: u16::from_be_bytes([cb[7], cb[8]]),
+        },
+        READ_HEADER => ScsiCommand::ReadHeader {
+            msf: (cb[1] & 0b00000010) != 0,
+            lba: u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]),
+            alloc_len: u16::from_be_bytes([cb[7], cb[8]]),
+        },
+        READ_CD => ScsiCommand::ReadCd {
+            expected_sector_type: (cb[1] >> 2) & 0b00000111,
+            dap: (cb[1] & 0b00000010) != 0,
+            lba: u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]),
+            len: u32::from_be_bytes([0, cb[6], cb[7], cb[8]]),
+            sync: (cb[9] & 0b10000000) != 0,
+            header_codes: (cb[9] >> 5) & 0b00000011,
+            user_data: (cb[9] & 0b00010000) != 0,
+            edc_ecc: (cb[9] & 0b00001000) != 0,
+            c2_error_info: (cb[9] >> 1) & 0b00000011,
+            subchannel: cb[10] & 0b00000111,
+        },
+        _ => ScsiCommand::Unknown,
+    }
+}
+
+/// Parses SSC specific command blocks falling back to [parse_cb] for the rest
+#[allow(dead_code)]
+fn parse_ssc_cb(cb: &[u8]) -> ScsiCommand {
+    if let Some(unsupported) = unsupported_cdb_format(cb) {
+        return unsupported;
+    }
+    match cb[0] {
+        REWIND => ScsiCommand::Rewind {
+            immed: (cb[1] & 0b00000001) != 0,
+        },
+        READ_BLOCK_LIMITS => ScsiCommand::ReadBlockLimits {
+            mloc: (cb[1] & 0b00000001) != 0,
+        },
+        READ_6 => ScsiCommand::ReadSequential {
+            sili: (cb[1] & 0b00000010) != 0,
+            fixed: (cb[1] & 0b00000001) != 0,
+            len: u32::from_be_bytes([0, cb[2], cb[3], cb[4]]),
+        },
+        WRITE_6 => ScsiCommand::WriteSequential {
+            fixed: (cb[1] & 0b00000001) != 0,
+            len: u32::from_be_bytes([0, cb[2], cb[3], cb[4]]),
+        },
+        WRITE_FILEMARKS_6 => ScsiCommand::WriteFilemarks {
+            wsmk: (cb[1] & 0b00000010) != 0,
+            immed: (cb[1] & 0b00000001) != 0,
+            count: u32::from_be_bytes([0, cb[2], cb[3], cb[4]]),
+        },
+        SPACE_6 => ScsiCommand::Space {
+            code: cb[1] & 0b00001111,
+            // sign-extend 24-bit two's complement
+            count: i32::from_be_bytes([cb[2], cb[3], cb[4], 0]) >> 8,
+        },
+        _ => parse_cb(cb),
+    }
+}
+
+/// Max number of Logical Units
+const MAX_LUNS: usize = 16;
+
+#[cfg(feature = "bbb")]
+const MODE_SENSE_6_DATA_MAX_LEN: usize =
+    MODE_PARAMETER_HEADER_6_LEN + BLOCK_DESCRIPTOR_LEN + MODE_PAGES_MAX_LEN;
+#[cfg(feature = "bbb")]
+const MODE_SENSE_10_DATA_MAX_LEN: usize =
+    MODE_PARAMETER_HEADER_10_LEN + BLOCK_DESCRIPTOR_LEN + MODE_PAGES_MAX_LEN;
+/// The largest response generated by the subclass itself, which the IO buffer has to fit
+#[cfg(feature = "bbb")]
+const RESPONSE_MAX_LEN: usize = const_max(
+    const_max(EXTENDED_INQUIRY_DATA_LEN, MODE_SENSE_10_DATA_MAX_LEN),
+    const_max(
+        UNIT_SERIAL_NUMBER_PAGE_MAX_LEN,
+        DEVICE_IDENTIFICATION_PAGE_MAX_LEN,
+    ),
+);
+
+/// The smallest IO buffer [Scsi::new] accepts with any packet size. It fits a CBW, a single
+/// packet and the largest response answered by the subclass itself
+#[cfg(feature = "bbb")]
+pub const MIN_BUFFER_LEN: usize = const_max(const_max(RESPONSE_MAX_LEN, CBW_LEN), MAX_PACKET_SIZE);
+
+/// The largest packet size of a full speed bulk endpoint
+#[cfg(feature = "bbb")]
+const MAX_PACKET_SIZE: usize = 64;
+
+/// Returns the length of an IO buffer for [Scsi::new] serving blocks of `block_size` with
+/// packets of `packet_size`
+///
+/// The buffer fits a whole block, so that every command can be served without [chunking] the
+/// data. Meant for sizing a static buffer at compile time:
+/// ```ignore
+/// const USB_TRANSPORT_BUF_LEN: usize = required_buffer_len(64, BlockSize::B512);
+/// static mut USB_TRANSPORT_BUF: [u8; USB_TRANSPORT_BUF_LEN] = [0; USB_TRANSPORT_BUF_LEN];
+/// ```
+///
+/// [chunking]: Command::read_write_chunks
+#[cfg(feature = "bbb")]
+pub const fn required_buffer_len(packet_size: u16, block_size: BlockSize) -> usize {
+    const_max(
+        const_max(MIN_BUFFER_LEN, packet_size as usize),
+        block_size.get() as usize,
+    )
+}
+
+#[cfg(feature = "bbb")]
+const fn const_max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+/// Logical Unit state maintained by the subclass itself
+#[derive(Default, Copy, Clone)]
+struct LogicalUnit {
+    reserved: bool,
+    /// Number of logical blocks, if registered
+    capacity: Option<u64>,
+    /// Logical block size, if set. The default of the device type otherwise
+    block_size: Option<BlockSize>,
+    write_protected: bool,
+    /// Whether standard INQUIRY is left to the block device of the unit, see
+    /// [Scsi::set_block_device]
+    device_inquiry: bool,
+    readiness: Readiness,
+    /// Sense data reported in order by the next REQUEST SENSE commands
+    sense: SenseQueue,
+    /// Unit attention conditions, each reported instead of executing the next command
+    unit_attention: SenseQueue,
+    /// Block following the last Read passed by the user, if no other command has been passed to
+    /// the user since
+    read_end: Option<u64>,
+    activity: HostActivity,
+}
+
+impl LogicalUnit {
+    /// Whether `len` blocks starting from `lba` fit the capacity, or `lba` is on the medium if
+    /// `len` is zero. If the capacity is unknown, whether the blocks are addressable at all
+    #[allow(dead_code)]
+    fn contains(&self, lba: u64, len: u64) -> bool {
+        match self.capacity {
+            Some(capacity) if len == 0 => lba < capacity,
+            Some(capacity) => lba.checked_add(len).is_some_and(|end| end <= capacity),
+            None => lba.checked_add(len).is_some(),
+        }
+    }
+}
+
+/// SCSI USB Mass Storage subclass
+pub struct Scsi<T: Transport> {
+    interface: InterfaceNumber,
+    pub(crate) transport: T,
+    device_type: PeripheralDeviceType,
+    inquiry: Option<InquiryData>,
+    serial_number: Option<SerialNumber>,
+    /// See [Scsi::set_on_log_reset]
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    on_log_reset: Option<fn(u8, LogReset)>,
+    /// See [Scsi::set_mode_pages]
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    mode_pages: [u8; MODE_PAGES_MAX_LEN],
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    mode_pages_len: usize,
+    units: [LogicalUnit; MAX_LUNS],
+    #[allow(dead_code)]
+    quirks: Quirks,
+    /// Whether the transport is driven from [UsbClass::poll]
+    auto_drive: bool,
+    /// Whether the callback waits for the whole OUT data transfer
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    buffer_data_out: bool,
+    /// See [Scsi::set_pull_up]
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    pull_up: Option<fn(bool)>,
+    fingerprint: Fingerprint,
+    #[cfg(feature = "history")]
+    history: History,
+}
+
+impl<T: Transport> Scsi<T> {
+    /// Returns the last [HISTORY_LEN](history::HISTORY_LEN) commands completed by the subclass or the user, from
+    /// the oldest to the most recent. Commands aborted by a reset are not recorded
+    #[cfg(feature = "history")]
+    pub fn history(&self) -> impl Iterator<Item = HistoryEntry> + '_ {
+        self.history.iter()
+    }
+
+    /// Drops the commands recorded so far
+    #[cfg(feature = "history")]
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    /// Returns a best-effort guess of the host OS based on the first commands completed after
+    /// a reset. See [fingerprint]
+    pub fn host_os(&self) -> HostOs {
+        self.fingerprint.host_os()
+    }
+
+    /// Whether a Logical Unit is reserved via RESERVE(6).
+    ///
+    /// RESERVE(6) and RELEASE(6) are handled by the subclass and never passed to the user.
+    /// Reservations are released on reset.
+    pub fn is_reserved(&self, lun: u8) -> bool {
+        self.units
+            .get(lun as usize)
+            .map(|unit| unit.reserved)
+            .unwrap_or(false)
+    }
+
+    /// Registers the capacity of a Logical Unit as a number of logical blocks.
+    ///
+    /// Once registered, Read and Write commands addressing blocks beyond the capacity are
+    /// failed by the subclass with ILLEGAL REQUEST / LOGICAL BLOCK ADDRESS OUT OF RANGE
+    /// sense and never passed to the user. READ CAPACITY(10/16), READ FORMAT CAPACITIES and
+    /// MODE SENSE(6/10) are answered by the subclass using the capacity and the [block size].
+    ///
+    /// [block size]: Scsi::set_block_size
+    ///
+    /// # Panics
+    /// Panics if `lun` is greater than `0x0F`
+    pub fn set_capacity(&mut self, lun: u8, num_blocks: u64) {
+        self.units[lun as usize].capacity = Some(num_blocks);
+    }
+
+    /// Registers the capacity and the block size of a Logical Unit served with `device`.
+    /// Same as [set_capacity] followed by [set_block_size]. If the device has [inquiry_data],
+    /// standard INQUIRY of the unit is passed to the user instead of being answered with the data
+    /// shared by all the units, so that [BlockDriver] answers it. See [BlockDriver]
+    ///
+    /// [set_capacity]: Scsi::set_capacity
+    /// [set_block_size]: Scsi::set_block_size
+    /// [inquiry_data]: BlockDevice::inquiry_data
+    /// [BlockDriver]: crate::subclass::scsi::block::BlockDriver
+    ///
+    /// # Panics
+    /// Panics if `lun` is greater than `0x0F`
+    pub fn set_block_device(&mut self, lun: u8, device: &impl BlockDevice) {
+        self.set_capacity(lun, device.num_blocks());
+        self.set_block_size(lun, device.block_size());
+        self.units[lun as usize].device_inquiry = device.inquiry_data().is_some();
+    }
+
+    /// Same as [set_block_device], for a Logical Unit served with an [AsyncBlockDevice]
+    ///
+    /// [set_block_device]: Scsi::set_block_device
+    ///
+    /// # Panics
+    /// Panics if `lun` is greater than `0x0F`
+    pub fn set_async_block_device(&mut self, lun: u8, device: &impl AsyncBlockDevice) {
+        self.set_capacity(lun, device.num_blocks());
+        self.set_block_size(lun, device.block_size());
+        self.units[lun as usize].device_inquiry = device.inquiry_data().is_some();
+    }
+
+    /// Returns the capacity of a Logical Unit if registered
+    pub fn capacity(&self, lun: u8) -> Option<u64> {
+        self.units.get(lun as usize).and_then(|unit| unit.capacity)
+    }
+
+    /// Sets the logical block size of a Logical Unit. The [default] of the device type otherwise
+    ///
+    /// # Panics
+    /// Panics if `lun` is greater than `0x0F`
+    ///
+    /// [default]: PeripheralDeviceType::default_block_size
+    pub fn set_block_size(&mut self, lun: u8, block_size: BlockSize) {
+        self.units[lun as usize].block_size = Some(block_size);
+    }
+
+    /// Returns the logical block size of a Logical Unit
+    pub fn block_size(&self, lun: u8) -> BlockSize {
+        self.units
+            .get(lun as usize)
+            .and_then(|unit| unit.block_size)
+            .unwrap_or(self.device_type.default_block_size())
+    }
+
+    /// Sets write protection of a Logical Unit.
+    ///
+    /// Write commands addressed to a write protected Logical Unit are failed by the subclass with
+    /// DATA PROTECT / WRITE PROTECTED sense and never passed to the user. The WP bit of
+    /// the mode data reported by the subclass follows this flag.
+    ///
+    /// Changing the flag establishes a unit attention condition (MODE PARAMETERS CHANGED), so
+    /// a host re-reads mode data.
+    ///
+    /// # Panics
+    /// Panics if `lun` is greater than `0x0F`
+    pub fn set_write_protected(&mut self, lun: u8, write_protected: bool) {
+        let unit = &mut self.units[lun as usize];
+        if unit.write_protected != write_protected {
+            unit.write_protected = write_protected;
+            unit.unit_attention
+                .push_unique(Sense::MODE_PARAMETERS_CHANGED);
+        }
+    }
+
+    /// Whether a Logical Unit is write protected
+    pub fn is_write_protected(&self, lun: u8) -> bool {
+        self.units
+            .get(lun as usize)
+            .map(|unit| unit.write_protected)
+            .unwrap_or(false)
+    }
+
+    /// Sets readiness of a Logical Unit. [Readiness::Ready] by default.
+    ///
+    /// While not ready, all the commands except INQUIRY and REQUEST SENSE are failed by
+    /// the subclass with NOT READY sense and never passed to the user. REQUEST SENSE reports
+    /// the readiness, including progress indication, if no other sense data is pending.
+    ///
+    /// Becoming ready establishes a unit attention condition (NOT READY TO READY CHANGE).
+    ///
+    /// # Panics
+    /// Panics if `lun` is greater than `0x0F`
+    pub fn set_readiness(&mut self, lun: u8, readiness: Readiness) {
+        let unit = &mut self.units[lun as usize];
+        if unit.readiness != Readiness::Ready && readiness == Readiness::Ready {
+            unit.unit_attention
+                .push_unique(Sense::NOT_READY_TO_READY_CHANGE);
+        }
+        unit.readiness = readiness;
+    }
+
+    /// Returns readiness of a Logical Unit
+    pub fn readiness(&self, lun: u8) -> Readiness {
+        self.units
+            .get(lun as usize)
+            .map(|unit| unit.readiness)
+            .unwrap_or_default()
+    }
+
+    /// Queues sense data of a Logical Unit.
+    ///
+    /// REQUEST SENSE commands addressed to this Logical Unit are answered by the subclass with
+    /// the queued sense data in order and never passed to the user. The descriptor format is used
+    /// if the host sets the DESC bit, the fixed format otherwise. Up to [SENSE_QUEUE_LEN]
+    /// conditions are queued, later ones are dropped and an equal condition in a row is queued
+    /// once. Pending sense is cleared on reset.
+    ///
+    /// # Panics
+    /// Panics if `lun` is greater than `0x0F`
+    ///
+    /// [SENSE_QUEUE_LEN]: sense::SENSE_QUEUE_LEN
+    pub fn set_sense(&mut self, lun: u8, sense: Sense) {
+        self.units[lun as usize].sense.push(sense);
+    }
+
+    /// Returns the oldest sense data pending for a Logical Unit
+    pub fn sense(&self, lun: u8) -> Option<Sense> {
+        self.units
+            .get(lun as usize)
+            .and_then(|unit| unit.sense.first())
+    }
+
+    /// Returns the number of sense conditions pending for a Logical Unit
+    pub fn pending_sense(&self, lun: u8) -> usize {
+        self.units
+            .get(lun as usize)
+            .map(|unit| unit.sense.len())
+            .unwrap_or(0)
+    }
+
+    /// Whether `kind` is a Read starting at the block following the last Read of a Logical Unit
+    #[cfg(feature = "bbb")]
+    pub(crate) fn continues_read(&self, kind: ScsiCommand, lun: u8) -> bool {
+        match kind {
+            ScsiCommand::Read { lba, .. } => self
+                .units
+                .get(lun as usize)
+                .is_some_and(|unit| unit.read_end == Some(lba)),
+            _ => false,
+        }
+    }
+
+    /// Tracks a command completed by the user with `status`: the end of a Read, any other
+    /// command breaking the continuity, and the host activity
+    #[cfg(feature = "bbb")]
+    pub(crate) fn track_completed(&mut self, kind: ScsiCommand, lun: u8, status: CommandStatus) {
+        self.fingerprint.observe(kind);
+        let unit = &mut self.units[lun as usize];
+        let passed = matches!(status, CommandStatus::Passed);
+        unit.read_end = match kind {
+            ScsiCommand::Read { lba, len } if passed => lba.checked_add(len),
+            _ => None,
+        };
+        match kind {
+            ScsiCommand::Read { .. }
+            | ScsiCommand::Write { .. }
+            | ScsiCommand::WriteAndVerify { .. }
+            | ScsiCommand::Verify { .. } => unit.activity.accessed = true,
+            ScsiCommand::PreventAllowMediumRemoval { prevent } if passed => {
+                unit.activity.removal_prevented = prevent;
+            }
+            ScsiCommand::StartStopUnit {
+                power_condition: 0,
+                start,
+                ..
+            } if passed => unit.activity.stopped = !start,
+            _ => {}
+        }
+    }
+
+    /// Returns what the host is doing with a Logical Unit, so that the device can tell when it's
+    /// safe to take the medium back, e.g. for local logging. The commands are tracked once
+    /// completed by the user. Clears [HostActivity::accessed]
+    ///
+    /// A bus reset releases the medium and starts the unit.
+    ///
+    /// # Panics
+    /// Panics if `lun` is greater than `0x0F`
+    pub fn host_activity(&mut self, lun: u8) -> HostActivity {
+        let unit = &mut self.units[lun as usize];
+        let activity = unit.activity;
+        unit.activity.accessed = false;
+        activity
+    }
+
+    /// Returns the peripheral device type
+    pub fn device_type(&self) -> PeripheralDeviceType {
+        self.device_type
+    }
+
+    /// Sets the peripheral device type which defines how command blocks are parsed, the type
+    /// reported in INQUIRY data answered by the subclass and the default logical block size.
+    /// [PeripheralDeviceType::DirectAccess] by default
+    pub fn set_device_type(&mut self, device_type: PeripheralDeviceType) {
+        self.device_type = device_type;
+    }
+
+    /// Registers standard INQUIRY data shared by all the Logical Units.
+    ///
+    /// Once registered, standard INQUIRY is answered by the subclass and never passed to the user.
+    /// The data is truncated to the allocation length of the command, and the peripheral device
+    /// type follows [Scsi::set_device_type]. INQUIRY of a page without EVPD is failed with
+    /// INVALID FIELD IN CDB sense, while EVPD requests are still passed to the user.
+    pub fn set_inquiry(&mut self, data: InquiryData) {
+        self.inquiry = Some(data);
+    }
+
+    /// Returns standard INQUIRY data, if registered
+    pub fn inquiry(&self) -> Option<&InquiryData> {
+        self.inquiry.as_ref()
+    }
+
+    /// Registers the identity of the device: standard INQUIRY data as per [Scsi::set_inquiry],
+    /// and the serial number, reported in the Unit Serial Number page. The page is then answered
+    /// by the subclass, while the other EVPD requests are still passed to the user. The Supported
+    /// VPD Pages page answered by the user is expected to list it
+    pub fn set_identity(&mut self, identity: &DeviceIdentity) {
+        self.inquiry = Some(identity.inquiry_data());
+        self.serial_number = Some(SerialNumber::new(identity.serial));
+    }
+
+    /// Returns the serial number registered with [Scsi::set_identity], if any
+    pub fn serial_number(&self) -> Option<&SerialNumber> {
+        self.serial_number.as_ref()
+    }
+
+    /// Sets a callback resetting the log parameters of a Logical Unit, e.g. the error counters
+    /// reported with LOG SENSE. Once set, LOG SELECT without a parameter list is answered by
+    /// the subclass, which calls the callback with the LUN and the request. LOG SELECT with
+    /// a parameter list is passed to the user regardless
+    pub fn set_on_log_reset(&mut self, callback: Option<fn(u8, LogReset)>) {
+        self.on_log_reset = callback;
+    }
+
+    /// Registers the mode pages answered by MODE SENSE(6/10) along with the mode parameter
+    /// header, as concatenated raw pages, e.g. built with [caching_mode_page]. Each request is
+    /// answered with the registered pages it selects, the request of [all the pages], which
+    /// Windows issues on mount, with all of them. The Caching page of
+    /// [Quirks::mode_sense_all_pages] is answered only if no page is registered
+    ///
+    /// [caching_mode_page]: mode::caching_mode_page
+    /// [all the pages]: mode::ALL_PAGES
+    ///
+    /// # Panics
+    /// Panics if the pages are malformed or longer than [MODE_PAGES_MAX_LEN]
+    pub fn set_mode_pages(&mut self, pages: &[u8]) {
+        assert!(pages.len() <= MODE_PAGES_MAX_LEN && is_valid_mode_pages(pages));
+        self.mode_pages[..pages.len()].copy_from_slice(pages);
+        self.mode_pages_len = pages.len();
+    }
+
+    /// Returns the mode pages registered with [Scsi::set_mode_pages]
+    pub fn mode_pages(&self) -> &[u8] {
+        &self.mode_pages[..self.mode_pages_len]
+    }
+
+    /// Returns the underlying transport
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the underlying transport.
+    ///
+    /// Driving the transport directly while a command is in progress, e.g. setting its status,
+    /// bypasses the subclass and may confuse it.
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Drives the transport from [UsbClass::poll], so that [UsbDevice::poll] alone moves the data
+    /// of both directions. Commands are then served with [handle_command] once [has_command]
+    /// tells one is waiting. Disabled by default
+    ///
+    /// [UsbClass::poll]: usb_device::class::UsbClass::poll
+    /// [UsbDevice::poll]: usb_device::device::UsbDevice::poll
+    /// [handle_command]: Scsi::handle_command
+    /// [has_command]: Scsi::has_command
+    pub fn set_auto_drive(&mut self, enabled: bool) {
+        self.auto_drive = enabled;
+    }
+}
+
+/// SCSI subclass implementation with [Bulk Only Transport]
+///
+/// [Bulk Only Transport]: crate::transport::bbb::BulkOnly
+#[cfg(feature = "bbb")]
+impl<'alloc, Bus: UsbBus + 'alloc, Buf: BorrowMut<[u8]>> Scsi<BulkOnly<'alloc, Bus, Buf>> {
+    /// Creates an SCSI over Bulk Only Transport instance
+    ///
+    /// # Arguments
+    /// * `alloc` - [UsbBusAllocator]
+    /// * `packet_size` - Maximum USB packet size. Allowed values: 8,16,32,64
+    /// * `max_lun` - The max index of the Logical Unit
+    /// * `buf` - The underlying IO buffer. It is **required** to fit at least a `CBW`, a single
+    ///   packet and 74 bytes of INQUIRY data answered by the subclass, see [MIN_BUFFER_LEN]. It is
+    ///   **recommended** that buffer fits at least one sector, see [required_buffer_len], though
+    ///   [Write] commands may be served with a smaller one using [read_write_chunks]
+    ///
+    /// # Errors
+    /// * [InvalidMaxLun]
+    /// * [BufferTooSmall]
+    ///
+    /// # Panics
+    /// Panics if endpoint allocations fails.
+    ///
+    /// [InvalidMaxLun]: crate::transport::bbb::BulkOnlyError::InvalidMaxLun
+    /// [BufferTooSmall]: crate::transport::bbb::BulkOnlyError::BufferTooSmall
+    /// [UsbBusAllocator]: usb_device::bus::UsbBusAllocator
+    /// [Write]: ScsiCommand::Write
+    /// [read_write_chunks]: Command::read_write_chunks
+    pub fn new(
+        alloc: &'alloc UsbBusAllocator<Bus>,
+        packet_size: u16,
+        max_lun: u8,
+        buf: Buf,
+    ) -> Result<Self, BulkOnlyError> {
+        Self::new_with_interval(alloc, packet_size, max_lun, buf, 0)
+    }
+
+    /// Creates an instance like [new], with `interval` as `bInterval` of the bulk endpoints.
+    /// See [BulkOnly::new_with_interval]
+    ///
+    /// # Errors
+    /// See [new]
+    ///
+    /// [new]: Scsi::new
+    pub fn new_with_interval(
+        alloc: &'alloc UsbBusAllocator<Bus>,
+        packet_size: u16,
+        max_lun: u8,
+        buf: Buf,
+        interval: u8,
+    ) -> Result<Self, BulkOnlyError> {
+        if buf.borrow().len() < MIN_BUFFER_LEN {
+            return Err(BulkOnlyError::BufferTooSmall);
+        }
+        BulkOnly::new_with_interval(alloc, packet_size, max_lun, buf, interval).map(|transport| {
+            Self {
+                interface: alloc.interface(),
+                transport,
+                device_type: Default::default(),
+                inquiry: None,
+                serial_number: None,
+                on_log_reset: None,
+                mode_pages: [0; MODE_PAGES_MAX_LEN],
+                mode_pages_len: 0,
+                units: Default::default(),
+                quirks: Default::default(),
+                auto_drive: false,
+                buffer_data_out: false,
+                pull_up: None,
+                fingerprint: Default::default(),
+                #[cfg(feature = "history")]
+                history: Default::default(),
+            }
+        })
+    }
+
+    /// Drive subclass in both directions
+    ///
+    /// The passed closure may or may not be called after each time this function is called.
+    /// Moreover, it may be called multiple times, if subclass is unable to proceed further.
+    ///
+    /// Same as [drive_transport] followed by [handle_command].
+    ///
+    /// # Arguments
+    /// * `callback` - closure, in which the SCSI command is processed
+    ///
+    /// [drive_transport]: Scsi::drive_transport
+    /// [handle_command]: Scsi::handle_command
+    pub fn poll<F>(&mut self, callback: F) -> Result<(), UsbError>
+    where
+        F: FnMut(Command<ScsiCommand, Scsi<BulkOnly<'alloc, Bus, Buf>>>),
+    {
+        self.drive_transport()?;
+        self.handle_command(callback)
+    }
+
+    /// Drive the transport in both directions without processing a command.
+    /// Returns `true` if a command waits to be processed with [handle_command]
+    ///
+    /// Allows to split the work between execution contexts, e.g. the USB interrupt calls this
+    /// along with [UsbDevice::poll] while a lower priority task handles commands. Both only
+    /// need a short lock of the subclass.
+    ///
+    /// [handle_command]: Scsi::handle_command
+    /// [UsbDevice::poll]: usb_device::device::UsbDevice::poll
+    pub fn drive_transport(&mut self) -> Result<bool, UsbError> {
+        map_ignore(self.transport.read())?;
+        map_ignore(self.transport.write())?;
+        Ok(self.has_command())
+    }
+
+    /// Returns `true` if a command waits to be processed with [handle_command]
+    ///
+    /// [handle_command]: Scsi::handle_command
+    pub fn has_command(&self) -> bool {
+        !self.transport.is_detached()
+            && self.transport.get_command().is_some()
+            && !self.transport.has_status()
+            && !self.awaits_data_out()
+    }
+
+    /// Holds the callback back until the whole OUT data transfer is in the IO buffer, so that
+    /// it's called once with all the data available, e.g. the parameter list of MODE SELECT or
+    /// UNMAP. Applies to the transfers the buffer takes in whole, i.e. `dCBWDataTransferLength`
+    /// of at most [max_buffered_data_out_len]. Longer ones, e.g. [Write] of many blocks, are
+    /// handed over as the data arrives. Disabled by default
+    ///
+    /// [max_buffered_data_out_len]: BulkOnly::max_buffered_data_out_len
+    /// [Write]: ScsiCommand::Write
+    pub fn set_buffer_data_out(&mut self, enabled: bool) {
+        self.buffer_data_out = enabled;
+    }
+
+    /// Sets a callback connecting the device to the bus (`true`) and disconnecting it from
+    /// the bus (`false`), e.g. by driving the D+ pull-up. Called by [detach] and [attach]
+    ///
+    /// [detach]: Scsi::detach
+    /// [attach]: Scsi::attach
+    pub fn set_pull_up(&mut self, callback: Option<fn(bool)>) {
+        self.pull_up = callback;
+    }
+
+    /// Stops answering the host, so that the firmware can own the medium for a while, e.g. to
+    /// rewrite the filesystem. The bulk endpoints NAK everything and no command is handed over
+    /// until [attach]. The device is disconnected from the bus if [set_pull_up] has been set.
+    /// See [BulkOnly::detach]
+    ///
+    /// A host left connected waits for the device and eventually resets it. A host the device
+    /// has been disconnected from forgets it and enumerates it again on [attach].
+    ///
+    /// [attach]: Scsi::attach
+    /// [set_pull_up]: Scsi::set_pull_up
+    pub fn detach(&mut self) {
+        self.transport.detach();
+        if let Some(pull_up) = self.pull_up {
+            pull_up(false);
+        }
+    }
+
+    /// Resumes answering the host after [detach]. Establishes a unit attention condition
+    /// (NOT READY TO READY CHANGE) of all the Logical Units, so that a host re-reads the medium
+    /// the firmware has just owned
+    ///
+    /// [detach]: Scsi::detach
+    pub fn attach(&mut self) {
+        for unit in self.units.iter_mut() {
+            unit.unit_attention
+                .push_unique(Sense::NOT_READY_TO_READY_CHANGE);
+        }
+        self.transport.attach();
+        if let Some(pull_up) = self.pull_up {
+            pull_up(true);
+        }
+    }
+
+    /// Returns `true` if the callback is held back until the rest of the OUT data arrives.
+    /// See [set_buffer_data_out](Scsi::set_buffer_data_out)
+    fn awaits_data_out(&self) -> bool {
+        match self.transport.phase() {
+            Some(CommandPhase::DataOut { received, expected }) => {
+                self.buffer_data_out
+                    && received < expected
+                    && expected as usize <= self.transport.max_buffered_data_out_len()
+            }
+            _ => false,
+        }
+    }
+
+    /// Process the pending command, if any, and drive the transport afterward
+    ///
+    /// The passed closure may be called multiple times, if subclass is unable to proceed further.
+    /// See [drive_transport]
+    ///
+    /// # Arguments
+    /// * `callback` - closure, in which the SCSI command is processed
+    ///
+    /// [drive_transport]: Scsi::drive_transport
+    pub fn handle_command<F>(&mut self, mut callback: F) -> Result<(), UsbError>
+    where
+        F: FnMut(Command<ScsiCommand, Scsi<BulkOnly<'alloc, Bus, Buf>>>),
+    {
+        if self.transport.is_detached() {
+            return Ok(());
+        }
+        if let Some(raw_cb) = self.transport.get_command() {
+            // exec callback only if user action required
+            if !self.transport.has_status() {
+                let lun = raw_cb.lun;
+                let kind = self.parse(raw_cb.bytes);
+
+                #[cfg(feature = "names")]
+                debug!(
+                    "usb: scsi: Command: {}",
+                    names::Traced::new(raw_cb.bytes[0], kind)
+                );
+                #[cfg(not(feature = "names"))]
+                debug!("usb: scsi: Command: {}", kind);
+
+                loop {
+                    // the checks run once, when the command is received. Conditions arising
+                    // later, e.g. a change of the write protection, apply to the next commands
+                    let dispatched =
+                        self.transport.is_dispatched() || !self.handle_builtin(kind, lun);
+                    if dispatched {
+                        self.transport.set_dispatched();
+                    }
+                    if dispatched && !self.awaits_data_out() {
+                        callback(Command {
+                            class: self,
+                            kind,
+                            lun,
+                        });
+                    }
+
+                    // drive transport in both directions after user action.
+                    // exec callback if not enough data
+                    match self.transport.write() {
+                        Err(TransportError::Error(BulkOnlyError::FullPacketExpected)) => {
+                            continue;
+                        }
+                        Ok(_)
+                        | Err(TransportError::Error(_))
+                        | Err(TransportError::Usb(UsbError::WouldBlock)) => { /* ignore */ }
+                        Err(TransportError::Usb(err)) => {
+                            return Err(err);
+                        }
+                    };
+                    map_ignore(self.transport.read())?;
+
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets host-specific workarounds of both the subclass and the transport. See [Quirks]
+    ///
+    /// [Quirks]: crate::quirks::Quirks
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+        self.transport.set_quirks(quirks);
+    }
+
+    /// Returns the last reset received since the previous call, if any.
+    ///
+    /// Both USB bus resets and Bulk-Only Mass Storage Resets are reported. Any per-command state
+    /// kept by the user, e.g. an offset into the current transfer, is expected to be dropped.
+    pub fn take_reset(&mut self) -> Option<Reset> {
+        self.transport.take_reset()
+    }
+
+    /// Returns the command dropped by the last reset or abandoned by the host in the middle of
+    /// the IN data transfer before its status has been set, if any. Returns `None` on
+    /// subsequent calls
+    pub fn take_aborted(&mut self) -> Option<Aborted<ScsiCommand>> {
+        let transferred = self.transport.aborted_data_transferred().unwrap_or(0);
+        let aborted = self.transport.aborted_command().map(|raw_cb| {
+            let kind = self.parse(raw_cb.bytes);
+            Aborted {
+                kind,
+                lun: raw_cb.lun,
+                tag: raw_cb.tag,
+                opcode: raw_cb.bytes[0],
+                lba: blocks_lba(kind),
+                transferred,
+            }
+        });
+        self.transport.clear_aborted_command();
+        aborted
+    }
+
+    fn parse(&self, cb: &[u8]) -> ScsiCommand {
+        match self.device_type {
+            PeripheralDeviceType::SequentialAccess => parse_ssc_cb(cb),
+            _ => parse_cb(cb),
+        }
+    }
+
+    /// Handles commands that the subclass takes care of by itself.
+    /// Returns `false` if the command should be passed to the user
+    fn handle_builtin(&mut self, kind: ScsiCommand, lun: u8) -> bool {
+        let block_size = self.block_size(lun);
+
+        // the host expects no data or the opposite direction of what the command transfers.
+        // Spec. BBB 6.7, cases 2, 3, 8 and 10
+        if let Some((direction, _)) = implied_data(kind, block_size) {
+            if self.transport.data_direction() != Some(direction) {
+                debug!("usb: scsi: Data direction mismatch: {}", kind);
+                self.set_builtin_status(kind, lun, CommandStatus::PhaseError);
+                return true;
+            }
+        }
+        // the host expects fewer blocks than the command transfers, so that the data phase
+        // would never end. Spec. BBB 6.7, cases 7 and 13. Fewer bytes than the host expects
+        // are left to the transport: those are reported as residue (cases 5 and 11)
+        if let Some(len) = blocks_data_len(kind, block_size) {
+            if len > self.transport.data_transfer_len() as u64 {
+                debug!(
+                    "usb: scsi: Data length mismatch: {}, {}",
+                    len,
+                    self.transport.data_transfer_len()
+                );
+                self.set_builtin_status(kind, lun, CommandStatus::PhaseError);
+                return true;
+            }
+        }
+
+        let unit = &mut self.units[lun as usize];
+        // the data of the device describes the unit, unlike the shared one
+        let inquiry = self.inquiry.filter(|_| !unit.device_inquiry);
+
+        // Spec. SAM: report a unit attention condition instead of executing a command
+        if !unit.unit_attention.is_empty()
+            && !matches!(
+                kind,
+                ScsiCommand::Inquiry { .. } | ScsiCommand::RequestSense { .. }
+            )
+        {
+            unit.sense.push(unit.unit_attention.pop().unwrap());
+            self.set_builtin_status(kind, lun, CommandStatus::Failed);
+            return true;
+        }
+
+        let status = match kind {
+            ScsiCommand::Reserve6 => {
+                unit.reserved = true; // the only initiator is a USB host
+                CommandStatus::Passed
+            }
+            ScsiCommand::Release6 => {
+                unit.reserved = false;
+                CommandStatus::Passed
+            }
+            ScsiCommand::RequestSense { desc, alloc_len }
+                if !unit.sense.is_empty()
+                    || !unit.unit_attention.is_empty()
+                    || unit.readiness != Readiness::Ready =>
+            {
+                let sense = unit
+                    .sense
+                    .pop()
+                    .or_else(|| unit.unit_attention.pop())
+                    .or(unit.readiness.sense())
+                    .unwrap();
+                if desc {
+                    let mut data = [0u8; DESCRIPTOR_SENSE_DATA_MAX_LEN];
+                    let len = sense.write_descriptor_bytes(&mut data);
+                    write_response(&mut self.transport, &data[..len], alloc_len);
+                } else {
+                    write_response(&mut self.transport, &sense.to_fixed_bytes(), alloc_len);
+                }
+                CommandStatus::Passed
+            }
+            ScsiCommand::Inquiry {
+                evpd: false,
+                page_code,
+                alloc_len,
+            } if inquiry.is_some() => {
+                if page_code != 0 {
+                    unit.sense.push(Sense::INVALID_FIELD_IN_CDB);
+                    CommandStatus::Failed
+                } else {
+                    let mut data = [0u8; EXTENDED_INQUIRY_DATA_LEN];
+                    let len =
+                        write_standard_inquiry(&mut data, self.device_type, &inquiry.unwrap());
+                    write_response(&mut self.transport, &data[..len], alloc_len);
+                    CommandStatus::Passed
+                }
+            }
+            ScsiCommand::Inquiry {
+                evpd: true,
+                page_code: UNIT_SERIAL_NUMBER_PAGE,
+                alloc_len,
+            } if self.serial_number.is_some() => {
+                let mut data = [0u8; UNIT_SERIAL_NUMBER_PAGE_MAX_LEN];
+                let len = write_unit_serial_number_page(
+                    &mut data,
+                    self.device_type,
+                    &self.serial_number.unwrap(),
+                );
+                write_response(&mut self.transport, &data[..len], alloc_len);
+                CommandStatus::Passed
+            }
+            ScsiCommand::Inquiry {
+                evpd: true,
+                page_code: SUPPORTED_VPD_PAGES,
+                alloc_len,
+            } if self.quirks.vpd_pages && inquiry.is_some() => {
+                let pages: &[u8] = if self.serial_number.is_some() {
+                    &[
+                        SUPPORTED_VPD_PAGES,
+                        UNIT_SERIAL_NUMBER_PAGE,
+                        DEVICE_IDENTIFICATION_PAGE,
+                    ]
+                } else {
+                    &[SUPPORTED_VPD_PAGES, DEVICE_IDENTIFICATION_PAGE]
+                };
+                let mut data = [0u8; 8];
+                let len = write_supported_vpd_pages(&mut data, self.device_type, pages);
+                write_response(&mut self.transport, &data[..len], alloc_len);
+                CommandStatus::Passed
+            }
+            ScsiCommand::Inquiry {
+                evpd: true,
+                page_code: DEVICE_IDENTIFICATION_PAGE,
+                alloc_len,
+            } if self.quirks.vpd_pages && inquiry.is_some() => {
+                let mut data = [0u8; DEVICE_IDENTIFICATION_PAGE_MAX_LEN];
+                let len = write_device_identification_page(
+                    &mut data,
+                    self.device_type,
+                    &inquiry.unwrap(),
+                    self.serial_number.as_ref(),
+                );
+                write_response(&mut self.transport, &data[..len], alloc_len);
+                CommandStatus::Passed
+            }
+            ScsiCommand::Inquiry { .. } => return false,
+            ScsiCommand::LogSelect {
+                pcr,
+                page_control,
+                page_code,
+                subpage_code,
+                parameter_list_len: 0,
+                ..
+            } if self.on_log_reset.is_some() => {
+                let reset = LogReset {
+                    pcr,
+                    page_control,
+                    page_code,
+                    subpage_code,
+                };
+                (self.on_log_reset.unwrap())(lun, reset);
+                CommandStatus::Passed
+            }
+            _ if unit.readiness != Readiness::Ready => {
+                unit.sense.push(unit.readiness.sense().unwrap());
+                CommandStatus::Failed
+            }
+            // Spec. SBC-2 5.10: the LBA is to be zero without PMI
+            ScsiCommand::ReadCapacity10 { lba, pmi: false } if lba != 0 => {
+                unit.sense.push(Sense::INVALID_FIELD_IN_CDB);
+                CommandStatus::Failed
+            }
+            ScsiCommand::ReadCapacity16 {
+                lba, pmi: false, ..
+            } if lba != 0 => {
+                unit.sense.push(Sense::INVALID_FIELD_IN_CDB);
+                CommandStatus::Failed
+            }
+            // with PMI, the last LBA of the medium is reported as there is no substantial delay
+            // in transferring data at any other block
+            ScsiCommand::ReadCapacity10 { .. } if unit.capacity.is_some() => {
+                let data = read_capacity_10(unit.capacity.unwrap(), block_size);
+                write_response(&mut self.transport, &data, data.len() as u32);
+                CommandStatus::Passed
+            }
+            ScsiCommand::ReadCapacity16 { alloc_len, .. } if unit.capacity.is_some() => {
+                let data = read_capacity_16(unit.capacity.unwrap(), block_size);
+                write_response(&mut self.transport, &data, alloc_len);
+                CommandStatus::Passed
+            }
+            ScsiCommand::ReadFormatCapacities { alloc_len } if unit.capacity.is_some() => {
+                let data = read_format_capacities(unit.capacity.unwrap(), block_size);
+                write_response(&mut self.transport, &data, alloc_len);
+                CommandStatus::Passed
+            }
+            ScsiCommand::ModeSense6 {
+                dbd,
+                page_code,
+                subpage_code,
+                alloc_len,
+                ..
+            } if unit.capacity.is_some() => {
+                let bd = block_descriptor(unit.capacity.unwrap(), block_size);
+                let mut pages = [0u8; MODE_PAGES_MAX_LEN];
+                let pages_len = mode_sense_pages(
+                    &mut pages,
+                    &self.mode_pages[..self.mode_pages_len],
+                    self.quirks,
+                    page_code,
+                    subpage_code,
+                );
+                let mut data = [0u8; MODE_SENSE_6_DATA_MAX_LEN];
+                let len = write_mode_sense_6(
+                    &mut data,
+                    unit.write_protected,
+                    (!dbd).then_some(&bd),
+                    &pages[..pages_len],
+                );
+                write_response(&mut self.transport, &data[..len], alloc_len);
+                CommandStatus::Passed
+            }
+            ScsiCommand::ModeSense10 {
+                dbd,
+                page_code,
+                subpage_code,
+                alloc_len,
+                ..
+            } if unit.capacity.is_some() => {
+                let bd = block_descriptor(unit.capacity.unwrap(), block_size);
+                let mut pages = [0u8; MODE_PAGES_MAX_LEN];
+                let pages_len = mode_sense_pages(
+                    &mut pages,
+                    &self.mode_pages[..self.mode_pages_len],
+                    self.quirks,
+                    page_code,
+                    subpage_code,
+                );
+                let mut data = [0u8; MODE_SENSE_10_DATA_MAX_LEN];
+                let len = write_mode_sense_10(
+                    &mut data,
+                    unit.write_protected,
+                    (!dbd).then_some(&bd),
+                    &pages[..pages_len],
+                );
+                write_response(&mut self.transport, &data[..len], alloc_len);
+                CommandStatus::Passed
+            }
+            ScsiCommand::Write { .. } | ScsiCommand::WriteAndVerify { .. }
+                if unit.write_protected =>
+            {
+                unit.sense.push(Sense::WRITE_PROTECTED);
+                CommandStatus::Failed
+            }
+            ScsiCommand::Read { lba, len }
+            | ScsiCommand::Write { lba, len }
+            | ScsiCommand::WriteAndVerify { lba, len, .. }
+            | ScsiCommand::Verify { lba, len, .. }
+                if !unit.contains(lba, len) =>
+            {
+                debug!("usb: scsi: LBA out of range: {}, {}", lba, len);
+                unit.sense.push(Sense::LBA_OUT_OF_RANGE);
+                CommandStatus::Failed
+            }
+            // Spec. SBC: transferring no blocks is not an error. Data the host expects
+            // regardless is reported as residue (BBB 6.7, cases 4 and 9)
+            ScsiCommand::Read { len: 0, .. }
+            | ScsiCommand::Write { len: 0, .. }
+            | ScsiCommand::WriteAndVerify { len: 0, .. }
+            | ScsiCommand::Verify { len: 0, .. } => CommandStatus::Passed,
+            _ => return false,
+        };
+        self.set_builtin_status(kind, lun, status);
+        true
+    }
+
+    #[cfg_attr(not(feature = "history"), allow(unused_variables))]
+    fn set_builtin_status(&mut self, kind: ScsiCommand, lun: u8, status: CommandStatus) {
+        self.fingerprint.observe(kind);
+        #[cfg(feature = "history")]
+        self.record(kind, lun, status);
+        self.transport.set_status(status);
+    }
+
+    /// Records the current command into the history
+    #[cfg(feature = "history")]
+    pub(crate) fn record(&mut self, kind: ScsiCommand, lun: u8, status: CommandStatus) {
+        let opcode = self.transport.get_command().map_or(0, |cb| cb.bytes[0]);
+        self.history
+            .push(HistoryEntry::new(opcode, lun, kind, status));
+    }
+}
+
+/// Direction and number of bytes of the data the command block implies, `None` if it implies
+/// none, e.g. a READ of zero blocks, or is not known. The length of a command with an allocation
+/// length is the upper bound the device may transfer. Block counts are converted with
+/// `block_size`, a READ CD counts the user data only
+#[cfg(feature = "bbb")]
+pub(crate) fn implied_data(
+    kind: ScsiCommand,
+    block_size: BlockSize,
+) -> Option<(UsbDirection, u64)> {
+    let blocks = |len: u64| len.saturating_mul(block_size.get() as u64);
+    let (direction, len) = match kind {
+        ScsiCommand::Inquiry { alloc_len, .. }
+        | ScsiCommand::ModeSense10 { alloc_len, .. }
+        | ScsiCommand::LogSense { alloc_len, .. }
+        | ScsiCommand::ReadFormatCapacities { alloc_len }
+        | ScsiCommand::ReadHeader { alloc_len, .. } => (UsbDirection::In, alloc_len as u64),
+        ScsiCommand::RequestSense { alloc_len, .. } | ScsiCommand::ModeSense6 { alloc_len, .. } => {
+            (UsbDirection::In, alloc_len as u64)
+        }
+        ScsiCommand::ReadCapacity16 { alloc_len, .. }
+        | ScsiCommand::ReadAttribute { alloc_len, .. }
+        | ScsiCommand::ReadDefectData { alloc_len, .. } => (UsbDirection::In, alloc_len as u64),
+        ScsiCommand::ReadCapacity10 { .. } => (UsbDirection::In, 8),
+        ScsiCommand::ReadBlockLimits { mloc } => (UsbDirection::In, if mloc { 20 } else { 6 }),
+        ScsiCommand::Read { len, .. } => (UsbDirection::In, blocks(len)),
+        ScsiCommand::ReadSequential { fixed, len, .. } => {
+            let len = len as u64;
+            (UsbDirection::In, if fixed { blocks(len) } else { len })
+        }
+        ScsiCommand::ReadCd { len, .. } => (UsbDirection::In, blocks(len as u64)),
+        ScsiCommand::ModeSelect6 {
+            parameter_list_len, ..
+        } => (UsbDirection::Out, parameter_list_len as u64),
+        ScsiCommand::ModeSelect10 {
+            parameter_list_len, ..
+        }
+        | ScsiCommand::LogSelect {
+            parameter_list_len, ..
+        } => (UsbDirection::Out, parameter_list_len as u64),
+        ScsiCommand::Write { len, .. }
+        | ScsiCommand::WriteAndVerify { len, .. }
+        | ScsiCommand::Verify {
+            len,
+            byte_check: true,
+            ..
+        } => (UsbDirection::Out, blocks(len)),
+        ScsiCommand::WriteSequential { fixed, len } => {
+            let len = len as u64;
+            (UsbDirection::Out, if fixed { blocks(len) } else { len })
+        }
+        ScsiCommand::WriteAttribute {
+            parameter_list_len, ..
+        } => (UsbDirection::Out, parameter_list_len as u64),
+        _ => return None,
+    };
+    (len > 0).then_some((direction, len))
+}
+
+/// Number of bytes of the blocks a Read or a Write transfers, `None` for other commands
+#[cfg(feature = "bbb")]
+fn blocks_data_len(kind: ScsiCommand, block_size: BlockSize) -> Option<u64> {
+    match kind {
+        ScsiCommand::Read { len, .. }
+        | ScsiCommand::Write { len, .. }
+        | ScsiCommand::WriteAndVerify { len, .. }
+        | ScsiCommand::Verify {
+            len,
+            byte_check: true,
+            ..
+        } => Some(len.saturating_mul(block_size.get() as u64)),
+        _ => None,
+    }
+}
+
+/// The first block a command transferring blocks addresses, `None` for other commands
+#[cfg(feature = "bbb")]
+fn blocks_lba(kind: ScsiCommand) -> Option<u64> {
+    match kind {
+        ScsiCommand::Read { lba, .. }
+        | ScsiCommand::Write { lba, .. }
+        | ScsiCommand::WriteAndVerify { lba, .. }
+        | ScsiCommand::Verify { lba, .. } => Some(lba),
+        ScsiCommand::ReadCd { lba, .. } => Some(lba as u64),
+        _ => None,
+    }
+}
+
+/// Writes the mode pages selected by MODE SENSE into `dst` returning the number of bytes
+/// written. Without registered pages, the request of all the pages is answered with the
+/// Caching page if the quirk is set
+#[cfg(feature = "bbb")]
+fn mode_sense_pages(
+    dst: &mut [u8; MODE_PAGES_MAX_LEN],
+    registered: &[u8],
+    quirks: Quirks,
+    page_code: u8,
+    subpage_code: u8,
+) -> usize {
+    if registered.is_empty() && page_code == ALL_PAGES && quirks.mode_sense_all_pages {
+        let caching = caching_mode_page();
+        dst[..caching.len()].copy_from_slice(&caching);
+        return caching.len();
+    }
+    select_mode_pages(dst, registered, page_code, subpage_code)
+}
+
+/// Writes at most `alloc_len` bytes of a subclass generated response into the IO buffer.
+/// The response is expected to fit the (empty) IO buffer
+#[cfg(feature = "bbb")]
+fn write_response<Bus: UsbBus, Buf: BorrowMut<[u8]>>(
+    transport: &mut BulkOnly<Bus, Buf>,
+    data: &[u8],
+    alloc_len: impl Into<u32>,
+) {
+    let len = min(alloc_len.into() as usize, data.len());
+    let _ = transport.write_data(&data[..len]);
+}
+
+impl<Bus, T> UsbClass<Bus> for Scsi<T>
+where
+    Bus: UsbBus,
+    T: Transport<Bus = Bus>,
+{
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.iad(
+            self.interface,
+            1,
+            CLASS_MASS_STORAGE,
+            SUBCLASS_SCSI,
+            T::PROTO,
+            None,
+        )?;
+        writer.interface(self.interface, CLASS_MASS_STORAGE, SUBCLASS_SCSI, T::PROTO)?;
+
+        self.transport.get_endpoint_descriptors(writer)?;
+
+        Ok(())
+    }
+
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> usb_device::Result<()> {
+        self.transport.get_bos_descriptors(writer)
+    }
+
+    fn reset(&mut self) {
+        self.units.iter_mut().for_each(|unit| {
+            unit.reserved = false;
+            unit.sense.clear();
+            unit.unit_attention.clear();
+            unit.read_end = None;
+            unit.activity = Default::default();
+        });
+        self.fingerprint = Default::default();
+        self.transport.reset()
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<Bus>) {
+        if !addressed_elsewhere(xfer.request(), self.interface) {
+            self.transport.control_in(xfer)
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<Bus>) {
+        if !addressed_elsewhere(xfer.request(), self.interface) {
+            self.transport.control_out(xfer)
+        }
+    }
+
+    fn poll(&mut self) {
+        if self.auto_drive {
+            self.transport.poll();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subclass::scsi::{parse_cb, parse_ssc_cb, ScsiCommand};
+
+    #[test]
+    fn should_parse_read_cd() {
+        let cb = [
+            0xBE, 0b00001010, 0x00, 0x01, 0x02, 0x03, 0x00, 0x00, 0x10, 0b11111010, 0x02, 0x00,
+        ];
+        let ScsiCommand::ReadCd {
+            expected_sector_type,
+            dap,
+            lba,
+            len,
+            sync,
+            header_codes,
+            user_data,
+            edc_ecc,
+            c2_error_info,
+            subchannel,
+        } = parse_cb(&cb)
+        else {
+            panic!("ReadCd expected");
+        };
+        assert_eq!(2, expected_sector_type);
+        assert!(dap);
+        assert_eq!(0x00010203, lba);
+        assert_eq!(0x10, len);
+        assert!(sync);
+        assert_eq!(0b11, header_codes);
+        assert!(user_data);
+        assert!(edc_ecc);
+        assert_eq!(0b01, c2_error_info);
+        assert_eq!(2, subchannel);
+    }
+
+    #[test]
+    fn should_parse_read_header() {
+        let cb = [
+            0x44, 0b00000010, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x08, 0x00,
+        ];
+        assert!(matches!(
+            parse_cb(&cb),
+            ScsiCommand::ReadHeader {
+                msf: true,
+                lba: 0x10,
+                alloc_len: 8
+            }
+        ));
+    }
+
+    #[test]
+    fn should_parse_medium_removal_and_start_stop() {
+        assert_eq!(
+            ScsiCommand::PreventAllowMediumRemoval { prevent: true },
+            parse_cb(&[0x1E, 0x00, 0x00, 0x00, 0x01, 0x00])
+        );
+        // eject
+        assert_eq!(
+            ScsiCommand::StartStopUnit {
+                immed: true,
+                power_condition: 0,
+                load_eject: true,
+                start: false
+            },
+            parse_cb(&[0x1B, 0x01, 0x00, 0x00, 0x02, 0x00])
+        );
+        assert_eq!(
+            ScsiCommand::StartStopUnit {
+                immed: false,
+                power_condition: 0x3,
+                load_eject: false,
+                start: true
+            },
+            parse_cb(&[0x1B, 0x00, 0x00, 0x00, 0x31, 0x00])
+        );
+    }
+
+    #[test]
+    fn should_parse_write_and_verify() {
+        let cb = [
+            0xAE, 0b00000010, 0x00, 0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(
+            ScsiCommand::WriteAndVerify {
+                lba: 0x100,
+                len: 0x10000,
+                byte_check: true
+            },
+            parse_cb(&cb)
+        );
+        let cb = [0x2E, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x02, 0x00];
+        assert_eq!(
+            ScsiCommand::WriteAndVerify {
+                lba: 7,
+                len: 2,
+                byte_check: false
+            },
+            parse_cb(&cb)
+        );
+    }
+
+    #[test]
+    fn should_parse_verify() {
+        let cb = [
+            0x2F, 0b00000010, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x02, 0x00,
+        ];
+        assert_eq!(
+            ScsiCommand::Verify {
+                lba: 7,
+                len: 2,
+                byte_check: true
+            },
+            parse_cb(&cb)
+        );
+        let cb = [
+            0x8F, 0x00, 0, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,
+        ];
+        assert_eq!(
+            ScsiCommand::Verify {
+                lba: 0x100,
+                len: 0x10,
+                byte_check: false
+            },
+            parse_cb(&cb)
+        );
+    }
+
+    #[test]
+    fn should_parse_read_defect_data() {
+        let cb = [0x37, 0x00, 0b00011101, 0, 0, 0, 0, 0x00, 0x04, 0x00];
+        assert!(matches!(
+            parse_cb(&cb),
+            ScsiCommand::ReadDefectData {
+                req_plist: true,
+                req_glist: true,
+                defect_list_format: 0b101,
+                alloc_len: 4
+            }
+        ));
+        let cb = [
+            0xB7, 0b00001000, 0, 0, 0, 0, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert!(matches!(
+            parse_cb(&cb),
+            ScsiCommand::ReadDefectData {
+                req_plist: false,
+                req_glist: true,
+                defect_list_format: 0,
+                alloc_len: 0x010000
+            }
+        ));
+    }
+
+    #[test]
+    fn should_parse_ssc_read_write_sequential() {
+        let cb = [0x08, 0b00000011, 0x01, 0x00, 0x00, 0x00];
+        assert!(matches!(
+            parse_ssc_cb(&cb),
+            ScsiCommand::ReadSequential {
+                sili: true,
+                fixed: true,
+                len: 0x010000
+            }
+        ));
+        let cb = [0x0A, 0b00000000, 0x00, 0x02, 0x00, 0x00];
+        assert!(matches!(
+            parse_ssc_cb(&cb),
+            ScsiCommand::WriteSequential {
+                fixed: false,
+                len: 0x0200
+            }
+        ));
+        // not an SSC command for a direct access device
+        assert!(matches!(parse_cb(&cb), ScsiCommand::Unknown));
+    }
+
+    #[test]
+    fn should_parse_ssc_space_with_negative_count() {
+        let cb = [0x11, 0b00000001, 0xFF, 0xFF, 0xFE, 0x00];
+        assert!(matches!(
+            parse_ssc_cb(&cb),
+            ScsiCommand::Space { code: 1, count: -2 }
+        ));
+    }
+
+    #[test]
+    fn should_parse_ssc_positioning_commands() {
+        assert!(matches!(
+            parse_ssc_cb(&[0x01, 0x01, 0, 0, 0, 0]),
+            ScsiCommand::Rewind { immed: true }
+        ));
+        assert!(matches!(
+            parse_ssc_cb(&[0x05, 0x00, 0, 0, 0, 0]),
+            ScsiCommand::ReadBlockLimits { mloc: false }
+        ));
+        assert!(matches!(
+            parse_ssc_cb(&[0x10, 0b00000010, 0x00, 0x00, 0x03, 0x00]),
+            ScsiCommand::WriteFilemarks {
+                wsmk: true,
+                immed: false,
+                count: 3
+            }
+        ));
+        // common commands are still parsed
+        assert!(matches!(
+            parse_ssc_cb(&[0x00, 0, 0, 0, 0, 0]),
+            ScsiCommand::TestUnitReady
+        ));
+    }
+
+    #[test]
+    fn should_flag_unsupported_cdb_format() {
+        // variable-length CDB
+        assert!(matches!(
+            parse_cb(&[0x7F, 0, 0, 0, 0, 0, 0, 0x08, 0x00, 0x09, 0, 0, 0, 0, 0, 0]),
+            ScsiCommand::UnsupportedCdbFormat {
+                opcode: 0x7F,
+                len: 16
+            }
+        ));
+        // READ(16) truncated to 10 bytes
+        assert!(matches!(
+            parse_ssc_cb(&[0x88, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            ScsiCommand::UnsupportedCdbFormat {
+                opcode: 0x88,
+                len: 10
+            }
+        ));
+        // vendor specific
+        assert!(matches!(parse_cb(&[0xC0]), ScsiCommand::Unknown));
+    }
+}
@@ -0,0 +1,516 @@
+//! SCSI mode parameters
+
+use crate::subclass::scsi::capacity::BLOCK_DESCRIPTOR_LEN;
+use crate::subclass::scsi::sense::Sense;
+
+/// Length of the MODE SENSE(6) mode parameter header
+pub const MODE_PARAMETER_HEADER_6_LEN: usize = 4;
+/// Length of the MODE SENSE(10) mode parameter header
+pub const MODE_PARAMETER_HEADER_10_LEN: usize = 8;
+
+/// Length of the Caching mode page
+pub const CACHING_MODE_PAGE_LEN: usize = 20;
+/// Length of the Control mode page
+pub const CONTROL_MODE_PAGE_LEN: usize = 12;
+
+/// Page code of the Caching mode page (SBC)
+pub const CACHING_PAGE_CODE: u8 = 0x08;
+/// Page code of the Control mode page (SPC)
+pub const CONTROL_PAGE_CODE: u8 = 0x0A;
+
+/// Page code requesting all the supported mode pages
+pub const ALL_PAGES: u8 = 0x3F;
+/// Subpage code requesting all the subpages of the requested pages
+pub const ALL_SUBPAGES: u8 = 0xFF;
+
+/// The most the mode pages registered with [Scsi::set_mode_pages] may take. Fits the Caching,
+/// Control and Informational Exceptions Control pages
+///
+/// [Scsi::set_mode_pages]: crate::subclass::scsi::Scsi::set_mode_pages
+pub const MODE_PAGES_MAX_LEN: usize = 48;
+
+/// WP bit of the device-specific parameter (SBC)
+const WRITE_PROTECTED: u8 = 0b10000000;
+/// LONGLBA bit of the MODE SELECT(10) parameter header
+const LONG_LBA: u8 = 0b00000001;
+/// Length of the long LBA block descriptor
+const LONG_BLOCK_DESCRIPTOR_LEN: usize = 16;
+/// SPF bit of the page code byte
+const SUB_PAGE_FORMAT: u8 = 0b01000000;
+
+/// Writes MODE SENSE(6) parameter data into `dst` returning the number of bytes written.
+/// `pages` are the raw mode pages following the block descriptor
+///
+/// # Panics
+/// Panics if `dst` doesn't fit the header, the block descriptor and the pages
+pub fn write_mode_sense_6(
+    dst: &mut [u8],
+    write_protected: bool,
+    block_descriptor: Option<&[u8; BLOCK_DESCRIPTOR_LEN]>,
+    pages: &[u8],
+) -> usize {
+    let descriptor_len = block_descriptor.map_or(0, |bd| bd.len());
+    let pages_start = MODE_PARAMETER_HEADER_6_LEN + descriptor_len;
+    let len = pages_start + pages.len();
+
+    dst[0] = (len - 1) as u8; // mode data length
+    dst[1] = 0x00; // medium type
+    dst[2] = if write_protected { WRITE_PROTECTED } else { 0 };
+    dst[3] = descriptor_len as u8;
+    if let Some(bd) = block_descriptor {
+        dst[MODE_PARAMETER_HEADER_6_LEN..pages_start].copy_from_slice(bd);
+    }
+    dst[pages_start..len].copy_from_slice(pages);
+    len
+}
+
+/// Writes MODE SENSE(10) parameter data into `dst` returning the number of bytes written.
+/// `pages` are the raw mode pages following the block descriptor
+///
+/// # Panics
+/// Panics if `dst` doesn't fit the header, the block descriptor and the pages
+pub fn write_mode_sense_10(
+    dst: &mut [u8],
+    write_protected: bool,
+    block_descriptor: Option<&[u8; BLOCK_DESCRIPTOR_LEN]>,
+    pages: &[u8],
+) -> usize {
+    let descriptor_len = block_descriptor.map_or(0, |bd| bd.len());
+    let pages_start = MODE_PARAMETER_HEADER_10_LEN + descriptor_len;
+    let len = pages_start + pages.len();
+
+    dst[..2].copy_from_slice(&((len - 2) as u16).to_be_bytes()); // mode data length
+    dst[2] = 0x00; // medium type
+    dst[3] = if write_protected { WRITE_PROTECTED } else { 0 };
+    dst[4..6].fill(0);
+    dst[6..8].copy_from_slice(&(descriptor_len as u16).to_be_bytes());
+    if let Some(bd) = block_descriptor {
+        dst[MODE_PARAMETER_HEADER_10_LEN..pages_start].copy_from_slice(bd);
+    }
+    dst[pages_start..len].copy_from_slice(pages);
+    len
+}
+
+/// Builds the Caching mode page (SBC) reporting both read and write caches disabled
+pub fn caching_mode_page() -> [u8; CACHING_MODE_PAGE_LEN] {
+    let mut page = [0u8; CACHING_MODE_PAGE_LEN];
+    page[0] = CACHING_PAGE_CODE;
+    page[1] = (CACHING_MODE_PAGE_LEN - 2) as u8; // page length
+    page[2] = 0b00000001; // RCD: read cache disabled, WCE: write cache disabled
+    page
+}
+
+/// Copies the pages of `pages` requested by MODE SENSE into `dst` returning the number of
+/// bytes copied. `pages` are raw mode pages, e.g. built with [caching_mode_page]. [ALL_PAGES]
+/// selects every page and [ALL_SUBPAGES] every subpage of the selected ones. Otherwise,
+/// a page of the sub-page format is selected by its subpage code only
+///
+/// # Panics
+/// Panics if `pages` are malformed or `dst` doesn't fit the selected pages
+pub fn select_mode_pages(dst: &mut [u8], pages: &[u8], page_code: u8, subpage_code: u8) -> usize {
+    let mut len = 0;
+    let mut rest = pages;
+    while !rest.is_empty() {
+        let (_, page_len) = decode_page(rest).unwrap();
+        let (page, next) = rest.split_at(page_len);
+        rest = next;
+
+        let sub_page = (page[0] & SUB_PAGE_FORMAT) != 0;
+        let code = (page[0] & 0b00111111, if sub_page { page[1] } else { 0 });
+        if (page_code == ALL_PAGES || page_code == code.0)
+            && (subpage_code == ALL_SUBPAGES || subpage_code == code.1)
+        {
+            dst[len..len + page_len].copy_from_slice(page);
+            len += page_len;
+        }
+    }
+    len
+}
+
+/// Whether `pages` are well-formed raw mode pages
+pub(crate) fn is_valid_mode_pages(pages: &[u8]) -> bool {
+    ModePages { rest: pages }.all(|page| page.is_ok())
+}
+
+/// MODE SELECT parameter list
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ModeParameterList<'a> {
+    pub medium_type: u8,
+    pub device_specific: u8,
+    /// Block descriptors, each [BLOCK_DESCRIPTOR_LEN] long, or 16 bytes long if `long_lba` is set
+    pub block_descriptors: &'a [u8],
+    /// Set if the block descriptors are of the long LBA format. MODE SELECT(10) only
+    pub long_lba: bool,
+    /// Raw mode pages following the block descriptors. See [ModeParameterList::pages]
+    pub pages: &'a [u8],
+}
+
+impl<'a> ModeParameterList<'a> {
+    /// Returns an iterator over the mode pages. Yields an error and stops at a malformed page
+    pub fn pages(&self) -> ModePages<'a> {
+        ModePages { rest: self.pages }
+    }
+}
+
+/// Mode page selected by the host
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum ModePage<'a> {
+    Caching(CachingPage),
+    Control(ControlPage),
+    /// Any other page. `data` follows the page header
+    Other {
+        page_code: u8,
+        /// Set for a page of the sub-page format only
+        subpage_code: Option<u8>,
+        data: &'a [u8],
+    },
+}
+
+/// Caching mode page (SBC)
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct CachingPage {
+    /// Initiator control
+    pub ic: bool,
+    /// Abort pre-fetch
+    pub abpf: bool,
+    /// Caching analysis permitted
+    pub cap: bool,
+    /// Discontinuity
+    pub disc: bool,
+    /// Size enable
+    pub size: bool,
+    /// Write cache enable
+    pub wce: bool,
+    /// Multiplication factor
+    pub mf: bool,
+    /// Read cache disable
+    pub rcd: bool,
+    /// Disable read-ahead
+    pub dra: bool,
+}
+
+/// Control mode page (SPC)
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct ControlPage {
+    /// Task set type
+    pub tst: u8,
+    /// Whether sense data is expected in the descriptor format
+    pub d_sense: bool,
+    /// Global logging target save disable
+    pub gltsd: bool,
+    /// Report log exception condition
+    pub rlec: bool,
+    pub queue_algorithm_modifier: u8,
+    /// Queue error management
+    pub qerr: u8,
+    /// Software write protect
+    pub swp: bool,
+    /// Busy timeout period in 100 ms units
+    pub busy_timeout_period: u16,
+}
+
+/// Iterator over mode pages. See [ModeParameterList::pages]
+#[derive(Clone, Debug)]
+pub struct ModePages<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for ModePages<'a> {
+    type Item = Result<ModePage<'a>, Sense>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let page = decode_page(self.rest);
+        match page {
+            Ok((_, len)) => self.rest = &self.rest[len..],
+            Err(_) => self.rest = &[],
+        }
+        Some(page.map(|(page, _)| page))
+    }
+}
+
+/// Decodes a MODE SELECT(6) parameter list. `data` is the parameter list as received, i.e.
+/// as long as the parameter list length of the command block.
+///
+/// Fails with [PARAMETER_LIST_LENGTH_ERROR] if the header or the block descriptors don't fit,
+/// and with [INVALID_FIELD_IN_PARAMETER_LIST] if the block descriptor length is not a multiple
+/// of [BLOCK_DESCRIPTOR_LEN]. The pages are validated while iterating
+///
+/// [PARAMETER_LIST_LENGTH_ERROR]: Sense::PARAMETER_LIST_LENGTH_ERROR
+/// [INVALID_FIELD_IN_PARAMETER_LIST]: Sense::INVALID_FIELD_IN_PARAMETER_LIST
+pub fn decode_mode_select_6(data: &[u8]) -> Result<ModeParameterList<'_>, Sense> {
+    if data.len() < MODE_PARAMETER_HEADER_6_LEN {
+        return Err(Sense::PARAMETER_LIST_LENGTH_ERROR);
+    }
+    decode_parameter_list(
+        data,
+        MODE_PARAMETER_HEADER_6_LEN,
+        data[1],
+        data[2],
+        false,
+        data[3] as usize,
+    )
+}
+
+/// Decodes a MODE SELECT(10) parameter list. See [decode_mode_select_6]
+pub fn decode_mode_select_10(data: &[u8]) -> Result<ModeParameterList<'_>, Sense> {
+    if data.len() < MODE_PARAMETER_HEADER_10_LEN {
+        return Err(Sense::PARAMETER_LIST_LENGTH_ERROR);
+    }
+    decode_parameter_list(
+        data,
+        MODE_PARAMETER_HEADER_10_LEN,
+        data[2],
+        data[3],
+        (data[4] & LONG_LBA) != 0,
+        u16::from_be_bytes([data[6], data[7]]) as usize,
+    )
+}
+
+fn decode_parameter_list(
+    data: &[u8],
+    header_len: usize,
+    medium_type: u8,
+    device_specific: u8,
+    long_lba: bool,
+    descriptors_len: usize,
+) -> Result<ModeParameterList<'_>, Sense> {
+    let descriptor_len = if long_lba {
+        LONG_BLOCK_DESCRIPTOR_LEN
+    } else {
+        BLOCK_DESCRIPTOR_LEN
+    };
+    if !descriptors_len.is_multiple_of(descriptor_len) {
+        return Err(Sense::INVALID_FIELD_IN_PARAMETER_LIST);
+    }
+    let pages_start = header_len + descriptors_len;
+    if data.len() < pages_start {
+        return Err(Sense::PARAMETER_LIST_LENGTH_ERROR);
+    }
+    Ok(ModeParameterList {
+        medium_type,
+        device_specific,
+        block_descriptors: &data[header_len..pages_start],
+        long_lba,
+        pages: &data[pages_start..],
+    })
+}
+
+/// Decodes the first page of `data` returning it along with its length including the header
+fn decode_page(data: &[u8]) -> Result<(ModePage<'_>, usize), Sense> {
+    let page_code = data[0] & 0b00111111;
+    let sub_page = (data[0] & SUB_PAGE_FORMAT) != 0;
+    let (subpage_code, header_len, page_len) = match (sub_page, data.len()) {
+        (false, 2..) => (None, 2, data[1] as usize),
+        (true, 4..) => (
+            Some(data[1]),
+            4,
+            u16::from_be_bytes([data[2], data[3]]) as usize,
+        ),
+        _ => return Err(Sense::PARAMETER_LIST_LENGTH_ERROR),
+    };
+    let len = header_len + page_len;
+    if data.len() < len {
+        return Err(Sense::PARAMETER_LIST_LENGTH_ERROR);
+    }
+    let page = &data[..len];
+    let decoded = match (page_code, subpage_code) {
+        (CACHING_PAGE_CODE, None) if len == CACHING_MODE_PAGE_LEN => {
+            ModePage::Caching(CachingPage {
+                ic: (page[2] & 0b10000000) != 0,
+                abpf: (page[2] & 0b01000000) != 0,
+                cap: (page[2] & 0b00100000) != 0,
+                disc: (page[2] & 0b00010000) != 0,
+                size: (page[2] & 0b00001000) != 0,
+                wce: (page[2] & 0b00000100) != 0,
+                mf: (page[2] & 0b00000010) != 0,
+                rcd: (page[2] & 0b00000001) != 0,
+                dra: (page[12] & 0b00100000) != 0,
+            })
+        }
+        (CONTROL_PAGE_CODE, None) if len == CONTROL_MODE_PAGE_LEN => {
+            ModePage::Control(ControlPage {
+                tst: page[2] >> 5,
+                d_sense: (page[2] & 0b00000100) != 0,
+                gltsd: (page[2] & 0b00000010) != 0,
+                rlec: (page[2] & 0b00000001) != 0,
+                queue_algorithm_modifier: page[3] >> 4,
+                qerr: (page[3] >> 1) & 0b00000011,
+                swp: (page[4] & 0b00001000) != 0,
+                busy_timeout_period: u16::from_be_bytes([page[8], page[9]]),
+            })
+        }
+        (CACHING_PAGE_CODE | CONTROL_PAGE_CODE, None) => {
+            return Err(Sense::INVALID_FIELD_IN_PARAMETER_LIST); // wrong page length
+        }
+        _ => ModePage::Other {
+            page_code,
+            subpage_code,
+            data: &page[header_len..],
+        },
+    };
+    Ok((decoded, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subclass::scsi::capacity::{block_descriptor, BlockSize};
+    use crate::subclass::scsi::mode::{
+        caching_mode_page, decode_mode_select_10, decode_mode_select_6, is_valid_mode_pages,
+        select_mode_pages, write_mode_sense_10, write_mode_sense_6, CachingPage, ControlPage,
+        ModePage, ALL_PAGES, ALL_SUBPAGES,
+    };
+    use crate::subclass::scsi::sense::Sense;
+
+    #[test]
+    fn should_write_mode_sense_6_header() {
+        let mut buf = [0xFFu8; 16];
+        assert_eq!(4, write_mode_sense_6(&mut buf, true, None, &[]));
+        assert_eq!([0x03, 0x00, 0x80, 0x00], buf[..4]);
+    }
+
+    #[test]
+    fn should_write_mode_sense_10_with_block_descriptor() {
+        let mut buf = [0xFFu8; 16];
+        let bd = block_descriptor(0x100, BlockSize::B4096);
+        assert_eq!(16, write_mode_sense_10(&mut buf, false, Some(&bd), &[]));
+        assert_eq!([0x00, 0x0E, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08], buf[..8]);
+        assert_eq!(bd, buf[8..]);
+    }
+
+    #[test]
+    fn should_write_mode_sense_6_with_caching_page() {
+        let mut buf = [0xFFu8; 32];
+        let page = caching_mode_page();
+        assert_eq!(24, write_mode_sense_6(&mut buf, false, None, &page));
+        assert_eq!([0x17, 0x00, 0x00, 0x00], buf[..4]);
+        assert_eq!([0x08, 0x12, 0x01], buf[4..7]);
+        assert_eq!(page, buf[4..24]);
+    }
+
+    #[test]
+    fn should_select_mode_pages() {
+        let pages = [
+            0x01, 0x02, 0xC0, 0x00, // Read-Write Error Recovery
+            0x4A, 0x01, 0x00, 0x01, 0xAA, // Control Extension sub-page
+            0x1C, 0x01, 0x00, // Informational Exceptions Control
+        ];
+        assert!(is_valid_mode_pages(&pages));
+        assert!(!is_valid_mode_pages(&pages[..7]));
+
+        let mut buf = [0u8; 16];
+        assert_eq!(7, select_mode_pages(&mut buf, &pages, ALL_PAGES, 0));
+        assert_eq!([0x01, 0x02, 0xC0, 0x00, 0x1C, 0x01, 0x00], buf[..7]);
+        assert_eq!(
+            12,
+            select_mode_pages(&mut buf, &pages, ALL_PAGES, ALL_SUBPAGES)
+        );
+        assert_eq!(pages, buf[..12]);
+        assert_eq!(4, select_mode_pages(&mut buf, &pages, 0x01, 0));
+        assert_eq!(5, select_mode_pages(&mut buf, &pages, 0x0A, 0x01));
+        assert_eq!([0x4A, 0x01, 0x00, 0x01, 0xAA], buf[..5]);
+        assert_eq!(0, select_mode_pages(&mut buf, &pages, 0x0A, 0));
+        assert_eq!(0, select_mode_pages(&mut buf, &pages, 0x19, ALL_SUBPAGES));
+    }
+
+    #[test]
+    fn should_decode_mode_select_6_pages() {
+        let mut caching = caching_mode_page();
+        caching[2] = 0b00000100; // WCE, read cache enabled
+        caching[12] = 0b00100000; // DRA
+        let mut control = [0u8; 12];
+        control[..5].copy_from_slice(&[0x0A, 0x0A, 0b00000100, 0b00010010, 0b00001000]);
+        control[8..10].copy_from_slice(&[0x01, 0x2C]);
+        let other = [0x40 | 0x1C, 0x01, 0x00, 0x02, 0xAB, 0xCD];
+
+        let mut data = vec![0x00, 0x00, 0x00, 0x08];
+        data.extend_from_slice(&block_descriptor(0x100, BlockSize::B512));
+        data.extend_from_slice(&caching);
+        data.extend_from_slice(&control);
+        data.extend_from_slice(&other);
+
+        let list = decode_mode_select_6(&data).unwrap();
+        assert_eq!(
+            block_descriptor(0x100, BlockSize::B512),
+            list.block_descriptors
+        );
+        let pages: Vec<_> = list.pages().collect();
+        assert_eq!(
+            vec![
+                Ok(ModePage::Caching(CachingPage {
+                    wce: true,
+                    dra: true,
+                    ..Default::default()
+                })),
+                Ok(ModePage::Control(ControlPage {
+                    d_sense: true,
+                    queue_algorithm_modifier: 0x1,
+                    qerr: 0x1,
+                    swp: true,
+                    busy_timeout_period: 300,
+                    ..Default::default()
+                })),
+                Ok(ModePage::Other {
+                    page_code: 0x1C,
+                    subpage_code: Some(0x01),
+                    data: &[0xAB, 0xCD],
+                }),
+            ],
+            pages
+        );
+    }
+
+    #[test]
+    fn should_decode_mode_select_10_header() {
+        let data = [0x00, 0x00, 0x05, 0x80, 0x01, 0x00, 0x00, 0x00];
+        let list = decode_mode_select_10(&data).unwrap();
+        assert_eq!(
+            (0x05, 0x80, true),
+            (list.medium_type, list.device_specific, list.long_lba)
+        );
+        assert!(list.block_descriptors.is_empty());
+        assert_eq!(None, list.pages().next());
+    }
+
+    #[test]
+    fn should_validate_mode_select_lengths() {
+        let err = |data: &[u8]| decode_mode_select_6(data).err();
+        assert_eq!(Some(Sense::PARAMETER_LIST_LENGTH_ERROR), err(&[0x00, 0x00]));
+        assert_eq!(
+            Some(Sense::INVALID_FIELD_IN_PARAMETER_LIST),
+            err(&[0x00, 0x00, 0x00, 0x04, 0, 0, 0, 0])
+        );
+        assert_eq!(
+            Some(Sense::PARAMETER_LIST_LENGTH_ERROR),
+            err(&[0x00, 0x00, 0x00, 0x08, 0, 0, 0, 0])
+        );
+        // long LBA block descriptors are 16 bytes long
+        assert_eq!(
+            Some(Sense::INVALID_FIELD_IN_PARAMETER_LIST),
+            decode_mode_select_10(&[0, 0, 0, 0, 0x01, 0, 0x00, 0x08, 0, 0, 0, 0, 0, 0, 0, 0]).err()
+        );
+
+        fn pages(data: &[u8]) -> Vec<Result<ModePage<'_>, Sense>> {
+            decode_mode_select_6(data).unwrap().pages().collect()
+        }
+        // truncated page
+        assert_eq!(
+            vec![Err(Sense::PARAMETER_LIST_LENGTH_ERROR)],
+            pages(&[0x00, 0x00, 0x00, 0x00, 0x08, 0x12, 0x00])
+        );
+        // Caching page of a wrong length, the rest is not decoded
+        assert_eq!(
+            vec![Err(Sense::INVALID_FIELD_IN_PARAMETER_LIST)],
+            pages(&[0x00, 0x00, 0x00, 0x00, 0x08, 0x01, 0x00, 0x0A, 0x0A])
+        );
+    }
+}
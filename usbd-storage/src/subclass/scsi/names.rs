@@ -0,0 +1,237 @@
+//! Spec names of SCSI commands
+//!
+//! Makes logs and support dumps read "WRITE(10) lba=0x800 len=8" rather than a dump of
+//! [ScsiCommand]. The names are keyed by the opcode, as several forms of a command, e.g.
+//! READ(10) and READ(16), are parsed into the same [ScsiCommand] variant.
+
+use crate::subclass::scsi::{
+    ScsiCommand, INQUIRY, LOG_SELECT, LOG_SENSE, MODE_SELECT_10, MODE_SELECT_6, MODE_SENSE_10,
+    MODE_SENSE_6, PREVENT_ALLOW_MEDIUM_REMOVAL, READ_10, READ_16, READ_6, READ_ATTRIBUTE,
+    READ_BLOCK_LIMITS, READ_CAPACITY_10, READ_CAPACITY_16, READ_CD, READ_DEFECT_DATA_10,
+    READ_DEFECT_DATA_12, READ_FORMAT_CAPACITIES, READ_HEADER, RELEASE_6, REQUEST_SENSE, RESERVE_6,
+    REWIND, SPACE_6, START_STOP_UNIT, TEST_UNIT_READY, VERIFY_10, VERIFY_12, VERIFY_16, WRITE_10,
+    WRITE_16, WRITE_6, WRITE_AND_VERIFY_10, WRITE_AND_VERIFY_12, WRITE_AND_VERIFY_16,
+    WRITE_ATTRIBUTE, WRITE_FILEMARKS_6,
+};
+
+/// Returns the spec name of a command by its opcode, `None` if the opcode is not known
+///
+/// Covers the commands the subclass parses and a few more hosts commonly issue. Opcodes shared
+/// by several command sets are named after the one parsed for a Direct Access device, e.g.
+/// `0x08` is READ(6).
+pub fn opcode_name(opcode: u8) -> Option<&'static str> {
+    let name = match opcode {
+        /* SPC */
+        TEST_UNIT_READY => "TEST UNIT READY",
+        REQUEST_SENSE => "REQUEST SENSE",
+        INQUIRY => "INQUIRY",
+        MODE_SENSE_6 => "MODE SENSE(6)",
+        MODE_SENSE_10 => "MODE SENSE(10)",
+        MODE_SELECT_6 => "MODE SELECT(6)",
+        MODE_SELECT_10 => "MODE SELECT(10)",
+        RESERVE_6 => "RESERVE(6)",
+        RELEASE_6 => "RELEASE(6)",
+        PREVENT_ALLOW_MEDIUM_REMOVAL => "PREVENT ALLOW MEDIUM REMOVAL",
+        0x1D => "SEND DIAGNOSTIC",
+        LOG_SELECT => "LOG SELECT",
+        LOG_SENSE => "LOG SENSE",
+        READ_ATTRIBUTE => "READ ATTRIBUTE",
+        WRITE_ATTRIBUTE => "WRITE ATTRIBUTE",
+        0xA0 => "REPORT LUNS",
+        0xA3 => "MAINTENANCE IN",
+
+        /* SBC */
+        READ_10 => "READ(10)",
+        READ_16 => "READ(16)",
+        0xA8 => "READ(12)",
+        READ_CAPACITY_10 => "READ CAPACITY(10)",
+        READ_CAPACITY_16 => "READ CAPACITY(16)",
+        START_STOP_UNIT => "START STOP UNIT",
+        WRITE_10 => "WRITE(10)",
+        WRITE_16 => "WRITE(16)",
+        0xAA => "WRITE(12)",
+        WRITE_AND_VERIFY_10 => "WRITE AND VERIFY(10)",
+        WRITE_AND_VERIFY_12 => "WRITE AND VERIFY(12)",
+        WRITE_AND_VERIFY_16 => "WRITE AND VERIFY(16)",
+        VERIFY_10 => "VERIFY(10)",
+        VERIFY_12 => "VERIFY(12)",
+        VERIFY_16 => "VERIFY(16)",
+        READ_DEFECT_DATA_10 => "READ DEFECT DATA(10)",
+        READ_DEFECT_DATA_12 => "READ DEFECT DATA(12)",
+        0x04 => "FORMAT UNIT",
+        0x35 => "SYNCHRONIZE CACHE(10)",
+        0x42 => "UNMAP",
+        0x93 => "WRITE SAME(16)",
+
+        /* SSC */
+        REWIND => "REWIND",
+        READ_BLOCK_LIMITS => "READ BLOCK LIMITS",
+        READ_6 => "READ(6)",
+        WRITE_6 => "WRITE(6)",
+        WRITE_FILEMARKS_6 => "WRITE FILEMARKS(6)",
+        SPACE_6 => "SPACE(6)",
+
+        /* MMC */
+        READ_FORMAT_CAPACITIES => "READ FORMAT CAPACITIES",
+        READ_HEADER => "READ HEADER",
+        READ_CD => "READ CD",
+        0x43 => "READ TOC/PMA/ATIP",
+        0x46 => "GET CONFIGURATION",
+        0x4A => "GET EVENT STATUS NOTIFICATION",
+        0x51 => "READ DISC INFORMATION",
+        _ => return None,
+    };
+    Some(name)
+}
+
+impl ScsiCommand {
+    /// Returns the spec name of the command
+    ///
+    /// A variant parsed from several forms is named after the command without the form, e.g.
+    /// "READ" for [Read](ScsiCommand::Read). Use [opcode_name] with the opcode of the command
+    /// block to tell the forms apart.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScsiCommand::Unknown => "UNKNOWN",
+            ScsiCommand::UnsupportedCdbFormat { opcode, .. } => {
+                opcode_name(*opcode).unwrap_or("UNKNOWN")
+            }
+            ScsiCommand::Read { .. } => "READ",
+            ScsiCommand::Write { .. } => "WRITE",
+            ScsiCommand::WriteAndVerify { .. } => "WRITE AND VERIFY",
+            ScsiCommand::Verify { .. } => "VERIFY",
+            ScsiCommand::ReadDefectData { .. } => "READ DEFECT DATA",
+            // unique opcode
+            _ => self.opcode().and_then(opcode_name).unwrap_or("UNKNOWN"),
+        }
+    }
+
+    /// Returns the opcode of the command, `None` if the variant is parsed from several forms
+    /// or from an unknown opcode
+    pub fn opcode(&self) -> Option<u8> {
+        let opcode = match self {
+            ScsiCommand::Unknown
+            | ScsiCommand::Read { .. }
+            | ScsiCommand::Write { .. }
+            | ScsiCommand::WriteAndVerify { .. }
+            | ScsiCommand::Verify { .. }
+            | ScsiCommand::ReadDefectData { .. } => return None,
+            ScsiCommand::UnsupportedCdbFormat { opcode, .. } => *opcode,
+            ScsiCommand::Inquiry { .. } => INQUIRY,
+            ScsiCommand::TestUnitReady => TEST_UNIT_READY,
+            ScsiCommand::RequestSense { .. } => REQUEST_SENSE,
+            ScsiCommand::ModeSense6 { .. } => MODE_SENSE_6,
+            ScsiCommand::ModeSense10 { .. } => MODE_SENSE_10,
+            ScsiCommand::ModeSelect6 { .. } => MODE_SELECT_6,
+            ScsiCommand::ModeSelect10 { .. } => MODE_SELECT_10,
+            ScsiCommand::Reserve6 => RESERVE_6,
+            ScsiCommand::Release6 => RELEASE_6,
+            ScsiCommand::PreventAllowMediumRemoval { .. } => PREVENT_ALLOW_MEDIUM_REMOVAL,
+            ScsiCommand::LogSelect { .. } => LOG_SELECT,
+            ScsiCommand::LogSense { .. } => LOG_SENSE,
+            ScsiCommand::ReadAttribute { .. } => READ_ATTRIBUTE,
+            ScsiCommand::WriteAttribute { .. } => WRITE_ATTRIBUTE,
+            ScsiCommand::ReadCapacity10 { .. } => READ_CAPACITY_10,
+            ScsiCommand::ReadCapacity16 { .. } => READ_CAPACITY_16,
+            ScsiCommand::StartStopUnit { .. } => START_STOP_UNIT,
+            ScsiCommand::Rewind { .. } => REWIND,
+            ScsiCommand::ReadBlockLimits { .. } => READ_BLOCK_LIMITS,
+            ScsiCommand::ReadSequential { .. } => READ_6,
+            ScsiCommand::WriteSequential { .. } => WRITE_6,
+            ScsiCommand::WriteFilemarks { .. } => WRITE_FILEMARKS_6,
+            ScsiCommand::Space { .. } => SPACE_6,
+            ScsiCommand::ReadFormatCapacities { .. } => READ_FORMAT_CAPACITIES,
+            ScsiCommand::ReadHeader { .. } => READ_HEADER,
+            ScsiCommand::ReadCd { .. } => READ_CD,
+        };
+        Some(opcode)
+    }
+}
+
+/// A command as logged: the spec name of the form received followed by the main fields
+#[cfg_attr(not(feature = "defmt"), allow(dead_code))]
+pub(crate) struct Traced {
+    opcode: u8,
+    kind: ScsiCommand,
+}
+
+impl Traced {
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    pub(crate) fn new(opcode: u8, kind: ScsiCommand) -> Self {
+        Self { opcode, kind }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Traced {
+    fn format(&self, f: defmt::Formatter) {
+        let name = opcode_name(self.opcode).unwrap_or_else(|| self.kind.name());
+        match self.kind {
+            ScsiCommand::Read { lba, len }
+            | ScsiCommand::Write { lba, len }
+            | ScsiCommand::WriteAndVerify { lba, len, .. }
+            | ScsiCommand::Verify { lba, len, .. } => {
+                defmt::write!(f, "{=str} lba={=u64:#x} len={=u64}", name, lba, len)
+            }
+            ScsiCommand::ReadCd { lba, len, .. } => {
+                defmt::write!(f, "{=str} lba={=u32:#x} len={=u32}", name, lba, len)
+            }
+            ScsiCommand::ReadSequential { len, .. } | ScsiCommand::WriteSequential { len, .. } => {
+                defmt::write!(f, "{=str} len={=u32}", name, len)
+            }
+            ScsiCommand::ModeSense6 { page_code, .. }
+            | ScsiCommand::ModeSense10 { page_code, .. } => {
+                defmt::write!(f, "{=str} page={=u8:#x}", name, page_code)
+            }
+            ScsiCommand::Inquiry {
+                evpd, page_code, ..
+            } => defmt::write!(
+                f,
+                "{=str} evpd={=bool} page={=u8:#x}",
+                name,
+                evpd,
+                page_code
+            ),
+            ScsiCommand::Unknown | ScsiCommand::UnsupportedCdbFormat { .. } => {
+                defmt::write!(f, "{=str} opcode={=u8:#x}", name, self.opcode)
+            }
+            _ => defmt::write!(f, "{=str}", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subclass::scsi::names::opcode_name;
+    use crate::subclass::scsi::ScsiCommand;
+
+    #[test]
+    fn should_name_opcodes() {
+        assert_eq!(Some("WRITE(10)"), opcode_name(0x2A));
+        assert_eq!(Some("READ CAPACITY(16)"), opcode_name(0x9E));
+        assert_eq!(Some("SYNCHRONIZE CACHE(10)"), opcode_name(0x35));
+        assert_eq!(None, opcode_name(0xFF));
+    }
+
+    #[test]
+    fn should_name_commands() {
+        let inquiry = ScsiCommand::Inquiry {
+            evpd: false,
+            page_code: 0,
+            alloc_len: 36,
+        };
+        assert_eq!(("INQUIRY", Some(0x12)), (inquiry.name(), inquiry.opcode()));
+        let write = ScsiCommand::Write { lba: 0x800, len: 8 };
+        assert_eq!(("WRITE", None), (write.name(), write.opcode()));
+        let unsupported = ScsiCommand::UnsupportedCdbFormat {
+            opcode: 0x35,
+            len: 6,
+        };
+        assert_eq!(
+            ("SYNCHRONIZE CACHE(10)", Some(0x35)),
+            (unsupported.name(), unsupported.opcode())
+        );
+        let unknown = ScsiCommand::Unknown;
+        assert_eq!(("UNKNOWN", None), (unknown.name(), unknown.opcode()));
+    }
+}
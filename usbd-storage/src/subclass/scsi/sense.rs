@@ -0,0 +1,338 @@
+//! SCSI sense data
+
+/// Length of the fixed format sense data
+pub const FIXED_SENSE_DATA_LEN: usize = 18;
+/// Max number of sense conditions queued per Logical Unit
+pub const SENSE_QUEUE_LEN: usize = 4;
+/// Max length of the descriptor format sense data, with the information and the sense-key specific
+/// descriptors
+pub const DESCRIPTOR_SENSE_DATA_MAX_LEN: usize =
+    DESCRIPTOR_SENSE_HEADER_LEN + INFORMATION_DESCRIPTOR_LEN + SENSE_KEY_SPECIFIC_DESCRIPTOR_LEN;
+
+const DESCRIPTOR_SENSE_HEADER_LEN: usize = 8;
+const INFORMATION_DESCRIPTOR_LEN: usize = 12;
+const SENSE_KEY_SPECIFIC_DESCRIPTOR_LEN: usize = 8;
+
+const INFORMATION_DESCRIPTOR: u8 = 0x00;
+const SENSE_KEY_SPECIFIC_DESCRIPTOR: u8 = 0x02;
+
+/// VALID bit of the fixed format and of the information descriptor
+const VALID: u8 = 0b10000000;
+/// SKSV bit of the sense-key specific field
+const SKSV: u8 = 0b10000000;
+
+/// Sense key
+///
+/// Refer to SPC
+#[repr(u8)]
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SenseKey {
+    #[default]
+    NoSense = 0x00,
+    RecoveredError = 0x01,
+    NotReady = 0x02,
+    MediumError = 0x03,
+    HardwareError = 0x04,
+    IllegalRequest = 0x05,
+    UnitAttention = 0x06,
+    DataProtect = 0x07,
+    BlankCheck = 0x08,
+    VendorSpecific = 0x09,
+    CopyAborted = 0x0A,
+    AbortedCommand = 0x0B,
+    VolumeOverflow = 0x0D,
+    Miscompare = 0x0E,
+}
+
+/// Sense data reported to the host with REQUEST SENSE
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sense {
+    pub key: SenseKey,
+    /// Additional Sense Code
+    pub asc: u8,
+    /// Additional Sense Code Qualifier
+    pub ascq: u8,
+    /// Progress indication of the sense-key specific field, where `0xFFFF` is complete
+    pub progress: Option<u16>,
+    /// The information field, e.g. the LBA of a failed block. The fixed format only fits 32 bits
+    pub information: Option<u64>,
+    /// Whether the sense data reports a deferred error rather than a current one
+    pub deferred: bool,
+}
+
+impl Sense {
+    /// NO SENSE
+    pub const NO_SENSE: Sense = Sense::new(SenseKey::NoSense, 0x00, 0x00);
+    /// ILLEGAL REQUEST / INVALID COMMAND OPERATION CODE
+    pub const INVALID_COMMAND_OPERATION_CODE: Sense =
+        Sense::new(SenseKey::IllegalRequest, 0x20, 0x00);
+    /// ILLEGAL REQUEST / LOGICAL BLOCK ADDRESS OUT OF RANGE
+    pub const LBA_OUT_OF_RANGE: Sense = Sense::new(SenseKey::IllegalRequest, 0x21, 0x00);
+    /// ILLEGAL REQUEST / INVALID FIELD IN CDB
+    pub const INVALID_FIELD_IN_CDB: Sense = Sense::new(SenseKey::IllegalRequest, 0x24, 0x00);
+    /// ILLEGAL REQUEST / INVALID FIELD IN PARAMETER LIST
+    pub const INVALID_FIELD_IN_PARAMETER_LIST: Sense =
+        Sense::new(SenseKey::IllegalRequest, 0x26, 0x00);
+    /// ILLEGAL REQUEST / PARAMETER LIST LENGTH ERROR
+    pub const PARAMETER_LIST_LENGTH_ERROR: Sense = Sense::new(SenseKey::IllegalRequest, 0x1A, 0x00);
+    /// MEDIUM ERROR / UNRECOVERED READ ERROR
+    pub const UNRECOVERED_READ_ERROR: Sense = Sense::new(SenseKey::MediumError, 0x11, 0x00);
+    /// MEDIUM ERROR / WRITE ERROR
+    pub const WRITE_ERROR: Sense = Sense::new(SenseKey::MediumError, 0x0C, 0x00);
+    /// MISCOMPARE / MISCOMPARE DURING VERIFY OPERATION
+    pub const MISCOMPARE_DURING_VERIFY: Sense = Sense::new(SenseKey::Miscompare, 0x1D, 0x00);
+    /// DATA PROTECT / WRITE PROTECTED
+    pub const WRITE_PROTECTED: Sense = Sense::new(SenseKey::DataProtect, 0x27, 0x00);
+    /// UNIT ATTENTION / MODE PARAMETERS CHANGED
+    pub const MODE_PARAMETERS_CHANGED: Sense = Sense::new(SenseKey::UnitAttention, 0x2A, 0x01);
+    /// UNIT ATTENTION / NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED
+    pub const NOT_READY_TO_READY_CHANGE: Sense = Sense::new(SenseKey::UnitAttention, 0x28, 0x00);
+    /// NOT READY / LOGICAL UNIT IS IN PROCESS OF BECOMING READY
+    pub const BECOMING_READY: Sense = Sense::new(SenseKey::NotReady, 0x04, 0x01);
+    /// NOT READY / MEDIUM NOT PRESENT
+    pub const MEDIUM_NOT_PRESENT: Sense = Sense::new(SenseKey::NotReady, 0x3A, 0x00);
+    /// NOT READY / LOGICAL UNIT NOT CONFIGURED
+    pub const LOGICAL_UNIT_NOT_CONFIGURED: Sense = Sense::new(SenseKey::NotReady, 0x68, 0x00);
+
+    pub const fn new(key: SenseKey, asc: u8, ascq: u8) -> Self {
+        Self {
+            key,
+            asc,
+            ascq,
+            progress: None,
+            information: None,
+            deferred: false,
+        }
+    }
+
+    /// Returns fixed format sense data (response code 0x70 for current errors, 0x71 for deferred).
+    /// The information field is only valid if it fits 32 bits
+    pub fn to_fixed_bytes(&self) -> [u8; FIXED_SENSE_DATA_LEN] {
+        let mut bytes = [0u8; FIXED_SENSE_DATA_LEN];
+        bytes[0] = if self.deferred { 0x71 } else { 0x70 };
+        bytes[2] = self.key as u8;
+        if let Some(information) = self.information.and_then(|i| u32::try_from(i).ok()) {
+            bytes[0] |= VALID;
+            bytes[3..7].copy_from_slice(&information.to_be_bytes());
+        }
+        bytes[7] = (FIXED_SENSE_DATA_LEN - 8) as u8; // additional sense length
+        bytes[12] = self.asc;
+        bytes[13] = self.ascq;
+        if let Some(progress) = self.progress {
+            bytes[15] = SKSV;
+            bytes[16..18].copy_from_slice(&progress.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Writes descriptor format sense data (response code 0x72 for current errors, 0x73 for
+    /// deferred) into `dst` returning the number of bytes written. The information descriptor
+    /// is included if the information field is set, the sense-key specific one if the progress is
+    ///
+    /// # Panics
+    /// Panics if `dst` is shorter than [DESCRIPTOR_SENSE_DATA_MAX_LEN]
+    pub fn write_descriptor_bytes(&self, dst: &mut [u8]) -> usize {
+        let dst = &mut dst[..DESCRIPTOR_SENSE_DATA_MAX_LEN];
+        dst.fill(0);
+        dst[0] = if self.deferred { 0x73 } else { 0x72 };
+        dst[1] = self.key as u8;
+        dst[2] = self.asc;
+        dst[3] = self.ascq;
+        let mut len = DESCRIPTOR_SENSE_HEADER_LEN;
+
+        if let Some(information) = self.information {
+            let descriptor = &mut dst[len..len + INFORMATION_DESCRIPTOR_LEN];
+            descriptor[0] = INFORMATION_DESCRIPTOR;
+            descriptor[1] = (INFORMATION_DESCRIPTOR_LEN - 2) as u8; // additional length
+            descriptor[2] = VALID;
+            descriptor[4..12].copy_from_slice(&information.to_be_bytes());
+            len += INFORMATION_DESCRIPTOR_LEN;
+        }
+        if let Some(progress) = self.progress {
+            let descriptor = &mut dst[len..len + SENSE_KEY_SPECIFIC_DESCRIPTOR_LEN];
+            descriptor[0] = SENSE_KEY_SPECIFIC_DESCRIPTOR;
+            descriptor[1] = (SENSE_KEY_SPECIFIC_DESCRIPTOR_LEN - 2) as u8; // additional length
+            descriptor[4] = SKSV;
+            descriptor[5..7].copy_from_slice(&progress.to_be_bytes());
+            len += SENSE_KEY_SPECIFIC_DESCRIPTOR_LEN;
+        }
+
+        dst[7] = (len - DESCRIPTOR_SENSE_HEADER_LEN) as u8; // additional sense length
+        len
+    }
+}
+
+/// Bounded FIFO of sense conditions reported in order. A condition queued while the queue is full
+/// is dropped, so the earlier ones are never overwritten
+#[derive(Default, Copy, Clone)]
+pub(crate) struct SenseQueue {
+    entries: [Sense; SENSE_QUEUE_LEN],
+    len: usize,
+}
+
+impl SenseQueue {
+    /// Queues `sense` returning `false` if it has been dropped. A condition equal to the last
+    /// queued one is not queued twice
+    pub(crate) fn push(&mut self, sense: Sense) -> bool {
+        if self.len > 0 && self.entries[self.len - 1] == sense {
+            return true;
+        }
+        if self.len == SENSE_QUEUE_LEN {
+            return false;
+        }
+        self.entries[self.len] = sense;
+        self.len += 1;
+        true
+    }
+
+    /// Queues `sense` unless an equal condition is already queued
+    pub(crate) fn push_unique(&mut self, sense: Sense) -> bool {
+        self.entries[..self.len].contains(&sense) || self.push(sense)
+    }
+
+    /// Removes the oldest condition
+    #[allow(dead_code)]
+    pub(crate) fn pop(&mut self) -> Option<Sense> {
+        let first = self.first()?;
+        self.entries.copy_within(1..self.len, 0);
+        self.len -= 1;
+        Some(first)
+    }
+
+    /// Returns the oldest condition
+    pub(crate) fn first(&self) -> Option<Sense> {
+        self.entries[..self.len].first().copied()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subclass::scsi::sense::{
+        Sense, SenseKey, SenseQueue, DESCRIPTOR_SENSE_DATA_MAX_LEN, SENSE_QUEUE_LEN,
+    };
+
+    #[test]
+    fn should_serialize_fixed_format() {
+        let bytes = Sense::LBA_OUT_OF_RANGE.to_fixed_bytes();
+        assert_eq!(0x70, bytes[0]);
+        assert_eq!(0x05, bytes[2]);
+        assert_eq!(10, bytes[7]);
+        assert_eq!(0x21, bytes[12]);
+        assert_eq!(0x00, bytes[13]);
+        assert_eq!([0x00, 0x00, 0x00], bytes[15..]);
+    }
+
+    #[test]
+    fn should_serialize_progress_indication() {
+        let sense = Sense {
+            progress: Some(0x8000),
+            ..Sense::BECOMING_READY
+        };
+        let bytes = sense.to_fixed_bytes();
+        assert_eq!([0x02, 0x04, 0x01], [bytes[2], bytes[12], bytes[13]]);
+        assert_eq!([0x80, 0x80, 0x00], bytes[15..]);
+    }
+
+    #[test]
+    fn should_serialize_fixed_format_information() {
+        let sense = Sense {
+            information: Some(0x01020304),
+            deferred: true,
+            ..Sense::LBA_OUT_OF_RANGE
+        };
+        let bytes = sense.to_fixed_bytes();
+        assert_eq!(0xF1, bytes[0]);
+        assert_eq!([0x01, 0x02, 0x03, 0x04], bytes[3..7]);
+
+        let sense = Sense {
+            information: Some(0x1_0000_0000),
+            ..Sense::LBA_OUT_OF_RANGE
+        };
+        let bytes = sense.to_fixed_bytes();
+        assert_eq!(0x70, bytes[0]);
+        assert_eq!([0x00; 4], bytes[3..7]);
+    }
+
+    #[test]
+    fn should_serialize_descriptor_format() {
+        let mut buf = [0xFFu8; DESCRIPTOR_SENSE_DATA_MAX_LEN];
+        assert_eq!(8, Sense::LBA_OUT_OF_RANGE.write_descriptor_bytes(&mut buf));
+        assert_eq!([0x72, 0x05, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00], buf[..8]);
+        assert_eq!([0x00; 20], buf[8..]);
+    }
+
+    #[test]
+    fn should_serialize_descriptors() {
+        let sense = Sense {
+            information: Some(0x0102030405060708),
+            progress: Some(0x8000),
+            deferred: true,
+            ..Sense::BECOMING_READY
+        };
+        let mut buf = [0u8; DESCRIPTOR_SENSE_DATA_MAX_LEN];
+        assert_eq!(28, sense.write_descriptor_bytes(&mut buf));
+        assert_eq!([0x73, 0x02, 0x04, 0x01], buf[..4]);
+        assert_eq!(20, buf[7]);
+        assert_eq!(
+            [0x00, 0x0A, 0x80, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08],
+            buf[8..20]
+        );
+        assert_eq!([0x02, 0x06, 0x00, 0x00, 0x80, 0x80, 0x00, 0x00], buf[20..]);
+
+        let sense = Sense {
+            progress: Some(0xFFFF),
+            ..Sense::BECOMING_READY
+        };
+        assert_eq!(16, sense.write_descriptor_bytes(&mut buf));
+        assert_eq!(8, buf[7]);
+        assert_eq!([0x02, 0x06], buf[8..10]);
+    }
+
+    #[test]
+    fn should_queue_sense_in_order() {
+        let mut queue = SenseQueue::default();
+        assert!(queue.push(Sense::MEDIUM_NOT_PRESENT));
+        assert!(queue.push(Sense::NOT_READY_TO_READY_CHANGE));
+        assert!(queue.push(Sense::NOT_READY_TO_READY_CHANGE)); // collapsed
+        assert_eq!(2, queue.len());
+        assert_eq!(Some(Sense::MEDIUM_NOT_PRESENT), queue.pop());
+        assert_eq!(Some(Sense::NOT_READY_TO_READY_CHANGE), queue.pop());
+        assert_eq!(None, queue.pop());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn should_drop_sense_queued_when_full() {
+        let mut queue = SenseQueue::default();
+        for asc in 0..SENSE_QUEUE_LEN as u8 {
+            assert!(queue.push(Sense::new(SenseKey::IllegalRequest, asc, 0)));
+        }
+        assert!(!queue.push(Sense::WRITE_PROTECTED));
+        assert!(queue.push_unique(Sense::new(SenseKey::IllegalRequest, 0, 0)));
+        assert_eq!(SENSE_QUEUE_LEN, queue.len());
+        assert_eq!(
+            Some(Sense::new(SenseKey::IllegalRequest, 0, 0)),
+            queue.pop()
+        );
+        assert!(queue.push(Sense::WRITE_PROTECTED));
+        assert_eq!(Some(Sense::WRITE_PROTECTED), queue.entries.last().copied());
+
+        queue.clear();
+        assert_eq!(None, queue.first());
+    }
+}
@@ -0,0 +1,646 @@
+//! SCSI command block serialization
+//!
+//! The inverse of the command block parser, i.e. builds command blocks as a host would. Useful for
+//! testing handlers and for host-side initiators.
+
+use crate::subclass::scsi::{
+    ScsiCommand, INQUIRY, LOG_SELECT, LOG_SENSE, MODE_SELECT_10, MODE_SELECT_6, MODE_SENSE_10,
+    MODE_SENSE_6, PREVENT_ALLOW_MEDIUM_REMOVAL, READ_10, READ_16, READ_6, READ_ATTRIBUTE,
+    READ_BLOCK_LIMITS, READ_CAPACITY_10, READ_CAPACITY_16, READ_CD, READ_DEFECT_DATA_10,
+    READ_DEFECT_DATA_12, READ_FORMAT_CAPACITIES, READ_HEADER, RELEASE_6, REQUEST_SENSE, RESERVE_6,
+    REWIND, SPACE_6, START_STOP_UNIT, TEST_UNIT_READY, VERIFY_10, VERIFY_16, WRITE_10, WRITE_16,
+    WRITE_6, WRITE_AND_VERIFY_10, WRITE_AND_VERIFY_16, WRITE_ATTRIBUTE, WRITE_FILEMARKS_6,
+};
+
+/// Max length of a command block carried by a CBW
+pub const CB_MAX_LEN: usize = 16;
+
+/// Returns `cmd` as a command block. See [cmd_into_bytes]
+#[cfg(feature = "std")]
+pub fn cmd_to_vec(cmd: ScsiCommand) -> std::vec::Vec<u8> {
+    let mut cb = [0u8; CB_MAX_LEN];
+    let len = cmd_into_bytes(cmd, &mut cb);
+    cb[..len].to_vec()
+}
+
+/// Writes `cmd` as a command block into `dst` returning the number of bytes written.
+///
+/// `Read`, `Write`, `WriteAndVerify` and `Verify` are serialized as their 10-byte forms whenever `lba`
+/// and `len` fit, and as the 16-byte forms otherwise. [ScsiCommand::Unknown] is serialized
+/// as an opcode unknown to the parser. [ScsiCommand::UnsupportedCdbFormat] is serialized as its opcode
+/// followed by zeros up to `len`.
+///
+/// # Panics
+/// Panics if `dst` doesn't fit the command block
+pub fn cmd_into_bytes(cmd: ScsiCommand, dst: &mut [u8]) -> usize {
+    const UNKNOWN: u8 = 0xFF;
+
+    let mut cb = [0u8; CB_MAX_LEN];
+    let len = match cmd {
+        ScsiCommand::Unknown => {
+            cb[0] = UNKNOWN;
+            6
+        }
+        ScsiCommand::UnsupportedCdbFormat { opcode, len } => {
+            cb[0] = opcode;
+            len as usize
+        }
+        ScsiCommand::Inquiry {
+            evpd,
+            page_code,
+            alloc_len,
+        } => {
+            cb[0] = INQUIRY;
+            cb[1] = evpd as u8;
+            cb[2] = page_code;
+            cb[3..5].copy_from_slice(&alloc_len.to_be_bytes());
+            6
+        }
+        ScsiCommand::TestUnitReady => {
+            cb[0] = TEST_UNIT_READY;
+            6
+        }
+        ScsiCommand::RequestSense { desc, alloc_len } => {
+            cb[0] = REQUEST_SENSE;
+            cb[1] = desc as u8;
+            cb[4] = alloc_len;
+            6
+        }
+        ScsiCommand::ModeSense6 {
+            dbd,
+            page_control,
+            page_code,
+            subpage_code,
+            alloc_len,
+        } => {
+            cb[0] = MODE_SENSE_6;
+            cb[1] = (dbd as u8) << 3;
+            cb[2] = ((page_control as u8) << 6) | (page_code & 0b00111111);
+            cb[3] = subpage_code;
+            cb[4] = alloc_len;
+            6
+        }
+        ScsiCommand::ModeSense10 {
+            dbd,
+            page_control,
+            page_code,
+            subpage_code,
+            alloc_len,
+        } => {
+            cb[0] = MODE_SENSE_10;
+            cb[1] = (dbd as u8) << 3;
+            cb[2] = ((page_control as u8) << 6) | (page_code & 0b00111111);
+            cb[3] = subpage_code;
+            cb[7..9].copy_from_slice(&alloc_len.to_be_bytes());
+            10
+        }
+        ScsiCommand::ModeSelect6 {
+            pf,
+            sp,
+            parameter_list_len,
+        } => {
+            cb[0] = MODE_SELECT_6;
+            cb[1] = ((pf as u8) << 4) | sp as u8;
+            cb[4] = parameter_list_len;
+            6
+        }
+        ScsiCommand::ModeSelect10 {
+            pf,
+            sp,
+            parameter_list_len,
+        } => {
+            cb[0] = MODE_SELECT_10;
+            cb[1] = ((pf as u8) << 4) | sp as u8;
+            cb[7..9].copy_from_slice(&parameter_list_len.to_be_bytes());
+            10
+        }
+        ScsiCommand::LogSelect {
+            pcr,
+            sp,
+            page_control,
+            page_code,
+            subpage_code,
+            parameter_list_len,
+        } => {
+            cb[0] = LOG_SELECT;
+            cb[1] = ((pcr as u8) << 1) | sp as u8;
+            cb[2] = ((page_control as u8) << 6) | (page_code & 0b00111111);
+            cb[3] = subpage_code;
+            cb[7..9].copy_from_slice(&parameter_list_len.to_be_bytes());
+            10
+        }
+        ScsiCommand::LogSense {
+            sp,
+            page_control,
+            page_code,
+            subpage_code,
+            parameter_pointer,
+            alloc_len,
+        } => {
+            cb[0] = LOG_SENSE;
+            cb[1] = sp as u8;
+            cb[2] = ((page_control as u8) << 6) | (page_code & 0b00111111);
+            cb[3] = subpage_code;
+            cb[5..7].copy_from_slice(&parameter_pointer.to_be_bytes());
+            cb[7..9].copy_from_slice(&alloc_len.to_be_bytes());
+            10
+        }
+        ScsiCommand::ReadAttribute {
+            service_action,
+            logical_volume,
+            partition,
+            first_attribute,
+            alloc_len,
+            cache,
+        } => {
+            cb[0] = READ_ATTRIBUTE;
+            cb[1] = service_action & 0b00011111;
+            cb[5] = logical_volume;
+            cb[7] = partition;
+            cb[8..10].copy_from_slice(&first_attribute.to_be_bytes());
+            cb[10..14].copy_from_slice(&alloc_len.to_be_bytes());
+            cb[14] = cache as u8;
+            16
+        }
+        ScsiCommand::WriteAttribute {
+            wtc,
+            logical_volume,
+            partition,
+            parameter_list_len,
+        } => {
+            cb[0] = WRITE_ATTRIBUTE;
+            cb[1] = wtc as u8;
+            cb[5] = logical_volume;
+            cb[7] = partition;
+            cb[10..14].copy_from_slice(&parameter_list_len.to_be_bytes());
+            16
+        }
+        ScsiCommand::Reserve6 => {
+            cb[0] = RESERVE_6;
+            6
+        }
+        ScsiCommand::Release6 => {
+            cb[0] = RELEASE_6;
+            6
+        }
+        ScsiCommand::PreventAllowMediumRemoval { prevent } => {
+            cb[0] = PREVENT_ALLOW_MEDIUM_REMOVAL;
+            cb[4] = prevent as u8;
+            6
+        }
+        ScsiCommand::StartStopUnit {
+            immed,
+            power_condition,
+            load_eject,
+            start,
+        } => {
+            cb[0] = START_STOP_UNIT;
+            cb[1] = immed as u8;
+            cb[4] = (power_condition << 4) | ((load_eject as u8) << 1) | start as u8;
+            6
+        }
+        ScsiCommand::ReadCapacity10 { lba, pmi } => {
+            cb[0] = READ_CAPACITY_10;
+            cb[2..6].copy_from_slice(&lba.to_be_bytes());
+            cb[8] = pmi as u8;
+            10
+        }
+        ScsiCommand::ReadCapacity16 {
+            lba,
+            pmi,
+            alloc_len,
+        } => {
+            cb[0] = READ_CAPACITY_16;
+            cb[1] = 0x10; // service action
+            cb[2..10].copy_from_slice(&lba.to_be_bytes());
+            cb[10..14].copy_from_slice(&alloc_len.to_be_bytes());
+            cb[14] = pmi as u8;
+            16
+        }
+        ScsiCommand::Read { lba, len } => rw_into_bytes(&mut cb, READ_10, READ_16, lba, len),
+        ScsiCommand::Write { lba, len } => rw_into_bytes(&mut cb, WRITE_10, WRITE_16, lba, len),
+        ScsiCommand::WriteAndVerify {
+            lba,
+            len,
+            byte_check,
+        } => {
+            cb[1] = (byte_check as u8) << 1;
+            rw_into_bytes(&mut cb, WRITE_AND_VERIFY_10, WRITE_AND_VERIFY_16, lba, len)
+        }
+        ScsiCommand::Verify {
+            lba,
+            len,
+            byte_check,
+        } => {
+            cb[1] = (byte_check as u8) << 1;
+            rw_into_bytes(&mut cb, VERIFY_10, VERIFY_16, lba, len)
+        }
+        ScsiCommand::ReadDefectData {
+            req_plist,
+            req_glist,
+            defect_list_format,
+            alloc_len,
+        } => {
+            let flags = ((req_plist as u8) << 4)
+                | ((req_glist as u8) << 3)
+                | (defect_list_format & 0b00000111);
+            match u16::try_from(alloc_len) {
+                Ok(alloc_len) => {
+                    cb[0] = READ_DEFECT_DATA_10;
+                    cb[2] = flags;
+                    cb[7..9].copy_from_slice(&alloc_len.to_be_bytes());
+                    10
+                }
+                Err(_) => {
+                    cb[0] = READ_DEFECT_DATA_12;
+                    cb[1] = flags;
+                    cb[6..10].copy_from_slice(&alloc_len.to_be_bytes());
+                    12
+                }
+            }
+        }
+        ScsiCommand::Rewind { immed } => {
+            cb[0] = REWIND;
+            cb[1] = immed as u8;
+            6
+        }
+        ScsiCommand::ReadBlockLimits { mloc } => {
+            cb[0] = READ_BLOCK_LIMITS;
+            cb[1] = mloc as u8;
+            6
+        }
+        ScsiCommand::ReadSequential { sili, fixed, len } => {
+            cb[0] = READ_6;
+            cb[1] = ((sili as u8) << 1) | fixed as u8;
+            cb[2..5].copy_from_slice(&len.to_be_bytes()[1..]);
+            6
+        }
+        ScsiCommand::WriteSequential { fixed, len } => {
+            cb[0] = WRITE_6;
+            cb[1] = fixed as u8;
+            cb[2..5].copy_from_slice(&len.to_be_bytes()[1..]);
+            6
+        }
+        ScsiCommand::WriteFilemarks { wsmk, immed, count } => {
+            cb[0] = WRITE_FILEMARKS_6;
+            cb[1] = ((wsmk as u8) << 1) | immed as u8;
+            cb[2..5].copy_from_slice(&count.to_be_bytes()[1..]);
+            6
+        }
+        ScsiCommand::Space { code, count } => {
+            cb[0] = SPACE_6;
+            cb[1] = code & 0b00001111;
+            cb[2..5].copy_from_slice(&count.to_be_bytes()[1..]);
+            6
+        }
+        ScsiCommand::ReadFormatCapacities { alloc_len } => {
+            cb[0] = READ_FORMAT_CAPACITIES;
+            cb[7..9].copy_from_slice(&alloc_len.to_be_bytes());
+            10
+        }
+        ScsiCommand::ReadHeader {
+            msf,
+            lba,
+            alloc_len,
+        } => {
+            cb[0] = READ_HEADER;
+            cb[1] = (msf as u8) << 1;
+            cb[2..6].copy_from_slice(&lba.to_be_bytes());
+            cb[7..9].copy_from_slice(&alloc_len.to_be_bytes());
+            10
+        }
+        ScsiCommand::ReadCd {
+            expected_sector_type,
+            dap,
+            lba,
+            len,
+            sync,
+            header_codes,
+            user_data,
+            edc_ecc,
+            c2_error_info,
+            subchannel,
+        } => {
+            cb[0] = READ_CD;
+            cb[1] = ((expected_sector_type & 0b00000111) << 2) | ((dap as u8) << 1);
+            cb[2..6].copy_from_slice(&lba.to_be_bytes());
+            cb[6..9].copy_from_slice(&len.to_be_bytes()[1..]);
+            cb[9] = ((sync as u8) << 7)
+                | ((header_codes & 0b00000011) << 5)
+                | ((user_data as u8) << 4)
+                | ((edc_ecc as u8) << 3)
+                | ((c2_error_info & 0b00000011) << 1);
+            cb[10] = subchannel & 0b00000111;
+            12
+        }
+    };
+    dst[..len].copy_from_slice(&cb[..len]);
+    len
+}
+
+/// Writes Read/Write command blocks using the 16-byte form only if the 10-byte one doesn't fit
+fn rw_into_bytes(cb: &mut [u8; CB_MAX_LEN], op_10: u8, op_16: u8, lba: u64, len: u64) -> usize {
+    match (u32::try_from(lba), u16::try_from(len)) {
+        (Ok(lba), Ok(len)) => {
+            cb[0] = op_10;
+            cb[2..6].copy_from_slice(&lba.to_be_bytes());
+            cb[7..9].copy_from_slice(&len.to_be_bytes());
+            10
+        }
+        _ => {
+            cb[0] = op_16;
+            cb[2..10].copy_from_slice(&lba.to_be_bytes());
+            cb[10..14].copy_from_slice(&(len as u32).to_be_bytes());
+            16
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subclass::scsi::serialize::{cmd_into_bytes, CB_MAX_LEN};
+    use crate::subclass::scsi::{parse_cb, parse_ssc_cb, PageControl, ScsiCommand};
+
+    fn round_trip(cmd: ScsiCommand, parse: fn(&[u8]) -> ScsiCommand) {
+        let mut cb = [0u8; CB_MAX_LEN];
+        let len = cmd_into_bytes(cmd, &mut cb);
+        assert_eq!(cmd, parse(&cb[..len]), "{:02X?}", &cb[..len]);
+    }
+
+    /// A few values of each field including the edges
+    const U8S: [u8; 3] = [0, 0x5A, u8::MAX];
+    const U16S: [u16; 3] = [0, 0x1234, u16::MAX];
+    const U24S: [u32; 3] = [0, 0x123456, 0xFFFFFF];
+    const U32S: [u32; 3] = [0, 0x12345678, u32::MAX];
+    const BOOLS: [bool; 2] = [false, true];
+
+    #[test]
+    fn should_round_trip_spc_commands() {
+        round_trip(ScsiCommand::Unknown, parse_cb);
+        round_trip(ScsiCommand::TestUnitReady, parse_cb);
+        round_trip(ScsiCommand::Reserve6, parse_cb);
+        round_trip(ScsiCommand::Release6, parse_cb);
+        for prevent in BOOLS {
+            round_trip(ScsiCommand::PreventAllowMediumRemoval { prevent }, parse_cb);
+        }
+        let unsupported = [(0x7F, 16), (0x7E, 12), (0x28, 6), (0x88, 10), (0xA8, 10)];
+        for (opcode, len) in unsupported {
+            round_trip(ScsiCommand::UnsupportedCdbFormat { opcode, len }, parse_cb);
+            round_trip(
+                ScsiCommand::UnsupportedCdbFormat { opcode, len },
+                parse_ssc_cb,
+            );
+        }
+        for (evpd, page_code, alloc_len) in BOOLS.map(|b| (b, U8S[1], U16S[2])) {
+            round_trip(
+                ScsiCommand::Inquiry {
+                    evpd,
+                    page_code,
+                    alloc_len,
+                },
+                parse_cb,
+            );
+        }
+        for (desc, alloc_len) in BOOLS.into_iter().zip(U8S) {
+            round_trip(ScsiCommand::RequestSense { desc, alloc_len }, parse_cb);
+        }
+        let page_controls = [
+            PageControl::CurrentValues,
+            PageControl::ChangeableValues,
+            PageControl::DefaultValues,
+            PageControl::SavedValues,
+        ];
+        for (i, page_control) in page_controls.into_iter().enumerate() {
+            let dbd = BOOLS[i % 2];
+            let page_code = 0x3F >> i;
+            round_trip(
+                ScsiCommand::ModeSense6 {
+                    dbd,
+                    page_control,
+                    page_code,
+                    subpage_code: U8S[i % 3],
+                    alloc_len: U8S[(i + 1) % 3],
+                },
+                parse_cb,
+            );
+            round_trip(
+                ScsiCommand::ModeSense10 {
+                    dbd,
+                    page_control,
+                    page_code,
+                    subpage_code: U8S[i % 3],
+                    alloc_len: U16S[(i + 1) % 3],
+                },
+                parse_cb,
+            );
+        }
+        for (i, (pf, sp)) in BOOLS.into_iter().zip(BOOLS.into_iter().rev()).enumerate() {
+            round_trip(
+                ScsiCommand::ModeSelect6 {
+                    pf,
+                    sp,
+                    parameter_list_len: U8S[i],
+                },
+                parse_cb,
+            );
+            round_trip(
+                ScsiCommand::ModeSelect10 {
+                    pf,
+                    sp,
+                    parameter_list_len: U16S[i],
+                },
+                parse_cb,
+            );
+        }
+        for (i, page_control) in page_controls.into_iter().enumerate() {
+            round_trip(
+                ScsiCommand::LogSelect {
+                    pcr: BOOLS[i % 2],
+                    sp: BOOLS[i / 2 % 2],
+                    page_control,
+                    page_code: [0x00, 0x02, 0x3F][i % 3],
+                    subpage_code: U8S[i % 3],
+                    parameter_list_len: U16S[(i + 1) % 3],
+                },
+                parse_cb,
+            );
+            round_trip(
+                ScsiCommand::LogSense {
+                    sp: BOOLS[i % 2],
+                    page_control,
+                    page_code: [0x00, 0x03, 0x3F][i % 3],
+                    subpage_code: U8S[(i + 1) % 3],
+                    parameter_pointer: U16S[i % 3],
+                    alloc_len: U16S[(i + 2) % 3],
+                },
+                parse_cb,
+            );
+        }
+        for i in 0..3 {
+            round_trip(
+                ScsiCommand::ReadAttribute {
+                    service_action: [0x00, 0x05, 0x1F][i],
+                    logical_volume: U8S[i],
+                    partition: U8S[(i + 1) % 3],
+                    first_attribute: U16S[i],
+                    alloc_len: U32S[(i + 2) % 3],
+                    cache: BOOLS[i % 2],
+                },
+                parse_cb,
+            );
+            round_trip(
+                ScsiCommand::WriteAttribute {
+                    wtc: BOOLS[i % 2],
+                    logical_volume: U8S[(i + 2) % 3],
+                    partition: U8S[i],
+                    parameter_list_len: U32S[i],
+                },
+                parse_cb,
+            );
+        }
+    }
+
+    #[test]
+    fn should_round_trip_sbc_commands() {
+        for (lba, pmi) in [(0, false), (u32::MAX, true)] {
+            round_trip(ScsiCommand::ReadCapacity10 { lba, pmi }, parse_cb);
+        }
+        for (i, power_condition) in [0, 0x5, 0xF].into_iter().enumerate() {
+            round_trip(
+                ScsiCommand::StartStopUnit {
+                    immed: BOOLS[i % 2],
+                    power_condition,
+                    load_eject: BOOLS[(i + 1) % 2],
+                    start: BOOLS[i / 2],
+                },
+                parse_cb,
+            );
+        }
+        for alloc_len in U32S {
+            for (lba, pmi) in [(0, false), (u64::MAX, true)] {
+                round_trip(
+                    ScsiCommand::ReadCapacity16 {
+                        lba,
+                        pmi,
+                        alloc_len,
+                    },
+                    parse_cb,
+                );
+            }
+        }
+        let lbas = [0, u32::MAX as u64, u32::MAX as u64 + 1, u64::MAX];
+        let lens = [0, u16::MAX as u64, u16::MAX as u64 + 1, u32::MAX as u64];
+        for lba in lbas {
+            for len in lens {
+                round_trip(ScsiCommand::Read { lba, len }, parse_cb);
+                round_trip(ScsiCommand::Write { lba, len }, parse_cb);
+                for byte_check in [false, true] {
+                    round_trip(
+                        ScsiCommand::WriteAndVerify {
+                            lba,
+                            len,
+                            byte_check,
+                        },
+                        parse_cb,
+                    );
+                    round_trip(
+                        ScsiCommand::Verify {
+                            lba,
+                            len,
+                            byte_check,
+                        },
+                        parse_cb,
+                    );
+                }
+            }
+        }
+        for (i, alloc_len) in [0, u16::MAX as u32, u16::MAX as u32 + 1, u32::MAX]
+            .into_iter()
+            .enumerate()
+        {
+            round_trip(
+                ScsiCommand::ReadDefectData {
+                    req_plist: BOOLS[i % 2],
+                    req_glist: BOOLS[(i / 2) % 2],
+                    defect_list_format: i as u8 + 4,
+                    alloc_len,
+                },
+                parse_cb,
+            );
+        }
+    }
+
+    #[test]
+    fn should_round_trip_ssc_commands() {
+        for flag in BOOLS {
+            round_trip(ScsiCommand::Rewind { immed: flag }, parse_ssc_cb);
+            round_trip(ScsiCommand::ReadBlockLimits { mloc: flag }, parse_ssc_cb);
+        }
+        for len in U24S {
+            for (sili, fixed) in BOOLS.into_iter().zip(BOOLS.into_iter().rev()) {
+                round_trip(
+                    ScsiCommand::ReadSequential { sili, fixed, len },
+                    parse_ssc_cb,
+                );
+                round_trip(ScsiCommand::WriteSequential { fixed, len }, parse_ssc_cb);
+                round_trip(
+                    ScsiCommand::WriteFilemarks {
+                        wsmk: sili,
+                        immed: fixed,
+                        count: len,
+                    },
+                    parse_ssc_cb,
+                );
+            }
+        }
+        for count in [0, 1, -1, 0x7FFFFF, -0x800000] {
+            round_trip(ScsiCommand::Space { code: 0x03, count }, parse_ssc_cb);
+        }
+        // direct access commands are still recognized
+        round_trip(ScsiCommand::TestUnitReady, parse_ssc_cb);
+    }
+
+    #[test]
+    fn should_round_trip_mmc_commands() {
+        for alloc_len in U16S {
+            round_trip(ScsiCommand::ReadFormatCapacities { alloc_len }, parse_cb);
+        }
+        for (msf, lba) in BOOLS.into_iter().zip(U32S) {
+            round_trip(
+                ScsiCommand::ReadHeader {
+                    msf,
+                    lba,
+                    alloc_len: U16S[1],
+                },
+                parse_cb,
+            );
+        }
+        for (i, len) in U24S.into_iter().enumerate() {
+            round_trip(
+                ScsiCommand::ReadCd {
+                    expected_sector_type: i as u8 * 2,
+                    dap: BOOLS[i % 2],
+                    lba: U32S[i],
+                    len,
+                    sync: BOOLS[(i + 1) % 2],
+                    header_codes: i as u8,
+                    user_data: BOOLS[i % 2],
+                    edc_ecc: BOOLS[(i + 1) % 2],
+                    c2_error_info: 2 - i as u8,
+                    subchannel: i as u8 * 2,
+                },
+                parse_cb,
+            );
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn should_serialize_into_vec() {
+        use crate::subclass::scsi::serialize::cmd_to_vec;
+
+        let cb = cmd_to_vec(ScsiCommand::Read { lba: 1, len: 2 });
+        assert_eq!(10, cb.len());
+        assert_eq!(ScsiCommand::Read { lba: 1, len: 2 }, parse_cb(&cb));
+    }
+}
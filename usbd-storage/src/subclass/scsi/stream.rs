@@ -0,0 +1,299 @@
+//! [embedded-io] streams over the block space of a Logical Unit
+//!
+//! [BlockStream] reads and writes a [BlockDevice] as a seekable stream of bytes, so that storage
+//! middleware operating over a stream, e.g. a filesystem crate, works on the medium the host
+//! sees. With the `embedded-io-async` feature it implements the traits of [embedded-io-async] as
+//! well. [StreamDevice] goes the other way: a [BlockDevice] over a seekable stream, e.g. an image
+//! of the medium behind another driver, served by [BlockDriver].
+//!
+//! [embedded-io]: https://crates.io/crates/embedded-io
+//! [embedded-io-async]: https://crates.io/crates/embedded-io-async
+//! [BlockDriver]: crate::subclass::scsi::block::BlockDriver
+
+use crate::subclass::scsi::block::BlockDevice;
+use crate::subclass::scsi::capacity::BlockSize;
+use crate::subclass::scsi::sense::{Sense, SenseKey};
+use core::borrow::BorrowMut;
+use embedded_io::{ErrorKind, ErrorType, Read, Seek, SeekFrom, Write};
+
+/// A seek beyond the medium is an [ErrorKind::InvalidInput], a write to a protected one is
+/// [ErrorKind::PermissionDenied]
+impl embedded_io::Error for Sense {
+    fn kind(&self) -> ErrorKind {
+        match self.key {
+            SenseKey::IllegalRequest if self.asc == Sense::LBA_OUT_OF_RANGE.asc => {
+                ErrorKind::InvalidInput
+            }
+            SenseKey::DataProtect => ErrorKind::PermissionDenied,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// Seekable stream of the bytes of a [BlockDevice]
+///
+/// Whole blocks are transferred right between the device and the data of the call, with
+/// [read_blocks] and [write_blocks]. Part of a block goes through `buf`, a buffer of a single
+/// block: the block is read first, so that a write leaves the rest of it intact. The stream ends
+/// at the end of the medium: a read there returns no bytes, a write or a seek beyond it fails
+/// with [LBA_OUT_OF_RANGE].
+///
+/// ```
+/// # use usbd_storage::subclass::scsi::block::BlockDevice;
+/// # use usbd_storage::subclass::scsi::capacity::BlockSize;
+/// # use usbd_storage::subclass::scsi::sense::Sense;
+/// use embedded_io::{Read, Seek, SeekFrom, Write};
+/// use usbd_storage::subclass::scsi::stream::BlockStream;
+/// # struct Ram([u8; 4096]);
+/// # impl BlockDevice for Ram {
+/// #     fn block_size(&self) -> BlockSize { BlockSize::B512 }
+/// #     fn num_blocks(&self) -> u64 { 8 }
+/// #     fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), Sense> {
+/// #         block.copy_from_slice(&self.0[lba as usize * 512..][..512]);
+/// #         Ok(())
+/// #     }
+/// #     fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), Sense> {
+/// #         self.0[lba as usize * 512..][..512].copy_from_slice(block);
+/// #         Ok(())
+/// #     }
+/// # }
+///
+/// let mut ram = Ram([0u8; 4096]);
+/// let mut stream = BlockStream::new(&mut ram, [0u8; 512]);
+/// stream.seek(SeekFrom::Start(510))?;
+/// stream.write_all(b"spans two blocks")?;
+/// stream.seek(SeekFrom::Current(-16))?;
+/// let mut data = [0u8; 16];
+/// stream.read_exact(&mut data).unwrap();
+/// assert_eq!(b"spans two blocks", &data);
+/// # Ok::<(), Sense>(())
+/// ```
+///
+/// [read_blocks]: BlockDevice::read_blocks
+/// [write_blocks]: BlockDevice::write_blocks
+/// [LBA_OUT_OF_RANGE]: Sense::LBA_OUT_OF_RANGE
+pub struct BlockStream<D: BlockDevice, Buf: BorrowMut<[u8]>> {
+    device: D,
+    buf: Buf,
+    pos: u64,
+}
+
+impl<D: BlockDevice, Buf: BorrowMut<[u8]>> BlockStream<D, Buf> {
+    /// Creates a stream at the start of `device`
+    ///
+    /// # Panics
+    /// Panics if `buf` doesn't fit a block of `device`
+    pub fn new(device: D, mut buf: Buf) -> Self {
+        assert!(
+            buf.borrow_mut().len() >= device.block_size().get() as usize,
+            "the buffer is expected to fit a block"
+        );
+        Self {
+            device,
+            buf,
+            pos: 0,
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Returns the block the stream is at, the position within it and the size of a block.
+    /// `None` at the end of the medium
+    fn block(&self) -> Option<(u64, usize, usize)> {
+        let block_size = self.device.block_size().get() as u64;
+        let lba = self.pos / block_size;
+        (lba < self.device.num_blocks()).then_some((
+            lba,
+            (self.pos % block_size) as usize,
+            block_size as usize,
+        ))
+    }
+}
+
+impl<D: BlockDevice, Buf: BorrowMut<[u8]>> ErrorType for BlockStream<D, Buf> {
+    type Error = Sense;
+}
+
+impl<D: BlockDevice, Buf: BorrowMut<[u8]>> Read for BlockStream<D, Buf> {
+    fn read(&mut self, dst: &mut [u8]) -> Result<usize, Sense> {
+        let Some((lba, offset, block_size)) = self.block().filter(|_| !dst.is_empty()) else {
+            return Ok(0);
+        };
+        let count = if offset == 0 && dst.len() >= block_size {
+            let blocks = (dst.len() / block_size) as u64;
+            let count = blocks.min(self.device.num_blocks() - lba) as usize * block_size;
+            self.device.read_blocks(lba, &mut dst[..count])?;
+            count
+        } else {
+            let block = &mut self.buf.borrow_mut()[..block_size];
+            self.device.read_block(lba, block)?;
+            let count = dst.len().min(block_size - offset);
+            dst[..count].copy_from_slice(&block[offset..offset + count]);
+            count
+        };
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl<D: BlockDevice, Buf: BorrowMut<[u8]>> Write for BlockStream<D, Buf> {
+    fn write(&mut self, src: &[u8]) -> Result<usize, Sense> {
+        if src.is_empty() {
+            return Ok(0);
+        }
+        let Some((lba, offset, block_size)) = self.block() else {
+            return Err(Sense::LBA_OUT_OF_RANGE);
+        };
+        let count = if offset == 0 && src.len() >= block_size {
+            let blocks = (src.len() / block_size) as u64;
+            let count = blocks.min(self.device.num_blocks() - lba) as usize * block_size;
+            self.device.write_blocks(lba, &src[..count])?;
+            count
+        } else {
+            let block = &mut self.buf.borrow_mut()[..block_size];
+            self.device.read_block(lba, block)?;
+            let count = src.len().min(block_size - offset);
+            block[offset..offset + count].copy_from_slice(&src[..count]);
+            self.device.write_block(lba, block)?;
+            count
+        };
+        self.pos += count as u64;
+        Ok(count)
+    }
+
+    /// Flushes the device, see [BlockDevice::flush]
+    fn flush(&mut self) -> Result<(), Sense> {
+        self.device.flush()
+    }
+}
+
+impl<D: BlockDevice, Buf: BorrowMut<[u8]>> Seek for BlockStream<D, Buf> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Sense> {
+        let len = self.device.num_blocks() * self.device.block_size().get() as u64;
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match pos {
+            Some(pos) if pos <= len => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            _ => Err(Sense::LBA_OUT_OF_RANGE),
+        }
+    }
+}
+
+/// Completes right away, the device is read and written as with [Read] and [Write]
+#[cfg(feature = "embedded-io-async")]
+impl<D: BlockDevice, Buf: BorrowMut<[u8]>> embedded_io_async::Read for BlockStream<D, Buf> {
+    async fn read(&mut self, dst: &mut [u8]) -> Result<usize, Sense> {
+        Read::read(self, dst)
+    }
+}
+
+#[cfg(feature = "embedded-io-async")]
+impl<D: BlockDevice, Buf: BorrowMut<[u8]>> embedded_io_async::Write for BlockStream<D, Buf> {
+    async fn write(&mut self, src: &[u8]) -> Result<usize, Sense> {
+        Write::write(self, src)
+    }
+
+    async fn flush(&mut self) -> Result<(), Sense> {
+        Write::flush(self)
+    }
+}
+
+#[cfg(feature = "embedded-io-async")]
+impl<D: BlockDevice, Buf: BorrowMut<[u8]>> embedded_io_async::Seek for BlockStream<D, Buf> {
+    async fn seek(&mut self, pos: SeekFrom) -> Result<u64, Sense> {
+        Seek::seek(self, pos)
+    }
+}
+
+/// [BlockDevice] over a seekable stream, the block `lba` at `lba` blocks into the stream
+///
+/// A failure to seek or read the stream, including its end before the end of the block, is
+/// reported as [UNRECOVERED_READ_ERROR]. A failure to seek, write or flush it as [WRITE_ERROR]
+///
+/// [UNRECOVERED_READ_ERROR]: Sense::UNRECOVERED_READ_ERROR
+/// [WRITE_ERROR]: Sense::WRITE_ERROR
+pub struct StreamDevice<S: Read + Write + Seek> {
+    stream: S,
+    block_size: BlockSize,
+    num_blocks: u64,
+}
+
+impl<S: Read + Write + Seek> StreamDevice<S> {
+    /// Creates a device of `num_blocks` blocks of `block_size` over `stream`
+    pub fn new(stream: S, block_size: BlockSize, num_blocks: u64) -> Self {
+        Self {
+            stream,
+            block_size,
+            num_blocks,
+        }
+    }
+
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn stream_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    fn seek(&mut self, lba: u64) -> Result<u64, S::Error> {
+        self.stream
+            .seek(SeekFrom::Start(lba * self.block_size.get() as u64))
+    }
+}
+
+impl<S: Read + Write + Seek> BlockDevice for StreamDevice<S> {
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), Sense> {
+        self.read_blocks(lba, block)
+    }
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), Sense> {
+        self.write_blocks(lba, block)
+    }
+
+    fn read_blocks(&mut self, lba: u64, blocks: &mut [u8]) -> Result<(), Sense> {
+        self.seek(lba).map_err(|_| Sense::UNRECOVERED_READ_ERROR)?;
+        self.stream
+            .read_exact(blocks)
+            .map_err(|_| Sense::UNRECOVERED_READ_ERROR)
+    }
+
+    fn write_blocks(&mut self, lba: u64, blocks: &[u8]) -> Result<(), Sense> {
+        self.seek(lba).map_err(|_| Sense::WRITE_ERROR)?;
+        self.stream
+            .write_all(blocks)
+            .map_err(|_| Sense::WRITE_ERROR)
+    }
+
+    fn flush(&mut self) -> Result<(), Sense> {
+        self.stream.flush().map_err(|_| Sense::WRITE_ERROR)
+    }
+}
@@ -1,18 +1,21 @@
 //! USB Floppy Interface
 
+use crate::subclass::addressed_elsewhere;
 use crate::transport::Transport;
 use crate::CLASS_MASS_STORAGE;
 use usb_device::bus::InterfaceNumber;
 use usb_device::bus::UsbBus;
-use usb_device::class::{ControlIn, UsbClass};
-use usb_device::descriptor::DescriptorWriter;
+use usb_device::class::{ControlIn, ControlOut, UsbClass};
+use usb_device::descriptor::{BosWriter, DescriptorWriter};
 #[cfg(feature = "bbb")]
 use {
     crate::fmt::debug,
-    crate::subclass::Command,
+    crate::quirks::Quirks,
+    crate::subclass::{map_ignore, Aborted, Command},
     crate::transport::bbb::{BulkOnly, BulkOnlyError},
-    crate::transport::TransportError,
+    crate::transport::{CommandStatus, Reset, TransportError},
     core::borrow::BorrowMut,
+    core::cmp::min,
     usb_device::bus::UsbBusAllocator,
     usb_device::UsbError,
 };
@@ -41,22 +44,225 @@ const WRITE_10: u8 = 0x2A;
 const WRITE_12: u8 = 0xAA;
 const WRITE_AND_VERIFY: u8 = 0x2E;
 
-pub fn lba_to_sector(lba: u32, sec_trk: u8) -> u32 {
-    lba % sec_trk as u32 + 1
+/// Returns the 1-based sector of `lba` within its track.
+/// `None` if `sec_trk` (sectors per track) is zero
+pub fn lba_to_sector(lba: u32, sec_trk: u8) -> Option<u32> {
+    lba.checked_rem(sec_trk as u32).map(|sector| sector + 1)
 }
 
-pub fn lba_to_head(lba: u32, sec_trk: u8, head_trk: u8) -> u32 {
-    (lba / sec_trk as u32) % head_trk as u32
+/// Returns the head `lba` is accessed with.
+/// `None` if `sec_trk` (sectors per track) or `head_trk` (heads per track) is zero
+pub fn lba_to_head(lba: u32, sec_trk: u8, head_trk: u8) -> Option<u32> {
+    lba.checked_div(sec_trk as u32)?
+        .checked_rem(head_trk as u32)
 }
 
-pub fn lba_to_track(lba: u32, sec_trk: u8, head_trk: u8) -> u32 {
-    (lba / sec_trk as u32) / head_trk as u32
+/// Returns the track (cylinder) of `lba`.
+/// `None` if `sec_trk` (sectors per track) or `head_trk` (heads per track) is zero
+pub fn lba_to_track(lba: u32, sec_trk: u8, head_trk: u8) -> Option<u32> {
+    lba.checked_div(sec_trk as u32)?
+        .checked_div(head_trk as u32)
+}
+
+/// Length of the mode parameter header
+const MODE_PARAMETER_HEADER_LEN: usize = 8;
+/// Length of the Timer and Protect page
+const TIMER_AND_PROTECT_PAGE_LEN: usize = 8;
+/// Page code of the Timer and Protect page
+pub const TIMER_AND_PROTECT_PAGE_CODE: u8 = 0x1C;
+/// Page code requesting all the mode pages
+pub const ALL_PAGES: u8 = 0x3F;
+/// Length of MODE SENSE data with all the pages written by [write_mode_sense]
+pub const MODE_SENSE_DATA_MAX_LEN: usize = MODE_PARAMETER_HEADER_LEN + TIMER_AND_PROTECT_PAGE_LEN;
+
+/// WP bit of the mode parameter header
+const WRITE_PROTECT: u8 = 0b10000000;
+/// SWPP bit of the Timer and Protect page
+const SOFTWARE_WRITE_PROTECT: u8 = 0b00000001;
+/// Fixed format sense data of DATA PROTECT, WRITE PROTECTED
+#[cfg(feature = "bbb")]
+const WRITE_PROTECTED_SENSE: [u8; 18] = [
+    0x70, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x0A, 0x00, 0x00, 0x00, 0x00, 0x27, 0x00, 0x00, 0x00,
+    0x00, 0x00,
+];
+
+/// Writes MODE SENSE data into `dst` returning the number of bytes written: the mode parameter
+/// header followed by the Timer and Protect page, if asked for by `page_code` either directly or
+/// with [ALL_PAGES]. The WP bit of the header and the SWPP bit of the page both follow
+/// `write_protected`. Spec. UFI 4.5
+///
+/// # Panics
+/// Panics if `dst` doesn't fit the data
+pub fn write_mode_sense(dst: &mut [u8], write_protected: bool, page_code: u8) -> usize {
+    let with_page = matches!(page_code, TIMER_AND_PROTECT_PAGE_CODE | ALL_PAGES);
+    let len = if with_page {
+        MODE_SENSE_DATA_MAX_LEN
+    } else {
+        MODE_PARAMETER_HEADER_LEN
+    };
+    let dst = &mut dst[..len];
+    dst.fill(0);
+    dst[..2].copy_from_slice(&((len - 2) as u16).to_be_bytes()); // mode data length
+    dst[2] = 0x00; // medium type: default
+    if write_protected {
+        dst[3] = WRITE_PROTECT;
+    }
+    if with_page {
+        let page = &mut dst[MODE_PARAMETER_HEADER_LEN..];
+        page[0] = TIMER_AND_PROTECT_PAGE_CODE;
+        page[1] = (TIMER_AND_PROTECT_PAGE_LEN - 2) as u8; // page length
+        if write_protected {
+            page[4] = SOFTWARE_WRITE_PROTECT;
+        }
+    }
+    len
+}
+
+/// Length of the capacity list header of READ FORMAT CAPACITIES data
+const CAPACITY_LIST_HEADER_LEN: usize = 4;
+/// Length of a capacity descriptor of READ FORMAT CAPACITIES data
+const CAPACITY_DESCRIPTOR_LEN: usize = 8;
+/// Max number of Formattable Capacity Descriptors of [FormatCapacities]
+pub const FORMATTABLE_CAPACITIES_MAX: usize = 4;
+/// Length of READ FORMAT CAPACITIES data with [FORMATTABLE_CAPACITIES_MAX] formattable descriptors
+pub const FORMAT_CAPACITIES_DATA_MAX_LEN: usize =
+    CAPACITY_LIST_HEADER_LEN + CAPACITY_DESCRIPTOR_LEN * (1 + FORMATTABLE_CAPACITIES_MAX);
+
+/// Geometry of a floppy disk format
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FloppyGeometry {
+    pub tracks: u16,
+    pub heads: u8,
+    pub sectors_per_track: u8,
+    /// Length of a block (sector) in bytes
+    pub block_len: u32,
+}
+
+impl FloppyGeometry {
+    /// 3.5" 720 KB: 80 tracks, 2 heads, 9 sectors per track of 512 bytes
+    pub const F720K: Self = Self::new(80, 2, 9, 512);
+    /// 3.5" 1.44 MB: 80 tracks, 2 heads, 18 sectors per track of 512 bytes
+    pub const F1440K: Self = Self::new(80, 2, 18, 512);
+    /// 3.5" 2.88 MB: 80 tracks, 2 heads, 36 sectors per track of 512 bytes
+    pub const F2880K: Self = Self::new(80, 2, 36, 512);
+
+    pub const fn new(tracks: u16, heads: u8, sectors_per_track: u8, block_len: u32) -> Self {
+        Self {
+            tracks,
+            heads,
+            sectors_per_track,
+            block_len,
+        }
+    }
+
+    /// Number of blocks of the format
+    pub const fn num_blocks(&self) -> u32 {
+        self.tracks as u32 * self.heads as u32 * self.sectors_per_track as u32
+    }
+}
+
+/// Medium reported by the Current/Maximum Capacity Descriptor
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MediumStatus {
+    /// The capacity is the maximum formattable capacity of an unformatted medium
+    Unformatted = 0b01,
+    /// The capacity is the one of the formatted medium
+    Formatted = 0b10,
+    /// The capacity is the maximum one the drive supports
+    NoMedium = 0b11,
+}
+
+/// READ FORMAT CAPACITIES data: the Current/Maximum Capacity Descriptor followed by up to
+/// [FORMATTABLE_CAPACITIES_MAX] Formattable Capacity Descriptors. Write it with
+/// [write_format_capacities]
+///
+/// ```
+/// use usbd_storage::subclass::ufi::{FloppyGeometry, FormatCapacities, MediumStatus};
+///
+/// const CAPACITIES: FormatCapacities =
+///     FormatCapacities::new(FloppyGeometry::F1440K, MediumStatus::Formatted)
+///         .formattable(FloppyGeometry::F1440K)
+///         .formattable(FloppyGeometry::F720K);
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FormatCapacities {
+    current: FloppyGeometry,
+    status: MediumStatus,
+    formattable: [FloppyGeometry; FORMATTABLE_CAPACITIES_MAX],
+    formattable_len: usize,
+}
+
+impl FormatCapacities {
+    /// Reports `current` as the capacity of the medium in the drive, or the maximum capacity
+    /// of the drive if there is no medium. No formats are listed as formattable
+    pub const fn new(current: FloppyGeometry, status: MediumStatus) -> Self {
+        Self {
+            current,
+            status,
+            formattable: [current; FORMATTABLE_CAPACITIES_MAX],
+            formattable_len: 0,
+        }
+    }
+
+    /// Lists `geometry` as a format the medium can be formatted with. Leave the list empty if
+    /// there is no medium
+    ///
+    /// # Panics
+    /// Panics if [FORMATTABLE_CAPACITIES_MAX] formats are listed already
+    pub const fn formattable(mut self, geometry: FloppyGeometry) -> Self {
+        assert!(self.formattable_len < FORMATTABLE_CAPACITIES_MAX);
+        self.formattable[self.formattable_len] = geometry;
+        self.formattable_len += 1;
+        self
+    }
+
+    /// Returns the formats listed as formattable
+    pub fn formattable_capacities(&self) -> &[FloppyGeometry] {
+        &self.formattable[..self.formattable_len]
+    }
+
+    /// Returns the length of the data
+    pub const fn data_len(&self) -> usize {
+        CAPACITY_LIST_HEADER_LEN + CAPACITY_DESCRIPTOR_LEN * (1 + self.formattable_len)
+    }
+}
+
+/// Writes READ FORMAT CAPACITIES data into `dst` returning the number of bytes written. Spec.
+/// UFI 4.10
+///
+/// # Panics
+/// Panics if `dst` doesn't fit [FormatCapacities::data_len] bytes
+pub fn write_format_capacities(dst: &mut [u8], capacities: &FormatCapacities) -> usize {
+    fn descriptor(dst: &mut [u8], geometry: &FloppyGeometry, code: u8) {
+        dst[..4].copy_from_slice(&geometry.num_blocks().to_be_bytes());
+        dst[4] = code;
+        dst[5..8].copy_from_slice(&geometry.block_len.to_be_bytes()[1..]);
+    }
+
+    let len = capacities.data_len();
+    let dst = &mut dst[..len];
+    dst[..3].fill(0);
+    dst[3] = (len - CAPACITY_LIST_HEADER_LEN) as u8; // capacity list length
+    let mut descriptors = dst[CAPACITY_LIST_HEADER_LEN..].chunks_exact_mut(CAPACITY_DESCRIPTOR_LEN);
+    descriptor(
+        descriptors.next().unwrap(),
+        &capacities.current,
+        capacities.status as u8,
+    );
+    for (dst, geometry) in descriptors.zip(capacities.formattable_capacities()) {
+        descriptor(dst, geometry, 0); // reserved
+    }
+    len
 }
 
 /// UFI command
 ///
 /// Refer to specification
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[non_exhaustive]
 pub enum UfiCommand {
@@ -129,6 +335,15 @@ pub enum UfiCommand {
     },
 }
 
+/// The first block a command transferring blocks addresses, `None` for other commands
+#[cfg(feature = "bbb")]
+fn blocks_lba(kind: UfiCommand) -> Option<u64> {
+    match kind {
+        UfiCommand::Read { lba, .. } | UfiCommand::Write { lba, .. } => Some(lba as u64),
+        _ => None,
+    }
+}
+
 #[allow(dead_code)]
 fn parse_cb(cb: &[u8]) -> UfiCommand {
     match cb[0] {
@@ -196,10 +411,261 @@ fn parse_cb(cb: &[u8]) -> UfiCommand {
     }
 }
 
+/// UFI command block serialization
+///
+/// The inverse of the command block parser, i.e. builds command blocks as a host would. Useful for
+/// testing handlers and for host-side initiators.
+#[cfg(any(feature = "test-util", test))]
+pub mod serialize {
+    use crate::subclass::ufi::{
+        UfiCommand, FORMAT_UNIT, INQUIRY, MODE_SELECT, MODE_SENSE, PREVENT_ALLOW_MEDIUM_REMOVAL,
+        READ_10, READ_12, READ_CAPACITY, READ_FORMAT_CAPACITIES, REQUEST_SENSE, REZERO_UNIT,
+        SEEK_10, SEND_DIAGNOSTIC, START_STOP, TEST_UNIT_READY, VERIFY, WRITE_10, WRITE_12,
+        WRITE_AND_VERIFY,
+    };
+
+    /// Length of a UFI command block
+    pub const CB_LEN: usize = 12;
+
+    /// Returns `cmd` as a command block. See [cmd_into_bytes]
+    #[cfg(feature = "std")]
+    pub fn cmd_to_vec(cmd: UfiCommand) -> std::vec::Vec<u8> {
+        let mut cb = [0u8; CB_LEN];
+        let len = cmd_into_bytes(cmd, &mut cb);
+        cb[..len].to_vec()
+    }
+
+    /// Writes `cmd` as a command block into `dst` returning the number of bytes written.
+    ///
+    /// `Read` and `Write` are serialized as their 10-byte forms whenever `len` fits 16 bits, and as
+    /// the 12-byte forms otherwise. `Write` with `verify` set is serialized as WRITE AND VERIFY,
+    /// which only carries 16 bits of `len`. [UfiCommand::Unknown] is serialized as an opcode
+    /// unknown to the parser.
+    ///
+    /// # Panics
+    /// Panics if `dst` doesn't fit the command block
+    pub fn cmd_into_bytes(cmd: UfiCommand, dst: &mut [u8]) -> usize {
+        const UNKNOWN: u8 = 0xFF;
+
+        let mut cb = [0u8; CB_LEN];
+        match cmd {
+            UfiCommand::Unknown => cb[0] = UNKNOWN,
+            UfiCommand::FormatUnit {
+                track,
+                parameter_list_len,
+            } => {
+                cb[0] = FORMAT_UNIT;
+                cb[2] = track;
+                cb[7..9].copy_from_slice(&parameter_list_len.to_be_bytes());
+            }
+            UfiCommand::Inquiry { alloc_len } => {
+                cb[0] = INQUIRY;
+                cb[4] = alloc_len;
+            }
+            UfiCommand::TestUnitReady => cb[0] = TEST_UNIT_READY,
+            UfiCommand::PreventAllowMediumRemoval { prevent } => {
+                cb[0] = PREVENT_ALLOW_MEDIUM_REMOVAL;
+                cb[4] = prevent as u8;
+            }
+            UfiCommand::ReadCapacity => cb[0] = READ_CAPACITY,
+            UfiCommand::RequestSense { alloc_len } => {
+                cb[0] = REQUEST_SENSE;
+                cb[4] = alloc_len;
+            }
+            UfiCommand::ModeSense {
+                page_control,
+                page_code,
+                param_list_len,
+            } => {
+                cb[0] = MODE_SENSE;
+                cb[2] = (page_control << 6) | (page_code & 0b00111111);
+                cb[7..9].copy_from_slice(&param_list_len.to_be_bytes());
+            }
+            UfiCommand::ModeSelect { parameter_list_len } => {
+                cb[0] = MODE_SELECT;
+                cb[7..9].copy_from_slice(&parameter_list_len.to_be_bytes());
+            }
+            UfiCommand::StartStop { start, eject } => {
+                cb[0] = START_STOP;
+                cb[4] = ((eject as u8) << 1) | start as u8;
+            }
+            UfiCommand::Read { lba, len } => {
+                cb[2..6].copy_from_slice(&lba.to_be_bytes());
+                match u16::try_from(len) {
+                    Ok(len) => {
+                        cb[0] = READ_10;
+                        cb[7..9].copy_from_slice(&len.to_be_bytes());
+                    }
+                    Err(_) => {
+                        cb[0] = READ_12;
+                        cb[6..10].copy_from_slice(&len.to_be_bytes());
+                    }
+                }
+            }
+            UfiCommand::Write { lba, len, verify } => {
+                cb[2..6].copy_from_slice(&lba.to_be_bytes());
+                match u16::try_from(len) {
+                    _ if verify => {
+                        cb[0] = WRITE_AND_VERIFY;
+                        cb[7..9].copy_from_slice(&(len as u16).to_be_bytes()); // truncated
+                    }
+                    Ok(len) => {
+                        cb[0] = WRITE_10;
+                        cb[7..9].copy_from_slice(&len.to_be_bytes());
+                    }
+                    Err(_) => {
+                        cb[0] = WRITE_12;
+                        cb[6..10].copy_from_slice(&len.to_be_bytes());
+                    }
+                }
+            }
+            UfiCommand::ReadFormatCapacities { alloc_len } => {
+                cb[0] = READ_FORMAT_CAPACITIES;
+                cb[7..9].copy_from_slice(&alloc_len.to_be_bytes());
+            }
+            UfiCommand::RezeroUnit => cb[0] = REZERO_UNIT,
+            UfiCommand::Seek { lba } => {
+                cb[0] = SEEK_10;
+                cb[2..6].copy_from_slice(&lba.to_be_bytes());
+            }
+            UfiCommand::SendDiagnostic { default } => {
+                cb[0] = SEND_DIAGNOSTIC;
+                cb[1] = (default as u8) << 2;
+            }
+            UfiCommand::Verify { lba, len } => {
+                cb[0] = VERIFY;
+                cb[2..6].copy_from_slice(&lba.to_be_bytes());
+                cb[7..9].copy_from_slice(&len.to_be_bytes());
+            }
+        }
+        dst[..CB_LEN].copy_from_slice(&cb);
+        CB_LEN
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use crate::subclass::ufi::parse_cb;
+        use crate::subclass::ufi::serialize::{cmd_into_bytes, CB_LEN};
+        use crate::subclass::ufi::UfiCommand;
+
+        fn round_trip(cmd: UfiCommand) {
+            let mut cb = [0u8; CB_LEN];
+            let len = cmd_into_bytes(cmd, &mut cb);
+            assert_eq!(cmd, parse_cb(&cb[..len]), "{:02X?}", &cb[..len]);
+        }
+
+        const U16S: [u16; 3] = [0, 0x1234, u16::MAX];
+        const U32S: [u32; 4] = [0, u16::MAX as u32, u16::MAX as u32 + 1, u32::MAX];
+
+        #[test]
+        fn should_round_trip_commands() {
+            round_trip(UfiCommand::Unknown);
+            round_trip(UfiCommand::TestUnitReady);
+            round_trip(UfiCommand::ReadCapacity);
+            round_trip(UfiCommand::RezeroUnit);
+            for (i, len) in U16S.into_iter().enumerate() {
+                let flag = i % 2 != 0;
+                round_trip(UfiCommand::FormatUnit {
+                    track: i as u8 * 0x7F,
+                    parameter_list_len: len,
+                });
+                round_trip(UfiCommand::Inquiry {
+                    alloc_len: len as u8,
+                });
+                round_trip(UfiCommand::RequestSense {
+                    alloc_len: len as u8,
+                });
+                round_trip(UfiCommand::PreventAllowMediumRemoval { prevent: flag });
+                round_trip(UfiCommand::ModeSense {
+                    page_control: i as u8,
+                    page_code: 0x3F >> i,
+                    param_list_len: len,
+                });
+                round_trip(UfiCommand::ModeSelect {
+                    parameter_list_len: len,
+                });
+                round_trip(UfiCommand::ReadFormatCapacities { alloc_len: len });
+                round_trip(UfiCommand::SendDiagnostic { default: flag });
+                round_trip(UfiCommand::Verify { lba: U32S[i], len });
+                round_trip(UfiCommand::Write {
+                    lba: U32S[i + 1],
+                    len: len as u32,
+                    verify: true,
+                });
+            }
+            for (start, eject) in [(false, false), (true, false), (false, true)] {
+                round_trip(UfiCommand::StartStop { start, eject });
+            }
+            for lba in U32S {
+                round_trip(UfiCommand::Seek { lba });
+                for len in U32S {
+                    round_trip(UfiCommand::Read { lba, len });
+                    round_trip(UfiCommand::Write {
+                        lba,
+                        len,
+                        verify: false,
+                    });
+                }
+            }
+        }
+    }
+}
+
 /// UFI subclass
 pub struct Ufi<T: Transport> {
     interface: InterfaceNumber,
     pub(crate) transport: T,
+    /// Whether the transport is driven from [UsbClass::poll]
+    auto_drive: bool,
+    /// See [Ufi::set_write_protected]
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    write_protected: Option<bool>,
+    /// Whether the last command has been failed for the medium being write protected
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    write_protect_sense: bool,
+}
+
+impl<T: Transport> Ufi<T> {
+    /// Returns the underlying transport
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the underlying transport.
+    ///
+    /// Driving the transport directly while a command is in progress, e.g. setting its status,
+    /// bypasses the subclass and may confuse it.
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Drives the transport from [UsbClass::poll], so that [UsbDevice::poll] alone moves the data
+    /// of both directions. Commands are then served with [handle_command] once [has_command]
+    /// tells one is waiting. Disabled by default
+    ///
+    /// [UsbClass::poll]: usb_device::class::UsbClass::poll
+    /// [UsbDevice::poll]: usb_device::device::UsbDevice::poll
+    /// [handle_command]: Ufi::handle_command
+    /// [has_command]: Ufi::has_command
+    pub fn set_auto_drive(&mut self, enabled: bool) {
+        self.auto_drive = enabled;
+    }
+
+    /// Sets whether the medium is write protected. Once set, MODE SENSE is answered by
+    /// the subclass with the WP bit of the header and the SWPP bit of the Timer and Protect page
+    /// following the flag. WRITE, WRITE AND VERIFY and FORMAT UNIT of a write protected medium
+    /// are failed with DATA PROTECT, WRITE PROTECTED sense reported by the next REQUEST SENSE.
+    /// None of these commands reach the callback then
+    pub fn set_write_protected(&mut self, write_protected: bool) {
+        self.write_protected = Some(write_protected);
+    }
+
+    /// Returns `true` if the medium is write protected. See [set_write_protected]
+    ///
+    /// [set_write_protected]: Ufi::set_write_protected
+    pub fn is_write_protected(&self) -> bool {
+        self.write_protected.unwrap_or(false)
+    }
 }
 
 /// UFI subclass implementation with [Bulk Only Transport]
@@ -207,23 +673,20 @@ pub struct Ufi<T: Transport> {
 /// [Bulk Only Transport]: crate::transport::bbb::BulkOnly
 #[cfg(feature = "bbb")]
 impl<'alloc, Bus: UsbBus + 'alloc, Buf: BorrowMut<[u8]>> Ufi<BulkOnly<'alloc, Bus, Buf>> {
-    /// Creates a UFI over Bulk Only Transport instance
+    /// Creates a UFI over Bulk Only Transport instance of a single Logical Unit
     ///
     /// # Arguments
     /// * `alloc` - [UsbBusAllocator]
     /// * `packet_size` - Maximum USB packet size. Allowed values: 8,16,32,64
-    /// * `max_lun` - The max index of the Logical Unit
     /// * `buf` - The underlying IO buffer. It is **required** to fit at least a `CBW` and/or a single
     ///   packet. It is **recommended** that buffer fits at least one sector
     ///
     /// # Errors
-    /// * [InvalidMaxLun]
     /// * [BufferTooSmall]
     ///
     /// # Panics
     /// Panics if endpoint allocations fails.
     ///
-    /// [InvalidMaxLun]: crate::transport::bbb::BulkOnlyError::InvalidMaxLun
     /// [BufferTooSmall]: crate::transport::bbb::BulkOnlyError::BufferTooSmall
     /// [UsbBusAllocator]: usb_device::bus::UsbBusAllocator
     pub fn new(
@@ -231,9 +694,51 @@ impl<'alloc, Bus: UsbBus + 'alloc, Buf: BorrowMut<[u8]>> Ufi<BulkOnly<'alloc, Bu
         packet_size: u16,
         buf: Buf,
     ) -> Result<Self, BulkOnlyError> {
-        BulkOnly::new(alloc, packet_size, 0, buf).map(|transport| Self {
-            interface: alloc.interface(),
-            transport,
+        Self::new_with_interval(alloc, packet_size, 0, buf, 0)
+    }
+
+    /// Creates an instance like [new] of several Logical Units, e.g. of a multi-drive floppy
+    /// emulator. `max_lun` is the max index of the Logical Unit, e.g. `1` for a dual drive.
+    /// Commands carry the LUN they are addressed to
+    ///
+    /// # Errors
+    /// * [InvalidMaxLun]
+    /// * [BufferTooSmall]
+    ///
+    /// [new]: Ufi::new
+    /// [InvalidMaxLun]: crate::transport::bbb::BulkOnlyError::InvalidMaxLun
+    /// [BufferTooSmall]: crate::transport::bbb::BulkOnlyError::BufferTooSmall
+    pub fn new_with_max_lun(
+        alloc: &'alloc UsbBusAllocator<Bus>,
+        packet_size: u16,
+        max_lun: u8,
+        buf: Buf,
+    ) -> Result<Self, BulkOnlyError> {
+        Self::new_with_interval(alloc, packet_size, max_lun, buf, 0)
+    }
+
+    /// Creates an instance like [new_with_max_lun], with `interval` as `bInterval` of the bulk
+    /// endpoints. See [BulkOnly::new_with_interval]
+    ///
+    /// # Errors
+    /// See [new_with_max_lun]
+    ///
+    /// [new_with_max_lun]: Ufi::new_with_max_lun
+    pub fn new_with_interval(
+        alloc: &'alloc UsbBusAllocator<Bus>,
+        packet_size: u16,
+        max_lun: u8,
+        buf: Buf,
+        interval: u8,
+    ) -> Result<Self, BulkOnlyError> {
+        BulkOnly::new_with_interval(alloc, packet_size, max_lun, buf, interval).map(|transport| {
+            Self {
+                interface: alloc.interface(),
+                transport,
+                auto_drive: false,
+                write_protected: None,
+                write_protect_sense: false,
+            }
         })
     }
 
@@ -242,24 +747,56 @@ impl<'alloc, Bus: UsbBus + 'alloc, Buf: BorrowMut<[u8]>> Ufi<BulkOnly<'alloc, Bu
     /// The passed closure may or may not be called after each time this function is called.
     /// Moreover, it may be called multiple times, if subclass is unable to proceed further.
     ///
+    /// Same as [drive_transport] followed by [handle_command].
+    ///
     /// # Arguments
     /// * `callback` - closure, in which the SCSI command is processed
-    pub fn poll<F>(&mut self, mut callback: F) -> Result<(), UsbError>
+    ///
+    /// [drive_transport]: Ufi::drive_transport
+    /// [handle_command]: Ufi::handle_command
+    pub fn poll<F>(&mut self, callback: F) -> Result<(), UsbError>
     where
         F: FnMut(Command<UfiCommand, Ufi<BulkOnly<'alloc, Bus, Buf>>>),
     {
-        fn map_ignore<T>(res: Result<T, TransportError<BulkOnlyError>>) -> Result<(), UsbError> {
-            match res {
-                Ok(_)
-                | Err(TransportError::Usb(UsbError::WouldBlock))
-                | Err(TransportError::Error(_)) => Ok(()),
-                Err(TransportError::Usb(err)) => Err(err),
-            }
-        }
-        // drive transport in both directions before user action
+        self.drive_transport()?;
+        self.handle_command(callback)
+    }
+
+    /// Drive the transport in both directions without processing a command.
+    /// Returns `true` if a command waits to be processed with [handle_command]
+    ///
+    /// Allows to split the work between execution contexts, e.g. the USB interrupt calls this
+    /// along with [UsbDevice::poll] while a lower priority task handles commands. Both only
+    /// need a short lock of the subclass.
+    ///
+    /// [handle_command]: Ufi::handle_command
+    /// [UsbDevice::poll]: usb_device::device::UsbDevice::poll
+    pub fn drive_transport(&mut self) -> Result<bool, UsbError> {
         map_ignore(self.transport.read())?;
         map_ignore(self.transport.write())?;
+        Ok(self.has_command())
+    }
 
+    /// Returns `true` if a command waits to be processed with [handle_command]
+    ///
+    /// [handle_command]: Ufi::handle_command
+    pub fn has_command(&self) -> bool {
+        self.transport.get_command().is_some() && !self.transport.has_status()
+    }
+
+    /// Process the pending command, if any, and drive the transport afterward
+    ///
+    /// The passed closure may be called multiple times, if subclass is unable to proceed further.
+    /// See [drive_transport]
+    ///
+    /// # Arguments
+    /// * `callback` - closure, in which the UFI command is processed
+    ///
+    /// [drive_transport]: Ufi::drive_transport
+    pub fn handle_command<F>(&mut self, mut callback: F) -> Result<(), UsbError>
+    where
+        F: FnMut(Command<UfiCommand, Ufi<BulkOnly<'alloc, Bus, Buf>>>),
+    {
         if let Some(raw_cb) = self.transport.get_command() {
             // exec callback only if user action required
             if !self.transport.has_status() {
@@ -268,6 +805,14 @@ impl<'alloc, Bus: UsbBus + 'alloc, Buf: BorrowMut<[u8]>> Ufi<BulkOnly<'alloc, Bu
 
                 debug!("usb: scsi: Command: {}", kind);
 
+                // checked once, a change of the write protection applies to the next commands
+                if !self.transport.is_dispatched() && self.handle_builtin(kind) {
+                    map_ignore(self.transport.write())?;
+                    map_ignore(self.transport.read())?;
+                    return Ok(());
+                }
+                self.transport.set_dispatched();
+
                 loop {
                     let command = Command {
                         class: self,
@@ -298,6 +843,80 @@ impl<'alloc, Bus: UsbBus + 'alloc, Buf: BorrowMut<[u8]>> Ufi<BulkOnly<'alloc, Bu
 
         Ok(())
     }
+
+    /// Handles the commands following the write protection flag, if set. Returns `true` if
+    /// the command has been handled
+    fn handle_builtin(&mut self, kind: UfiCommand) -> bool {
+        let Some(write_protected) = self.write_protected else {
+            return false;
+        };
+        // INQUIRY doesn't clear the sense data. Spec. UFI 4.2
+        let sense = if matches!(kind, UfiCommand::Inquiry { .. }) {
+            self.write_protect_sense
+        } else {
+            core::mem::take(&mut self.write_protect_sense)
+        };
+        let status = match kind {
+            UfiCommand::RequestSense { alloc_len } if sense => {
+                let len = min(alloc_len as usize, WRITE_PROTECTED_SENSE.len());
+                let _ = self.transport.write_data(&WRITE_PROTECTED_SENSE[..len]);
+                CommandStatus::Passed
+            }
+            UfiCommand::ModeSense {
+                page_code,
+                param_list_len,
+                ..
+            } => {
+                let mut data = [0u8; MODE_SENSE_DATA_MAX_LEN];
+                let len = write_mode_sense(&mut data, write_protected, page_code);
+                let len = min(param_list_len as usize, len);
+                let _ = self.transport.write_data(&data[..len]);
+                CommandStatus::Passed
+            }
+            UfiCommand::Write { .. } | UfiCommand::FormatUnit { .. } if write_protected => {
+                self.write_protect_sense = true;
+                CommandStatus::Failed
+            }
+            _ => return false,
+        };
+        self.transport.set_status(status);
+        true
+    }
+
+    /// Sets host-specific workarounds of the transport. See [Quirks]
+    ///
+    /// [Quirks]: crate::quirks::Quirks
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.transport.set_quirks(quirks);
+    }
+
+    /// Returns the last reset received since the previous call, if any.
+    ///
+    /// Both USB bus resets and Bulk-Only Mass Storage Resets are reported. Any per-command state
+    /// kept by the user, e.g. an offset into the current transfer, is expected to be dropped.
+    pub fn take_reset(&mut self) -> Option<Reset> {
+        self.transport.take_reset()
+    }
+
+    /// Returns the command dropped by the last reset or abandoned by the host in the middle of
+    /// the IN data transfer before its status has been set, if any. Returns `None` on
+    /// subsequent calls
+    pub fn take_aborted(&mut self) -> Option<Aborted<UfiCommand>> {
+        let transferred = self.transport.aborted_data_transferred().unwrap_or(0);
+        let aborted = self.transport.aborted_command().map(|raw_cb| {
+            let kind = parse_cb(raw_cb.bytes);
+            Aborted {
+                kind,
+                lun: raw_cb.lun,
+                tag: raw_cb.tag,
+                opcode: raw_cb.bytes[0],
+                lba: blocks_lba(kind),
+                transferred,
+            }
+        });
+        self.transport.clear_aborted_command();
+        aborted
+    }
 }
 
 impl<Bus, T> UsbClass<Bus> for Ufi<T>
@@ -324,11 +943,132 @@ where
         Ok(())
     }
 
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> usb_device::Result<()> {
+        self.transport.get_bos_descriptors(writer)
+    }
+
     fn reset(&mut self) {
         self.transport.reset()
     }
 
     fn control_in(&mut self, xfer: ControlIn<Bus>) {
-        self.transport.control_in(xfer)
+        if !addressed_elsewhere(xfer.request(), self.interface) {
+            self.transport.control_in(xfer)
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<Bus>) {
+        if !addressed_elsewhere(xfer.request(), self.interface) {
+            self.transport.control_out(xfer)
+        }
+    }
+
+    fn poll(&mut self) {
+        if self.auto_drive {
+            self.transport.poll();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subclass::ufi::{
+        lba_to_head, lba_to_sector, lba_to_track, write_format_capacities, write_mode_sense,
+        FloppyGeometry, FormatCapacities, MediumStatus, FORMAT_CAPACITIES_DATA_MAX_LEN,
+        MODE_SENSE_DATA_MAX_LEN,
+    };
+
+    /// 1.44 MB floppy: 80 tracks, 2 heads, 18 sectors per track
+    const SEC_TRK: u8 = 18;
+    const HEAD_TRK: u8 = 2;
+
+    fn chs(lba: u32) -> (u32, u32, u32) {
+        (
+            lba_to_track(lba, SEC_TRK, HEAD_TRK).unwrap(),
+            lba_to_head(lba, SEC_TRK, HEAD_TRK).unwrap(),
+            lba_to_sector(lba, SEC_TRK).unwrap(),
+        )
+    }
+
+    #[test]
+    fn should_convert_lba_to_chs() {
+        assert_eq!((0, 0, 1), chs(0));
+        assert_eq!((0, 0, 18), chs(17));
+        assert_eq!((0, 1, 1), chs(18));
+        assert_eq!((0, 1, 18), chs(35));
+        assert_eq!((1, 0, 1), chs(36));
+        assert_eq!((79, 1, 18), chs(2879)); // the last block
+    }
+
+    #[test]
+    fn should_convert_lba_to_chs_at_boundaries() {
+        assert_eq!(Some(1), lba_to_sector(u32::MAX, 1));
+        assert_eq!(Some(1), lba_to_sector(u32::MAX, 255)); // 2^32 - 1 is a multiple of 255
+        assert_eq!(Some(u32::MAX % 254 + 1), lba_to_sector(u32::MAX, 254));
+        assert_eq!(Some(0), lba_to_head(u32::MAX, 1, 1));
+        assert_eq!(Some(u32::MAX), lba_to_track(u32::MAX, 1, 1));
+        assert_eq!(Some(u32::MAX / 255 / 255), lba_to_track(u32::MAX, 255, 255));
+        assert_eq!(Some(u32::MAX / 255 % 255), lba_to_head(u32::MAX, 255, 255));
+    }
+
+    #[test]
+    fn should_reject_zero_geometry() {
+        assert_eq!(None, lba_to_sector(0, 0));
+        assert_eq!(None, lba_to_head(0, 0, HEAD_TRK));
+        assert_eq!(None, lba_to_head(0, SEC_TRK, 0));
+        assert_eq!(None, lba_to_track(0, 0, HEAD_TRK));
+        assert_eq!(None, lba_to_track(u32::MAX, SEC_TRK, 0));
+    }
+
+    #[test]
+    fn should_write_format_capacities() {
+        assert_eq!(
+            (1440, 2880, 5760),
+            (
+                FloppyGeometry::F720K.num_blocks(),
+                FloppyGeometry::F1440K.num_blocks(),
+                FloppyGeometry::F2880K.num_blocks()
+            )
+        );
+
+        let mut buf = [0xFFu8; FORMAT_CAPACITIES_DATA_MAX_LEN];
+        let capacities = FormatCapacities::new(FloppyGeometry::F1440K, MediumStatus::Formatted)
+            .formattable(FloppyGeometry::F1440K)
+            .formattable(FloppyGeometry::F720K);
+        assert_eq!(28, write_format_capacities(&mut buf, &capacities));
+        assert_eq!(
+            [
+                0x00, 0x00, 0x00, 0x18, // header
+                0x00, 0x00, 0x0B, 0x40, 0x02, 0x00, 0x02, 0x00, // current: formatted
+                0x00, 0x00, 0x0B, 0x40, 0x00, 0x00, 0x02, 0x00, // formattable
+                0x00, 0x00, 0x05, 0xA0, 0x00, 0x00, 0x02, 0x00,
+            ],
+            buf[..28]
+        );
+        assert_eq!(0xFF, buf[28]);
+
+        let custom = FloppyGeometry::new(77, 2, 8, 1024); // 1.25 MB of PC-98
+        let capacities = FormatCapacities::new(custom, MediumStatus::NoMedium);
+        assert_eq!(12, write_format_capacities(&mut buf, &capacities));
+        assert_eq!([0x00, 0x00, 0x04, 0xD0, 0x03, 0x00, 0x04, 0x00], buf[4..12]);
+    }
+
+    #[test]
+    fn should_write_mode_sense() {
+        let mut buf = [0xFFu8; MODE_SENSE_DATA_MAX_LEN + 1];
+        assert_eq!(16, write_mode_sense(&mut buf, true, 0x3F));
+        assert_eq!(
+            [
+                0x00, 0x0E, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, // header: WP
+                0x1C, 0x06, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // Timer and Protect: SWPP
+                0xFF,
+            ],
+            buf
+        );
+        assert_eq!(16, write_mode_sense(&mut buf, false, 0x1C));
+        assert_eq!([0x00, 0x0E, 0x00, 0x00], buf[..4]);
+        assert_eq!(0x00, buf[12]);
+        assert_eq!(8, write_mode_sense(&mut buf, true, 0x01));
+        assert_eq!([0x00, 0x06, 0x00, 0x80], buf[..4]);
     }
 }
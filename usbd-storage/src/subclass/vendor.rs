@@ -0,0 +1,149 @@
+//! Vendor-specific subclass
+
+use crate::subclass::addressed_elsewhere;
+use crate::transport::vendor::SimpleVendorTransport;
+use crate::transport::{Transport, TransportError};
+use crate::CLASS_MASS_STORAGE;
+use core::borrow::BorrowMut;
+use usb_device::bus::{InterfaceNumber, UsbBus, UsbBusAllocator};
+use usb_device::class::{ControlIn, ControlOut, UsbClass};
+use usb_device::descriptor::{BosWriter, DescriptorWriter};
+use usb_device::UsbError;
+
+/// Vendor-specific subclass code
+pub const SUBCLASS_VENDOR_SPECIFIC: u8 = 0xFF;
+
+/// Vendor-specific subclass
+///
+/// Registers a Mass Storage interface, leaving the meaning of the data to the transport and
+/// the host tool. Works with any [Transport], e.g. [SimpleVendorTransport]
+pub struct Vendor<T: Transport> {
+    interface: InterfaceNumber,
+    transport: T,
+    /// Whether the transport is driven from [UsbClass::poll]
+    auto_drive: bool,
+}
+
+impl<T: Transport> Vendor<T> {
+    /// Creates a vendor-specific subclass over `transport`
+    pub fn new(alloc: &UsbBusAllocator<T::Bus>, transport: T) -> Self {
+        Self {
+            interface: alloc.interface(),
+            transport,
+            auto_drive: false,
+        }
+    }
+
+    /// Returns the underlying transport
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the underlying transport
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Drives the transport from [UsbClass::poll], so that [UsbDevice::poll] alone moves the data
+    /// of both directions. Disabled by default
+    ///
+    /// [UsbClass::poll]: usb_device::class::UsbClass::poll
+    /// [UsbDevice::poll]: usb_device::device::UsbDevice::poll
+    pub fn set_auto_drive(&mut self, enabled: bool) {
+        self.auto_drive = enabled;
+    }
+}
+
+/// Vendor-specific subclass implementation with [Simple Vendor Transport]
+///
+/// [Simple Vendor Transport]: crate::transport::vendor::SimpleVendorTransport
+impl<'alloc, Bus: UsbBus + 'alloc, Buf: BorrowMut<[u8]>>
+    Vendor<SimpleVendorTransport<'alloc, Bus, Buf>>
+{
+    /// Drive subclass in both directions
+    ///
+    /// The passed closure is called once a request waits for a response, and is expected to
+    /// [respond] to it. Otherwise, it's called again on the next poll.
+    ///
+    /// # Arguments
+    /// * `callback` - closure, in which the request is processed
+    ///
+    /// [respond]: SimpleVendorTransport::respond
+    pub fn poll<F>(&mut self, callback: F) -> Result<(), UsbError>
+    where
+        F: FnOnce(&mut SimpleVendorTransport<'alloc, Bus, Buf>),
+    {
+        map_ignore(self.transport.read())?;
+        if self.transport.request().is_some() {
+            callback(&mut self.transport);
+        }
+        map_ignore(self.transport.write())
+    }
+}
+
+impl<Bus, T> UsbClass<Bus> for Vendor<T>
+where
+    Bus: UsbBus,
+    T: Transport<Bus = Bus>,
+{
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.iad(
+            self.interface,
+            1,
+            CLASS_MASS_STORAGE,
+            SUBCLASS_VENDOR_SPECIFIC,
+            T::PROTO,
+            None,
+        )?;
+        writer.interface(
+            self.interface,
+            CLASS_MASS_STORAGE,
+            SUBCLASS_VENDOR_SPECIFIC,
+            T::PROTO,
+        )?;
+
+        self.transport.get_endpoint_descriptors(writer)?;
+
+        Ok(())
+    }
+
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> usb_device::Result<()> {
+        self.transport.get_bos_descriptors(writer)
+    }
+
+    fn reset(&mut self) {
+        self.transport.reset()
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<Bus>) {
+        if !addressed_elsewhere(xfer.request(), self.interface) {
+            self.transport.control_in(xfer)
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<Bus>) {
+        if !addressed_elsewhere(xfer.request(), self.interface) {
+            self.transport.control_out(xfer)
+        }
+    }
+
+    fn poll(&mut self) {
+        if self.auto_drive {
+            self.transport.poll();
+        }
+    }
+}
+
+/// Maps a result of driving a transport to the result of a subclass' poll.
+/// `WouldBlock` and the errors of the transport itself are not reported
+fn map_ignore<E: core::fmt::Debug>(res: Result<(), TransportError<E>>) -> Result<(), UsbError> {
+    match res {
+        Ok(_) | Err(TransportError::Usb(UsbError::WouldBlock)) | Err(TransportError::Error(_)) => {
+            Ok(())
+        }
+        Err(TransportError::Usb(err)) => Err(err),
+    }
+}
@@ -2,15 +2,19 @@
 
 use crate::buffer::Buffer;
 use crate::fmt::{info, trace};
-use crate::transport::{CommandStatus, Transport, TransportError};
+use crate::quirks::{GetMaxLun, Quirks, Unstall};
+#[cfg(feature = "metrics")]
+use crate::transport::metrics::{Metrics, MetricsRecorder};
+use crate::transport::{CommandStatus, Reset, Transport, TransportError};
 use core::borrow::BorrowMut;
 use core::cmp::min;
+use core::fmt::{Display, Formatter};
 use usb_device::bus::{UsbBus, UsbBusAllocator};
-use usb_device::class::ControlIn;
+use usb_device::class::{ControlIn, ControlOut};
 use usb_device::class_prelude::DescriptorWriter;
-use usb_device::control::{Recipient, RequestType};
-use usb_device::endpoint::{Endpoint, In, Out};
-use usb_device::UsbError;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::endpoint::{Endpoint, EndpointAddress, EndpointDirection, EndpointType, In, Out};
+use usb_device::{UsbDirection, UsbError};
 
 /// Bulk Only Transport interface protocol
 pub(crate) const TRANSPORT_BBB: u8 = 0x50;
@@ -21,7 +25,7 @@ const CLASS_SPECIFIC_GET_MAX_LUN: u8 = 0xFE;
 const CBW_SIGNATURE_LE: [u8; 4] = 0x43425355u32.to_le_bytes();
 const CSW_SIGNATURE_LE: [u8; 4] = 0x53425355u32.to_le_bytes();
 
-const CBW_LEN: usize = 31;
+pub(crate) const CBW_LEN: usize = 31;
 const CSW_LEN: usize = 13;
 
 struct InvalidCbwError; // Inner transport-specific error
@@ -38,16 +42,215 @@ pub enum BulkOnlyError {
     InvalidState,
     /// Data Transfer expects a full packet to be sent next but not enough data available
     FullPacketExpected,
-    /// The IO buffer cannot fit a CBW or a single full packet
+    /// The IO buffer cannot fit a CBW, a single full packet or a response generated by
+    /// the subclass
     BufferTooSmall,
 }
 
+impl Display for BulkOnlyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            BulkOnlyError::IoBufferOverflow => "IO buffer overflow",
+            BulkOnlyError::InvalidMaxLun => "invalid max LUN",
+            BulkOnlyError::InvalidState => "not in Data Transfer state",
+            BulkOnlyError::FullPacketExpected => "full packet expected",
+            BulkOnlyError::BufferTooSmall => "IO buffer too small",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BulkOnlyError {}
+
+/// Outcome of [write_data_hinted] sizing the next write of a chunked producer
+///
+/// [write_data_hinted]: crate::transport::bbb::BulkOnly::write_data_hinted
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WriteHint {
+    /// Number of bytes actually written
+    pub written: usize,
+    /// Number of bytes the IO buffer accepts before the transport is driven again.
+    /// Never more than `expected`
+    pub available: usize,
+    /// Number of bytes the host still expects, not counting the ones waiting in the IO buffer.
+    /// `0` once the host has got all the data it asked for
+    pub expected: usize,
+}
+
+/// Cursor over the free space of the IO buffer during the IN data transfer. See [data_in_chunks]
+///
+/// [data_in_chunks]: crate::transport::bbb::BulkOnly::data_in_chunks
+pub struct DataInChunks<'t, 'alloc, Bus: UsbBus, Buf: BorrowMut<[u8]>> {
+    transport: &'t mut BulkOnly<'alloc, Bus, Buf>,
+    max_len: usize,
+}
+
+impl<'alloc, Bus: UsbBus, Buf: BorrowMut<[u8]>> DataInChunks<'_, 'alloc, Bus, Buf> {
+    /// Returns the next chunk of the IO buffer to fill, up to `max_len` bytes long. `None` once
+    /// the buffer is full or the host expects no more data. The next chunk follows the part of
+    /// the previous one filled
+    pub fn next_chunk(&mut self) -> Option<DataInChunk<'_, 'alloc, Bus, Buf>> {
+        let transport = &mut *self.transport;
+        let expected = (transport.ctx.cbw.data_transfer_len as usize)
+            .saturating_sub(transport.buf.available_read());
+        let len = min(min(self.max_len, transport.buf.free_space()), expected);
+        (len > 0).then_some(DataInChunk { transport, len })
+    }
+}
+
+/// Free space of the IO buffer to fill with IN data. The bytes are queued as they are filled
+pub struct DataInChunk<'t, 'alloc, Bus: UsbBus, Buf: BorrowMut<[u8]>> {
+    transport: &'t mut BulkOnly<'alloc, Bus, Buf>,
+    len: usize,
+}
+
+impl<Bus: UsbBus, Buf: BorrowMut<[u8]>> DataInChunk<'_, '_, Bus, Buf> {
+    /// Number of bytes the chunk still takes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Fills the chunk with as much of `src` as it takes returning the number of bytes filled
+    pub fn fill_from(&mut self, src: &[u8]) -> usize {
+        self.fill_with(|dst| {
+            let count = min(dst.len(), src.len());
+            dst[..count].copy_from_slice(&src[..count]);
+            count
+        })
+    }
+
+    /// Hands the rest of the chunk to `f`, e.g. to read the medium right into the IO buffer,
+    /// which returns the number of bytes it has filled from the start. Returns that number
+    pub fn fill_with(&mut self, f: impl FnOnce(&mut [u8]) -> usize) -> usize {
+        let len = self.len;
+        let count = self
+            .transport
+            .buf
+            .write_all(len, (), |dst| Ok(min(f(dst), len)))
+            .unwrap_or(0);
+        self.len -= count;
+        count
+    }
+}
+
+/// Phase of the command in progress. See [phase]
+///
+/// [phase]: crate::transport::bbb::BulkOnly::phase
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommandPhase {
+    /// The command has been received and transfers no data
+    CommandReceived,
+    /// `received` bytes of `expected` by `dCBWDataTransferLength` have been received from the
+    /// host, whether read from the IO buffer or not
+    DataOut { received: u32, expected: u32 },
+    /// `sent` bytes of `expected` by `dCBWDataTransferLength` have been written, whether sent to
+    /// the host or still waiting in the IO buffer
+    DataIn { sent: u32, expected: u32 },
+}
+
+/// Counters of the Bulk Only Transport events since creation or [reset_stats], saturating
+/// at `u32::MAX`. A "USB health" figure for the application
+///
+/// [reset_stats]: crate::transport::bbb::BulkOnly::reset_stats
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct BulkOnlyStats {
+    /// Number of times the IN endpoint has been stalled
+    pub in_stalls: u32,
+    /// Number of times the OUT endpoint has been stalled
+    pub out_stalls: u32,
+    /// Number of times the OUT endpoint was busy while receiving a CBW
+    pub command_busy: u32,
+    /// Number of times an endpoint was busy during Data Transfer
+    pub data_busy: u32,
+    /// Number of times the IN endpoint was busy while sending a CSW
+    pub status_busy: u32,
+    /// Number of USB bus resets
+    pub bus_resets: u32,
+    /// Number of Bulk-Only Mass Storage Resets
+    pub class_resets: u32,
+    /// Number of Reset Recovery cycles completed by the host
+    pub reset_recoveries: u32,
+}
+
+/// A bulk packet mirrored to the callback set with [set_packet_tap]
+///
+/// [set_packet_tap]: crate::transport::bbb::BulkOnly::set_packet_tap
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TappedPacket<'a> {
+    /// [UsbDirection::In] for the packets sent to the host
+    pub direction: UsbDirection,
+    pub ep: EndpointAddress,
+    pub bytes: &'a [u8],
+    /// Number of packets tapped before this one. Wraps around. Orders the packets of a capture
+    /// in the absence of a clock
+    pub seq: u32,
+}
+
+/// An event the callback set with [set_notify] is notified of
+///
+/// [set_notify]: crate::transport::bbb::BulkOnly::set_notify
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Notification {
+    /// A CBW has been received. The command waits to be processed
+    CommandPending,
+    /// A packet of OUT data has been read into the IO buffer
+    DataReceived,
+}
+
+/// A deliberate violation of the next CSW. See [inject_csw_fault]
+///
+/// [inject_csw_fault]: crate::transport::bbb::BulkOnly::inject_csw_fault
+#[cfg(feature = "csw-faults")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CswFault {
+    /// `dCSWTag` doesn't match `dCBWTag` of the command
+    WrongTag,
+    /// `dCSWSignature` is not `USBS`
+    WrongSignature,
+    /// `dCSWDataResidue` is greater than `dCBWDataTransferLength` of the command
+    BadResidue,
+}
+
+/// Mirrors the bulk packets to a callback
+#[derive(Default)]
+struct PacketTap {
+    callback: Option<fn(TappedPacket<'_>)>,
+    seq: u32,
+}
+
+impl PacketTap {
+    fn mirror(&mut self, direction: UsbDirection, ep: EndpointAddress, bytes: &[u8]) {
+        if let Some(callback) = self.callback {
+            callback(TappedPacket {
+                direction,
+                ep,
+                bytes,
+                seq: self.seq,
+            });
+            self.seq = self.seq.wrapping_add(1);
+        }
+    }
+}
+
 /// Raw Command Block bytes
 ///
 /// The `bytes` field is a truncated slice
 pub struct CommandBlock<'a> {
     pub bytes: &'a [u8],
     pub lun: u8,
+    /// `dCBWTag` of the CBW the block came with
+    pub tag: u32,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -61,6 +264,20 @@ enum State {
     StatusTransfer,       // writing CSW packets
 }
 
+/// Progress of Reset Recovery. Spec. section 5.3.4
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum Recovery {
+    /// No recovery in progress
+    None,
+    /// An invalid CBW has been received. Both endpoints stay stalled until
+    /// Bulk-Only Mass Storage Reset
+    AwaitingReset,
+    /// Bulk-Only Mass Storage Reset has been received. The flags are set for the endpoints
+    /// whose halt hasn't been cleared yet
+    AwaitingClearHalt { in_ep: bool, out_ep: bool },
+}
+
 #[repr(u8)]
 #[derive(Default, Debug, Copy, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -90,9 +307,41 @@ pub struct BulkOnly<'alloc, Bus: UsbBus, Buf: BorrowMut<[u8]>> {
     out_ep: Endpoint<'alloc, Bus, Out>,
     buf: Buffer<Buf>,
     state: State,
-    cbw: CommandBlockWrapper,
-    cs: Option<CommandStatus>,
+    /// The command in progress. Dropped on going Idle
+    ctx: CommandContext,
     max_lun: u8,
+    reset: Option<Reset>,
+    /// The command dropped by the last reset or abandoned by the host before its status has been
+    /// set, along with the number of bytes of its data transfer done by then
+    aborted: Option<(CommandBlockWrapper, u32)>,
+    recovery: Recovery,
+    /// Number of times a packet transfer is retried if the endpoint is busy
+    io_retries: u8,
+    /// See [set_on_data_progress]
+    ///
+    /// [set_on_data_progress]: crate::transport::bbb::BulkOnly::set_on_data_progress
+    on_data_progress: Option<fn(u8, u32, u32)>,
+    /// See [set_packet_tap]
+    ///
+    /// [set_packet_tap]: crate::transport::bbb::BulkOnly::set_packet_tap
+    tap: PacketTap,
+    /// See [set_notify]
+    ///
+    /// [set_notify]: crate::transport::bbb::BulkOnly::set_notify
+    notify: Option<fn(Notification)>,
+    /// See [detach]
+    ///
+    /// [detach]: crate::transport::bbb::BulkOnly::detach
+    detached: bool,
+    stats: BulkOnlyStats,
+    #[cfg(feature = "metrics")]
+    metrics: MetricsRecorder,
+    quirks: Quirks,
+    /// See [inject_csw_fault]
+    ///
+    /// [inject_csw_fault]: crate::transport::bbb::BulkOnly::inject_csw_fault
+    #[cfg(feature = "csw-faults")]
+    csw_fault: Option<CswFault>,
 }
 
 impl<'alloc, Bus, Buf> BulkOnly<'alloc, Bus, Buf>
@@ -105,9 +354,10 @@ where
     /// # Arguments
     /// * `alloc` - [UsbBusAllocator]
     /// * `packet_size` - Maximum USB packet size. Allowed values: 8,16,32,64
-    /// * `max_lun` - The max index of the Logical Unit
-    /// * `buf` - The underlying IO buffer. It is **required** to fit at least a `CBW` and/or a single
-    ///   packet. It is **recommended** that buffer fits at least one `LBA` size
+    /// * `max_lun` - The max index of the Logical Unit. A CBW addressed to a greater one is not
+    ///   meaningful and handled as an invalid one
+    /// * `buf` - The underlying IO buffer. It is **required** to fit at least a `CBW` (31 bytes)
+    ///   and a single packet. It is **recommended** that buffer fits at least one `LBA` size
     ///
     /// # Errors
     /// * [InvalidMaxLun]
@@ -124,6 +374,29 @@ where
         packet_size: u16,
         max_lun: u8,
         buf: Buf,
+    ) -> Result<BulkOnly<'alloc, Bus, Buf>, BulkOnlyError> {
+        Self::new_with_interval(alloc, packet_size, max_lun, buf, 0)
+    }
+
+    /// Creates Bulk Only Transport instance like [new], with `interval` as `bInterval` of
+    /// the bulk endpoints
+    ///
+    /// Full speed hosts ignore it. For a high speed OUT endpoint it's the maximum NAK rate.
+    /// A few embedded hosts misbehave with the default of zero. The value is passed to
+    /// [UsbBus::alloc_ep], which may or may not take it into account, and is written to
+    /// the endpoint descriptors as is
+    ///
+    /// # Errors
+    /// See [new]
+    ///
+    /// [new]: BulkOnly::new
+    /// [UsbBus::alloc_ep]: usb_device::bus::UsbBus::alloc_ep
+    pub fn new_with_interval(
+        alloc: &'alloc UsbBusAllocator<Bus>,
+        packet_size: u16,
+        max_lun: u8,
+        buf: Buf,
+        interval: u8,
     ) -> Result<BulkOnly<'alloc, Bus, Buf>, BulkOnlyError> {
         if max_lun > 0x0F {
             return Err(BulkOnlyError::InvalidMaxLun);
@@ -135,18 +408,38 @@ where
         }
 
         Ok(BulkOnly {
-            in_ep: alloc.bulk(packet_size),
-            out_ep: alloc.bulk(packet_size),
+            in_ep: alloc_bulk(alloc, packet_size, interval),
+            out_ep: alloc_bulk(alloc, packet_size, interval),
             buf: Buffer::new(buf),
             state: State::Idle,
-            cbw: Default::default(),
-            cs: Default::default(),
+            ctx: Default::default(),
             max_lun,
+            reset: None,
+            aborted: None,
+            recovery: Recovery::None,
+            io_retries: 0,
+            on_data_progress: None,
+            tap: Default::default(),
+            notify: None,
+            detached: false,
+            stats: Default::default(),
+            #[cfg(feature = "metrics")]
+            metrics: Default::default(),
+            quirks: Default::default(),
+            #[cfg(feature = "csw-faults")]
+            csw_fault: None,
         })
     }
 
     /// Drives a transport by reading a single packet
+    ///
+    /// No CBW is accepted while Reset Recovery is in progress. See [in_reset_recovery]
+    ///
+    /// [in_reset_recovery]: crate::transport::bbb::BulkOnly::in_reset_recovery
     pub fn read(&mut self) -> BulkOnlyTransportResult<()> {
+        if self.detached || self.recovery != Recovery::None {
+            return Ok(());
+        }
         match self.state {
             State::Idle | State::CommandTransfer => self.handle_read_cbw(),
             State::DataTransferFromHost => self.handle_read_from_host(),
@@ -156,6 +449,9 @@ where
 
     /// Drives a transport by writing a single packet
     pub fn write(&mut self) -> BulkOnlyTransportResult<()> {
+        if self.detached {
+            return Ok(());
+        }
         match self.state {
             State::StatusTransfer => self.handle_write_csw(),
             State::DataTransferToHost => self.handle_write_to_host(),
@@ -179,7 +475,40 @@ where
             State::DataTransferToHost | State::DataTransferFromHost | State::DataTransferNoData
         ));
         info!("usb: bbb: Set status: {}", status);
-        self.cs = Some(status);
+        self.ctx.cs = Some(status);
+    }
+
+    /// Sets a `status` of the current command like [set_status]. If the command has no data
+    /// transfer, the `CSW` is written right away instead of on the next [write], cutting
+    /// the latency of frequent commands like TEST UNIT READY
+    ///
+    /// The same goes for an IN command ending before any data has been written, e.g. failed
+    /// right away. The IN endpoint is stalled and the whole `dCBWDataTransferLength` is reported
+    /// as residue (Spec. 6.7.2, cases 4 and 5)
+    ///
+    /// # Errors
+    /// Errors of writing the `CSW`. If the endpoint is busy, the `CSW` stays in the IO buffer and
+    /// is sent by the next [write]
+    ///
+    /// # Panics
+    /// See [set_status]
+    ///
+    /// [set_status]: BulkOnly::set_status
+    /// [write]: BulkOnly::write
+    pub fn send_status(&mut self, status: CommandStatus) -> BulkOnlyTransportResult<()> {
+        self.set_status(status);
+        if matches!(self.state, State::DataTransferNoData) || self.no_data_written() {
+            self.end_data_transfer()?;
+            // the CSW doesn't fit a single packet of 8 bytes
+            while matches!(self.state, State::StatusTransfer) {
+                let available = self.buf.available_read();
+                self.write()?;
+                if self.buf.available_read() == available {
+                    break;
+                }
+            }
+        }
+        Ok(())
     }
 
     /// Returns a Command Block if present
@@ -187,14 +516,18 @@ where
         match self.state {
             State::Idle | State::CommandTransfer => None,
             _ => Some(CommandBlock {
-                bytes: &self.cbw.block[..self.cbw.block_len],
-                lun: self.cbw.lun,
+                bytes: &self.ctx.cbw.block[..self.ctx.cbw.block_len],
+                lun: self.ctx.cbw.lun,
+                tag: self.ctx.cbw.tag,
             }),
         }
     }
 
     /// Reads data from the IO buffer returning the number of bytes actually read
     ///
+    /// The packet boundaries are not preserved: short packets in the middle of the transfer
+    /// are read back to back, and a zero length packet carries nothing
+    ///
     /// # Arguments
     /// * `dst` - buffer, to read bytes into
     ///
@@ -204,18 +537,82 @@ where
     ///
     /// [BulkOnlyError::InvalidState]: crate::transport::bbb::BulkOnlyError::InvalidState
     pub fn read_data(&mut self, dst: &mut [u8]) -> BulkOnlyTransportResult<usize> {
+        self.read_data_with(|buf| {
+            // fill 'dst' or however much is in 'buf'
+            let size = min(dst.len(), buf.len());
+            dst[..size].copy_from_slice(&buf[..size]);
+            size
+        })
+    }
+
+    /// Hands the data available in the IO buffer to `f`, which returns the number of bytes
+    /// it has consumed
+    pub(crate) fn read_data_with(
+        &mut self,
+        f: impl FnOnce(&[u8]) -> usize,
+    ) -> BulkOnlyTransportResult<usize> {
         if !matches!(self.state, State::DataTransferFromHost) {
             return Err(TransportError::Error(BulkOnlyError::InvalidState));
         }
-        Ok(self
-            .buf
-            .read(|buf| {
-                // fill 'dst' or however much is in 'buf'
-                let size = min(dst.len(), buf.len());
-                dst[..size].copy_from_slice(&buf[..size]);
-                Ok::<usize, ()>(size)
-            })
-            .unwrap())
+        let count = self.buf.read(|buf| Ok::<usize, ()>(f(buf))).unwrap();
+        self.ctx.data_consumed += count as u32;
+        Ok(count)
+    }
+
+    /// Number of bytes of the current OUT data transfer already read from the IO buffer
+    pub fn data_consumed(&self) -> u32 {
+        self.ctx.data_consumed
+    }
+
+    /// Number of bytes of the current data transfer the host still expects to transfer over the
+    /// bus, i.e. what is left of `dCBWDataTransferLength`. Reported with the CSW
+    pub fn data_residue(&self) -> u32 {
+        self.ctx.cbw.data_transfer_len
+    }
+
+    /// `dCBWDataTransferLength` of the current command as declared by the host, zero if no data
+    /// is expected
+    pub fn data_transfer_len(&self) -> u32 {
+        self.ctx.data_transferred + self.ctx.cbw.data_transfer_len
+    }
+
+    /// The longest OUT data transfer the IO buffer takes in whole without any of it being read,
+    /// however the host splits it into packets
+    pub fn max_buffered_data_out_len(&self) -> usize {
+        self.buf.capacity() - self.packet_size()
+    }
+
+    /// Returns the phase of the command in progress, `None` if there is none or its status has
+    /// been set already
+    pub fn phase(&self) -> Option<CommandPhase> {
+        if self.status_present() {
+            return None;
+        }
+        let expected = self.data_transfer_len();
+        match self.state {
+            State::DataTransferNoData => Some(CommandPhase::CommandReceived),
+            State::DataTransferFromHost => Some(CommandPhase::DataOut {
+                received: self.ctx.data_transferred,
+                expected,
+            }),
+            State::DataTransferToHost => Some(CommandPhase::DataIn {
+                sent: self.ctx.data_transferred + self.buf.available_read() as u32,
+                expected,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Direction of the data transfer declared by the CBW of the current command, `None` if
+    /// the host expects no data or there is no command. The direction bit of a CBW declaring no
+    /// data is ignored. Spec. 5.1
+    pub fn data_direction(&self) -> Option<UsbDirection> {
+        match (self.state, self.ctx.cbw.direction) {
+            (State::Idle | State::CommandTransfer, _) | (_, DataDirection::NotExpected) => None,
+            _ if self.data_transfer_len() == 0 => None,
+            (_, DataDirection::In) => Some(UsbDirection::In),
+            (_, DataDirection::Out) => Some(UsbDirection::Out),
+        }
     }
 
     /// Writes data from the IO buffer returning the number of bytes actually written
@@ -235,12 +632,35 @@ where
         if !self.status_present() {
             Ok(self
                 .buf
-                .write(&src[..min(src.len(), self.cbw.data_transfer_len as usize)]))
+                .write(&src[..min(src.len(), self.ctx.cbw.data_transfer_len as usize)]))
         } else {
             Err(TransportError::Error(BulkOnlyError::InvalidState))
         }
     }
 
+    /// Writes data into the IO buffer like [write_data], but also returns how much more data
+    /// could be written before the next poll and how much the host still expects.
+    ///
+    /// Allows producers reading from slow media to size the next chunk precisely. An empty `src`
+    /// only queries the hint.
+    ///
+    /// # Errors
+    /// Returns [BulkOnlyError::InvalidState] if called
+    /// during any but IN Data Transfer state.
+    ///
+    /// [write_data]: crate::transport::bbb::BulkOnly::write_data
+    /// [BulkOnlyError::InvalidState]: crate::transport::bbb::BulkOnlyError::InvalidState
+    pub fn write_data_hinted(&mut self, src: &[u8]) -> BulkOnlyTransportResult<WriteHint> {
+        let written = self.write_data(src)?;
+        let expected =
+            (self.ctx.cbw.data_transfer_len as usize).saturating_sub(self.buf.available_read());
+        Ok(WriteHint {
+            written,
+            available: min(self.buf.free_space(), expected),
+            expected,
+        })
+    }
+
     /// Tries to write all data from `src` into the IO buffer returning the number of bytes actually written
     ///
     /// # Errors
@@ -269,27 +689,244 @@ where
         }
     }
 
+    /// Returns a cursor over the free space of the IO buffer, so that the IN data is written
+    /// in place chunk by chunk instead of being copied from another buffer. Each chunk is up to
+    /// `max_len` bytes long, e.g. a block, and no longer than the host still expects
+    ///
+    /// # Errors
+    /// Returns [BulkOnlyError::InvalidState] if called during any but IN Data Transfer state
+    /// or once the status has been set
+    ///
+    /// [BulkOnlyError::InvalidState]: crate::transport::bbb::BulkOnlyError::InvalidState
+    pub fn data_in_chunks(
+        &mut self,
+        max_len: usize,
+    ) -> BulkOnlyTransportResult<DataInChunks<'_, 'alloc, Bus, Buf>> {
+        if !matches!(self.state, State::DataTransferToHost) || self.status_present() {
+            return Err(TransportError::Error(BulkOnlyError::InvalidState));
+        }
+        Ok(DataInChunks {
+            transport: self,
+            max_len,
+        })
+    }
+
+    /// Tells that the data of the current IN transfer isn't ready yet, e.g. slow media is still
+    /// being read. The next [write] leaves a partial packet in the IO buffer as is instead of
+    /// returning [BulkOnlyError::FullPacketExpected], so the host is NAKed until more data is
+    /// written. Has no effect during any but IN Data Transfer state
+    ///
+    /// [write]: crate::transport::bbb::BulkOnly::write
+    /// [BulkOnlyError::FullPacketExpected]: crate::transport::bbb::BulkOnlyError::FullPacketExpected
+    pub fn set_data_pending(&mut self) {
+        if matches!(self.state, State::DataTransferToHost) {
+            self.ctx.data_pending = true;
+        }
+    }
+
+    /// Marks the current command as checked by the subclass and handed to the user, so that
+    /// the checks aren't run again on the next polls
+    #[cfg_attr(not(any(feature = "scsi", feature = "ufi")), allow(dead_code))]
+    pub(crate) fn set_dispatched(&mut self) {
+        if self.get_command().is_some() {
+            self.ctx.dispatched = true;
+        }
+    }
+
+    /// Whether the current command has been handed to the user. See [set_dispatched]
+    ///
+    /// [set_dispatched]: crate::transport::bbb::BulkOnly::set_dispatched
+    #[cfg_attr(not(any(feature = "scsi", feature = "ufi")), allow(dead_code))]
+    pub(crate) fn is_dispatched(&self) -> bool {
+        self.ctx.dispatched
+    }
+
     /// Whether a Command Status has been set
     pub fn has_status(&self) -> bool {
         self.status_present()
     }
 
+    /// Sets host-specific workarounds. See [Quirks]
+    ///
+    /// [Quirks]: crate::quirks::Quirks
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Makes the next CSW violate the spec as told by `fault`, so that the recovery of a host
+    /// driver can be exercised against a device under control. The fault applies to a single
+    /// CSW, the next command gets a valid one again. Injecting a fault before the previous one
+    /// has been applied replaces it
+    #[cfg(feature = "csw-faults")]
+    pub fn inject_csw_fault(&mut self, fault: CswFault) {
+        self.csw_fault = Some(fault);
+    }
+
+    /// Sets how many times a single [read] or [write] retries a packet transfer if the endpoint
+    /// is busy before returning [UsbError::WouldBlock]. `0` by default.
+    ///
+    /// Helps with USB peripherals whose FIFOs are briefly unavailable right after the previous
+    /// transfer, e.g. OTG-FS, at the cost of a longer `poll`
+    ///
+    /// [read]: crate::transport::bbb::BulkOnly::read
+    /// [write]: crate::transport::bbb::BulkOnly::write
+    /// [UsbError::WouldBlock]: usb_device::UsbError::WouldBlock
+    pub fn set_io_retries(&mut self, retries: u8) {
+        self.io_retries = retries;
+    }
+
+    /// Sets a callback notified of the progress of data transfers, e.g. to drive a progress
+    /// indicator during long host copies. Called with the LUN, the number of bytes transferred
+    /// so far and `dCBWDataTransferLength` each time a packet of data is sent or received.
+    /// Called from [read] and [write], so expected to return quickly
+    ///
+    /// [read]: crate::transport::bbb::BulkOnly::read
+    /// [write]: crate::transport::bbb::BulkOnly::write
+    pub fn set_on_data_progress(&mut self, callback: Option<fn(u8, u32, u32)>) {
+        self.on_data_progress = callback;
+    }
+
+    /// Sets a callback mirroring every bulk packet sent or received, including CBWs, CSWs and
+    /// zero length packets, e.g. to capture the traffic into external RAM or over RTT for an
+    /// offline analysis, say, in Wireshark's USB dissector. Called from [read] and [write], so
+    /// expected to return quickly
+    ///
+    /// [read]: crate::transport::bbb::BulkOnly::read
+    /// [write]: crate::transport::bbb::BulkOnly::write
+    pub fn set_packet_tap(&mut self, callback: Option<fn(TappedPacket<'_>)>) {
+        self.tap.callback = callback;
+    }
+
+    /// Sets a callback notified once a command becomes pending or OUT data arrives, e.g. to
+    /// wake the task processing commands instead of polling it. Called from [read], which runs
+    /// in the USB interrupt in many applications, so expected to return quickly, e.g. signal
+    /// an executor or set a flag
+    ///
+    /// [read]: crate::transport::bbb::BulkOnly::read
+    pub fn set_notify(&mut self, callback: Option<fn(Notification)>) {
+        self.notify = callback;
+    }
+
+    /// Returns the counters of stalls, busy endpoints and resets. See [BulkOnlyStats]
+    pub fn stats(&self) -> BulkOnlyStats {
+        self.stats
+    }
+
+    /// Zeroes the counters returned by [stats]
+    ///
+    /// [stats]: crate::transport::bbb::BulkOnly::stats
+    pub fn reset_stats(&mut self) {
+        self.stats = Default::default();
+    }
+
+    /// Returns the histograms of the transfer lengths and of the gaps between commands.
+    /// See [Metrics]
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics.metrics
+    }
+
+    /// Drops the values recorded by [metrics]
+    ///
+    /// [metrics]: crate::transport::bbb::BulkOnly::metrics
+    #[cfg(feature = "metrics")]
+    pub fn reset_metrics(&mut self) {
+        self.metrics.metrics = Default::default();
+    }
+
+    /// Returns the last reset received since the previous call, if any
+    pub fn take_reset(&mut self) -> Option<Reset> {
+        self.reset.take()
+    }
+
+    /// Whether the transport waits for the host to complete Reset Recovery: Bulk-Only Mass
+    /// Storage Reset followed by Clear Feature HALT to both bulk endpoints. Entered once an
+    /// invalid CBW has been received or a class reset has been requested. A bus reset
+    /// completes it implicitly
+    pub fn in_reset_recovery(&self) -> bool {
+        self.recovery != Recovery::None
+    }
+
+    /// Stops moving data over the bulk endpoints, so that the peripheral NAKs whatever the host
+    /// sends or asks for, until [attach]. The command in progress, if any, is kept as is and
+    /// resumed on [attach], unless dropped by a reset in the meantime. Class requests are
+    /// still served
+    ///
+    /// [attach]: crate::transport::bbb::BulkOnly::attach
+    pub fn detach(&mut self) {
+        self.detached = true;
+    }
+
+    /// Resumes moving data over the bulk endpoints. See [detach]
+    ///
+    /// [detach]: crate::transport::bbb::BulkOnly::detach
+    pub fn attach(&mut self) {
+        self.detached = false;
+    }
+
+    /// Whether the transport has been detached with [detach]
+    ///
+    /// [detach]: crate::transport::bbb::BulkOnly::detach
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    /// Returns a Command Block dropped before its status has been set, either by the last reset
+    /// or by the host abandoning the IN data transfer with Clear Feature HALT to the endpoint
+    /// that hasn't been stalled. In the latter case the remaining data is dropped and
+    /// the `CSW` reports Phase Error. See [clear_aborted_command]
+    ///
+    /// [clear_aborted_command]: crate::transport::bbb::BulkOnly::clear_aborted_command
+    pub fn aborted_command(&self) -> Option<CommandBlock<'_>> {
+        self.aborted.as_ref().map(|(cbw, _)| CommandBlock {
+            bytes: &cbw.block[..cbw.block_len],
+            lun: cbw.lun,
+            tag: cbw.tag,
+        })
+    }
+
+    /// Returns the number of bytes of the data transfer the aborted command had sent or
+    /// received over the bus by the time it was dropped. See [aborted_command]
+    ///
+    /// [aborted_command]: crate::transport::bbb::BulkOnly::aborted_command
+    pub fn aborted_data_transferred(&self) -> Option<u32> {
+        self.aborted.map(|(_, transferred)| transferred)
+    }
+
+    /// Clears the aborted Command Block once it has been handled
+    pub fn clear_aborted_command(&mut self) {
+        self.aborted = None;
+    }
+
     fn handle_read_cbw(&mut self) -> BulkOnlyTransportResult<()> {
-        self.read_packet()?; // propagate if error or WouldBlock
+        let count = self.read_packet()?;
+        if count == 0 {
+            return Ok(()); // a zero length packet carries no part of a CBW
+        }
 
         if self.buf.available_read() >= CBW_LEN {
             // try parse CBW if enough data available
-            match self.try_parse_cbw() {
+            if self.buf.available_read() > CBW_LEN {
+                // some hosts pad a CBW to a full packet
+                info!(
+                    "usb: bbb: Discard CBW padding: {}",
+                    self.buf.available_read() - CBW_LEN
+                );
+            }
+            let cbw = self.try_parse_cbw();
+            self.buf.clean(); // drop padding if any
+            match cbw {
                 Ok(cbw) => {
                     info!("usb: bbb: Recv CBW: {}", cbw);
                     self.start_data_transfer(cbw);
                 }
-                Err(_) => {
-                    // Spec. 6.6.1
-                    self.stall_eps();
-                    self.reset();
-                }
+                Err(_) => self.reject_cbw(),
             }
+        } else if count < self.packet_size() {
+            // a short packet ends the transfer. Spec. 6.2.1. A CBW shorter than 31 bytes isn't
+            // valid, and its bytes must not merge with the next one
+            info!("usb: bbb: Recv short CBW: {}", self.buf.available_read());
+            self.reject_cbw();
         } else {
             // we've read something but it's not enough yet
             self.enter_state(State::CommandTransfer)
@@ -298,10 +935,21 @@ where
     }
 
     fn handle_read_from_host(&mut self) -> BulkOnlyTransportResult<()> {
-        if !self.status_present() {
+        if !self.status_present() && !self.ctx.phase_error {
             let count = self.read_packet()?; // propagate if error or WouldBlock
-            self.cbw.data_transfer_len = self.cbw.data_transfer_len.saturating_sub(count as u32);
-            trace!("usb: bbb: Data residue: {}", self.cbw.data_transfer_len);
+            let residue = self.ctx.cbw.data_transfer_len as usize;
+            if count > residue {
+                // the host sends more data than declared. drop the surplus and stop accepting
+                // data. the command is reported with Phase Error regardless of its status
+                info!("usb: bbb: Drop surplus data: {}", count - residue);
+                self.buf.discard_last(count - residue);
+                self.ctx.phase_error = true;
+                self.stall_out_ep();
+            }
+            self.advance_data(count);
+            if min(count, residue) > 0 {
+                self.notify(Notification::DataReceived);
+            }
         }
         self.check_end_data_transfer()
     }
@@ -313,12 +961,18 @@ where
         // return an error
 
         let max_packet_size = self.packet_size() as u32;
+        let data_pending = core::mem::take(&mut self.ctx.data_pending);
+
+        // the padding completes the last packet of the data passed
+        if self.fill_expected() {
+            self.fill_data();
+        }
 
         // if enough data is expected by data transfer or if there is no status.
         // therefore, a full packet is not expected if data transfer is interrupted
         // by failing a command
         let full_packet_expected =
-            self.cbw.data_transfer_len >= max_packet_size && !self.status_present();
+            self.ctx.cbw.data_transfer_len >= max_packet_size && !self.status_present();
 
         let full_packet = self.buf.available_read() >= max_packet_size as usize;
         let full_packet_or_zero = full_packet || !full_packet_expected;
@@ -327,16 +981,32 @@ where
             // attempt to send data from buffer if any
             if self.buf.available_read() > 0 {
                 let count = self.write_packet()?; // propagate if error
-                self.cbw.data_transfer_len =
-                    self.cbw.data_transfer_len.saturating_sub(count as u32);
-                trace!("usb: bbb: Data residue: {}", self.cbw.data_transfer_len);
+                self.ctx.short_packet_sent = count < max_packet_size as usize;
+                self.advance_data(count);
             }
             self.check_end_data_transfer()
+        } else if data_pending {
+            Ok(()) // wait for the rest of the packet
         } else {
             Err(TransportError::Error(BulkOnlyError::FullPacketExpected))
         }
     }
 
+    /// Counts `count` bytes of the data transfer as done, reporting the progress to
+    /// the callback set with [set_on_data_progress]
+    ///
+    /// [set_on_data_progress]: crate::transport::bbb::BulkOnly::set_on_data_progress
+    fn advance_data(&mut self, count: usize) {
+        let count = min(count as u32, self.ctx.cbw.data_transfer_len);
+        self.ctx.cbw.data_transfer_len -= count;
+        self.ctx.data_transferred += count;
+        trace!("usb: bbb: Data residue: {}", self.ctx.cbw.data_transfer_len);
+        if let Some(on_data_progress) = self.on_data_progress.filter(|_| count > 0) {
+            let total = self.ctx.data_transferred + self.ctx.cbw.data_transfer_len;
+            on_data_progress(self.ctx.cbw.lun, self.ctx.data_transferred, total);
+        }
+    }
+
     fn handle_no_data_transfer(&mut self) -> BulkOnlyTransportResult<()> {
         self.check_end_data_transfer()
     }
@@ -344,24 +1014,40 @@ where
     fn handle_write_csw(&mut self) -> BulkOnlyTransportResult<()> {
         self.write_packet()?; // propagate if error
         if self.buf.available_read() == 0 {
-            self.enter_state(State::Idle) // done with status transfer
+            self.enter_state(State::Idle); // done with status transfer
+            #[cfg(feature = "metrics")]
+            self.metrics.status_sent();
         }
         Ok(())
     }
 
     fn check_end_data_transfer(&mut self) -> BulkOnlyTransportResult<()> {
         match self.state {
-            State::DataTransferNoData | State::DataTransferFromHost => {
-                // command is passed or failed. IO buffer is irrelevant. end data transfer
-                if self.cs.is_some() {
-                    self.end_data_transfer()?;
-                }
+            // command is passed short. pad the data expected by the host, if opted in
+            State::DataTransferToHost if self.fill_expected() => {
+                self.fill_data();
             }
-            State::DataTransferToHost => {
-                // command is passed or failed. empty IO buffer first. if empty, end data transfer
-                if self.cs.is_some() && self.buf.available_read() == 0 {
-                    self.end_data_transfer()?;
-                }
+            // command is passed or failed. IO buffer is irrelevant. end data transfer
+            State::DataTransferNoData | State::DataTransferFromHost if self.ctx.cs.is_some() => {
+                self.end_data_transfer()?;
+            }
+            // command is passed or failed. empty IO buffer first. if empty, end data transfer
+            State::DataTransferToHost
+                if self.ctx.cs.is_some() && self.buf.available_read() == 0 =>
+            {
+                self.end_data_transfer()?;
+            }
+            // the host expects exactly the data left in the IO buffer. stage CSW right after it,
+            // so that it is sent as soon as the data drains
+            State::DataTransferToHost
+                if self.ctx.cs.is_some()
+                    && self.buf.available_read() == self.ctx.cbw.data_transfer_len as usize
+                    && self.buf.free_space() >= CSW_LEN =>
+            {
+                self.ctx.staged_data = self.buf.available_read();
+                self.push_csw();
+                self.enter_state(State::StatusTransfer);
+                self.write()?; // flush
             }
             _ => {}
         }
@@ -371,10 +1057,17 @@ where
 
     fn end_data_transfer(&mut self) -> BulkOnlyTransportResult<()> {
         // spec. 6.7.2 and 6.7.3
-        if self.cbw.data_transfer_len > 0 {
+        if self.ctx.cbw.data_transfer_len > 0 {
             match self.state {
+                State::DataTransferToHost
+                    if self.quirks.zlp_on_short_in && !self.ctx.short_packet_sent =>
+                {
+                    self.in_ep.write(&[]).map_err(TransportError::Usb)?; // retry if busy
+                    self.tap.mirror(UsbDirection::In, self.in_ep.address(), &[]);
+                    self.ctx.short_packet_sent = true;
+                }
+                State::DataTransferToHost if self.quirks.zlp_on_short_in => {}
                 State::DataTransferToHost => {
-                    //TODO: send zlp right here
                     self.stall_in_ep();
                 }
                 State::DataTransferFromHost => {
@@ -385,28 +1078,94 @@ where
         }
 
         // write CSW into buffer
-        let csw = self.build_csw().unwrap();
         self.buf.clean();
-        self.buf.write(csw.as_slice());
+        self.push_csw();
 
         self.enter_state(State::StatusTransfer);
         self.write() // flush
     }
 
+    /// Whether the current IN data transfer has neither sent nor buffered any data, and there is
+    /// nothing to pad it with
+    fn no_data_written(&self) -> bool {
+        matches!(self.state, State::DataTransferToHost)
+            && self.ctx.data_transferred == 0
+            && self.buf.available_read() == 0
+            && !self.fill_expected()
+    }
+
+    /// Whether the data of the passed command is to be padded up to the length expected by
+    /// the host. See [Quirks::fill_short_in]
+    fn fill_expected(&self) -> bool {
+        self.quirks.fill_short_in.is_some()
+            && matches!(self.ctx.cs, Some(CommandStatus::Passed))
+            && !self.ctx.phase_error
+            && self.buf.available_read() < self.ctx.cbw.data_transfer_len as usize
+    }
+
+    /// Fills the IO buffer with [Quirks::fill_short_in] up to the length expected by the host
+    fn fill_data(&mut self) {
+        let fill = self.quirks.fill_short_in.unwrap_or_default();
+        let count = min(
+            self.buf.free_space(),
+            self.ctx.cbw.data_transfer_len as usize - self.buf.available_read(),
+        );
+        trace!("usb: bbb: Fill bytes: {}", count);
+        let _ = self.buf.write_all::<()>(count, (), |dst| {
+            dst.fill(fill);
+            Ok(count)
+        });
+    }
+
     #[inline]
     fn status_present(&self) -> bool {
-        self.cs.is_some()
+        self.ctx.cs.is_some()
     }
 
-    fn build_csw(&mut self) -> Option<[u8; CSW_LEN]> {
-        self.cs.map(|status| {
-            let mut csw = [0u8; CSW_LEN];
-            csw[..4].copy_from_slice(CSW_SIGNATURE_LE.as_slice());
-            csw[4..8].copy_from_slice(self.cbw.tag.to_le_bytes().as_slice());
-            csw[8..12].copy_from_slice(self.cbw.data_transfer_len.to_le_bytes().as_slice());
-            csw[12..].copy_from_slice(&[status as u8]);
-            csw
-        })
+    /// Writes CSW into the IO buffer after the data staged in it, if any.
+    /// The caller must ensure that the status is set and there is enough space
+    fn push_csw(&mut self) {
+        let status = match self.ctx.cs {
+            Some(_) if self.ctx.phase_error => CommandStatus::PhaseError,
+            Some(status) => status,
+            None => unreachable!(),
+        };
+        // the staged data is expected to reach the host
+        let residue = self.ctx.cbw.data_transfer_len - self.ctx.staged_data as u32;
+        let cbw = &self.ctx.cbw;
+        #[cfg(feature = "csw-faults")]
+        let fault = self.csw_fault.take();
+        self.buf
+            .write_all::<()>(CSW_LEN, (), |dst| {
+                let csw = &mut dst[..CSW_LEN];
+                csw[..4].copy_from_slice(CSW_SIGNATURE_LE.as_slice());
+                csw[4..8].copy_from_slice(cbw.tag.to_le_bytes().as_slice());
+                csw[8..12].copy_from_slice(residue.to_le_bytes().as_slice());
+                csw[12] = status as u8;
+                #[cfg(feature = "csw-faults")]
+                Self::apply_csw_fault(fault, cbw, csw);
+                Ok(CSW_LEN)
+            })
+            .unwrap();
+    }
+
+    /// Corrupts the CSW of `cbw` in place as told by the injected fault, if any
+    #[cfg(feature = "csw-faults")]
+    fn apply_csw_fault(fault: Option<CswFault>, cbw: &CommandBlockWrapper, csw: &mut [u8]) {
+        match fault {
+            Some(CswFault::WrongTag) => {
+                let tag = !cbw.tag;
+                csw[4..8].copy_from_slice(tag.to_le_bytes().as_slice());
+            }
+            Some(CswFault::WrongSignature) => {
+                csw[..4].copy_from_slice(CBW_SIGNATURE_LE.as_slice());
+            }
+            Some(CswFault::BadResidue) => {
+                let residue = cbw.data_transfer_len.saturating_add(1);
+                csw[8..12].copy_from_slice(residue.to_le_bytes().as_slice());
+            }
+            None => {}
+        }
     }
 
     /// The caller must ensure that there is enough data available
@@ -428,7 +1187,13 @@ where
             return Err(InvalidCbwError);
         }
 
-        CommandBlockWrapper::from_le_bytes(&raw_cbw[4..]) // parse CBW (skipping signature)
+        // parse CBW (skipping signature)
+        let cbw = CommandBlockWrapper::from_le_bytes(&raw_cbw[4..])?;
+        // not meaningful. Spec. 6.2.2
+        if cbw.lun > self.max_lun {
+            return Err(InvalidCbwError);
+        }
+        Ok(cbw)
     }
 
     fn start_data_transfer(&mut self, mut cbw: CommandBlockWrapper) {
@@ -447,7 +1212,17 @@ where
                 cbw.data_transfer_len = 0; // original value ignored
             }
         };
-        self.cbw = cbw;
+        #[cfg(feature = "metrics")]
+        self.metrics.command(cbw.data_transfer_len);
+        self.ctx.cbw = cbw;
+        self.notify(Notification::CommandPending);
+    }
+
+    #[inline]
+    fn notify(&self, notification: Notification) {
+        if let Some(notify) = self.notify {
+            notify(notification);
+        }
     }
 
     #[inline]
@@ -455,16 +1230,36 @@ where
         self.in_ep.max_packet_size() as usize // same for both In and Out EPs
     }
 
+    /// Read single packet into [buf] returning number of bytes actually read
+    ///
+    /// A zero length packet is read as `0` bytes. [UsbError::WouldBlock] is returned once
+    /// the endpoint stays busy for all the attempts, leaving the IO buffer untouched
     fn read_packet(&mut self) -> BulkOnlyTransportResult<usize> {
-        let count = self.buf.write_all(
-            self.packet_size(),
-            TransportError::Error(BulkOnlyError::IoBufferOverflow),
-            |buf| match self.out_ep.read(buf) {
-                Ok(count) => Ok(count),
-                Err(UsbError::WouldBlock) => Ok(0),
-                Err(err) => Err(TransportError::Usb(err)),
-            },
-        )?;
+        let packet_size = self.packet_size();
+        let mut attempts = self.io_retries as usize + 1;
+        let count = loop {
+            // the buffer only advances by what the endpoint has actually returned
+            let res = self.buf.write_all(
+                packet_size,
+                TransportError::Error(BulkOnlyError::IoBufferOverflow),
+                |buf| {
+                    let count = self.out_ep.read(buf).map_err(TransportError::Usb)?;
+                    self.tap
+                        .mirror(UsbDirection::Out, self.out_ep.address(), &buf[..count]);
+                    Ok(count)
+                },
+            );
+            match res {
+                Err(TransportError::Usb(UsbError::WouldBlock)) => {
+                    self.count_busy();
+                    if attempts == 1 {
+                        return Err(TransportError::Usb(UsbError::WouldBlock));
+                    }
+                    attempts -= 1;
+                }
+                res => break res?,
+            }
+        };
 
         trace!(
             "usb: bbb: Read bytes: {}, buf available: {}",
@@ -472,27 +1267,49 @@ where
             self.buf.available_read()
         );
 
-        if count == 0 {
-            Err(TransportError::Usb(UsbError::WouldBlock))
-        } else {
-            Ok(count)
-        }
+        Ok(count)
     }
 
     /// Write single packet from [buf] returning number of bytes actually written
+    ///
+    /// Returns `0` if there is nothing to write. [UsbError::WouldBlock] is returned once
+    /// the endpoint stays busy for all the attempts, leaving the IO buffer untouched
     fn write_packet(&mut self) -> BulkOnlyTransportResult<usize> {
-        let packet_size = self.packet_size();
-        let count = self.buf.read(|buf| {
-            if !buf.is_empty() {
-                match self.in_ep.write(&buf[..min(packet_size, buf.len())]) {
-                    Ok(count) => Ok(count),
-                    Err(UsbError::WouldBlock) => Ok(0),
-                    Err(err) => Err(TransportError::Usb(err)),
+        // a staged CSW starts a new packet
+        let packet_size = match self.ctx.staged_data {
+            0 => self.packet_size(),
+            staged => min(self.packet_size(), staged),
+        };
+        let mut attempts = self.io_retries as usize + 1;
+        let count = loop {
+            let res = self.buf.read(|buf| {
+                if buf.is_empty() {
+                    return Ok(0); // not enough data in buf, though it's not an error
                 }
-            } else {
-                Ok(0) // not enough data in buf, though it's not an error
+                let count = self
+                    .in_ep
+                    .write(&buf[..min(packet_size, buf.len())])
+                    .map_err(TransportError::Usb)?;
+                self.tap
+                    .mirror(UsbDirection::In, self.in_ep.address(), &buf[..count]);
+                Ok(count)
+            });
+            match res {
+                Err(TransportError::Usb(UsbError::WouldBlock)) => {
+                    self.count_busy();
+                    if attempts == 1 {
+                        return Err(TransportError::Usb(UsbError::WouldBlock));
+                    }
+                    attempts -= 1;
+                }
+                res => break res?,
             }
-        })?;
+        };
+
+        if self.ctx.staged_data > 0 {
+            self.ctx.staged_data -= count;
+            self.advance_data(count);
+        }
 
         trace!(
             "usb: bbb: Wrote bytes: {}, buf available: {}",
@@ -500,39 +1317,133 @@ where
             self.buf.available_read()
         );
 
-        if count == 0 {
-            Err(TransportError::Usb(UsbError::WouldBlock))
-        } else {
-            Ok(count)
+        Ok(count)
+    }
+
+    /// Counts a busy endpoint within the current phase
+    fn count_busy(&mut self) {
+        #[cfg(feature = "metrics")]
+        if matches!(self.state, State::Idle) {
+            self.metrics.idle_poll();
         }
+        let counter = match self.state {
+            State::Idle | State::CommandTransfer => &mut self.stats.command_busy,
+            State::StatusTransfer => &mut self.stats.status_busy,
+            _ => &mut self.stats.data_busy,
+        };
+        *counter = counter.saturating_add(1);
     }
 
     #[inline]
-    fn stall_eps(&self) {
+    fn stall_eps(&mut self) {
         self.stall_in_ep();
         self.stall_out_ep();
     }
 
     #[inline]
-    fn stall_in_ep(&self) {
+    fn unstall_eps(&self) {
+        self.in_ep.unstall();
+        self.out_ep.unstall();
+    }
+
+    #[inline]
+    fn stall_in_ep(&mut self) {
         info!("usb: bbb: Stall IN ep");
+        self.stats.in_stalls = self.stats.in_stalls.saturating_add(1);
         self.in_ep.stall();
     }
 
     #[inline]
-    fn stall_out_ep(&self) {
+    fn stall_out_ep(&mut self) {
         info!("usb: bbb: Stall OUT ep");
+        self.stats.out_stalls = self.stats.out_stalls.saturating_add(1);
         self.out_ep.stall();
     }
 
+    /// Handles Clear Feature HALT to an endpoint. Returns `true` if the request has to be
+    /// accepted keeping the endpoint stalled
+    fn handle_clear_halt(&mut self, index: u16) -> bool {
+        // usb-device masks the index the same way
+        let addr = (index as u8) & 0x8f;
+        let is_in = addr == u8::from(self.in_ep.address()) & 0x8f;
+        let is_out = addr == u8::from(self.out_ep.address()) & 0x8f;
+        if !is_in && !is_out {
+            return false;
+        }
+
+        match &mut self.recovery {
+            Recovery::None => {
+                // the IN endpoint is stalled only once the data transfer is over. the host
+                // clearing halt in the middle of it gives up on the rest of the data
+                if is_in && matches!(self.state, State::DataTransferToHost) {
+                    self.abandon_data_to_host();
+                }
+                false
+            }
+            Recovery::AwaitingReset => {
+                // Spec. 6.6.1. Clearing halt before Bulk-Only Mass Storage Reset
+                // doesn't unstall the endpoint
+                info!("usb: bbb: Keep ep stalled until reset: {}", addr);
+                true
+            }
+            Recovery::AwaitingClearHalt { in_ep, out_ep } => {
+                *in_ep &= !is_in;
+                *out_ep &= !is_out;
+                if !*in_ep && !*out_ep {
+                    info!("usb: bbb: Reset Recovery completed");
+                    self.stats.reset_recoveries = self.stats.reset_recoveries.saturating_add(1);
+                    self.recovery = Recovery::None;
+                }
+                false
+            }
+        }
+    }
+
+    /// The host has abandoned the IN data transfer. Drops the data left in the IO buffer and
+    /// moves on to the CSW reporting Phase Error. The command is kept as aborted if its status
+    /// hasn't been set yet
+    fn abandon_data_to_host(&mut self) {
+        info!("usb: bbb: IN data transfer abandoned: {}", self.ctx.cbw);
+        if !self.status_present() {
+            self.aborted = Some((self.ctx.cbw, self.ctx.data_transferred));
+            self.ctx.cs = Some(CommandStatus::PhaseError);
+        }
+        self.ctx.phase_error = true;
+        self.buf.clean();
+        self.push_csw();
+        self.enter_state(State::StatusTransfer);
+    }
+
+    /// Drops an invalid CBW. Spec. 6.6.1. The endpoints stay stalled until Reset Recovery
+    fn reject_cbw(&mut self) {
+        self.stall_eps();
+        self.recovery = Recovery::AwaitingReset;
+        self.enter_state(State::Idle);
+    }
+
+    /// Drops the current command and buffered data. The command is kept as aborted if
+    /// its status hasn't been set yet
+    fn abort(&mut self) {
+        if matches!(
+            self.state,
+            State::DataTransferToHost | State::DataTransferFromHost | State::DataTransferNoData
+        ) && !self.status_present()
+        {
+            info!("usb: bbb: Abort command: {}", self.ctx.cbw);
+            self.aborted = Some((self.ctx.cbw, self.ctx.data_transferred));
+        }
+        self.enter_state(State::Idle);
+        #[cfg(feature = "metrics")]
+        self.metrics.interrupt();
+    }
+
     #[inline]
     fn enter_state(&mut self, state: State) {
         info!("usb: bbb: Enter state: {}", state);
         // clean if going Idle
         if matches!(state, State::Idle) {
             self.buf.clean();
-            self.cbw = Default::default();
-            self.cs = None;
+            self.ctx = Default::default();
         }
         self.state = state;
     }
@@ -552,11 +1463,24 @@ where
         Ok(())
     }
 
+    /// Same as [read] followed by [write]. Errors are dropped, a failed transfer is retried on
+    /// the next poll
+    ///
+    /// [read]: crate::transport::bbb::BulkOnly::read
+    /// [write]: crate::transport::bbb::BulkOnly::write
+    fn poll(&mut self) {
+        let _ = self.read();
+        let _ = self.write();
+    }
+
     fn reset(&mut self) {
         info!("usb: bbb: Recv reset");
-        self.in_ep.unstall();
-        self.out_ep.unstall();
-        self.enter_state(State::Idle);
+        // a bus reset clears halt of all the endpoints, whatever the unstall policy
+        self.unstall_eps();
+        self.recovery = Recovery::None;
+        self.abort();
+        self.reset = Some(Reset::Bus);
+        self.stats.bus_resets = self.stats.bus_resets.saturating_add(1);
     }
 
     fn control_in(&mut self, xfer: ControlIn<Self::Bus>) {
@@ -569,20 +1493,114 @@ where
 
         info!("usb: bbb: Recv ctrl_in: {}", req);
 
-        match req.request {
-            // Spec. section 3.1
-            CLASS_SPECIFIC_BULK_ONLY_MASS_STORAGE_RESET => {}
-            // Spec. section 3.2
-            CLASS_SPECIFIC_GET_MAX_LUN => {
-                // always respond with LUN
-                xfer.accept_with(&[self.max_lun])
-                    .expect("Failed to accept Get Max Lun!");
+        // Spec. section 3.2. The response is truncated to `wLength` by usb-device, so that
+        // a request of no data stage is accepted with an empty one, and a longer one gets
+        // a short data stage
+        if req.request == CLASS_SPECIFIC_GET_MAX_LUN {
+            let max_lun = match self.quirks.get_max_lun {
+                _ if req.value != 0 => None,
+                GetMaxLun::Respond => Some(self.max_lun),
+                GetMaxLun::RespondZero => Some(0),
+                GetMaxLun::StallSingleLun if self.max_lun == 0 => None,
+                GetMaxLun::StallSingleLun => Some(self.max_lun),
+            };
+            let result = match max_lun {
+                Some(max_lun) => xfer.accept_with(&[max_lun]),
+                None => xfer.reject(),
+            };
+            if let Err(err) = result {
+                info!("usb: bbb: Failed to answer Get Max Lun: {}", err);
+            }
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<Self::Bus>) {
+        let req = xfer.request();
+
+        // observe the host clearing halt of the bulk endpoints during Reset Recovery.
+        // if not accepted, usb-device handles the request
+        if req.request_type == RequestType::Standard
+            && req.recipient == Recipient::Endpoint
+            && req.request == Request::CLEAR_FEATURE
+            && req.value == Request::FEATURE_ENDPOINT_HALT
+        {
+            if self.handle_clear_halt(req.index) {
+                if let Err(err) = xfer.accept() {
+                    info!("usb: bbb: Failed to accept Clear Feature HALT: {}", err);
+                }
+            }
+            return;
+        }
+
+        // not interested in this request
+        if !(req.request_type == RequestType::Class && req.recipient == Recipient::Interface) {
+            return;
+        }
+
+        info!("usb: bbb: Recv ctrl_out: {}", req);
+
+        // Spec. section 3.1
+        if req.request == CLASS_SPECIFIC_BULK_ONLY_MASS_STORAGE_RESET {
+            if req.value != 0 || req.length != 0 {
+                if let Err(err) = xfer.reject() {
+                    info!(
+                        "usb: bbb: Failed to reject Bulk-Only Mass Storage Reset: {}",
+                        err
+                    );
+                }
+                return;
+            }
+            // aborts the command in any state, including a data transfer in progress.
+            // endpoint STALL conditions are preserved unless told otherwise, a host clears
+            // them itself
+            self.abort();
+            self.recovery = match self.quirks.unstall {
+                Unstall::ClearFeature => Recovery::AwaitingClearHalt {
+                    in_ep: true,
+                    out_ep: true,
+                },
+                Unstall::Reset => {
+                    self.unstall_eps();
+                    Recovery::None
+                }
+            };
+            self.reset = Some(Reset::Class);
+            self.stats.class_resets = self.stats.class_resets.saturating_add(1);
+            if let Err(err) = xfer.accept() {
+                info!(
+                    "usb: bbb: Failed to accept Bulk-Only Mass Storage Reset: {}",
+                    err
+                );
             }
-            _ => {}
         }
     }
 }
 
+/// State of a single command from its CBW until its CSW is sent
+#[derive(Default, Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct CommandContext {
+    cbw: CommandBlockWrapper,
+    cs: Option<CommandStatus>,
+    /// Whether the last packet of the IN data transfer was short
+    short_packet_sent: bool,
+    /// Whether the host has sent more data than declared by the CBW
+    phase_error: bool,
+    /// Number of bytes of the OUT data transfer read from the IO buffer
+    data_consumed: u32,
+    /// Number of data bytes preceding the CSW staged in the IO buffer
+    staged_data: usize,
+    /// Whether the data of the IN transfer isn't ready yet. Reset by the next [write]
+    ///
+    /// [write]: crate::transport::bbb::BulkOnly::write
+    data_pending: bool,
+    /// Number of bytes of the data transfer sent or received
+    data_transferred: u32,
+    /// Whether the subclass has checked the command and handed it to the user
+    #[cfg_attr(not(any(feature = "scsi", feature = "ufi")), allow(dead_code))]
+    dispatched: bool,
+}
+
 #[derive(Default, Debug, Copy, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 struct CommandBlockWrapper {
@@ -624,6 +1642,20 @@ impl CommandBlockWrapper {
     }
 }
 
+/// Allocates a bulk endpoint with `interval` as its `bInterval`
+///
+/// # Panics
+/// Panics if the allocation fails, like [UsbBusAllocator::bulk]
+fn alloc_bulk<Bus: UsbBus, D: EndpointDirection>(
+    alloc: &UsbBusAllocator<Bus>,
+    packet_size: u16,
+    interval: u8,
+) -> Endpoint<'_, Bus, D> {
+    alloc
+        .alloc(None, EndpointType::Bulk, packet_size, interval)
+        .expect("alloc_ep failed")
+}
+
 #[cfg(test)]
 mod tests {
     use crate::transport::bbb::BulkOnly;
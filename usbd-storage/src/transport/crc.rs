@@ -0,0 +1,108 @@
+//! CRC-32 integrity check of data phases
+//!
+//! Meant for vendor-specific transports whose host tools append a CRC trailer to the data.
+//! [Crc32] is updated as packets land, so there is no need to buffer the whole transfer.
+//! The trailer is the CRC-32 (IEEE 802.3) of the data in little-endian byte order.
+
+/// Length of the CRC trailer
+pub const CRC_TRAILER_LEN: usize = 4;
+
+const POLY: u32 = 0xEDB88320; // reflected 0x04C11DB7
+
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Running CRC-32 of a data phase
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self { state: u32::MAX }
+    }
+
+    /// Adds `bytes` landed in a packet
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.state = (self.state >> 8) ^ TABLE[((self.state ^ byte as u32) & 0xFF) as usize];
+        }
+    }
+
+    /// Returns the CRC of the bytes added so far
+    pub const fn value(&self) -> u32 {
+        !self.state
+    }
+
+    /// Starts over for the next data phase
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Whether `trailer` matches the CRC of the bytes added so far
+    pub fn check_trailer(&self, trailer: [u8; CRC_TRAILER_LEN]) -> bool {
+        u32::from_le_bytes(trailer) == self.value()
+    }
+
+    /// Returns the trailer to append to the bytes added so far
+    pub const fn trailer(&self) -> [u8; CRC_TRAILER_LEN] {
+        self.value().to_le_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::crc::Crc32;
+
+    #[test]
+    fn should_compute_crc32() {
+        let mut crc = Crc32::new();
+        assert_eq!(0, crc.value());
+        crc.update(b"123456789");
+        assert_eq!(0xCBF43926, crc.value());
+        crc.reset();
+        assert_eq!(Crc32::new(), crc);
+    }
+
+    #[test]
+    fn should_check_trailer_of_data_landing_in_packets() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut whole = Crc32::new();
+        whole.update(&data);
+
+        let mut crc = Crc32::new();
+        for packet in data.chunks(64) {
+            crc.update(packet);
+        }
+        assert!(crc.check_trailer(whole.trailer()));
+        crc.update(&[0]);
+        assert!(!crc.check_trailer(whole.trailer()));
+    }
+}
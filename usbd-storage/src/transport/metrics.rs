@@ -0,0 +1,161 @@
+//! Histograms of the host workload
+//!
+//! Tell how the actual host issues commands, e.g. to size the IO buffer after the transfers it
+//! requests and the time it leaves the device between commands rather than after a guess.
+//! There is no clock, so the gaps between commands are measured in idle polls of the transport.
+
+use core::cmp::min;
+
+/// Number of buckets of a [Histogram]
+pub const HISTOGRAM_BUCKETS: usize = 24;
+
+/// Counts of values in power of two buckets, saturating at `u32::MAX`
+///
+/// Bucket `0` counts zeros, bucket `i` counts values of `2^(i-1)..2^i`. The last one counts
+/// all the values from `2^(HISTOGRAM_BUCKETS-2)` on
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Histogram {
+    buckets: [u32; HISTOGRAM_BUCKETS],
+}
+
+impl Histogram {
+    /// Returns the counts per bucket
+    pub fn buckets(&self) -> &[u32; HISTOGRAM_BUCKETS] {
+        &self.buckets
+    }
+
+    /// Returns the number of values recorded
+    pub fn count(&self) -> u32 {
+        self.buckets
+            .iter()
+            .fold(0u32, |sum, count| sum.saturating_add(*count))
+    }
+
+    /// Returns the greatest value counted by the bucket `i`
+    pub fn bucket_max(i: usize) -> u32 {
+        match i {
+            0 => 0,
+            _ if i >= HISTOGRAM_BUCKETS - 1 => u32::MAX,
+            _ => (1 << i) - 1,
+        }
+    }
+
+    /// Returns the greatest value of the bucket with `percent` of the values in it or below,
+    /// `None` if nothing has been recorded yet. E.g. a buffer of `percentile(95)` bytes fits
+    /// 95% of the transfers
+    pub fn percentile(&self, percent: u8) -> Option<u32> {
+        let count = self.count() as u64;
+        if count == 0 {
+            return None;
+        }
+        let threshold = (count * min(percent, 100) as u64).div_ceil(100).max(1);
+        let mut sum = 0u64;
+        self.buckets.iter().enumerate().find_map(|(i, bucket)| {
+            sum += *bucket as u64;
+            (sum >= threshold).then(|| Self::bucket_max(i))
+        })
+    }
+
+    #[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+    pub(crate) fn record(&mut self, value: u32) {
+        let i = min(
+            (u32::BITS - value.leading_zeros()) as usize,
+            HISTOGRAM_BUCKETS - 1,
+        );
+        self.buckets[i] = self.buckets[i].saturating_add(1);
+    }
+}
+
+/// The host workload seen by the transport since creation or the last reset of the metrics
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Metrics {
+    /// Data transfer length declared by the commands received, in bytes
+    pub transfer_len: Histogram,
+    /// Idle polls between the status of a command and the next command. Gaps broken by
+    /// a reset are not recorded
+    pub command_gap: Histogram,
+}
+
+/// Collects [Metrics] as the transport goes
+#[derive(Default)]
+pub(crate) struct MetricsRecorder {
+    pub(crate) metrics: Metrics,
+    /// Idle polls since the last status, `None` if no status has been sent since a reset
+    gap: Option<u32>,
+}
+
+#[cfg_attr(not(feature = "bbb"), allow(dead_code))]
+impl MetricsRecorder {
+    pub(crate) fn command(&mut self, transfer_len: u32) {
+        if let Some(gap) = self.gap.take() {
+            self.metrics.command_gap.record(gap);
+        }
+        self.metrics.transfer_len.record(transfer_len);
+    }
+
+    pub(crate) fn idle_poll(&mut self) {
+        if let Some(gap) = self.gap.as_mut() {
+            *gap = gap.saturating_add(1);
+        }
+    }
+
+    pub(crate) fn status_sent(&mut self) {
+        self.gap = Some(0);
+    }
+
+    pub(crate) fn interrupt(&mut self) {
+        self.gap = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::metrics::{Histogram, MetricsRecorder, HISTOGRAM_BUCKETS};
+
+    #[test]
+    fn should_bucket_values() {
+        let mut histogram = Histogram::default();
+        assert_eq!(None, histogram.percentile(50));
+        for value in [0, 1, 2, 3, 512, 4096, 4096, 4096, u32::MAX] {
+            histogram.record(value);
+        }
+        let buckets = histogram.buckets();
+        assert_eq!([1, 1, 2], buckets[..3]);
+        assert_eq!(1, buckets[10]);
+        assert_eq!(3, buckets[13]);
+        assert_eq!(1, buckets[HISTOGRAM_BUCKETS - 1]);
+        assert_eq!(9, histogram.count());
+
+        assert_eq!(
+            (511, 1023),
+            (Histogram::bucket_max(9), Histogram::bucket_max(10))
+        );
+        assert_eq!(Some(3), histogram.percentile(40));
+        assert_eq!(Some(8191), histogram.percentile(80));
+        assert_eq!(Some(u32::MAX), histogram.percentile(100));
+    }
+
+    #[test]
+    fn should_record_gaps_between_commands() {
+        let mut recorder = MetricsRecorder::default();
+        recorder.idle_poll();
+        recorder.command(512);
+        recorder.status_sent();
+        recorder.idle_poll();
+        recorder.idle_poll();
+        recorder.command(0);
+        recorder.status_sent();
+        recorder.idle_poll();
+        recorder.interrupt();
+        recorder.command(0);
+
+        let metrics = recorder.metrics;
+        assert_eq!(3, metrics.transfer_len.count());
+        assert_eq!(2, metrics.transfer_len.buckets()[0]);
+        assert_eq!(1, metrics.command_gap.count());
+        assert_eq!(1, metrics.command_gap.buckets()[2]);
+    }
+}
@@ -1,13 +1,19 @@
 //! USB Mass Storage transports
 
-use core::fmt::Debug;
+use core::fmt::{Debug, Display, Formatter};
 use usb_device::bus::UsbBus;
-use usb_device::class::ControlIn;
-use usb_device::descriptor::DescriptorWriter;
+use usb_device::class::{ControlIn, ControlOut};
+use usb_device::descriptor::{BosWriter, DescriptorWriter};
 use usb_device::UsbError;
 
 #[cfg(feature = "bbb")]
 pub mod bbb;
+pub mod crc;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod msos;
+#[cfg(feature = "vendor")]
+pub mod vendor;
 
 /// Interface protocol for specific transports
 pub const TRANSPORT_VENDOR_SPECIFIC: u8 = 0xFF;
@@ -26,11 +32,38 @@ pub trait Transport {
     /// Registers all required USB **endpoints** using a provided `writer`.
     fn get_endpoint_descriptors(&self, writer: &mut DescriptorWriter) -> Result<(), UsbError>;
 
+    /// Writes device capability descriptors of the BOS descriptor, e.g. [MS OS 2.0] platform
+    /// capability. Writes none by default.
+    ///
+    /// [MS OS 2.0]: crate::transport::msos
+    fn get_bos_descriptors(&self, _writer: &mut BosWriter) -> Result<(), UsbError> {
+        Ok(())
+    }
+
     /// Called after a USB reset after the bus reset sequence is complete.
     fn reset(&mut self);
 
     /// Called when a control request is received with direction DeviceToHost.
     fn control_in(&mut self, xfer: ControlIn<Self::Bus>);
+
+    /// Called when a control request is received with direction HostToDevice.
+    fn control_out(&mut self, _xfer: ControlOut<Self::Bus>) {}
+
+    /// Drives the transport in both directions. Called by the subclasses from [UsbClass::poll]
+    /// once enabled with their `set_auto_drive`. Does nothing by default.
+    ///
+    /// [UsbClass::poll]: usb_device::class::UsbClass::poll
+    fn poll(&mut self) {}
+}
+
+/// A reset received by a [Transport]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Reset {
+    /// USB bus reset
+    Bus,
+    /// Transport-specific class request, e.g. Bulk-Only Mass Storage Reset
+    Class,
 }
 
 /// Generic error type that could be used by [Transport] impls.
@@ -43,11 +76,23 @@ pub enum TransportError<E: Debug> {
     Error(E),
 }
 
+impl<E: Debug + Display> Display for TransportError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TransportError::Usb(err) => write!(f, "USB error: {:?}", err),
+            TransportError::Error(err) => write!(f, "transport error: {}", err),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: Debug + Display> std::error::Error for TransportError<E> {}
+
 /// The status of a Mass Storage command.
 ///
 /// Refer to the USB-MS doc.
 #[repr(u8)]
-#[derive(Default, Copy, Clone)]
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum CommandStatus {
     #[default]
@@ -0,0 +1,223 @@
+//! Microsoft OS 2.0 descriptors
+//!
+//! Make Windows bind WinUSB to a device with a vendor-specific transport without an INF.
+//! A [Transport] emits the platform capability from [get_bos_descriptors] and answers the
+//! descriptor set request from [control_in]:
+//!
+//! ```ignore
+//! fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<(), UsbError> {
+//!     self.msos.write_bos_capability(writer)
+//! }
+//!
+//! fn control_in(&mut self, xfer: ControlIn<Self::Bus>) {
+//!     if self.msos.is_descriptor_set_request(xfer.request()) {
+//!         let _ = xfer.accept(|buf| self.msos.write_descriptor_set(buf));
+//!     }
+//! }
+//! ```
+//!
+//! BOS descriptors require the device to report USB 2.1, see [UsbDeviceBuilder::usb_rev].
+//!
+//! [Transport]: crate::transport::Transport
+//! [get_bos_descriptors]: crate::transport::Transport::get_bos_descriptors
+//! [control_in]: crate::transport::Transport::control_in
+//! [UsbDeviceBuilder::usb_rev]: usb_device::device::UsbDeviceBuilder::usb_rev
+
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::descriptor::{capability_type, BosWriter};
+use usb_device::{UsbDirection, UsbError};
+
+/// `wIndex` of the vendor request retrieving the descriptor set
+pub const MS_OS_20_DESCRIPTOR_INDEX: u16 = 0x07;
+
+/// Max length of the descriptor set
+pub const DESCRIPTOR_SET_MAX_LEN: usize = SET_HEADER_LEN
+    + CONFIGURATION_SUBSET_HEADER_LEN
+    + FUNCTION_SUBSET_HEADER_LEN
+    + COMPATIBLE_ID_LEN;
+
+/// {D8DD60DF-4589-4CC7-9CD2-659D9E648A9F} in little endian
+const PLATFORM_CAPABILITY_UUID: [u8; 16] = [
+    0xDF, 0x60, 0xDD, 0xD8, 0x89, 0x45, 0xC7, 0x4C, 0x9C, 0xD2, 0x65, 0x9D, 0x9E, 0x64, 0x8A, 0x9F,
+];
+/// Windows 8.1
+const WINDOWS_VERSION: u32 = 0x06030000;
+
+const SET_HEADER_DESCRIPTOR: u16 = 0x00;
+const SUBSET_HEADER_CONFIGURATION: u16 = 0x01;
+const SUBSET_HEADER_FUNCTION: u16 = 0x02;
+const FEATURE_COMPATIBLE_ID: u16 = 0x03;
+
+const SET_HEADER_LEN: usize = 10;
+const CONFIGURATION_SUBSET_HEADER_LEN: usize = 8;
+const FUNCTION_SUBSET_HEADER_LEN: usize = 8;
+const COMPATIBLE_ID_LEN: usize = 20;
+
+/// Microsoft OS 2.0 descriptors declaring WinUSB compatibility
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MsOs20 {
+    vendor_code: u8,
+    first_interface: Option<u8>,
+}
+
+impl MsOs20 {
+    /// Declares the whole device WinUSB compatible. For devices with a single interface
+    ///
+    /// # Arguments
+    /// * `vendor_code` - `bRequest` of the vendor request retrieving the descriptor set
+    pub const fn new(vendor_code: u8) -> Self {
+        Self {
+            vendor_code,
+            first_interface: None,
+        }
+    }
+
+    /// Declares a single function of a composite device WinUSB compatible
+    ///
+    /// # Arguments
+    /// * `vendor_code` - `bRequest` of the vendor request retrieving the descriptor set
+    /// * `first_interface` - the number of the first interface of the function
+    pub const fn for_function(vendor_code: u8, first_interface: u8) -> Self {
+        Self {
+            vendor_code,
+            first_interface: Some(first_interface),
+        }
+    }
+
+    /// Returns the length of the descriptor set
+    pub const fn descriptor_set_len(&self) -> usize {
+        match self.first_interface {
+            Some(_) => DESCRIPTOR_SET_MAX_LEN,
+            None => SET_HEADER_LEN + COMPATIBLE_ID_LEN,
+        }
+    }
+
+    /// Writes the platform capability descriptor pointing a host to the descriptor set
+    pub fn write_bos_capability(&self, writer: &mut BosWriter) -> Result<(), UsbError> {
+        let mut data = [0u8; 25];
+        data[1..17].copy_from_slice(&PLATFORM_CAPABILITY_UUID);
+        data[17..21].copy_from_slice(&WINDOWS_VERSION.to_le_bytes());
+        data[21..23].copy_from_slice(&(self.descriptor_set_len() as u16).to_le_bytes());
+        data[23] = self.vendor_code;
+        data[24] = 0x00; // alternate enumeration not supported
+        writer.capability(capability_type::PLATFORM, &data)
+    }
+
+    /// Whether `req` is the vendor request retrieving the descriptor set
+    pub fn is_descriptor_set_request(&self, req: &Request) -> bool {
+        req.direction == UsbDirection::In
+            && req.request_type == RequestType::Vendor
+            && req.recipient == Recipient::Device
+            && req.request == self.vendor_code
+            && req.index == MS_OS_20_DESCRIPTOR_INDEX
+    }
+
+    /// Writes the descriptor set into `dst` returning the number of bytes written
+    ///
+    /// # Errors
+    /// Returns [UsbError::BufferOverflow] if `dst` doesn't fit the descriptor set
+    ///
+    /// [UsbError::BufferOverflow]: usb_device::UsbError::BufferOverflow
+    pub fn write_descriptor_set(&self, dst: &mut [u8]) -> Result<usize, UsbError> {
+        let total_len = self.descriptor_set_len();
+        if dst.len() < total_len {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        write_header(dst, SET_HEADER_LEN, SET_HEADER_DESCRIPTOR);
+        dst[4..8].copy_from_slice(&WINDOWS_VERSION.to_le_bytes());
+        dst[8..10].copy_from_slice(&(total_len as u16).to_le_bytes());
+        let mut pos = SET_HEADER_LEN;
+
+        if let Some(first_interface) = self.first_interface {
+            let subset = &mut dst[pos..];
+            write_header(
+                subset,
+                CONFIGURATION_SUBSET_HEADER_LEN,
+                SUBSET_HEADER_CONFIGURATION,
+            );
+            subset[4] = 0; // configuration index
+            subset[5] = 0;
+            subset[6..8].copy_from_slice(&((total_len - pos) as u16).to_le_bytes());
+            pos += CONFIGURATION_SUBSET_HEADER_LEN;
+
+            let subset = &mut dst[pos..];
+            write_header(subset, FUNCTION_SUBSET_HEADER_LEN, SUBSET_HEADER_FUNCTION);
+            subset[4] = first_interface;
+            subset[5] = 0;
+            subset[6..8].copy_from_slice(&((total_len - pos) as u16).to_le_bytes());
+            pos += FUNCTION_SUBSET_HEADER_LEN;
+        }
+
+        let compatible_id = &mut dst[pos..pos + COMPATIBLE_ID_LEN];
+        write_header(compatible_id, COMPATIBLE_ID_LEN, FEATURE_COMPATIBLE_ID);
+        compatible_id[4..12].copy_from_slice(b"WINUSB\0\0");
+        compatible_id[12..20].fill(0); // sub-compatible ID
+
+        Ok(total_len)
+    }
+}
+
+fn write_header(dst: &mut [u8], len: usize, descriptor_type: u16) {
+    dst[..2].copy_from_slice(&(len as u16).to_le_bytes());
+    dst[2..4].copy_from_slice(&descriptor_type.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::msos::{MsOs20, DESCRIPTOR_SET_MAX_LEN, MS_OS_20_DESCRIPTOR_INDEX};
+    use usb_device::control::{Recipient, Request, RequestType};
+    use usb_device::UsbDirection;
+
+    #[test]
+    fn should_write_device_descriptor_set() {
+        let mut buf = [0xFFu8; DESCRIPTOR_SET_MAX_LEN];
+        assert_eq!(Ok(30), MsOs20::new(0x20).write_descriptor_set(&mut buf));
+        assert_eq!(
+            [0x0A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x06, 0x1E, 0x00],
+            buf[..10]
+        );
+        assert_eq!([0x14, 0x00, 0x03, 0x00], buf[10..14]);
+        assert_eq!(b"WINUSB\0\0", &buf[14..22]);
+        assert_eq!([0u8; 8], buf[22..30]);
+    }
+
+    #[test]
+    fn should_write_function_descriptor_set() {
+        let mut buf = [0xFFu8; DESCRIPTOR_SET_MAX_LEN];
+        let msos = MsOs20::for_function(0x20, 2);
+        assert_eq!(Ok(46), msos.write_descriptor_set(&mut buf));
+        assert_eq!([0x2E, 0x00], buf[8..10]); // total length
+        assert_eq!(
+            [0x08, 0x00, 0x01, 0x00, 0x00, 0x00, 0x24, 0x00],
+            buf[10..18]
+        );
+        assert_eq!(
+            [0x08, 0x00, 0x02, 0x00, 0x02, 0x00, 0x1C, 0x00],
+            buf[18..26]
+        );
+        assert_eq!([0x14, 0x00, 0x03, 0x00], buf[26..30]);
+        assert!(msos.write_descriptor_set(&mut buf[..45]).is_err());
+    }
+
+    #[test]
+    fn should_match_descriptor_set_request() {
+        let msos = MsOs20::new(0x20);
+        let mut req = Request {
+            direction: UsbDirection::In,
+            request_type: RequestType::Vendor,
+            recipient: Recipient::Device,
+            request: 0x20,
+            value: 0,
+            index: MS_OS_20_DESCRIPTOR_INDEX,
+            length: 0xFF,
+        };
+        assert!(msos.is_descriptor_set_request(&req));
+        req.index = 0x08;
+        assert!(!msos.is_descriptor_set_request(&req));
+        req.index = MS_OS_20_DESCRIPTOR_INDEX;
+        req.request = 0x21;
+        assert!(!msos.is_descriptor_set_request(&req));
+    }
+}
@@ -0,0 +1,293 @@
+//! Simple vendor-specific transport
+//!
+//! A template for vendor-specific transports talking to custom host tools over a single pair of
+//! bulk endpoints. Both the requests and the responses are framed trivially: a 4-byte
+//! little-endian payload length followed by the payload. A response ending on a packet boundary
+//! is terminated with a zero length packet.
+//!
+//! The host sends a request and reads its response before sending the next one.
+
+use crate::fmt::{info, trace};
+use crate::transport::crc::Crc32;
+use crate::transport::{Transport, TransportError, TRANSPORT_VENDOR_SPECIFIC};
+use core::borrow::BorrowMut;
+use core::cmp::min;
+use core::fmt::{Display, Formatter};
+use usb_device::bus::{UsbBus, UsbBusAllocator};
+use usb_device::class::ControlIn;
+use usb_device::class_prelude::DescriptorWriter;
+use usb_device::endpoint::{Endpoint, In, Out};
+use usb_device::UsbError;
+
+/// Length of the frame header carrying the payload length
+pub const FRAME_HEADER_LEN: usize = 4;
+
+/// Simple vendor-specific transport error
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SimpleVendorError {
+    /// The IO buffer cannot fit a frame header and a single packet
+    BufferTooSmall,
+    /// The request doesn't fit the IO buffer. The request is dropped
+    RequestTooLarge,
+    /// The response doesn't fit the IO buffer
+    ResponseTooLarge,
+    /// There is no request to respond to
+    InvalidState,
+}
+
+impl Display for SimpleVendorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            SimpleVendorError::BufferTooSmall => "IO buffer too small",
+            SimpleVendorError::RequestTooLarge => "request too large",
+            SimpleVendorError::ResponseTooLarge => "response too large",
+            SimpleVendorError::InvalidState => "no request to respond to",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SimpleVendorError {}
+
+type SimpleVendorResult<T> = Result<T, TransportError<SimpleVendorError>>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum State {
+    /// Receiving a request. The IO buffer holds the bytes received so far
+    Receiving,
+    /// The request is waiting for a response
+    Request,
+    /// Sending a response. The IO buffer holds the frame
+    Responding,
+}
+
+/// Simple vendor-specific transport
+///
+/// Expected to be driven via [read] and [write] methods. Requests and responses go through
+/// an underlying IO buffer, so each of them is required to fit it along with the frame header.
+///
+/// [read]: SimpleVendorTransport::read
+/// [write]: SimpleVendorTransport::write
+pub struct SimpleVendorTransport<'alloc, Bus: UsbBus, Buf: BorrowMut<[u8]>> {
+    in_ep: Endpoint<'alloc, Bus, In>,
+    out_ep: Endpoint<'alloc, Bus, Out>,
+    buf: Buf,
+    state: State,
+    /// Number of bytes received or sent of the current frame
+    pos: usize,
+    /// Length of the current frame including the header
+    frame_len: usize,
+    /// The CRC of the request payload received so far
+    crc: Crc32,
+}
+
+impl<'alloc, Bus, Buf> SimpleVendorTransport<'alloc, Bus, Buf>
+where
+    Bus: UsbBus,
+    Buf: BorrowMut<[u8]>,
+{
+    /// Creates a simple vendor-specific transport instance
+    ///
+    /// # Arguments
+    /// * `alloc` - [UsbBusAllocator]
+    /// * `packet_size` - Maximum USB packet size. Allowed values: 8,16,32,64
+    /// * `buf` - The underlying IO buffer. It is **required** to fit at least a frame header and
+    ///   a single packet. It limits the length of requests and responses
+    ///
+    /// # Errors
+    /// * [BufferTooSmall]
+    ///
+    /// # Panics
+    /// Panics if endpoint allocations fails.
+    ///
+    /// [BufferTooSmall]: SimpleVendorError::BufferTooSmall
+    /// [UsbBusAllocator]: usb_device::bus::UsbBusAllocator
+    pub fn new(
+        alloc: &'alloc UsbBusAllocator<Bus>,
+        packet_size: u16,
+        buf: Buf,
+    ) -> Result<SimpleVendorTransport<'alloc, Bus, Buf>, SimpleVendorError> {
+        let buf_len = buf.borrow().len();
+        if buf_len < FRAME_HEADER_LEN + packet_size as usize {
+            return Err(SimpleVendorError::BufferTooSmall);
+        }
+
+        Ok(SimpleVendorTransport {
+            in_ep: alloc.bulk(packet_size),
+            out_ep: alloc.bulk(packet_size),
+            buf,
+            state: State::Receiving,
+            pos: 0,
+            frame_len: 0,
+            crc: Crc32::new(),
+        })
+    }
+
+    /// Drives a transport by reading a single packet of a request
+    ///
+    /// # Errors
+    /// [RequestTooLarge] if the request doesn't fit the IO buffer. The rest of it is expected
+    /// to be dropped by the host tool, e.g. by resetting the device
+    ///
+    /// [RequestTooLarge]: SimpleVendorError::RequestTooLarge
+    pub fn read(&mut self) -> SimpleVendorResult<()> {
+        if self.state != State::Receiving {
+            return Ok(());
+        }
+
+        let packet_size = self.packet_size();
+        let buf = self.buf.borrow_mut();
+        if buf.len() - self.pos < packet_size {
+            self.clean();
+            return Err(TransportError::Error(SimpleVendorError::RequestTooLarge));
+        }
+        let count = self
+            .out_ep
+            .read(&mut buf[self.pos..self.pos + packet_size])
+            .map_err(TransportError::Usb)?;
+        let start = self.pos;
+        self.pos += count;
+        trace!("usb: vendor: Read bytes: {}", count);
+
+        if self.frame_len == 0 && self.pos >= FRAME_HEADER_LEN {
+            let header = buf[..FRAME_HEADER_LEN].try_into().unwrap();
+            let payload_len = u32::from_le_bytes(header) as usize;
+            if payload_len > buf.len() - FRAME_HEADER_LEN {
+                info!("usb: vendor: Request too large: {}", payload_len);
+                self.clean();
+                return Err(TransportError::Error(SimpleVendorError::RequestTooLarge));
+            }
+            self.frame_len = FRAME_HEADER_LEN + payload_len;
+        }
+
+        if self.frame_len != 0 {
+            // the bytes past the frame aren't part of the request
+            self.pos = min(self.pos, self.frame_len);
+            let landed = &buf[start.max(FRAME_HEADER_LEN)..self.pos.max(FRAME_HEADER_LEN)];
+            self.crc.update(landed);
+            if self.pos == self.frame_len {
+                info!(
+                    "usb: vendor: Recv request: {}",
+                    self.frame_len - FRAME_HEADER_LEN
+                );
+                self.state = State::Request;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drives a transport by writing a single packet of a response
+    pub fn write(&mut self) -> SimpleVendorResult<()> {
+        if self.state != State::Responding {
+            return Ok(());
+        }
+
+        if self.pos < self.frame_len {
+            let end = min(self.pos + self.packet_size(), self.frame_len);
+            let count = self
+                .in_ep
+                .write(&self.buf.borrow()[self.pos..end])
+                .map_err(TransportError::Usb)?;
+            self.pos += count;
+            trace!("usb: vendor: Wrote bytes: {}", count);
+            // otherwise a zero length packet terminates the response
+            if self.pos == self.frame_len && !self.frame_len.is_multiple_of(self.packet_size()) {
+                self.clean();
+            }
+            return Ok(());
+        }
+
+        self.in_ep.write(&[]).map_err(TransportError::Usb)?;
+        self.clean();
+        Ok(())
+    }
+
+    /// Returns the payload of the request waiting for a response
+    pub fn request(&self) -> Option<&[u8]> {
+        match self.state {
+            State::Request => Some(&self.buf.borrow()[FRAME_HEADER_LEN..self.frame_len]),
+            _ => None,
+        }
+    }
+
+    /// Returns the CRC of the request payload received so far. Complete once [request] returns
+    /// the request, e.g. to check a trailer the host tool appends to the payload
+    ///
+    /// [request]: SimpleVendorTransport::request
+    pub fn request_crc(&self) -> Crc32 {
+        self.crc
+    }
+
+    /// Responds to the request with `payload`. The request is discarded and the response is sent
+    /// on the next [write]s
+    ///
+    /// # Errors
+    /// * [InvalidState] if there is no request to respond to
+    /// * [ResponseTooLarge] if the response doesn't fit the IO buffer
+    ///
+    /// [write]: SimpleVendorTransport::write
+    /// [InvalidState]: SimpleVendorError::InvalidState
+    /// [ResponseTooLarge]: SimpleVendorError::ResponseTooLarge
+    pub fn respond(&mut self, payload: &[u8]) -> Result<(), SimpleVendorError> {
+        if self.state != State::Request {
+            return Err(SimpleVendorError::InvalidState);
+        }
+        let buf = self.buf.borrow_mut();
+        let frame_len = FRAME_HEADER_LEN + payload.len();
+        if frame_len > buf.len() {
+            return Err(SimpleVendorError::ResponseTooLarge);
+        }
+        buf[..FRAME_HEADER_LEN].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf[FRAME_HEADER_LEN..frame_len].copy_from_slice(payload);
+        self.pos = 0;
+        self.frame_len = frame_len;
+        self.state = State::Responding;
+        Ok(())
+    }
+
+    fn packet_size(&self) -> usize {
+        self.in_ep.max_packet_size() as usize
+    }
+
+    /// Gets ready for the next request
+    fn clean(&mut self) {
+        self.state = State::Receiving;
+        self.pos = 0;
+        self.frame_len = 0;
+        self.crc.reset();
+    }
+}
+
+impl<Bus, Buf> Transport for SimpleVendorTransport<'_, Bus, Buf>
+where
+    Bus: UsbBus,
+    Buf: BorrowMut<[u8]>,
+{
+    const PROTO: u8 = TRANSPORT_VENDOR_SPECIFIC;
+    type Bus = Bus;
+
+    fn get_endpoint_descriptors(&self, writer: &mut DescriptorWriter) -> Result<(), UsbError> {
+        writer.endpoint(&self.in_ep)?;
+        writer.endpoint(&self.out_ep)?;
+        Ok(())
+    }
+
+    /// Same as [read] followed by [write]. Errors are dropped
+    ///
+    /// [read]: SimpleVendorTransport::read
+    /// [write]: SimpleVendorTransport::write
+    fn poll(&mut self) {
+        let _ = self.read();
+        let _ = self.write();
+    }
+
+    /// Drops the request or the response in progress
+    fn reset(&mut self) {
+        info!("usb: vendor: Recv reset");
+        self.clean();
+    }
+
+    fn control_in(&mut self, _xfer: ControlIn<Self::Bus>) {}
+}
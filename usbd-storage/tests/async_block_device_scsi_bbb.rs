@@ -0,0 +1,286 @@
+mod common;
+
+use crate::common::bbb::{Cbw, CommandStatus, DataDirection, DummyUsbBus};
+use crate::common::initiator::Initiator;
+use crate::common::ramdisk::RamDisk;
+use crate::common::scsi::cmd_into_bytes;
+use core::task::{Context, Poll, Waker};
+use std::time::Duration;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usbd_storage::subclass::scsi::async_block::{AsyncBlockDevice, AsyncBlockDriver};
+use usbd_storage::subclass::scsi::block::BlockDevice;
+use usbd_storage::subclass::scsi::capacity::BlockSize;
+use usbd_storage::subclass::scsi::sense::Sense;
+use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const BLOCK_SIZE: usize = 512;
+const BLOCKS: usize = 32;
+const LATENCY: usize = 3;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Op {
+    Read(u64),
+    Write(u64),
+    Flush,
+}
+
+/// [RamDisk] completing each transfer after `latency` calls, dropping the writes to `stuck`
+struct AsyncDisk {
+    disk: RamDisk,
+    latency: usize,
+    stuck: Option<u64>,
+    /// the transfer in progress and the calls left until it completes
+    current: Option<(Op, usize)>,
+    /// the transfers completed so far
+    completed: Vec<Op>,
+    /// number of calls returning `Poll::Pending`
+    pending: usize,
+}
+
+impl AsyncDisk {
+    fn new(latency: usize) -> Self {
+        Self {
+            disk: RamDisk::new(BLOCK_SIZE, BLOCKS),
+            latency,
+            stuck: None,
+            current: None,
+            completed: vec![],
+            pending: 0,
+        }
+    }
+
+    /// Returns whether `op` completes with this call
+    fn poll(&mut self, cx: &mut Context<'_>, op: Op) -> bool {
+        let wait = match self.current {
+            Some((current, wait)) if current == op => wait,
+            // another transfer abandons the one in progress
+            _ => self.latency,
+        };
+        if wait > 0 {
+            self.current = Some((op, wait - 1));
+            self.pending += 1;
+            cx.waker().wake_by_ref();
+            return false;
+        }
+        self.current = None;
+        self.completed.push(op);
+        true
+    }
+}
+
+impl AsyncBlockDevice for AsyncDisk {
+    fn block_size(&self) -> BlockSize {
+        BlockDevice::block_size(&self.disk)
+    }
+
+    fn num_blocks(&self) -> u64 {
+        BlockDevice::num_blocks(&self.disk)
+    }
+
+    fn poll_read_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        lba: u64,
+        block: &mut [u8],
+    ) -> Poll<Result<(), Sense>> {
+        if !self.poll(cx, Op::Read(lba)) {
+            return Poll::Pending;
+        }
+        Poll::Ready(self.disk.read_block(lba, block))
+    }
+
+    fn poll_write_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        lba: u64,
+        block: &[u8],
+    ) -> Poll<Result<(), Sense>> {
+        if !self.poll(cx, Op::Write(lba)) {
+            return Poll::Pending;
+        }
+        if Some(lba) == self.stuck {
+            return Poll::Ready(Ok(()));
+        }
+        Poll::Ready(self.disk.write_block(lba, block))
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Sense>> {
+        if !self.poll(cx, Op::Flush) {
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn request_sense<F: FnMut()>(initiator: &mut Initiator<F>) -> (u8, u8) {
+    let cmd = ScsiCommand::RequestSense {
+        desc: false,
+        alloc_len: 18,
+    };
+    let (data, csw) = initiator.execute(cmd, DataDirection::In, 18, &[]);
+    assert_eq!(CommandStatus::Passed, csw.status);
+    (data[2] & 0x0F, data[12])
+}
+
+#[test]
+fn should_serve_reads_and_writes_with_async_device() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let mut disk = AsyncDisk::new(LATENCY);
+            scsi.set_async_block_device(0, &disk);
+            let mut driver = AsyncBlockDriver::new([0u8; 2 * BLOCK_SIZE]);
+            let mut cx = Context::from_waker(Waker::noop());
+
+            let data: Vec<u8> = (0..5 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+            {
+                let mut initiator = Initiator::new(&bus, || {
+                    scsi.poll(|cmd| {
+                        if driver.poll_handle(&mut cx, &mut disk, cmd).is_some() {
+                            panic!("unexpected command");
+                        }
+                    })
+                    .unwrap();
+                })
+                .with_idle_polls(2 * LATENCY + 4);
+
+                assert_eq!((BLOCKS as u64, BLOCK_SIZE), initiator.read_capacity_10());
+                initiator.write_10(3, &data, BLOCK_SIZE);
+                assert_eq!(data, initiator.read_10(3, 5, BLOCK_SIZE));
+            }
+            assert_eq!(
+                data.as_slice(),
+                &disk.disk.data()[3 * BLOCK_SIZE..8 * BLOCK_SIZE]
+            );
+            // each block waits for the device once written and once read
+            assert_eq!(10 * LATENCY, disk.pending, "packet size {packet_size}");
+            let writes = (3..8).map(Op::Write);
+            let reads = (3..8).map(Op::Read);
+            assert_eq!(writes.chain(reads).collect::<Vec<_>>(), disk.completed);
+        }
+    });
+}
+
+#[test]
+fn should_verify_and_flush_with_async_device() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        let mut disk = AsyncDisk::new(LATENCY);
+        disk.stuck = Some(3);
+        scsi.set_async_block_device(0, &disk);
+        let mut driver = AsyncBlockDriver::new([0u8; 2 * BLOCK_SIZE]);
+        let mut cx = Context::from_waker(Waker::noop());
+        let data = [0xAAu8; 2 * BLOCK_SIZE];
+        let len = data.len() as u32;
+
+        {
+            let mut initiator = Initiator::new(&bus, || {
+                scsi.poll(|cmd| {
+                    if driver.poll_handle(&mut cx, &mut disk, cmd).is_some() {
+                        panic!("unexpected command");
+                    }
+                })
+                .unwrap();
+            })
+            .with_idle_polls(2 * LATENCY + 4);
+
+            let mut block = cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 2 });
+            block[1] |= 1 << 3;
+            let (_, csw) = initiator.execute_raw(block, DataDirection::Out, len, &data);
+            assert_eq!(CommandStatus::Passed, csw.status);
+
+            let write_and_verify = ScsiCommand::WriteAndVerify {
+                lba: 2,
+                len: 2,
+                byte_check: true,
+            };
+            let (_, csw) = initiator.execute(write_and_verify, DataDirection::Out, len, &data);
+            assert_eq!(CommandStatus::Failed, csw.status);
+            assert_eq!((0x0E, 0x1D), request_sense(&mut initiator));
+
+            let verify = |byte_check| ScsiCommand::Verify {
+                lba: 0,
+                len: 2,
+                byte_check,
+            };
+            let (_, csw) = initiator.execute(verify(true), DataDirection::Out, len, &data);
+            assert_eq!(CommandStatus::Passed, csw.status);
+            let (_, csw) = initiator.execute(verify(false), DataDirection::NotExpected, 0, &[]);
+            assert_eq!(CommandStatus::Passed, csw.status);
+        }
+        let (write, read) = (Op::Write, Op::Read);
+        let expected = [
+            // the FUA flush follows the last block
+            vec![write(0), write(1), Op::Flush],
+            vec![write(2), read(2), write(3), read(3)],
+            vec![read(0), read(1), read(0), read(1)],
+        ];
+        assert_eq!(expected.concat(), disk.completed);
+    });
+}
+
+#[test]
+fn should_abandon_transfer_of_reset_command() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        // the device never completes until the reset
+        let mut disk = AsyncDisk::new(usize::MAX);
+        scsi.set_async_block_device(0, &disk);
+        let mut driver = AsyncBlockDriver::new([0u8; 2 * BLOCK_SIZE]);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        bus.write_cbw(Cbw {
+            data_transfer_len: 2 * BLOCK_SIZE as u32,
+            direction: DataDirection::Out,
+            block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 2 }),
+        });
+        bus.write_data(&[0x11u8; 2 * BLOCK_SIZE]);
+        for _ in 0..32 {
+            scsi.poll(|cmd| {
+                if driver.poll_handle(&mut cx, &mut disk, cmd).is_some() {
+                    panic!("unexpected command");
+                }
+            })
+            .unwrap();
+        }
+        assert!(matches!(disk.current, Some((Op::Write(0), _))));
+        UsbClass::reset(&mut scsi);
+        assert!(scsi.take_aborted().is_some());
+        while bus.read_packet().is_some() {}
+
+        disk.latency = LATENCY;
+        let data = [0x22u8; BLOCK_SIZE];
+        {
+            let mut initiator = Initiator::new(&bus, || {
+                scsi.poll(|cmd| {
+                    if driver.poll_handle(&mut cx, &mut disk, cmd).is_some() {
+                        panic!("unexpected command");
+                    }
+                })
+                .unwrap();
+            })
+            .with_idle_polls(2 * LATENCY + 4);
+            initiator.write_10(1, &data, BLOCK_SIZE);
+        }
+        // the block of the dropped command is never written
+        assert_eq!(vec![Op::Write(1)], disk.completed);
+        assert_eq!(&data, &disk.disk.data()[BLOCK_SIZE..2 * BLOCK_SIZE]);
+        assert!(disk.disk.data()[..BLOCK_SIZE].iter().all(|b| *b == 0));
+    });
+}
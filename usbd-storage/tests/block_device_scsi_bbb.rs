@@ -0,0 +1,686 @@
+mod common;
+
+use crate::common::bbb::{Cbw, CommandStatus, DataDirection, DummyUsbBus};
+use crate::common::initiator::Initiator;
+use crate::common::ramdisk::RamDisk;
+use crate::common::scsi::cmd_into_bytes;
+use std::time::Duration;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usbd_storage::subclass::scsi::block::{BlockDevice, BlockDriver, LunTable};
+use usbd_storage::subclass::scsi::capacity::BlockSize;
+use usbd_storage::subclass::scsi::inquiry::InquiryData;
+use usbd_storage::subclass::scsi::sense::Sense;
+use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const BLOCK_SIZE: usize = 512;
+const BLOCKS: usize = 32;
+/// The block of [FaultyDisk] failing to be read and written
+const BAD_BLOCK: u64 = 20;
+
+/// [RamDisk] with a bad block
+struct FaultyDisk(RamDisk);
+
+impl BlockDevice for FaultyDisk {
+    fn block_size(&self) -> BlockSize {
+        BlockDevice::block_size(&self.0)
+    }
+
+    fn num_blocks(&self) -> u64 {
+        BlockDevice::num_blocks(&self.0)
+    }
+
+    fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), Sense> {
+        if lba == BAD_BLOCK {
+            return Err(Sense::UNRECOVERED_READ_ERROR);
+        }
+        self.0.read_block(lba, block)
+    }
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), Sense> {
+        if lba == BAD_BLOCK {
+            return Err(Sense::WRITE_ERROR);
+        }
+        self.0.write_block(lba, block)
+    }
+
+    fn inquiry_data(&self) -> Option<InquiryData> {
+        Some(InquiryData::new("usbd", "Faulty disk", "0.1"))
+    }
+}
+
+/// [RamDisk] behind a write cache of a single block, failing to flush it if `flush_error` is set
+struct CachedDisk {
+    disk: RamDisk,
+    cached: Option<(u64, Vec<u8>)>,
+    flush_error: bool,
+}
+
+impl BlockDevice for CachedDisk {
+    fn block_size(&self) -> BlockSize {
+        BlockDevice::block_size(&self.disk)
+    }
+
+    fn num_blocks(&self) -> u64 {
+        BlockDevice::num_blocks(&self.disk)
+    }
+
+    fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), Sense> {
+        match &self.cached {
+            Some((cached, data)) if *cached == lba => block.copy_from_slice(data),
+            _ => self.disk.read_block(lba, block)?,
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), Sense> {
+        self.flush()?;
+        self.cached = Some((lba, block.to_vec()));
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Sense> {
+        if self.flush_error {
+            return Err(Sense::WRITE_ERROR);
+        }
+        if let Some((lba, data)) = self.cached.take() {
+            self.disk.write_block(lba, &data)?;
+        }
+        Ok(())
+    }
+}
+
+/// [RamDisk] silently dropping the writes to `stuck`
+struct StuckDisk {
+    disk: RamDisk,
+    stuck: u64,
+}
+
+impl BlockDevice for StuckDisk {
+    fn block_size(&self) -> BlockSize {
+        BlockDevice::block_size(&self.disk)
+    }
+
+    fn num_blocks(&self) -> u64 {
+        BlockDevice::num_blocks(&self.disk)
+    }
+
+    fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), Sense> {
+        self.disk.read_block(lba, block)
+    }
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), Sense> {
+        if lba == self.stuck {
+            return Ok(());
+        }
+        self.disk.write_block(lba, block)
+    }
+}
+
+/// [RamDisk] recording the first block and the number of blocks of each multi-block transfer,
+/// programmed in pages of `page` blocks
+struct ExtentDisk {
+    disk: RamDisk,
+    page: u32,
+    reads: Vec<(u64, usize)>,
+    writes: Vec<(u64, usize)>,
+}
+
+impl ExtentDisk {
+    fn new(page: u32) -> Self {
+        Self {
+            disk: RamDisk::new(BLOCK_SIZE, BLOCKS),
+            page,
+            reads: vec![],
+            writes: vec![],
+        }
+    }
+}
+
+impl BlockDevice for ExtentDisk {
+    fn block_size(&self) -> BlockSize {
+        BlockDevice::block_size(&self.disk)
+    }
+
+    fn num_blocks(&self) -> u64 {
+        BlockDevice::num_blocks(&self.disk)
+    }
+
+    fn read_block(&mut self, _: u64, _: &mut [u8]) -> Result<(), Sense> {
+        unreachable!("blocks are expected to be read at once")
+    }
+
+    fn write_block(&mut self, _: u64, _: &[u8]) -> Result<(), Sense> {
+        unreachable!("blocks are expected to be written at once")
+    }
+
+    fn read_blocks(&mut self, lba: u64, blocks: &mut [u8]) -> Result<(), Sense> {
+        self.reads.push((lba, blocks.len() / BLOCK_SIZE));
+        let start = lba as usize * BLOCK_SIZE;
+        blocks.copy_from_slice(&self.disk.data()[start..start + blocks.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, blocks: &[u8]) -> Result<(), Sense> {
+        let page = self.page as usize;
+        assert_eq!(
+            0,
+            lba % page as u64,
+            "the write is expected to start at a page"
+        );
+        assert_eq!(
+            0,
+            blocks.len() % (page * BLOCK_SIZE),
+            "the write is expected to end at one"
+        );
+        self.writes.push((lba, blocks.len() / BLOCK_SIZE));
+        let start = lba as usize * BLOCK_SIZE;
+        self.disk.data_mut()[start..start + blocks.len()].copy_from_slice(blocks);
+        Ok(())
+    }
+
+    fn page_blocks(&self) -> u32 {
+        self.page
+    }
+}
+
+fn request_sense<F: FnMut()>(initiator: &mut Initiator<F>) -> (u8, u8) {
+    let cmd = ScsiCommand::RequestSense {
+        desc: false,
+        alloc_len: 18,
+    };
+    let (data, csw) = initiator.execute(cmd, DataDirection::In, 18, &[]);
+    assert_eq!(CommandStatus::Passed, csw.status);
+    (data[2] & 0x0F, data[12])
+}
+
+#[test]
+fn should_serve_reads_and_writes_with_block_device() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let mut disk = FaultyDisk(RamDisk::new(BLOCK_SIZE, BLOCKS));
+            scsi.set_block_device(0, &disk);
+            let mut driver = BlockDriver::new([0u8; 2 * BLOCK_SIZE]);
+
+            {
+                let mut initiator = Initiator::new(&bus, || {
+                    scsi.poll(|cmd| {
+                        if driver.handle(&mut disk, cmd).is_some() {
+                            panic!("unexpected command");
+                        }
+                    })
+                    .unwrap();
+                });
+
+                assert_eq!((BLOCKS as u64, BLOCK_SIZE), initiator.read_capacity_10());
+
+                let data: Vec<u8> = (0..5 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+                initiator.write_10(3, &data, BLOCK_SIZE);
+                assert_eq!(data, initiator.read_10(3, 5, BLOCK_SIZE));
+                assert_eq!(
+                    [0u8; BLOCK_SIZE].as_slice(),
+                    initiator.read_10(8, 1, BLOCK_SIZE)
+                );
+
+                // zero blocks
+                for (cmd, direction) in [
+                    (ScsiCommand::Read { lba: 0, len: 0 }, DataDirection::In),
+                    (ScsiCommand::Write { lba: 0, len: 0 }, DataDirection::Out),
+                ] {
+                    let (data, csw) = initiator.execute(cmd, direction, 0, &[]);
+                    assert_eq!(CommandStatus::Passed, csw.status, "{cmd:?}");
+                    assert!(data.is_empty());
+                }
+
+                // the blocks before the bad one are transferred
+                let len = 2 * BLOCK_SIZE as u32;
+                let cmd = ScsiCommand::Read {
+                    lba: BAD_BLOCK - 1,
+                    len: 2,
+                };
+                let (data, csw) = initiator.execute(cmd, DataDirection::In, len, &[]);
+                assert_eq!(CommandStatus::Failed, csw.status);
+                assert_eq!(len - data.len() as u32, csw.data_transfer_len);
+                assert!(data.len() >= BLOCK_SIZE, "packet size {packet_size}");
+                assert_eq!((0x03, 0x11), request_sense(&mut initiator));
+
+                let cmd = ScsiCommand::Write {
+                    lba: BAD_BLOCK - 1,
+                    len: 2,
+                };
+                let data = [0xAAu8; 2 * BLOCK_SIZE];
+                let (_, csw) = initiator.execute(cmd, DataDirection::Out, len, &data);
+                assert_eq!(CommandStatus::Failed, csw.status);
+                assert_eq!((0x03, 0x0C), request_sense(&mut initiator));
+
+                let cmd = ScsiCommand::Verify {
+                    lba: BAD_BLOCK - 1,
+                    len: 2,
+                    byte_check: false,
+                };
+                let (_, csw) = initiator.execute(cmd, DataDirection::NotExpected, 0, &[]);
+                assert_eq!(CommandStatus::Failed, csw.status);
+                assert_eq!((0x03, 0x11), request_sense(&mut initiator));
+            }
+            let written = &disk.0.data()[(BAD_BLOCK as usize - 1) * BLOCK_SIZE..];
+            assert_eq!([0xAAu8; BLOCK_SIZE].as_slice(), &written[..BLOCK_SIZE]);
+        }
+    });
+}
+
+#[test]
+fn should_flush_block_device_on_fua() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        let mut disk = CachedDisk {
+            disk: RamDisk::new(BLOCK_SIZE, BLOCKS),
+            cached: None,
+            flush_error: false,
+        };
+        scsi.set_block_device(0, &disk);
+        let mut driver = BlockDriver::new([0u8; 2 * BLOCK_SIZE]);
+
+        let mut write = |disk: &mut CachedDisk, lba: u64, fua: bool| {
+            let mut initiator = Initiator::new(&bus, || {
+                scsi.poll(|cmd| {
+                    if driver.handle(disk, cmd).is_some() {
+                        panic!("unexpected command");
+                    }
+                })
+                .unwrap();
+            });
+            let mut block = cmd_into_bytes(ScsiCommand::Write { lba, len: 2 });
+            block[1] |= (fua as u8) << 3;
+            let data = [0xAAu8; 2 * BLOCK_SIZE];
+            let (_, csw) =
+                initiator.execute_raw(block, DataDirection::Out, data.len() as u32, &data);
+            csw.status
+        };
+
+        assert_eq!(CommandStatus::Passed, write(&mut disk, 0, false));
+        assert_eq!(Some(1), disk.cached.as_ref().map(|(lba, _)| *lba));
+        assert_eq!(CommandStatus::Passed, write(&mut disk, 4, true));
+        assert!(disk.cached.is_none());
+        assert!(disk.disk.data()[..6 * BLOCK_SIZE]
+            .chunks(BLOCK_SIZE)
+            .enumerate()
+            .all(|(lba, block)| block
+                .iter()
+                .all(|b| *b == [0xAA, 0xAA, 0, 0, 0xAA, 0xAA][lba])));
+
+        disk.flush_error = true;
+        assert_eq!(CommandStatus::Failed, write(&mut disk, 8, true));
+    });
+}
+
+#[test]
+fn should_transfer_as_many_blocks_at_once_as_buffer_fits() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        let mut disk = ExtentDisk::new(1);
+        scsi.set_block_device(0, &disk);
+        let mut driver = BlockDriver::new([0u8; 4 * BLOCK_SIZE]);
+
+        {
+            let mut initiator = Initiator::new(&bus, || {
+                scsi.poll(|cmd| {
+                    if driver.handle(&mut disk, cmd).is_some() {
+                        panic!("unexpected command");
+                    }
+                })
+                .unwrap();
+            });
+
+            let data: Vec<u8> = (0..6 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+            initiator.write_10(1, &data, BLOCK_SIZE);
+            assert_eq!(data, initiator.read_10(1, 6, BLOCK_SIZE));
+            let cmd = ScsiCommand::Verify {
+                lba: 0,
+                len: 6,
+                byte_check: false,
+            };
+            let (_, csw) = initiator.execute(cmd, DataDirection::NotExpected, 0, &[]);
+            assert_eq!(CommandStatus::Passed, csw.status);
+        }
+        // the writes are aligned to the capacity of the buffer, the reads start where the data does
+        assert_eq!(vec![(1, 3), (4, 3)], disk.writes);
+        assert_eq!(vec![(1, 4), (5, 2), (0, 4), (4, 2)], disk.reads);
+    });
+}
+
+#[test]
+fn should_write_whole_pages_of_block_device() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        let mut disk = ExtentDisk::new(4);
+        disk.disk.data_mut().fill(0x55);
+        scsi.set_block_device(0, &disk);
+        let mut driver = BlockDriver::new([0u8; 8 * BLOCK_SIZE]);
+        let mut expected = disk.disk.data().to_vec();
+
+        {
+            let mut initiator = Initiator::new(&bus, || {
+                scsi.poll(|cmd| {
+                    if driver.handle(&mut disk, cmd).is_some() {
+                        panic!("unexpected command");
+                    }
+                })
+                .unwrap();
+            });
+
+            let data = [0x11u8; 3 * BLOCK_SIZE];
+            initiator.write_10(2, &data, BLOCK_SIZE);
+            expected[2 * BLOCK_SIZE..5 * BLOCK_SIZE].copy_from_slice(&data);
+
+            let data = [0x22u8; 10 * BLOCK_SIZE];
+            initiator.write_10(6, &data, BLOCK_SIZE);
+            expected[6 * BLOCK_SIZE..16 * BLOCK_SIZE].copy_from_slice(&data);
+
+            let data = [0x33u8; 2 * BLOCK_SIZE];
+            let cmd = ScsiCommand::WriteAndVerify {
+                lba: 17,
+                len: 2,
+                byte_check: true,
+            };
+            let len = data.len() as u32;
+            let (_, csw) = initiator.execute(cmd, DataDirection::Out, len, &data);
+            assert_eq!(CommandStatus::Passed, csw.status);
+            expected[17 * BLOCK_SIZE..19 * BLOCK_SIZE].copy_from_slice(&data);
+        }
+        // the blocks of the partial pages not written by the host are read first
+        assert_eq!(vec![(0, 8), (4, 4), (8, 8), (16, 4)], disk.writes);
+        let reads = [(0, 2), (5, 3), (4, 2), (16, 1), (19, 1), (17, 2)];
+        assert_eq!(reads.to_vec(), disk.reads);
+        assert_eq!(expected, disk.disk.data());
+    });
+}
+
+#[test]
+#[should_panic(expected = "the buffer is expected to fit two pages of the device")]
+fn should_panic_if_buffer_is_under_two_pages() {
+    let mut io_buf = [0u8; 1024];
+    let bus = DummyUsbBus::new();
+    let usb_bus = UsbBusAllocator::new(bus.clone());
+    let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+    let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+    let mut disk = ExtentDisk::new(4);
+    scsi.set_block_device(0, &disk);
+    let mut driver = BlockDriver::new([0u8; 4 * BLOCK_SIZE]);
+    bus.write_cbw(Cbw {
+        data_transfer_len: BLOCK_SIZE as u32,
+        direction: DataDirection::Out,
+        block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 1 }),
+    });
+    scsi.poll(|cmd| {
+        driver.handle(&mut disk, cmd);
+    })
+    .unwrap();
+}
+
+#[test]
+fn should_verify_written_blocks() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        let mut disk = StuckDisk {
+            disk: RamDisk::new(BLOCK_SIZE, BLOCKS),
+            stuck: 3,
+        };
+        scsi.set_block_device(0, &disk);
+        let data = [0xAAu8; 2 * BLOCK_SIZE];
+        let len = data.len() as u32;
+        let write_and_verify = |lba, byte_check| ScsiCommand::WriteAndVerify {
+            lba,
+            len: 2,
+            byte_check,
+        };
+
+        {
+            let mut driver = BlockDriver::new([0u8; 2 * BLOCK_SIZE]);
+            let mut initiator = Initiator::new(&bus, || {
+                scsi.poll(|cmd| {
+                    if driver.handle(&mut disk, cmd).is_some() {
+                        panic!("unexpected command");
+                    }
+                })
+                .unwrap();
+            });
+
+            let (_, csw) =
+                initiator.execute(write_and_verify(0, true), DataDirection::Out, len, &data);
+            assert_eq!(CommandStatus::Passed, csw.status);
+            assert_eq!(data.as_slice(), initiator.read_10(0, 2, BLOCK_SIZE));
+            // the last block is never written, which only a byte check notices
+            let (_, csw) =
+                initiator.execute(write_and_verify(2, false), DataDirection::Out, len, &data);
+            assert_eq!(CommandStatus::Passed, csw.status);
+            let (_, csw) =
+                initiator.execute(write_and_verify(2, true), DataDirection::Out, len, &data);
+            assert_eq!(CommandStatus::Failed, csw.status);
+            assert_eq!((0x0E, 0x1D), request_sense(&mut initiator));
+        }
+
+        let verify = |lba, byte_check| ScsiCommand::Verify {
+            lba,
+            len: 2,
+            byte_check,
+        };
+        let mut driver = BlockDriver::new([0u8; 2 * BLOCK_SIZE]);
+        let mut initiator = Initiator::new(&bus, || {
+            scsi.poll(|cmd| {
+                if driver.handle(&mut disk, cmd).is_some() {
+                    panic!("unexpected command");
+                }
+            })
+            .unwrap();
+        });
+
+        let (_, csw) = initiator.execute(verify(0, true), DataDirection::Out, len, &data);
+        assert_eq!(CommandStatus::Passed, csw.status);
+        let (_, csw) = initiator.execute(verify(2, true), DataDirection::Out, len, &data);
+        assert_eq!(CommandStatus::Failed, csw.status);
+        assert_eq!((0x0E, 0x1D), request_sense(&mut initiator));
+        // without a byte check there is no data, the blocks are only read
+        let (_, csw) = initiator.execute(verify(2, false), DataDirection::NotExpected, 0, &[]);
+        assert_eq!(CommandStatus::Passed, csw.status);
+    });
+}
+
+#[test]
+#[should_panic(expected = "the buffer is expected to fit two blocks")]
+fn should_panic_if_buffer_is_under_two_blocks() {
+    let _ = BlockDriver::new([0u8; BLOCK_SIZE]);
+}
+
+#[test]
+fn should_fail_commands_if_block_size_differs() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        // not registered, so the unit has blocks of 512 bytes
+        let mut disk = RamDisk::new(4096, 4);
+        let mut driver = BlockDriver::new([0u8; 2 * 4096]);
+
+        {
+            let mut initiator = Initiator::new(&bus, || {
+                scsi.poll(|cmd| {
+                    if driver.handle(&mut disk, cmd).is_some() {
+                        panic!("unexpected command");
+                    }
+                })
+                .unwrap();
+            });
+
+            let cmd = ScsiCommand::Write { lba: 0, len: 8 };
+            let data = [0xAAu8; 4096];
+            let (_, csw) = initiator.execute(cmd, DataDirection::Out, 4096, &data);
+            assert_eq!(CommandStatus::Failed, csw.status);
+            assert_eq!((0x02, 0x68), request_sense(&mut initiator));
+        }
+        assert!(disk.data().iter().all(|b| *b == 0));
+    });
+}
+
+#[test]
+fn should_read_medium_again_after_aborted_read() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        // a block larger than the IO buffer
+        let mut disk = RamDisk::new(4096, 4);
+        scsi.set_block_device(0, &disk);
+        let mut driver = BlockDriver::new([0u8; 2 * 4096]);
+
+        // the host reads nothing, so the driver stops in the middle of a block
+        bus.write_cbw(Cbw {
+            data_transfer_len: 4096,
+            direction: DataDirection::In,
+            block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+        });
+        for _ in 0..8 {
+            scsi.poll(|cmd| {
+                if driver.handle(&mut disk, cmd).is_some() {
+                    panic!("unexpected command");
+                }
+            })
+            .unwrap();
+        }
+        UsbClass::reset(&mut scsi);
+        assert!(scsi.take_aborted().is_some());
+        while bus.read_packet().is_some() {}
+
+        // the medium changes behind the driver, e.g. written by the firmware
+        disk.data_mut().fill(0x77);
+        let mut initiator = Initiator::new(&bus, || {
+            scsi.poll(|cmd| {
+                if driver.handle(&mut disk, cmd).is_some() {
+                    panic!("unexpected command");
+                }
+            })
+            .unwrap();
+        });
+        assert_eq!(vec![0x77u8; 4096], initiator.read_10(0, 1, 4096));
+    });
+}
+
+#[test]
+fn should_answer_inquiry_of_registered_device() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        scsi.set_inquiry(InquiryData::new("usbd", "RAM disk", "0.1"));
+        let mut faulty = FaultyDisk(RamDisk::new(BLOCK_SIZE, BLOCKS));
+        let mut ram = RamDisk::new(BLOCK_SIZE, BLOCKS);
+        let mut driver = BlockDriver::new([0u8; 2 * BLOCK_SIZE]);
+
+        scsi.set_block_device(0, &faulty);
+        {
+            let mut initiator = Initiator::new(&bus, || {
+                scsi.poll(|cmd| {
+                    if driver.handle(&mut faulty, cmd).is_some() {
+                        panic!("unexpected command");
+                    }
+                })
+                .unwrap();
+            });
+            assert_eq!(b"Faulty disk     ", &initiator.inquiry()[16..32]);
+        }
+
+        // the medium is swapped for one without INQUIRY data of its own
+        scsi.set_block_device(0, &ram);
+        {
+            let mut initiator = Initiator::new(&bus, || {
+                scsi.poll(|cmd| {
+                    if driver.handle(&mut ram, cmd).is_some() {
+                        panic!("unexpected command");
+                    }
+                })
+                .unwrap();
+            });
+            assert_eq!(b"RAM disk        ", &initiator.inquiry()[16..32]);
+        }
+    });
+}
+
+#[test]
+fn should_route_commands_to_lun_table() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut faulty = FaultyDisk(RamDisk::new(BLOCK_SIZE, BLOCKS));
+        let mut ram = RamDisk::new(BLOCK_SIZE, 2 * BLOCKS);
+        let mut luns: LunTable<2, &mut dyn BlockDevice> = LunTable::new([&mut faulty, &mut ram]);
+        let mut scsi = Scsi::new(&usb_bus, 64, luns.max_lun(), io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        scsi.set_inquiry(InquiryData::new("usbd", "RAM disk", "0.1"));
+        luns.register(&mut scsi);
+        let mut driver = BlockDriver::new([0u8; 2 * BLOCK_SIZE]);
+
+        {
+            let mut initiator = Initiator::new(&bus, || {
+                scsi.poll(|cmd| {
+                    if luns.handle(&mut driver, cmd).is_some() {
+                        panic!("unexpected command");
+                    }
+                })
+                .unwrap();
+            });
+
+            let data = [0x55u8; BLOCK_SIZE];
+            initiator.set_lun(1);
+            assert_eq!(b"RAM disk        ", &initiator.inquiry()[16..32]);
+            assert_eq!(
+                (2 * BLOCKS as u64, BLOCK_SIZE),
+                initiator.read_capacity_10()
+            );
+            initiator.write_10(BAD_BLOCK, &data, BLOCK_SIZE);
+            assert_eq!(data.as_slice(), initiator.read_10(BAD_BLOCK, 1, BLOCK_SIZE));
+
+            initiator.set_lun(0);
+            assert_eq!(b"Faulty disk     ", &initiator.inquiry()[16..32]);
+            assert_eq!((BLOCKS as u64, BLOCK_SIZE), initiator.read_capacity_10());
+            let cmd = ScsiCommand::Read {
+                lba: BAD_BLOCK,
+                len: 1,
+            };
+            let (_, csw) = initiator.execute(cmd, DataDirection::In, BLOCK_SIZE as u32, &[]);
+            assert_eq!(CommandStatus::Failed, csw.status);
+            assert_eq!((0x03, 0x11), request_sense(&mut initiator));
+        }
+        let written = &ram.data()[BAD_BLOCK as usize * BLOCK_SIZE..];
+        assert_eq!([0x55u8; BLOCK_SIZE].as_slice(), &written[..BLOCK_SIZE]);
+    });
+}
@@ -7,19 +7,32 @@ use usb_device::{UsbDirection, UsbError};
 const MAX_CB_LEN: u8 = 16;
 const CSW_LEN: u8 = 13;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum CommandStatus {
     Passed = 0x00,
     Failed = 0x01,
     PhaseError = 0x02,
 }
 
+/// A fault injected into an endpoint. Applied to the next read or write of a device
+#[derive(Debug, Copy, Clone)]
+pub enum Fault {
+    /// Fail with an error
+    Error(UsbError),
+    /// Fail with [UsbError::WouldBlock] a number of times in a row
+    WouldBlock(usize),
+    /// Transfer only a number of bytes of a packet, dropping the rest of an OUT packet
+    Truncate(usize),
+}
+
 #[allow(dead_code)]
+#[derive(Clone)]
 pub enum DataDirection {
     Out,
     In,
     NotExpected,
 }
+#[derive(Clone)]
 pub struct Cbw {
     pub(crate) data_transfer_len: u32,
     pub(crate) direction: DataDirection,
@@ -82,9 +95,14 @@ pub struct DummyEp {
     addr: EndpointAddress,
     max_packet_size: u16,
     stalled: bool,
+    /// bytes_written at the moment the endpoint got stalled
+    stalled_at: Option<usize>,
     bytes_written: usize,
     bytes_read: usize,
     packets: VecDeque<Vec<u8>>,
+    faults: VecDeque<Fault>,
+    /// number of faults applied so far
+    faults_hit: usize,
 }
 
 impl DummyEp {
@@ -93,19 +111,44 @@ impl DummyEp {
             addr,
             max_packet_size,
             stalled: false,
+            stalled_at: None,
             bytes_written: 0,
             bytes_read: 0,
             packets: VecDeque::new(),
+            faults: VecDeque::new(),
+            faults_hit: 0,
         }
     }
 
+    fn take_fault(&mut self) -> Option<Fault> {
+        let fault = match self.faults.front_mut()? {
+            Fault::WouldBlock(n) if *n > 1 => {
+                *n -= 1;
+                Fault::WouldBlock(1)
+            }
+            _ => self.faults.pop_front().unwrap(),
+        };
+        self.faults_hit += 1;
+        Some(fault)
+    }
+
     pub fn write_bytes(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            self.packets.push_back(vec![]); // zero length packet
+        }
         for chunk in bytes.chunks(self.max_packet_size as usize) {
             self.packets.push_back(chunk.to_vec());
         }
         self.bytes_written += bytes.len();
     }
 
+    pub fn set_stalled(&mut self, stalled: bool) {
+        if stalled && !self.stalled {
+            self.stalled_at = Some(self.bytes_written);
+        }
+        self.stalled = stalled;
+    }
+
     pub fn read_packet(&mut self) -> Option<Vec<u8>> {
         let packet = self.packets.pop_front();
         if let Some(len) = packet.as_ref().map(|p| p.len()) {
@@ -121,6 +164,8 @@ pub struct BytesProcessed {
     ep_in: (usize, usize),
     /// (written, read)
     ep_out: (usize, usize),
+    /// faults applied to both endpoints, so that a device is driven through them
+    faults_hit: usize,
 }
 
 #[derive(Clone)]
@@ -142,6 +187,24 @@ impl DummyUsbBus {
         ep.write_bytes(cbw.into_bytes().as_slice());
     }
 
+    /// Write Command Block Wrapper addressed to a Logical Unit
+    pub fn write_cbw_to_lun(&self, cbw: Cbw, lun: u8) {
+        let mut lock = self.inner.lock().unwrap();
+        let ep = lock.ep_out.as_mut().unwrap();
+        let mut bytes = cbw.into_bytes();
+        bytes[13] = lun;
+        ep.write_bytes(bytes.as_slice());
+    }
+
+    /// Write Command Block Wrapper padded to a full packet as some USB hosts do
+    pub fn write_padded_cbw(&self, cbw: Cbw) {
+        let mut lock = self.inner.lock().unwrap();
+        let ep = lock.ep_out.as_mut().unwrap();
+        let mut bytes = cbw.into_bytes();
+        bytes.resize(bytes.len().next_multiple_of(ep.max_packet_size as usize), 0);
+        ep.write_bytes(bytes.as_slice());
+    }
+
     /// Read Command Status as if it was read by a USB host
     pub fn read_cs(&self) -> Option<Csw> {
         let mut bytes = vec![];
@@ -159,6 +222,16 @@ impl DummyUsbBus {
         ep.write_bytes(data);
     }
 
+    /// Write a single packet as is, e.g. a short or a zero length one, as if it was written by
+    /// a USB host during Host to Device data transfer
+    pub fn write_packet(&self, packet: &[u8]) {
+        let mut lock = self.inner.lock().unwrap();
+        let ep = lock.ep_out.as_mut().unwrap();
+        assert!(packet.len() <= ep.max_packet_size as usize);
+        ep.packets.push_back(packet.to_vec());
+        ep.bytes_written += packet.len();
+    }
+
     /// Read a single packet as if it was read by a USB host during Device to Host data transfer
     pub fn read_packet(&self) -> Option<Vec<u8>> {
         let mut lock = self.inner.lock().unwrap();
@@ -187,6 +260,130 @@ impl DummyUsbBus {
         bytes
     }
 
+    /// Read a Device to Host data transfer as a USB host would: until `n` bytes are read, a short
+    /// packet is received or the point at which the device has stalled the IN endpoint
+    pub fn read_data(&self, n: usize) -> Vec<u8> {
+        let mut lock = self.inner.lock().unwrap();
+        let ep = lock.ep_in.as_mut().unwrap();
+
+        let mut bytes = vec![];
+        while bytes.len() < n && ep.stalled_at.is_none_or(|pos| ep.bytes_read < pos) {
+            match ep.read_packet() {
+                None => {
+                    break;
+                }
+                Some(mut packet) => {
+                    let short = packet.len() < ep.max_packet_size as usize;
+                    bytes.append(&mut packet);
+                    if short {
+                        break;
+                    }
+                }
+            }
+        }
+
+        bytes
+    }
+
+    /// Whether the device has stalled the OUT endpoint
+    pub fn is_out_stalled(&self) -> bool {
+        self.inner.lock().unwrap().ep_out.as_ref().unwrap().stalled
+    }
+
+    /// Clear halt condition of both endpoints as a USB host would during recovery
+    pub fn clear_halt(&self) {
+        let mut lock = self.inner.lock().unwrap();
+        let inner = &mut *lock;
+        for ep in [inner.ep_in.as_mut(), inner.ep_out.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            ep.stalled = false;
+            ep.stalled_at = None;
+        }
+        // the host has aborted the transfer to a stalled endpoint
+        if let Some(ep) = inner.ep_out.as_mut() {
+            ep.packets.clear();
+        }
+    }
+
+    /// Whether the device has stalled the IN endpoint
+    pub fn is_in_stalled(&self) -> bool {
+        self.inner.lock().unwrap().ep_in.as_ref().unwrap().stalled
+    }
+
+    /// Send a control request without a data stage as if it was sent by a USB host.
+    /// Delivered to a device on the next [UsbDevice::poll]
+    ///
+    /// [UsbDevice::poll]: usb_device::device::UsbDevice::poll
+    pub fn control_out(&self, request_type: u8, request: u8, value: u16, index: u16) {
+        self.setup(request_type, request, value, index, 0);
+    }
+
+    /// Send a device to host control request as if it was sent by a USB host.
+    /// Delivered to a device on the next [UsbDevice::poll]. See [control_in_data]
+    ///
+    /// [UsbDevice::poll]: usb_device::device::UsbDevice::poll
+    /// [control_in_data]: DummyUsbBus::control_in_data
+    pub fn control_in(&self, request_type: u8, request: u8, value: u16, index: u16, length: u16) {
+        self.setup(request_type | 0x80, request, value, index, length);
+    }
+
+    /// Data sent by the device in response to the last control request. `None` if rejected
+    pub fn control_in_data(&self) -> Option<Vec<u8>> {
+        let lock = self.inner.lock().unwrap();
+        (!lock.ctrl_stalled).then(|| lock.ctrl_in.clone())
+    }
+
+    fn setup(&self, request_type: u8, request: u8, value: u16, index: u16, length: u16) {
+        let mut setup = [0u8; 8];
+        setup[0] = request_type;
+        setup[1] = request;
+        setup[2..4].copy_from_slice(&value.to_le_bytes());
+        setup[4..6].copy_from_slice(&index.to_le_bytes());
+        setup[6..8].copy_from_slice(&length.to_le_bytes());
+        let mut lock = self.inner.lock().unwrap();
+        lock.ctrl_setup.push_back(setup);
+        lock.ctrl_in.clear();
+        lock.ctrl_stalled = false;
+    }
+
+    /// Send Bulk-Only Mass Storage Reset as if it was sent by a USB host
+    pub fn bulk_only_reset(&self) {
+        self.control_out(0b0010_0001, 0xFF, 0, 0);
+    }
+
+    /// Send Clear Feature HALT to the IN or the OUT endpoint as if it was sent by a USB host
+    pub fn clear_feature_halt(&self, in_ep: bool) {
+        let lock = self.inner.lock().unwrap();
+        let ep = if in_ep { &lock.ep_in } else { &lock.ep_out };
+        let addr = u8::from(ep.as_ref().unwrap().addr);
+        drop(lock);
+        self.control_out(0b0000_0010, 0x01, 0, addr as u16);
+    }
+
+    /// Whether the device has rejected the last control request
+    pub fn is_control_rejected(&self) -> bool {
+        self.inner.lock().unwrap().ctrl_stalled
+    }
+
+    /// Inject a fault into the IN endpoint
+    pub fn inject_in(&self, fault: Fault) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.ep_in.as_mut().unwrap().faults.push_back(fault);
+    }
+
+    /// Inject a fault into the OUT endpoint
+    pub fn inject_out(&self, fault: Fault) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.ep_out.as_mut().unwrap().faults.push_back(fault);
+    }
+
+    /// Whether `err` is the last injected error, which is expected to be returned by a device
+    pub fn is_injected(&self, err: UsbError) -> bool {
+        self.inner.lock().unwrap().injected_error.take() == Some(err)
+    }
+
     pub fn bytes_processed(&self) -> BytesProcessed {
         let lock = self.inner.lock().unwrap();
         BytesProcessed {
@@ -200,6 +397,11 @@ impl DummyUsbBus {
                 .as_ref()
                 .map(|ep| (ep.bytes_written, ep.bytes_read))
                 .unwrap()),
+            faults_hit: [lock.ep_in.as_ref(), lock.ep_out.as_ref()]
+                .into_iter()
+                .flatten()
+                .map(|ep| ep.faults_hit)
+                .sum(),
         }
     }
 }
@@ -208,6 +410,12 @@ struct Inner {
     enabled: bool,
     ep_in: Option<DummyEp>,
     ep_out: Option<DummyEp>,
+    injected_error: Option<UsbError>,
+    /// SETUP packets not read by a device yet
+    ctrl_setup: VecDeque<[u8; 8]>,
+    /// data written by a device to the control IN endpoint
+    ctrl_in: Vec<u8>,
+    ctrl_stalled: bool,
 }
 
 impl Inner {
@@ -216,6 +424,10 @@ impl Inner {
             enabled: false,
             ep_in: None,
             ep_out: None,
+            injected_error: None,
+            ctrl_setup: VecDeque::new(),
+            ctrl_in: Vec::new(),
+            ctrl_stalled: false,
         }
     }
 }
@@ -231,23 +443,22 @@ impl UsbBus for DummyUsbBus {
     ) -> usb_device::Result<EndpointAddress> {
         assert!(!self.inner.lock().unwrap().enabled);
 
-        const EP_OUT_ADDR: usize = 0xFF;
-        const EP_IN_ADDR: usize = 0xEE;
+        const EP_BULK: usize = 1;
         const EP_CTRL: usize = 0;
 
         if matches!(ep_type, EndpointType::Control) {
-            return Ok(EndpointAddress::from(EP_CTRL as u8));
+            return Ok(EndpointAddress::from_parts(EP_CTRL, ep_dir));
         }
 
         let mut lock = self.inner.lock().unwrap();
         let addr = match ep_dir {
             UsbDirection::Out => {
-                let addr = EndpointAddress::from(EP_OUT_ADDR as u8);
+                let addr = EndpointAddress::from_parts(EP_BULK, UsbDirection::Out);
                 lock.ep_out.replace(DummyEp::new(addr, max_packet_size));
                 addr
             }
             UsbDirection::In => {
-                let addr = EndpointAddress::from(EP_IN_ADDR as u8);
+                let addr = EndpointAddress::from_parts(EP_BULK, UsbDirection::In);
                 lock.ep_in.replace(DummyEp::new(addr, max_packet_size));
                 addr
             }
@@ -266,6 +477,10 @@ impl UsbBus for DummyUsbBus {
 
     fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
         let mut lock = self.inner.lock().unwrap();
+        if ep_addr.index() == 0 {
+            lock.ctrl_in.extend_from_slice(buf);
+            return Ok(buf.len());
+        }
         let ep = lock.ep_in.as_mut().unwrap();
 
         if ep.addr != ep_addr {
@@ -276,47 +491,85 @@ impl UsbBus for DummyUsbBus {
             return Err(UsbError::BufferOverflow);
         }
 
-        ep.write_bytes(buf);
-
-        Ok(buf.len())
+        match ep.take_fault() {
+            Some(Fault::Error(err)) => {
+                lock.injected_error = Some(err);
+                Err(err)
+            }
+            Some(Fault::WouldBlock(_)) => Err(UsbError::WouldBlock),
+            Some(Fault::Truncate(n)) => {
+                let n = n.min(buf.len());
+                if n > 0 {
+                    ep.write_bytes(&buf[..n]);
+                }
+                Ok(n)
+            }
+            None => {
+                ep.write_bytes(buf);
+                Ok(buf.len())
+            }
+        }
     }
 
     fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
         let mut lock = self.inner.lock().unwrap();
+        if ep_addr.index() == 0 {
+            let setup = lock.ctrl_setup.pop_front().ok_or(UsbError::WouldBlock)?;
+            buf[..setup.len()].copy_from_slice(&setup);
+            return Ok(setup.len());
+        }
         let ep = lock.ep_out.as_mut().unwrap();
 
         if ep.addr != ep_addr {
             return Err(UsbError::InvalidEndpoint);
         }
 
+        if ep.stalled {
+            return Err(UsbError::WouldBlock); // the host gets STALL handshake
+        }
+
         if let Some(n) = ep.packets.front().map(|p| p.len()) {
             if n > buf.len() {
                 return Err(UsbError::BufferOverflow);
             }
         }
 
-        match ep.read_packet() {
-            Some(packet) => {
-                let n = packet.len();
-                buf[..n].copy_from_slice(packet.as_slice());
-                Ok(n)
-            }
-            None => Err(UsbError::WouldBlock),
+        if ep.packets.is_empty() {
+            return Err(UsbError::WouldBlock);
         }
+
+        let truncate_to = match ep.take_fault() {
+            Some(Fault::Error(err)) => {
+                lock.injected_error = Some(err);
+                return Err(err);
+            }
+            Some(Fault::WouldBlock(_)) => return Err(UsbError::WouldBlock),
+            Some(Fault::Truncate(n)) => n,
+            None => usize::MAX,
+        };
+
+        let packet = ep.read_packet().unwrap();
+        let n = packet.len().min(truncate_to);
+        buf[..n].copy_from_slice(&packet[..n]);
+        Ok(n)
     }
 
     fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
         let mut lock = self.inner.lock().unwrap();
+        if ep_addr.index() == 0 {
+            lock.ctrl_stalled = stalled;
+            return;
+        }
 
         if let Some(ep) = lock.ep_in.as_mut() {
             if ep.addr == ep_addr {
-                return ep.stalled = stalled;
+                return ep.set_stalled(stalled);
             }
         }
 
         if let Some(ep) = lock.ep_out.as_mut() {
             if ep.addr == ep_addr {
-                ep.stalled = stalled
+                ep.set_stalled(stalled)
             }
         }
     }
@@ -344,6 +597,27 @@ impl UsbBus for DummyUsbBus {
     fn resume(&self) {}
 
     fn poll(&self) -> PollResult {
-        PollResult::None
+        const EP_BULK_BIT: u16 = 1 << 1;
+
+        let lock = self.inner.lock().unwrap();
+        let ep_setup = !lock.ctrl_setup.is_empty() as u16;
+        // OUT packets are pending, the host has read all the IN packets
+        let ep_out = match lock.ep_out.as_ref() {
+            Some(ep) if !ep.packets.is_empty() => EP_BULK_BIT,
+            _ => 0,
+        };
+        let ep_in_complete = match lock.ep_in.as_ref() {
+            Some(ep) if ep.packets.is_empty() => EP_BULK_BIT,
+            _ => 0,
+        };
+        if ep_setup | ep_out | ep_in_complete == 0 {
+            PollResult::None
+        } else {
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        }
     }
 }
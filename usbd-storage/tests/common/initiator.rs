@@ -0,0 +1,267 @@
+use crate::common::bbb::{Cbw, CommandStatus, Csw, DataDirection, DummyUsbBus};
+use crate::common::scsi::cmd_into_bytes;
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use usbd_storage::subclass::scsi::ScsiCommand;
+
+/// Max number of consecutive idle device polls until a command is considered complete
+const IDLE_POLLS: usize = 4;
+
+/// Host side Bulk Only Transport initiator
+///
+/// Issues commands over [DummyUsbBus] driving the Device via `poll` in between.
+pub struct Initiator<'a, F: FnMut()> {
+    bus: &'a DummyUsbBus,
+    poll: F,
+    idle_polls: usize,
+    lun: u8,
+}
+
+impl<'a, F: FnMut()> Initiator<'a, F> {
+    pub fn new(bus: &'a DummyUsbBus, poll: F) -> Self {
+        Self {
+            bus,
+            poll,
+            idle_polls: IDLE_POLLS,
+            lun: 0,
+        }
+    }
+
+    /// Sets the number of consecutive idle device polls until a command is considered complete,
+    /// e.g. to wait for a slow medium
+    pub fn with_idle_polls(mut self, idle_polls: usize) -> Self {
+        self.idle_polls = idle_polls;
+        self
+    }
+
+    /// Addresses the following commands to a Logical Unit, `0` by default
+    pub fn set_lun(&mut self, lun: u8) {
+        self.lun = lun;
+    }
+
+    /// Executes a single command returning the data read from the Device and the status
+    pub fn execute(
+        &mut self,
+        cmd: ScsiCommand,
+        direction: DataDirection,
+        data_transfer_len: u32,
+        data_out: &[u8],
+    ) -> (Vec<u8>, Csw) {
+        self.execute_raw(cmd_into_bytes(cmd), direction, data_transfer_len, data_out)
+    }
+
+    /// Same as [Initiator::execute] with a raw command block, e.g. of a command the subclass
+    /// doesn't parse
+    pub fn execute_raw(
+        &mut self,
+        block: Vec<u8>,
+        direction: DataDirection,
+        data_transfer_len: u32,
+        data_out: &[u8],
+    ) -> (Vec<u8>, Csw) {
+        let is_in = matches!(direction, DataDirection::In);
+        let cbw = Cbw {
+            data_transfer_len,
+            direction,
+            block,
+        };
+        self.bus.write_cbw_to_lun(cbw, self.lun);
+        self.bus.write_data(data_out);
+
+        self.drive();
+
+        let data_in = if is_in {
+            self.bus.read_data(data_transfer_len as usize)
+        } else {
+            vec![]
+        };
+        let csw = self.bus.read_cs().expect("no CSW received");
+        self.bus.clear_halt();
+
+        (data_in, csw)
+    }
+
+    pub fn test_unit_ready(&mut self) -> CommandStatus {
+        self.execute(
+            ScsiCommand::TestUnitReady,
+            DataDirection::NotExpected,
+            0,
+            &[],
+        )
+        .1
+        .status
+    }
+
+    pub fn inquiry(&mut self) -> Vec<u8> {
+        let cmd = ScsiCommand::Inquiry {
+            evpd: false,
+            page_code: 0,
+            alloc_len: 36,
+        };
+        let (data, csw) = self.execute(cmd, DataDirection::In, 36, &[]);
+        assert_eq!(CommandStatus::Passed, csw.status);
+        data
+    }
+
+    /// Returns (num_blocks, block_size)
+    pub fn read_capacity_10(&mut self) -> (u64, usize) {
+        let cmd = ScsiCommand::ReadCapacity10 { lba: 0, pmi: false };
+        let (data, csw) = self.execute(cmd, DataDirection::In, 8, &[]);
+        assert_eq!(CommandStatus::Passed, csw.status);
+        assert_eq!(0, csw.data_transfer_len);
+        let last_lba = u32::from_be_bytes(data[..4].try_into().unwrap());
+        let block_size = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        (last_lba as u64 + 1, block_size as usize)
+    }
+
+    pub fn read_10(&mut self, lba: u64, len: u64, block_size: usize) -> Vec<u8> {
+        let transfer_len = (len as usize * block_size) as u32;
+        let (data, csw) = self.execute(
+            ScsiCommand::Read { lba, len },
+            DataDirection::In,
+            transfer_len,
+            &[],
+        );
+        assert_eq!(CommandStatus::Passed, csw.status);
+        assert_eq!(0, csw.data_transfer_len);
+        assert_eq!(transfer_len as usize, data.len());
+        data
+    }
+
+    pub fn write_10(&mut self, lba: u64, data: &[u8], block_size: usize) {
+        assert_eq!(0, data.len() % block_size);
+        let len = (data.len() / block_size) as u64;
+        let (_, csw) = self.execute(
+            ScsiCommand::Write { lba, len },
+            DataDirection::Out,
+            data.len() as u32,
+            data,
+        );
+        assert_eq!(CommandStatus::Passed, csw.status);
+        assert_eq!(0, csw.data_transfer_len);
+    }
+
+    /// Polls the Device until no more bytes are being processed
+    fn drive(&mut self) {
+        let mut bytes_processed = self.bus.bytes_processed();
+        let mut idle = 0;
+        while idle < self.idle_polls {
+            (self.poll)();
+            let new = self.bus.bytes_processed();
+            if new == bytes_processed {
+                idle += 1;
+            } else {
+                idle = 0;
+                bytes_processed = new;
+            }
+        }
+    }
+}
+
+/// A seekable byte stream over the Device's logical blocks backed by [Initiator]
+pub struct BlockStream<'a, F: FnMut()> {
+    initiator: Initiator<'a, F>,
+    num_blocks: u64,
+    block_size: usize,
+    pos: u64,
+}
+
+impl<'a, F: FnMut()> BlockStream<'a, F> {
+    /// Max number of blocks to transfer with a single command
+    const MAX_BLOCKS: u64 = 8;
+
+    pub fn new(mut initiator: Initiator<'a, F>) -> Self {
+        let (num_blocks, block_size) = initiator.read_capacity_10();
+        Self {
+            initiator,
+            num_blocks,
+            block_size,
+            pos: 0,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.num_blocks * self.block_size as u64
+    }
+
+    /// Returns (lba, offset in block, number of whole blocks covered by `count` bytes)
+    fn split(&self, count: usize) -> (u64, usize, u64) {
+        let lba = self.pos / self.block_size as u64;
+        let offset = (self.pos % self.block_size as u64) as usize;
+        let blocks = if offset == 0 {
+            (count / self.block_size) as u64
+        } else {
+            0
+        };
+        (lba, offset, blocks.min(Self::MAX_BLOCKS))
+    }
+}
+
+impl<F: FnMut()> Read for BlockStream<'_, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = buf
+            .len()
+            .min((self.len() - self.pos.min(self.len())) as usize);
+        if count == 0 {
+            return Ok(0);
+        }
+        let (lba, offset, blocks) = self.split(count);
+        let n = if blocks > 0 {
+            let data = self.initiator.read_10(lba, blocks, self.block_size);
+            buf[..data.len()].copy_from_slice(&data);
+            data.len()
+        } else {
+            let data = self.initiator.read_10(lba, 1, self.block_size);
+            let n = count.min(self.block_size - offset);
+            buf[..n].copy_from_slice(&data[offset..offset + n]);
+            n
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<F: FnMut()> Write for BlockStream<'_, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = buf
+            .len()
+            .min((self.len() - self.pos.min(self.len())) as usize);
+        if count == 0 {
+            return Err(io::Error::from(ErrorKind::WriteZero));
+        }
+        let (lba, offset, blocks) = self.split(count);
+        let n = if blocks > 0 {
+            let n = blocks as usize * self.block_size;
+            self.initiator.write_10(lba, &buf[..n], self.block_size);
+            n
+        } else {
+            // read-modify-write a partial block
+            let mut data = self.initiator.read_10(lba, 1, self.block_size);
+            let n = count.min(self.block_size - offset);
+            data[offset..offset + n].copy_from_slice(&buf[..n]);
+            self.initiator.write_10(lba, &data, self.block_size);
+            n
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<F: FnMut()> Seek for BlockStream<'_, F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => self.len() as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new < 0 || new as u64 > self.len() {
+            return Err(io::Error::from(ErrorKind::InvalidInput));
+        }
+        self.pos = new as u64;
+        Ok(self.pos)
+    }
+}
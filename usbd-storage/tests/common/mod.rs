@@ -1,10 +1,22 @@
+// shared between test crates, each of which uses only a part of it
+#![allow(dead_code)]
+
 use std::sync::mpsc::sync_channel;
 use std::thread;
 use std::time::Duration;
 use usbd_storage::subclass::Command;
 
 pub mod bbb;
+#[cfg(feature = "scsi")]
+pub mod initiator;
+#[cfg(feature = "scsi")]
+pub mod ramdisk;
+#[cfg(feature = "scsi")]
 pub mod scsi;
+#[cfg(feature = "scsi")]
+pub mod slowdisk;
+#[cfg(feature = "ufi")]
+pub mod ufi;
 
 pub const PACKET_SIZE: [u16; 4] = [8, 16, 32, 64];
 
@@ -15,12 +27,17 @@ pub enum Step<BUS, CMD, CLASS> {
     DevIo,
     /// Handle a command on the Device side
     DevCmdHandle(fn(Command<CMD, CLASS>) -> ()),
+    /// Act on the Device class directly
+    DevAction(fn(&mut CLASS) -> ()),
 }
 
 // perhaps not the best way, but it's easier that battling against escaped borrows in closures
 #[macro_export]
 macro_rules! run_on_scsi_bbb_bus_timed {
     { $timeout:expr, $steps:expr } => {
+        run_on_scsi_bbb_bus_timed! { $timeout, |_| {}, $steps }
+    };
+    { $timeout:expr, $setup:expr, $steps:expr } => {
             use common;
 
             common::timeout($timeout, || {
@@ -33,12 +50,18 @@ macro_rules! run_on_scsi_bbb_bus_timed {
                 let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
                 let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
 
+                let setup: fn(&mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>) = $setup;
+                setup(&mut scsi);
+
                 for step in &steps {
                     match step {
                         Step::DevIo => {
                             let mut bytes_processed = dummy_bus.bytes_processed();
                             loop {
-                                scsi.poll(|_| {}).unwrap();
+                                match scsi.poll(|_| {}) {
+                                    Err(err) if dummy_bus.is_injected(err) => {}
+                                    res => res.unwrap(),
+                                }
                                 let new = dummy_bus.bytes_processed();
                                 if new == bytes_processed {
                                     break;
@@ -50,14 +73,20 @@ macro_rules! run_on_scsi_bbb_bus_timed {
                         Step::HostIo(func) => {
                             func(&dummy_bus);
                         }
+                        Step::DevAction(func) => {
+                            func(&mut scsi);
+                        }
                         Step::DevCmdHandle(func) => {
                             let mut command_processed = false;
                             loop {
-                                scsi.poll(|command| {
+                                let res = scsi.poll(|command| {
                                     func(command);
                                     command_processed = true;
-                                })
-                                    .unwrap();
+                                });
+                                match res {
+                                    Err(err) if dummy_bus.is_injected(err) => {}
+                                    res => res.unwrap(),
+                                }
 
                                 if command_processed {
                                     break;
@@ -0,0 +1,120 @@
+use usb_device::bus::UsbBus;
+use usbd_storage::subclass::scsi::block::BlockDevice;
+use usbd_storage::subclass::scsi::capacity::BlockSize;
+use usbd_storage::subclass::scsi::sense::Sense;
+use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+use usbd_storage::subclass::Command;
+use usbd_storage::transport::bbb::BulkOnly;
+
+/// A RAM backed SCSI block device handling commands on the Device side
+pub struct RamDisk {
+    block_size: usize,
+    data: Vec<u8>,
+    /// bytes transferred so far by the current Read/Write command
+    offset: usize,
+}
+
+impl RamDisk {
+    pub fn new(block_size: usize, num_blocks: usize) -> Self {
+        Self {
+            block_size,
+            data: vec![0u8; block_size * num_blocks],
+            offset: 0,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.data.len() / self.block_size
+    }
+
+    pub fn handle<Bus: UsbBus>(
+        &mut self,
+        mut cmd: Command<ScsiCommand, Scsi<BulkOnly<Bus, &mut [u8]>>>,
+    ) {
+        match cmd.kind {
+            ScsiCommand::TestUnitReady | ScsiCommand::PreventAllowMediumRemoval { .. } => {
+                cmd.pass();
+            }
+            ScsiCommand::Inquiry { .. } => {
+                let mut data = [0u8; 36];
+                data[1] = 0x80; // removable
+                data[2] = 0x04; // SPC-2
+                data[3] = 0x02; // response data format
+                data[4] = 32; // additional length
+                data[8..16].copy_from_slice(b"USBDSTOR");
+                data[16..32].copy_from_slice(b"RAM DISK        ");
+                data[32..36].copy_from_slice(b"1.00");
+                cmd.try_write_data_all(&data).unwrap();
+                cmd.pass();
+            }
+            ScsiCommand::ReadCapacity10 { .. } => {
+                let mut data = [0u8; 8];
+                data[..4].copy_from_slice(&(self.num_blocks() as u32 - 1).to_be_bytes());
+                data[4..].copy_from_slice(&(self.block_size as u32).to_be_bytes());
+                cmd.try_write_data_all(&data).unwrap();
+                cmd.pass();
+            }
+            ScsiCommand::Read { lba, len } => {
+                let start = lba as usize * self.block_size;
+                let total = len as usize * self.block_size;
+                if self.offset < total {
+                    let from = start + self.offset;
+                    self.offset += cmd.write_data(&self.data[from..start + total]).unwrap();
+                }
+                if self.offset == total {
+                    self.offset = 0;
+                    cmd.pass();
+                }
+            }
+            ScsiCommand::Write { lba, len } => {
+                let start = lba as usize * self.block_size;
+                let total = len as usize * self.block_size;
+                if self.offset < total {
+                    let from = start + self.offset;
+                    self.offset += cmd.read_data(&mut self.data[from..start + total]).unwrap();
+                }
+                if self.offset == total {
+                    self.offset = 0;
+                    cmd.pass();
+                }
+            }
+            _ => {
+                cmd.fail_with_sense(Sense::INVALID_COMMAND_OPERATION_CODE);
+            }
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> BlockSize {
+        BlockSize::new(self.block_size as u32).unwrap()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks() as u64
+    }
+
+    fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), Sense> {
+        let start = lba as usize * self.block_size;
+        block.copy_from_slice(&self.data[start..start + self.block_size]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), Sense> {
+        let start = lba as usize * self.block_size;
+        self.data[start..start + self.block_size].copy_from_slice(block);
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use crate::common::ramdisk::RamDisk;
+use usb_device::bus::UsbBus;
+use usbd_storage::subclass::scsi::sense::Sense;
+use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+use usbd_storage::subclass::Command;
+use usbd_storage::transport::bbb::BulkOnly;
+
+/// A [RamDisk] behaving like slow media
+///
+/// Each block of a Read/Write becomes available only after `latency` device polls, in the
+/// meantime IN data is reported pending and OUT data is left unread, so the host is NAKed.
+/// One in `error_rate` blocks, chosen pseudo-randomly, fails the command with a medium error.
+pub struct SlowDisk {
+    disk: RamDisk,
+    latency: usize,
+    error_rate: u32,
+    rng: u32,
+    /// polls left until the next block is available
+    wait: usize,
+    /// bytes transferred so far by the current Read/Write command
+    offset: usize,
+    /// bytes of the current Read/Write command available to transfer so far
+    ready: usize,
+    /// number of blocks failed so far
+    errors: usize,
+}
+
+impl SlowDisk {
+    /// `error_rate` of `0` never fails
+    pub fn new(disk: RamDisk, latency: usize, error_rate: u32, seed: u32) -> Self {
+        Self {
+            disk,
+            latency,
+            error_rate,
+            rng: seed.max(1),
+            wait: latency,
+            offset: 0,
+            ready: 0,
+            errors: 0,
+        }
+    }
+
+    pub fn disk(&self) -> &RamDisk {
+        &self.disk
+    }
+
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+
+    pub fn handle<Bus: UsbBus>(
+        &mut self,
+        mut cmd: Command<ScsiCommand, Scsi<BulkOnly<Bus, &mut [u8]>>>,
+    ) {
+        let block_size = self.disk.block_size();
+        match cmd.kind {
+            ScsiCommand::Read { lba, len } => {
+                let start = lba as usize * block_size;
+                let total = len as usize * block_size;
+                if self.offset == self.ready && self.ready < total {
+                    if !self.fetch() {
+                        cmd.pending();
+                        return;
+                    }
+                    if self.roll_error() {
+                        self.finish();
+                        cmd.fail_with_sense(Sense::UNRECOVERED_READ_ERROR);
+                        return;
+                    }
+                    self.ready += block_size;
+                }
+                if self.offset < self.ready {
+                    let data = &self.disk.data()[start + self.offset..start + self.ready];
+                    self.offset += cmd.write_data(data).unwrap();
+                }
+                if self.offset == total {
+                    self.finish();
+                    cmd.pass();
+                }
+            }
+            ScsiCommand::Write { lba, len } => {
+                let start = lba as usize * block_size;
+                let total = len as usize * block_size;
+                if self.offset == self.ready && self.ready < total {
+                    if !self.fetch() {
+                        return;
+                    }
+                    if self.roll_error() {
+                        self.finish();
+                        cmd.fail_with_sense(Sense::WRITE_ERROR);
+                        return;
+                    }
+                    self.ready += block_size;
+                }
+                let data = &mut self.disk.data_mut()[start + self.offset..start + self.ready];
+                self.offset += cmd.read_data(data).unwrap();
+                if self.offset == total {
+                    self.finish();
+                    cmd.pass();
+                }
+            }
+            _ => self.disk.handle(cmd),
+        }
+    }
+
+    /// Whether the next block is available, counting down the latency otherwise
+    fn fetch(&mut self) -> bool {
+        if self.wait > 0 {
+            self.wait -= 1;
+            false
+        } else {
+            self.wait = self.latency;
+            true
+        }
+    }
+
+    fn roll_error(&mut self) -> bool {
+        // xorshift32
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        let failed = self.error_rate != 0 && self.rng.is_multiple_of(self.error_rate);
+        self.errors += failed as usize;
+        failed
+    }
+
+    fn finish(&mut self) {
+        self.offset = 0;
+        self.ready = 0;
+        self.wait = self.latency;
+    }
+}
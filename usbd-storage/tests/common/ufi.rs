@@ -0,0 +1,8 @@
+use usbd_storage::subclass::ufi::serialize::CB_LEN;
+use usbd_storage::subclass::ufi::UfiCommand;
+
+pub fn cmd_into_bytes(cmd: UfiCommand) -> Vec<u8> {
+    let mut cb = [0u8; CB_LEN];
+    let len = usbd_storage::subclass::ufi::serialize::cmd_into_bytes(cmd, &mut cb);
+    cb[..len].to_vec()
+}
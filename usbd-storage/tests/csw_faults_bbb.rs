@@ -0,0 +1,74 @@
+mod common;
+
+use crate::common::bbb::{Cbw, DataDirection, DummyUsbBus};
+use crate::common::scsi::cmd_into_bytes;
+use std::time::Duration;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+use usbd_storage::transport::bbb::CswFault;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// Number of device polls after which a command is considered done
+const POLLS: usize = 64;
+const TAG: u32 = 0;
+
+/// Reads the raw CSW of a command regardless of its validity
+fn read_raw_csw(bus: &DummyUsbBus) -> Vec<u8> {
+    let mut csw = vec![];
+    while csw.len() < 13 {
+        csw.append(&mut bus.read_packet().expect("CSW"));
+    }
+    csw
+}
+
+#[test]
+fn should_emit_injected_csw_faults() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 512];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+            let mut execute = |fault: Option<CswFault>| {
+                if let Some(fault) = fault {
+                    scsi.transport_mut().inject_csw_fault(fault);
+                }
+                bus.write_cbw(Cbw {
+                    data_transfer_len: 0,
+                    direction: DataDirection::NotExpected,
+                    block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+                });
+                for _ in 0..POLLS {
+                    scsi.poll(|cmd| cmd.pass()).unwrap();
+                }
+                read_raw_csw(&bus)
+            };
+
+            let csw = execute(Some(CswFault::WrongTag));
+            assert_eq!(!TAG, u32::from_le_bytes(csw[4..8].try_into().unwrap()));
+            assert_eq!(b"USBS", &csw[..4]);
+
+            let csw = execute(Some(CswFault::WrongSignature));
+            assert_ne!(b"USBS", &csw[..4]);
+            assert_eq!(TAG, u32::from_le_bytes(csw[4..8].try_into().unwrap()));
+
+            let csw = execute(Some(CswFault::BadResidue));
+            assert_eq!(1, u32::from_le_bytes(csw[8..12].try_into().unwrap()));
+
+            // a single CSW is affected
+            let csw = execute(None);
+            assert_eq!(b"USBS", &csw[..4]);
+            assert_eq!(
+                (TAG, 0, 0x00),
+                (
+                    u32::from_le_bytes(csw[4..8].try_into().unwrap()),
+                    u32::from_le_bytes(csw[8..12].try_into().unwrap()),
+                    csw[12]
+                )
+            );
+        }
+    });
+}
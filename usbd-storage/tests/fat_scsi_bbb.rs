@@ -0,0 +1,91 @@
+mod common;
+
+use crate::common::bbb::{CommandStatus, DummyUsbBus};
+use crate::common::initiator::{BlockStream, Initiator};
+use crate::common::ramdisk::RamDisk;
+use fatfs::{FileSystem, FormatVolumeOptions, FsOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usbd_storage::subclass::scsi::capacity::BlockSize;
+use usbd_storage::subclass::scsi::Scsi;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Larger blocks are tested with some packet sizes only as partial block writes of `fatfs` are slow
+const LARGE_BLOCKS: [(u16, BlockSize); 3] = [
+    (64, BlockSize::B2048),
+    (16, BlockSize::B4096),
+    (64, BlockSize::B4096),
+];
+const VOLUME_SIZE: usize = 1024 * 1024;
+
+const FILE_NAME: &str = "HELLO.TXT";
+const FILE_CONTENTS: &[u8] = b"Hello from usbd-storage!";
+
+#[test]
+fn should_format_mount_and_round_trip_fat_volume() {
+    common::timeout(TIMEOUT, || {
+        for (packet_size, block_size) in common::PACKET_SIZE
+            .map(|packet_size| (packet_size, BlockSize::B512))
+            .into_iter()
+            .chain(LARGE_BLOCKS)
+        {
+            let block_len = block_size.get() as usize;
+            let mut io_buf = [0u8; 1024];
+            let dummy_bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(dummy_bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let mut disk = RamDisk::new(block_len, VOLUME_SIZE / block_len);
+            scsi.set_block_size(0, block_size);
+            scsi.set_capacity(0, disk.num_blocks() as u64);
+
+            {
+                let mut initiator = Initiator::new(&dummy_bus, || {
+                    scsi.poll(|command| disk.handle(command)).unwrap();
+                });
+
+                // what a host does before mounting
+                assert_eq!(36, initiator.inquiry().len());
+                assert_eq!(CommandStatus::Passed, initiator.test_unit_ready());
+
+                let mut stream = BlockStream::new(initiator);
+                let options = FormatVolumeOptions::new().bytes_per_sector(block_len as u16);
+                fatfs::format_volume(&mut stream, options).unwrap();
+
+                // mount and write
+                stream.seek(SeekFrom::Start(0)).unwrap();
+                {
+                    let fs = FileSystem::new(&mut stream, FsOptions::new()).unwrap();
+                    let root = fs.root_dir();
+                    root.create_dir("DIR").unwrap();
+                    let mut file = root.create_file(FILE_NAME).unwrap();
+                    file.write_all(FILE_CONTENTS).unwrap();
+                    file.flush().unwrap();
+                    drop(file);
+                    drop(root);
+                    fs.unmount().unwrap();
+                }
+
+                // mount again and read back
+                stream.seek(SeekFrom::Start(0)).unwrap();
+                {
+                    let fs = FileSystem::new(&mut stream, FsOptions::new()).unwrap();
+                    let root = fs.root_dir();
+                    assert!(root.open_dir("DIR").is_ok());
+                    let mut contents = vec![];
+                    root.open_file(FILE_NAME)
+                        .unwrap()
+                        .read_to_end(&mut contents)
+                        .unwrap();
+                    assert_eq!(FILE_CONTENTS, contents.as_slice());
+                }
+            }
+
+            // boot sector signature is on the media
+            assert_eq!([0x55, 0xAA], disk.data()[510..512]);
+        }
+    });
+}
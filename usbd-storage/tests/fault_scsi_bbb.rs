@@ -0,0 +1,250 @@
+mod common;
+
+use crate::common::bbb::{Cbw, CommandStatus, Csw, DataDirection, DummyUsbBus, Fault};
+use crate::common::scsi::cmd_into_bytes;
+use crate::common::Step;
+use std::time::Duration;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usb_device::UsbError;
+use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+use usbd_storage::subclass::Command;
+use usbd_storage::transport::bbb::BulkOnly;
+use usbd_storage::transport::{CommandStatus as TransportCommandStatus, TransportError};
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+fn write_read_cbw(bus: &DummyUsbBus) {
+    let cbw = Cbw {
+        data_transfer_len: 512,
+        direction: DataDirection::In,
+        block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+    };
+    bus.write_cbw(cbw);
+}
+
+fn write_block(mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>) {
+    cmd.try_write_data_all([0xAAu8; 512].as_slice()).unwrap();
+    cmd.pass();
+}
+
+fn read_block(mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>) {
+    let mut buf = [0u8; 512];
+    assert_eq!(512, cmd.read_data(buf.as_mut_slice()).unwrap()); // a block has been buffered
+    assert_eq!([0x55u8; 512], buf);
+    cmd.pass();
+}
+
+fn assert_block_read(bus: &DummyUsbBus) {
+    assert_eq!([0xAAu8; 512].as_slice(), bus.read_n_bytes(512).as_slice());
+    assert_passed(bus);
+}
+
+fn assert_passed(bus: &DummyUsbBus) {
+    let expected_csw = Csw {
+        data_transfer_len: 0,
+        status: CommandStatus::Passed,
+    };
+    assert_eq!(expected_csw, bus.read_cs().unwrap());
+}
+
+#[test]
+fn should_recover_from_would_block_storm_writing_to_host() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            write_read_cbw(bus);
+            bus.inject_in(Fault::WouldBlock(100));
+        }),
+        Step::DevCmdHandle(write_block),
+        Step::DevIo,
+        Step::HostIo(assert_block_read),
+    ] }
+}
+
+#[test]
+fn should_recover_from_error_writing_to_host() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            write_read_cbw(bus);
+            bus.inject_in(Fault::Error(UsbError::Unsupported));
+            bus.inject_in(Fault::WouldBlock(3));
+            bus.inject_in(Fault::Error(UsbError::Unsupported));
+        }),
+        Step::DevCmdHandle(write_block),
+        Step::DevIo,
+        Step::HostIo(assert_block_read),
+    ] }
+}
+
+#[test]
+fn should_resend_rest_of_truncated_packet_to_host() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            write_read_cbw(bus);
+            bus.inject_in(Fault::Truncate(4));
+        }),
+        Step::DevCmdHandle(write_block),
+        Step::DevIo,
+        Step::HostIo(assert_block_read),
+    ] }
+}
+
+#[test]
+fn should_recover_from_errors_reading_from_host() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.inject_out(Fault::WouldBlock(10));
+            bus.inject_out(Fault::Error(UsbError::Unsupported));
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+            bus.write_data([0x55u8; 512].as_slice());
+            bus.inject_out(Fault::Error(UsbError::Unsupported));
+            bus.inject_out(Fault::WouldBlock(10));
+        }),
+        Step::DevIo,
+        Step::DevCmdHandle(read_block),
+        Step::DevIo,
+        Step::HostIo(assert_passed),
+    ] }
+}
+
+#[test]
+fn should_recover_from_spurious_reset_reading_from_host() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 1024,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 2 }),
+            };
+            bus.write_cbw(cbw);
+            bus.write_data([0x55u8; 512].as_slice());
+        }),
+        Step::DevIo,
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                let mut buf = [0u8; 512];
+                cmd.read_data(buf.as_mut_slice()).unwrap(); // the second block never comes
+            },
+        ),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            UsbClass::reset(scsi);
+            assert!(scsi.take_aborted().is_some());
+        }),
+        Step::HostIo(write_read_cbw),
+        Step::DevCmdHandle(write_block),
+        Step::DevIo,
+        Step::HostIo(assert_block_read),
+    ] }
+}
+
+#[test]
+fn should_retry_busy_endpoints_within_single_transfer() {
+    common::timeout(TIMEOUT, || {
+        for retries in [0, 2, 3] {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            scsi.transport_mut().set_io_retries(retries);
+
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+            };
+            bus.write_cbw(cbw);
+            bus.inject_out(Fault::WouldBlock(3));
+            let res = scsi.transport_mut().read();
+            if retries < 3 {
+                assert!(matches!(
+                    res,
+                    Err(TransportError::Usb(UsbError::WouldBlock))
+                ));
+                assert!(scsi.transport().get_command().is_none());
+                continue;
+            }
+            res.unwrap();
+            assert!(scsi.transport().get_command().is_some());
+
+            bus.inject_in(Fault::WouldBlock(3));
+            scsi.transport_mut()
+                .set_status(TransportCommandStatus::Passed);
+            scsi.transport_mut().write().unwrap(); // the CSW is flushed by a single write
+            assert_passed(&bus);
+        }
+    });
+}
+
+#[test]
+fn should_count_busy_endpoints_per_phase() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        scsi.transport_mut().set_io_retries(1);
+
+        let cbw = Cbw {
+            data_transfer_len: 0,
+            direction: DataDirection::NotExpected,
+            block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+        };
+        bus.write_cbw(cbw);
+        bus.inject_out(Fault::WouldBlock(3));
+        assert!(scsi.transport_mut().read().is_err()); // both attempts
+        scsi.transport_mut().read().unwrap();
+        bus.inject_in(Fault::WouldBlock(1));
+        scsi.transport_mut()
+            .set_status(TransportCommandStatus::Passed);
+        scsi.transport_mut().write().unwrap();
+        assert_passed(&bus);
+
+        write_read_cbw(&bus);
+        scsi.transport_mut().read().unwrap();
+        scsi.transport_mut()
+            .try_write_data_all([0xAAu8; 512].as_slice())
+            .unwrap();
+        bus.inject_in(Fault::WouldBlock(2));
+        assert!(scsi.transport_mut().write().is_err());
+
+        let stats = scsi.transport().stats();
+        assert_eq!(3, stats.command_busy);
+        assert_eq!(2, stats.data_busy);
+        assert_eq!(1, stats.status_busy);
+        scsi.transport_mut().reset_stats();
+        assert_eq!(0, scsi.transport().stats().data_busy);
+    });
+}
+
+#[test]
+fn should_not_count_zero_length_packets_as_data() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.write_data(&[]); // a stray zero length packet before the CBW
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+            bus.write_data(&[]);
+            bus.inject_out(Fault::WouldBlock(5));
+            bus.write_data([0x55u8; 512].as_slice());
+        }),
+        // a zero length packet doesn't move the transfer, so a device is driven past each one
+        Step::DevIo,
+        Step::DevIo,
+        Step::DevIo,
+        Step::DevCmdHandle(read_block),
+        Step::DevIo,
+        Step::HostIo(assert_passed),
+    ] }
+}
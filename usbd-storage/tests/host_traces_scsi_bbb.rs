@@ -0,0 +1,239 @@
+//! Command sequences modelled after the ones hosts issue on mounting a drive
+//!
+//! Each trace is replayed against the whole stack with a [RamDisk] behind it. The framing of
+//! every response is checked: the status, the residue and the amount of IN data. The traces
+//! follow what Linux, Windows and macOS are known to send and are expected to be extended with
+//! any sequence a host is found to choke on.
+
+mod common;
+
+use crate::common::bbb::{CommandStatus, DataDirection, DummyUsbBus};
+use crate::common::initiator::Initiator;
+use crate::common::ramdisk::RamDisk;
+use crate::common::scsi::cmd_into_bytes;
+use std::time::Duration;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usbd_storage::subclass::scsi::fingerprint::HostOs;
+use usbd_storage::subclass::scsi::inquiry::InquiryData;
+use usbd_storage::subclass::scsi::{PageControl, Scsi, ScsiCommand};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const BLOCK_SIZE: usize = 512;
+const NUM_BLOCKS: usize = 64;
+
+/// SYNCHRONIZE CACHE(10), not parsed by the subclass
+const SYNCHRONIZE_CACHE_10: [u8; 10] = [0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// A command of a trace along with the response the host expects
+struct Exchange {
+    block: Vec<u8>,
+    direction: DataDirection,
+    data_transfer_len: u32,
+    status: CommandStatus,
+    /// Number of bytes of IN data. The rest of `data_transfer_len` is expected as residue
+    data_len: usize,
+    /// The first block read, if the IN data is blocks of the disk
+    lba: Option<u64>,
+}
+
+fn data_in(cmd: ScsiCommand, data_transfer_len: u32, data_len: usize) -> Exchange {
+    Exchange {
+        block: cmd_into_bytes(cmd),
+        direction: DataDirection::In,
+        data_transfer_len,
+        status: CommandStatus::Passed,
+        data_len,
+        lba: None,
+    }
+}
+
+fn no_data(block: Vec<u8>, status: CommandStatus) -> Exchange {
+    Exchange {
+        block,
+        direction: DataDirection::NotExpected,
+        data_transfer_len: 0,
+        status,
+        data_len: 0,
+        lba: None,
+    }
+}
+
+fn inquiry() -> Exchange {
+    let cmd = ScsiCommand::Inquiry {
+        evpd: false,
+        page_code: 0,
+        alloc_len: 36,
+    };
+    data_in(cmd, 36, 36)
+}
+
+fn test_unit_ready() -> Exchange {
+    no_data(
+        cmd_into_bytes(ScsiCommand::TestUnitReady),
+        CommandStatus::Passed,
+    )
+}
+
+fn read_capacity_10() -> Exchange {
+    data_in(ScsiCommand::ReadCapacity10 { lba: 0, pmi: false }, 8, 8)
+}
+
+fn mode_sense_6(page_code: u8, alloc_len: u8, data_len: usize) -> Exchange {
+    let cmd = ScsiCommand::ModeSense6 {
+        dbd: false,
+        page_control: PageControl::CurrentValues,
+        page_code,
+        subpage_code: 0,
+        alloc_len,
+    };
+    data_in(cmd, alloc_len as u32, data_len)
+}
+
+fn request_sense() -> Exchange {
+    let cmd = ScsiCommand::RequestSense {
+        desc: false,
+        alloc_len: 18,
+    };
+    data_in(cmd, 18, 18)
+}
+
+fn read_10(lba: u64, len: u64) -> Exchange {
+    let data_len = len as usize * BLOCK_SIZE;
+    Exchange {
+        lba: Some(lba),
+        ..data_in(ScsiCommand::Read { lba, len }, data_len as u32, data_len)
+    }
+}
+
+/// Probes the drive as `sd` over `usb-storage` does, asking for all the mode pages with
+/// exactly 192 bytes, and flushes the cache on unmount
+fn linux() -> Vec<Exchange> {
+    vec![
+        inquiry(),
+        test_unit_ready(),
+        read_capacity_10(),
+        // the header and the block descriptor
+        mode_sense_6(0x3F, 192, 12),
+        // the Caching mode page, cut to the header
+        mode_sense_6(0x08, 4, 4),
+        read_10(0, 8),
+        test_unit_ready(),
+        // not supported by the RAM disk, the host asks why
+        no_data(SYNCHRONIZE_CACHE_10.to_vec(), CommandStatus::Failed),
+        request_sense(),
+    ]
+}
+
+/// Asks for the formattable capacities first
+fn windows() -> Vec<Exchange> {
+    let mode_sense_10 = ScsiCommand::ModeSense10 {
+        dbd: false,
+        page_control: PageControl::CurrentValues,
+        page_code: 0x3F,
+        subpage_code: 0,
+        alloc_len: 8,
+    };
+    vec![
+        inquiry(),
+        // the capacity list header and the current capacity descriptor
+        data_in(
+            ScsiCommand::ReadFormatCapacities { alloc_len: 0xFC },
+            0xFC,
+            12,
+        ),
+        inquiry(),
+        read_capacity_10(),
+        // the header only
+        data_in(mode_sense_10, 8, 8),
+        read_10(0, 1),
+        test_unit_ready(),
+        read_10(1, 2),
+    ]
+}
+
+/// Locks the medium and asks for the Informational Exceptions Control mode page
+fn macos() -> Vec<Exchange> {
+    let prevent = ScsiCommand::PreventAllowMediumRemoval { prevent: true };
+    let read_capacity_16 = ScsiCommand::ReadCapacity16 {
+        lba: 0,
+        pmi: false,
+        alloc_len: 32,
+    };
+    vec![
+        inquiry(),
+        test_unit_ready(),
+        no_data(cmd_into_bytes(prevent), CommandStatus::Passed),
+        read_capacity_10(),
+        data_in(read_capacity_16, 32, 32),
+        mode_sense_6(0x1C, 0xFF, 12),
+        read_10(0, 1),
+        read_10(NUM_BLOCKS as u64 - 1, 1),
+    ]
+}
+
+fn replay(trace: fn() -> Vec<Exchange>, host_os: HostOs) {
+    common::timeout(TIMEOUT, move || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let dummy_bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(dummy_bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            scsi.set_inquiry(InquiryData::new("USBDSTOR", "RAM DISK", "1.00"));
+            scsi.set_capacity(0, NUM_BLOCKS as u64);
+            let mut disk = RamDisk::new(BLOCK_SIZE, NUM_BLOCKS);
+            for (i, byte) in disk.data_mut().iter_mut().enumerate() {
+                *byte = (i / BLOCK_SIZE) as u8;
+            }
+
+            {
+                let mut initiator = Initiator::new(&dummy_bus, || {
+                    scsi.poll(|command| disk.handle(command)).unwrap();
+                });
+
+                for (i, exchange) in trace().into_iter().enumerate() {
+                    let (data, csw) = initiator.execute_raw(
+                        exchange.block.clone(),
+                        exchange.direction.clone(),
+                        exchange.data_transfer_len,
+                        &[],
+                    );
+                    let context = format!("exchange {} of {:02X?}", i, exchange.block);
+                    assert_eq!(exchange.status, csw.status, "{}", context);
+                    assert_eq!(exchange.data_len, data.len(), "{}", context);
+                    let residue = match exchange.direction {
+                        DataDirection::In => exchange.data_transfer_len - data.len() as u32,
+                        _ => 0,
+                    };
+                    assert_eq!(residue, csw.data_transfer_len, "{}", context);
+
+                    if let Some(lba) = exchange.lba {
+                        let mut blocks = data.chunks(BLOCK_SIZE).zip(lba..);
+                        let filled =
+                            |(block, lba): (&[u8], u64)| block.iter().all(|b| *b == lba as u8);
+                        assert!(blocks.all(filled), "{}", context);
+                    }
+                }
+            }
+
+            assert_eq!(host_os, scsi.host_os());
+        }
+    });
+}
+
+#[test]
+fn should_replay_linux_mount() {
+    replay(linux, HostOs::Linux);
+}
+
+#[test]
+fn should_replay_windows_mount() {
+    replay(windows, HostOs::Windows);
+}
+
+#[test]
+fn should_replay_macos_mount() {
+    replay(macos, HostOs::MacOs);
+}
@@ -3,12 +3,30 @@ mod common;
 use crate::common::bbb::{Cbw, CommandStatus, Csw, DataDirection, DummyUsbBus};
 use crate::common::scsi::cmd_into_bytes;
 use crate::common::Step;
+use std::cmp::min;
+use std::sync::Mutex;
 use std::time::Duration;
 use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
 use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
-use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+use usb_device::UsbDirection;
+use usbd_storage::quirks::{GetMaxLun, Quirks, Unstall};
+use usbd_storage::reenumerate::force_reenumeration;
+use usbd_storage::subclass::scsi::capacity::BlockSize;
+use usbd_storage::subclass::scsi::fingerprint::HostOs;
+use usbd_storage::subclass::scsi::inquiry::{DeviceIdentity, InquiryData};
+use usbd_storage::subclass::scsi::log::{decode_log_select, LogReset};
+use usbd_storage::subclass::scsi::mode::{caching_mode_page, decode_mode_select_6, ModePage};
+use usbd_storage::subclass::scsi::sense::Sense;
+use usbd_storage::subclass::scsi::{
+    required_buffer_len, PageControl, PeripheralDeviceType, Readiness, Scsi, ScsiCommand,
+    MIN_BUFFER_LEN,
+};
 use usbd_storage::subclass::Command;
-use usbd_storage::transport::bbb::BulkOnly;
+use usbd_storage::transport::bbb::{
+    BulkOnly, BulkOnlyError, CommandPhase, Notification, WriteHint,
+};
+use usbd_storage::transport::{CommandStatus as TransportCommandStatus, Reset};
 
 const TIMEOUT: Duration = Duration::from_secs(1);
 
@@ -154,3 +172,3313 @@ fn should_fail_in_the_middle_writing_data_to_host() {
         }),
     ] }
 }
+
+#[test]
+fn should_stall_in_when_failed_before_writing_data() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| cmd.pending(),
+        ),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            scsi.transport_mut()
+                .send_status(TransportCommandStatus::Failed)
+                .unwrap();
+        }),
+        // no poll in between
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert!(bus.is_in_stalled());
+            assert!(bus.read_data(512).is_empty());
+            let expected_csw = Csw {
+                data_transfer_len: 512,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_end_in_data_with_zlp_when_failed_before_writing_data_with_quirk() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, set_quirks, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| cmd.fail(),
+        ),
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert!(!bus.is_in_stalled());
+            assert_eq!(Some(vec![]), bus.read_packet());
+            let expected_csw = Csw {
+                data_transfer_len: 512,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_pass_reserve_and_release_without_user_action() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::Reserve6),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::Release6),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_fail_reading_out_of_range_with_sense() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| scsi.set_capacity(0, 100),
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 1024,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 99, len: 2 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 1024,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            bus.clear_halt();
+
+            let cbw = Cbw {
+                data_transfer_len: 18,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::RequestSense { desc: false, alloc_len: 18 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let sense = bus.read_data(18);
+            assert_eq!(18, sense.len());
+            assert_eq!([0x70, 0x05, 0x21, 0x00], [sense[0], sense[2], sense[12], sense[13]]);
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_report_sense_in_descriptor_format() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| scsi.set_sense(
+            0,
+            Sense {
+                information: Some(0x1234),
+                ..Sense::LBA_OUT_OF_RANGE
+            },
+        ),
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 252,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::RequestSense { desc: true, alloc_len: 252 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let sense = bus.read_data(252);
+            assert_eq!(20, sense.len());
+            assert_eq!([0x72, 0x05, 0x21, 0x00], sense[..4]);
+            assert_eq!(12, sense[7]);
+            assert_eq!([0x00, 0x0A, 0x80, 0x00], sense[8..12]);
+            assert_eq!(0x1234u64.to_be_bytes(), sense[12..20]);
+            let expected_csw = Csw {
+                data_transfer_len: 232,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_report_queued_sense_in_order() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            scsi.set_sense(0, Sense::MEDIUM_NOT_PRESENT);
+            scsi.set_sense(0, Sense::NOT_READY_TO_READY_CHANGE);
+            scsi.set_sense(0, Sense::NOT_READY_TO_READY_CHANGE);
+            assert_eq!(2, scsi.pending_sense(0));
+            assert_eq!(Some(Sense::MEDIUM_NOT_PRESENT), scsi.sense(0));
+        },
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 18,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::RequestSense { desc: false, alloc_len: 18 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let sense = bus.read_data(18);
+            assert_eq!([0x02, 0x3A, 0x00], [sense[2], sense[12], sense[13]]);
+            bus.read_cs().unwrap();
+
+            let cbw = Cbw {
+                data_transfer_len: 18,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::RequestSense { desc: false, alloc_len: 18 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let sense = bus.read_data(18);
+            assert_eq!([0x06, 0x28, 0x00], [sense[2], sense[12], sense[13]]);
+            bus.read_cs().unwrap();
+        }),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            assert_eq!(0, scsi.pending_sense(0));
+            assert_eq!(None, scsi.sense(0));
+        }),
+    ] }
+}
+
+#[test]
+fn should_pass_writing_in_range_to_user() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| scsi.set_capacity(0, 100),
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 99, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+            bus.write_data([0u8; 512].as_slice());
+        }),
+        Step::DevIo,
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                assert!(matches!(cmd.kind, ScsiCommand::Write { lba: 99, len: 1 }));
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+/// 16 TiB with 512 byte blocks
+const LARGE_CAPACITY: u64 = 1 << 35;
+
+#[test]
+fn should_report_large_capacity() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| scsi.set_capacity(0, LARGE_CAPACITY),
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 8,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::ReadCapacity10 { lba: 0, pmi: false }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            // the host is expected to issue READ CAPACITY(16)
+            assert_eq!(
+                [0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x02, 0x00],
+                bus.read_data(8).as_slice()
+            );
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+
+            let cbw = Cbw {
+                data_transfer_len: 32,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::ReadCapacity16 {
+                    lba: 0,
+                    pmi: false,
+                    alloc_len: 32,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let data = bus.read_data(32);
+            assert_eq!(32, data.len());
+            assert_eq!((LARGE_CAPACITY - 1).to_be_bytes(), data[..8]);
+            assert_eq!(512u32.to_be_bytes(), data[8..12]);
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_decode_pmi_and_honor_alloc_len_of_read_capacity() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| scsi.set_capacity(0, 100),
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 8,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::ReadCapacity10 { lba: 42, pmi: true }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            // the last LBA of the medium regardless of the one asked about
+            assert_eq!(
+                [0x00, 0x00, 0x00, 0x63, 0x00, 0x00, 0x02, 0x00],
+                bus.read_data(8).as_slice()
+            );
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+
+            let cbw = Cbw {
+                data_transfer_len: 8,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::ReadCapacity10 { lba: 42, pmi: false }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert!(bus.read_data(8).is_empty());
+            let expected_csw = Csw {
+                data_transfer_len: 8,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            bus.clear_halt();
+        }),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            assert_eq!(Some(Sense::INVALID_FIELD_IN_CDB), scsi.sense(0));
+        }),
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 32,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::ReadCapacity16 {
+                    lba: 0,
+                    pmi: false,
+                    alloc_len: 12,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let data = bus.read_data(32);
+            assert_eq!(12, data.len());
+            assert_eq!(99u64.to_be_bytes(), data[..8]);
+            assert_eq!(512u32.to_be_bytes(), data[8..12]);
+            let expected_csw = Csw {
+                data_transfer_len: 20,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_pass_zero_length_read_and_write_without_user_action() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| scsi.set_capacity(0, 100),
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 99, len: 0 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+
+            // case 9. Ho > Dn
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 0 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert!(bus.is_out_stalled());
+            bus.clear_halt();
+            let expected_csw = Csw {
+                data_transfer_len: 512,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+
+            // the LBA is still checked
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 100, len: 0 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_pass_reading_above_32_bit_lba() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| scsi.set_capacity(0, LARGE_CAPACITY),
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: LARGE_CAPACITY - 1, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                assert!(matches!(cmd.kind, ScsiCommand::Read { lba, len: 1 } if lba == LARGE_CAPACITY - 1));
+                cmd.try_write_data_all([0xAAu8; 512].as_slice()).unwrap();
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!([0xAAu8; 512].as_slice(), bus.read_data(512).as_slice());
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_fail_writing_beyond_large_capacity() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| scsi.set_capacity(0, LARGE_CAPACITY),
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 1024,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: LARGE_CAPACITY - 1, len: 2 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 1024,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_fail_writing_past_last_addressable_block() {
+    // no capacity registered
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 1024,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: u64::MAX, len: 2 }),
+            };
+            bus.write_cbw(cbw);
+            bus.write_data([0u8; 1024].as_slice());
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 1024,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+fn set_write_protected(scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>) {
+    scsi.set_capacity(0, 100);
+    scsi.set_write_protected(0, true);
+}
+
+#[test]
+fn should_report_write_protection_after_unit_attention() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, set_write_protected, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+
+            let cbw = Cbw {
+                data_transfer_len: 18,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::RequestSense { desc: false, alloc_len: 18 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let sense = bus.read_data(18);
+            assert_eq!([0x70, 0x06, 0x2A, 0x01], [sense[0], sense[2], sense[12], sense[13]]);
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+
+            let cbw = Cbw {
+                data_transfer_len: 255,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::ModeSense6 {
+                    dbd: false,
+                    page_control: PageControl::CurrentValues,
+                    page_code: 0x3F,
+                    subpage_code: 0,
+                    alloc_len: 255,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(
+                [0x0B, 0x00, 0x80, 0x08, 0x00, 0x00, 0x00, 0x64, 0x00, 0x00, 0x02, 0x00],
+                bus.read_data(255).as_slice()
+            );
+            let expected_csw = Csw {
+                data_transfer_len: 255 - 12,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_reject_writing_when_write_protected() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, set_write_protected, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.read_cs().unwrap(); // unit attention
+
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 512,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            bus.clear_halt();
+
+            let cbw = Cbw {
+                data_transfer_len: 18,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::RequestSense { desc: false, alloc_len: 18 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            // the unit attention has been queued first
+            let sense = bus.read_data(18);
+            assert_eq!([0x70, 0x06, 0x2A, 0x01], [sense[0], sense[2], sense[12], sense[13]]);
+            bus.read_cs().unwrap();
+
+            let cbw = Cbw {
+                data_transfer_len: 18,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::RequestSense { desc: false, alloc_len: 18 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let sense = bus.read_data(18);
+            assert_eq!([0x70, 0x07, 0x27, 0x00], [sense[0], sense[2], sense[12], sense[13]]);
+        }),
+    ] }
+}
+
+#[test]
+fn should_reject_write_and_verify_when_write_protected() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, set_write_protected, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.read_cs().unwrap(); // unit attention
+
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::WriteAndVerify {
+                    lba: 0,
+                    len: 1,
+                    byte_check: true,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 512,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_report_becoming_ready_with_progress() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            scsi.set_readiness(0, Readiness::BecomingReady { progress: Some(0x4000) })
+        },
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+
+            let cbw = Cbw {
+                data_transfer_len: 18,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::RequestSense { desc: false, alloc_len: 18 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let sense = bus.read_data(18);
+            assert_eq!([0x70, 0x02, 0x04, 0x01], [sense[0], sense[2], sense[12], sense[13]]);
+            assert_eq!([0x80, 0x40, 0x00], sense[15..]);
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_report_reset_and_drop_pending_command() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                cmd.write_data([0xAAu8; 64].as_slice()).unwrap(); // the rest of the block never comes
+            },
+        ),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            assert_eq!(None, scsi.take_reset());
+            UsbClass::reset(scsi);
+            assert_eq!(Some(Reset::Bus), scsi.take_reset());
+            assert_eq!(None, scsi.take_reset());
+        }),
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.read_data(512); // drop data sent before the reset
+
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::Reserve6),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_report_command_aborted_by_reset() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 1024,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 7, len: 2 }),
+            };
+            bus.write_cbw(cbw);
+            bus.write_data([0u8; 64].as_slice());
+        }),
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                let mut buf = [0u8; 64];
+                cmd.read_data(buf.as_mut_slice()).unwrap();
+            },
+        ),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            UsbClass::reset(scsi);
+            let aborted = scsi.take_aborted().unwrap();
+            assert!(matches!(aborted.kind, ScsiCommand::Write { lba: 7, len: 2 }));
+            assert_eq!(0, aborted.lun);
+            assert_eq!((0x2A, Some(7)), (aborted.opcode, aborted.lba));
+            // as much as the packets received before the callback
+            assert!((1..=64).contains(&aborted.transferred));
+            assert!(scsi.take_aborted().is_none());
+        }),
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::Reserve6),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            // completed commands are not reported
+            UsbClass::reset(scsi);
+            assert!(scsi.take_aborted().is_none());
+        }),
+    ] }
+}
+
+#[test]
+fn should_report_progress_of_command_aborted_by_bulk_only_reset() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+        let mut cbw = Cbw {
+            data_transfer_len: 2048,
+            direction: DataDirection::Out,
+            block: cmd_into_bytes(ScsiCommand::Write { lba: 5, len: 4 }),
+        }
+        .into_bytes();
+        cbw[4..8].copy_from_slice(&0x12345678u32.to_le_bytes());
+        bus.write_data(&cbw);
+        bus.write_data([0x55u8; 640].as_slice());
+        for _ in 0..16 {
+            scsi.drive_transport().unwrap();
+        }
+        scsi.handle_command(|mut cmd| {
+            let mut block = [0u8; 512];
+            assert_eq!(512, cmd.read_data(block.as_mut_slice()).unwrap());
+        })
+        .unwrap();
+
+        bus.bulk_only_reset();
+        usb_dev.poll(&mut [&mut scsi]);
+        let aborted = scsi.take_aborted().unwrap();
+        assert!(matches!(
+            aborted.kind,
+            ScsiCommand::Write { lba: 5, len: 4 }
+        ));
+        assert_eq!(
+            (0x12345678, 0x2A, Some(5), 640),
+            (
+                aborted.tag,
+                aborted.opcode,
+                aborted.lba,
+                aborted.transferred
+            )
+        );
+        assert!(scsi.take_aborted().is_none());
+    });
+}
+
+fn set_quirks(scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>) {
+    let mut quirks = Quirks::default();
+    quirks.zlp_on_short_in = true;
+    scsi.set_quirks(quirks);
+}
+
+#[test]
+fn should_accept_padded_cbw() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::Reserve6),
+            };
+            bus.write_padded_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_discard_cbw_padding_before_data() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 1 }),
+            };
+            bus.write_padded_cbw(cbw);
+            bus.write_data([0xAAu8; 512].as_slice());
+        }),
+        Step::DevIo,
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                let mut buf = [0u8; 512];
+                assert_eq!(512, cmd.read_data(buf.as_mut_slice()).unwrap());
+                assert_eq!([0xAAu8; 512], buf);
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_end_short_in_data_with_zlp_with_quirk() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, set_quirks, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 1024,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 2 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                cmd.try_write_data_all([0xAAu8; 512].as_slice()).unwrap();
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!([0xAAu8; 512].as_slice(), bus.read_n_bytes(512).as_slice());
+            assert_eq!(Some(vec![]), bus.read_packet());
+            let expected_csw = Csw {
+                data_transfer_len: 512,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_phase_fail_commands_of_mismatching_direction() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        // case 8. Hi <> Do
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert!(bus.is_in_stalled());
+            let expected_csw = Csw {
+                data_transfer_len: 512,
+                status: CommandStatus::PhaseError,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            bus.clear_halt();
+
+            // case 10. Ho <> Di
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert!(bus.is_out_stalled());
+            let expected_csw = Csw {
+                data_transfer_len: 512,
+                status: CommandStatus::PhaseError,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            bus.clear_halt();
+
+            // case 2. Hn < Di
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::Inquiry {
+                    evpd: false,
+                    page_code: 0,
+                    alloc_len: 36,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::PhaseError,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+
+            // no data is transferred by a READ of zero blocks
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 0 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+        }),
+    ] }
+}
+
+#[test]
+fn should_phase_fail_blocks_exceeding_data_transfer_len() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        // case 7. Hi < Di
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 2 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert!(bus.is_in_stalled());
+            let expected_csw = Csw {
+                data_transfer_len: 512,
+                status: CommandStatus::PhaseError,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            bus.clear_halt();
+
+            // case 13. Ho < Do
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 2 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert!(bus.is_out_stalled());
+            let expected_csw = Csw {
+                data_transfer_len: 512,
+                status: CommandStatus::PhaseError,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            bus.clear_halt();
+
+            // case 5. Hi > Di is up to the handler
+            let cbw = Cbw {
+                data_transfer_len: 1024,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                cmd.try_write_data_all([0u8; 512].as_slice()).unwrap();
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(512, bus.read_data(1024).len());
+            let expected_csw = Csw {
+                data_transfer_len: 512,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_tell_data_expected_by_host_and_implied_by_cdb() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        // the response to a command with an allocation length is truncated to what the host expects
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 18,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Inquiry {
+                    evpd: true,
+                    page_code: 0xB0,
+                    alloc_len: 36,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                assert_eq!(Some((UsbDirection::In, 18)), cmd.host_expects());
+                assert_eq!(Some((UsbDirection::In, 36)), cmd.cdb_implies());
+                let (_, len) = cmd.host_expects().unwrap();
+                cmd.try_write_data_all(&[0u8; 36][..len as usize]).unwrap();
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(18, bus.read_data(18).len());
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+
+            let cbw = Cbw {
+                data_transfer_len: 1024,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                assert_eq!(Some((UsbDirection::In, 1024)), cmd.host_expects());
+                assert_eq!(Some((UsbDirection::In, 512)), cmd.cdb_implies());
+                cmd.fail();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.clear_halt();
+            assert_eq!(CommandStatus::Failed, bus.read_cs().unwrap().status);
+        }),
+    ] }
+}
+
+/// Writes `data` in packets of varying sizes, short ones in the middle, the final one followed by
+/// a zero length packet
+fn write_odd_sized_packets(bus: &DummyUsbBus, data: &[u8], packet_size: u16) {
+    let mut rest = data;
+    let mut i = 0;
+    while !rest.is_empty() {
+        let len = min(rest.len(), 1 + (i * 13) % packet_size as usize);
+        bus.write_packet(&rest[..len]);
+        rest = &rest[len..];
+        i += 1;
+    }
+    bus.write_packet(&[]);
+}
+
+#[test]
+fn should_write_data_in_place_chunk_by_chunk() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+            bus.write_cbw(Cbw {
+                data_transfer_len: 2048,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 4 }),
+            });
+
+            let mut sent = 0;
+            let mut received = vec![];
+            for _ in 0..1024 {
+                scsi.poll(|mut cmd| {
+                    let mut chunks = cmd.data_in_chunks(300).unwrap();
+                    while let Some(mut chunk) = chunks.next_chunk() {
+                        assert!(chunk.len() <= 300);
+                        let start = sent;
+                        sent += chunk.fill_with(|dst| {
+                            for (i, byte) in dst.iter_mut().enumerate() {
+                                *byte = ((start + i) % 251) as u8;
+                            }
+                            dst.len()
+                        });
+                    }
+                    if sent == 2048 {
+                        assert!(cmd.data_in_chunks(300).unwrap().next_chunk().is_none());
+                        cmd.pass();
+                    }
+                })
+                .unwrap();
+                received.extend(bus.read_data(2048 - received.len()));
+                if received.len() == 2048 {
+                    break;
+                }
+            }
+            let expected: Vec<u8> = (0..2048).map(|i| (i % 251) as u8).collect();
+            assert_eq!(expected, received, "{packet_size}");
+            for _ in 0..4 {
+                scsi.poll(|_| panic!("unexpected command")).unwrap();
+            }
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }
+    });
+}
+
+#[test]
+fn should_read_data_sent_in_odd_sized_packets() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+            let data: Vec<u8> = (0..512).map(|i| (i % 251) as u8).collect();
+            bus.write_cbw(Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 1 }),
+            });
+            write_odd_sized_packets(&bus, &data, packet_size);
+
+            let mut received = vec![];
+            for _ in 0..1024 {
+                scsi.poll(|mut cmd| {
+                    let mut chunk = [0u8; 100]; // not aligned to packets nor blocks
+                    let count = cmd.read_data(&mut chunk).unwrap();
+                    received.extend_from_slice(&chunk[..count]);
+                    assert!(received.len() + cmd.data_residue() as usize <= 512);
+                    if received.len() == 512 {
+                        cmd.pass();
+                    }
+                })
+                .unwrap();
+            }
+            assert_eq!(data, received, "{packet_size}");
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            assert!(!bus.is_out_stalled());
+
+            // the zero length packet isn't taken for a part of the next CBW
+            bus.write_cbw(Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+            });
+            for _ in 0..16 {
+                scsi.poll(|cmd| cmd.pass()).unwrap();
+            }
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+        }
+    });
+}
+
+#[test]
+fn should_chunk_blocks_sent_in_odd_sized_packets() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+            let data: Vec<u8> = (0..1024).map(|i| (i % 251) as u8).collect();
+            bus.write_cbw(Cbw {
+                data_transfer_len: 1024,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 4, len: 2 }),
+            });
+            write_odd_sized_packets(&bus, &data, packet_size);
+
+            let mut medium = vec![0u8; 1024];
+            for _ in 0..1024 {
+                scsi.poll(|mut cmd| {
+                    let done = cmd
+                        .read_write_chunks(|chunk| {
+                            assert!(chunk.offset_in_block + chunk.bytes.len() <= 512);
+                            let pos = (chunk.lba - 4) as usize * 512 + chunk.offset_in_block;
+                            medium[pos..pos + chunk.bytes.len()].copy_from_slice(chunk.bytes);
+                        })
+                        .unwrap();
+                    if done {
+                        cmd.pass();
+                    }
+                })
+                .unwrap();
+            }
+            assert_eq!(data, medium, "{packet_size}");
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }
+    });
+}
+
+#[test]
+fn should_report_command_phase_to_resuming_callback() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let data: Vec<u8> = (0..512).map(|i| (i % 251) as u8).collect();
+
+            bus.write_cbw(Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+            });
+            for _ in 0..16 {
+                scsi.poll(|cmd| {
+                    assert_eq!(CommandPhase::CommandReceived, cmd.phase());
+                    cmd.pass();
+                })
+                .unwrap();
+            }
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+
+            // resumed by the phase alone, a few bytes at a time
+            bus.write_cbw(Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+            });
+            for _ in 0..1024 {
+                scsi.poll(|mut cmd| {
+                    let CommandPhase::DataIn { sent, expected } = cmd.phase() else {
+                        panic!("unexpected {:?}", cmd.phase());
+                    };
+                    assert_eq!(512, expected);
+                    let end = min(sent as usize + 100, 512);
+                    cmd.write_data(&data[sent as usize..end]).unwrap();
+                    if let CommandPhase::DataIn { sent: 512, .. } = cmd.phase() {
+                        cmd.pass();
+                    }
+                })
+                .unwrap();
+            }
+            assert_eq!(data, bus.read_data(512), "{packet_size}");
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+
+            bus.write_cbw(Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 1 }),
+            });
+            bus.write_data(&data);
+            let mut received = vec![];
+            for _ in 0..1024 {
+                scsi.poll(|mut cmd| {
+                    let CommandPhase::DataOut {
+                        received: count,
+                        expected,
+                    } = cmd.phase()
+                    else {
+                        panic!("unexpected {:?}", cmd.phase());
+                    };
+                    assert_eq!(512, expected);
+                    assert!(received.len() <= count as usize);
+                    let mut chunk = [0u8; 100];
+                    let read = cmd.read_data(&mut chunk).unwrap();
+                    received.extend_from_slice(&chunk[..read]);
+                    if received.len() == 512 {
+                        cmd.pass();
+                    }
+                })
+                .unwrap();
+            }
+            assert_eq!(data, received, "{packet_size}");
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+        }
+    });
+}
+
+#[test]
+fn should_call_once_with_whole_data_out() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            scsi.set_buffer_data_out(true);
+            let data: Vec<u8> = (0..2048).map(|i| (i % 251) as u8).collect();
+
+            bus.write_cbw(Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 1 }),
+            });
+            let mut calls = 0;
+            for chunk in data[..512].chunks(32) {
+                assert_eq!(0, calls);
+                assert!(!scsi.has_command());
+                for packet in chunk.chunks(packet_size as usize) {
+                    bus.write_packet(packet);
+                }
+                for _ in 0..8 {
+                    scsi.poll(|mut cmd| {
+                        calls += 1;
+                        let mut received = [0u8; 512];
+                        assert_eq!(512, cmd.read_data(&mut received).unwrap());
+                        assert_eq!(data[..512], received);
+                        cmd.pass();
+                    })
+                    .unwrap();
+                }
+            }
+            assert_eq!(1, calls, "{packet_size}");
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+
+            // too long to be buffered. handed over as the data arrives
+            bus.write_cbw(Cbw {
+                data_transfer_len: 2048,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 4 }),
+            });
+            bus.write_data(&data);
+            let (mut calls, mut received) = (0, vec![]);
+            for _ in 0..1024 {
+                scsi.poll(|mut cmd| {
+                    calls += 1;
+                    let mut chunk = [0u8; 512];
+                    let read = cmd.read_data(&mut chunk).unwrap();
+                    received.extend_from_slice(&chunk[..read]);
+                    if received.len() == 2048 {
+                        cmd.pass();
+                    }
+                })
+                .unwrap();
+            }
+            assert!(calls > 1);
+            assert_eq!(data, received, "{packet_size}");
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+        }
+    });
+}
+
+#[test]
+fn should_phase_fail_when_host_sends_surplus_data() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+            bus.write_data([0xAAu8; 512].as_slice());
+            bus.write_data([0x55u8; 64].as_slice()); // not declared by the CBW
+        }),
+        Step::DevIo,
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                let mut buf = [0u8; 1024];
+                assert_eq!(512, cmd.read_data(buf.as_mut_slice()).unwrap());
+                assert_eq!([0xAAu8; 512], buf[..512]);
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::PhaseError,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            assert!(bus.is_out_stalled());
+            bus.clear_halt();
+
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::Reserve6),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_split_driving_transport_and_handling_command() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 64,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Inquiry {
+                    evpd: false,
+                    page_code: 0,
+                    alloc_len: 64,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            // as the USB interrupt would do. a CBW may take a few packets
+            assert!((0..8).any(|_| scsi.drive_transport().unwrap()));
+            assert!(scsi.has_command());
+            assert!(scsi.drive_transport().unwrap());
+        }),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            // as a command handling task would do
+            scsi.handle_command(|mut cmd| {
+                cmd.try_write_data_all([0x11u8; 64].as_slice()).unwrap();
+                cmd.pass();
+            })
+            .unwrap();
+            assert!(!scsi.has_command());
+        }),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            // the USB interrupt sends the rest of the data and the status
+            for _ in 0..16 {
+                assert!(!scsi.drive_transport().unwrap());
+            }
+        }),
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(vec![0x11u8; 64], bus.read_data(64));
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_give_access_to_transport() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            assert!((0..8).any(|_| scsi.drive_transport().unwrap()));
+            let cb = scsi.transport().get_command().unwrap();
+            assert_eq!(0, cb.lun);
+            assert_eq!(0x00, cb.bytes[0]); // TEST UNIT READY
+
+            scsi.transport_mut().set_status(TransportCommandStatus::Passed);
+            assert!(!scsi.drive_transport().unwrap());
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_hint_how_much_data_to_write_next() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 2048,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 4 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                // limited by the IO buffer
+                let hint = WriteHint { written: 0, available: 1024, expected: 2048 };
+                assert_eq!(hint, cmd.write_data_hinted(&[]).unwrap());
+                let hint = WriteHint { written: 1024, available: 0, expected: 1024 };
+                assert_eq!(hint, cmd.write_data_hinted([0x11u8; 1536].as_slice()).unwrap());
+                cmd.fail();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(vec![0x11u8; 1024], bus.read_data(2048));
+            let expected_csw = Csw {
+                data_transfer_len: 1024,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            bus.clear_halt();
+
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                // limited by the host
+                let hint = WriteHint { written: 0, available: 512, expected: 512 };
+                assert_eq!(hint, cmd.write_data_hinted(&[]).unwrap());
+                let hint = WriteHint { written: 512, available: 0, expected: 0 };
+                assert_eq!(hint, cmd.write_data_hinted([0x22u8; 512].as_slice()).unwrap());
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(vec![0x22u8; 512], bus.read_data(512));
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_read_write_data_in_chunks_within_blocks() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 1024,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::Write { lba: 3, len: 2 }),
+            };
+            bus.write_cbw(cbw);
+            bus.write_data((0..1024).map(|i| i as u8).collect::<Vec<_>>().as_slice());
+        }),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            let mut blocks = [vec![], vec![]];
+            let mut done = false;
+            while !done {
+                scsi.poll(|mut cmd| {
+                    done = cmd
+                        .read_write_chunks(|chunk| {
+                            let block: &mut Vec<u8> = &mut blocks[chunk.lba as usize - 3];
+                            assert_eq!(block.len(), chunk.offset_in_block);
+                            assert!(chunk.offset_in_block + chunk.bytes.len() <= 512);
+                            block.extend_from_slice(chunk.bytes);
+                        })
+                        .unwrap();
+                    if done {
+                        cmd.pass();
+                    }
+                })
+                .unwrap();
+            }
+            let data = (0..1024).map(|i| i as u8).collect::<Vec<_>>();
+            assert_eq!(data[..512], blocks[0]);
+            assert_eq!(data[512..], blocks[1]);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+fn set_inquiry(scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>) {
+    let mut data = InquiryData {
+        removable: true,
+        ..InquiryData::new("UNKNOWN", "USB Flash", "1.23")
+    };
+    data.version_descriptors[0] = 0x1728; // USB
+    scsi.set_inquiry(data);
+}
+
+#[test]
+fn should_answer_inquiry_truncated_to_alloc_len() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, set_inquiry, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 36,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Inquiry {
+                    evpd: false,
+                    page_code: 0,
+                    alloc_len: 5,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            // ADDITIONAL LENGTH tells the host to ask for the whole data
+            assert_eq!(vec![0x00, 0x80, 0x04, 0x02, 74 - 5], bus.read_data(36));
+            let expected_csw = Csw {
+                data_transfer_len: 31,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            bus.clear_halt();
+
+            let cbw = Cbw {
+                data_transfer_len: 96,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Inquiry {
+                    evpd: false,
+                    page_code: 0,
+                    alloc_len: 96,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let data = bus.read_data(96);
+            assert_eq!(74, data.len());
+            assert_eq!(b"UNKNOWN USB Flash       1.23", &data[8..36]);
+            assert_eq!([0x17, 0x28], data[58..60]);
+            let expected_csw = Csw {
+                data_transfer_len: 96 - 74,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_fail_inquiry_of_page_without_evpd() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, set_inquiry, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 36,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Inquiry {
+                    evpd: false,
+                    page_code: 0x80,
+                    alloc_len: 36,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 36,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            assert_eq!(Some(Sense::INVALID_FIELD_IN_CDB), scsi.sense(0));
+        }),
+    ] }
+}
+
+#[test]
+fn should_answer_vpd_pages_asked_by_macos() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            scsi.set_identity(&DeviceIdentity {
+                vendor: "ACME",
+                product: "USB Flash",
+                revision: "1.23",
+                serial: "FOOBAR1234",
+            });
+            scsi.set_quirks(Quirks::macos());
+        },
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 4096,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Inquiry {
+                    evpd: false,
+                    page_code: 0,
+                    alloc_len: 4096,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(36, bus.read_data(4096).len());
+            let expected_csw = Csw {
+                data_transfer_len: 4096 - 36,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            bus.clear_halt();
+
+            let cbw = Cbw {
+                data_transfer_len: 255,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Inquiry {
+                    evpd: true,
+                    page_code: 0x00,
+                    alloc_len: 255,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(
+                [0x00, 0x00, 0x00, 0x03, 0x00, 0x80, 0x83],
+                bus.read_data(255).as_slice()
+            );
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+            bus.clear_halt();
+
+            let cbw = Cbw {
+                data_transfer_len: 255,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Inquiry {
+                    evpd: true,
+                    page_code: 0x83,
+                    alloc_len: 255,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let data = bus.read_data(255);
+            assert_eq!([0x00, 0x83, 0x00, 0x26, 0x02, 0x01, 0x00, 0x22], data[..8]);
+            assert_eq!(b"ACME    USB Flash       FOOBAR1234", &data[8..]);
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+        }),
+    ] }
+}
+
+#[test]
+fn should_answer_unit_serial_number_of_identity() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            scsi.set_identity(&DeviceIdentity {
+                vendor: "ACME",
+                product: "USB Flash",
+                revision: "1.23",
+                serial: "FOOBAR1234",
+            });
+        },
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 36,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Inquiry {
+                    evpd: false,
+                    page_code: 0,
+                    alloc_len: 36,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(b"ACME    USB Flash       1.23", &bus.read_data(36)[8..36]);
+            bus.read_cs().unwrap();
+
+            let cbw = Cbw {
+                data_transfer_len: 64,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Inquiry {
+                    evpd: true,
+                    page_code: 0x80,
+                    alloc_len: 64,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let data = bus.read_data(64);
+            assert_eq!([0x00, 0x80, 0x00, 10], data[..4]);
+            assert_eq!(b"FOOBAR1234", &data[4..]);
+            let expected_csw = Csw {
+                data_transfer_len: 64 - 14,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            bus.clear_halt();
+
+            // other pages are left to the user
+            let cbw = Cbw {
+                data_transfer_len: 64,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Inquiry {
+                    evpd: true,
+                    page_code: 0x83,
+                    alloc_len: 64,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| cmd.fail(),
+        ),
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(CommandStatus::Failed, bus.read_cs().unwrap().status);
+        }),
+    ] }
+}
+
+#[test]
+fn should_reset_log_parameters_with_hook() {
+    static RESETS: Mutex<Vec<(u8, LogReset)>> = Mutex::new(Vec::new());
+
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            scsi.set_on_log_reset(Some(|lun, reset| RESETS.lock().unwrap().push((lun, reset))));
+            scsi.set_buffer_data_out(true);
+        },
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::LogSelect {
+                    pcr: true,
+                    sp: false,
+                    page_control: PageControl::CurrentValues,
+                    page_code: 0x02,
+                    subpage_code: 0,
+                    parameter_list_len: 0,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+            let expected = LogReset {
+                pcr: true,
+                page_control: PageControl::CurrentValues,
+                page_code: 0x02,
+                subpage_code: 0,
+            };
+            assert_eq!(vec![(0, expected)], std::mem::take(&mut *RESETS.lock().unwrap()));
+
+            // a parameter list is left to the user
+            let cbw = Cbw {
+                data_transfer_len: 9,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::LogSelect {
+                    pcr: false,
+                    sp: false,
+                    page_control: PageControl::CurrentValues,
+                    page_code: 0,
+                    subpage_code: 0,
+                    parameter_list_len: 9,
+                }),
+            };
+            bus.write_cbw(cbw);
+            bus.write_data(&[0x02, 0x00, 0x00, 0x05, 0x00, 0x01, 0x00, 0x01, 0x00]);
+        }),
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                let mut data = [0u8; 9];
+                assert_eq!(9, cmd.read_data(&mut data).unwrap());
+                let page = decode_log_select(&data).next().unwrap().unwrap();
+                assert_eq!(1, page.parameters().count());
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+            assert!(RESETS.lock().unwrap().is_empty());
+        }),
+    ] }
+}
+
+#[test]
+fn should_emulate_cd_dvd_device() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            scsi.set_device_type(PeripheralDeviceType::CdDvd);
+            scsi.set_capacity(0, 100);
+            scsi.set_inquiry(InquiryData::new("UNKNOWN", "CD-ROM", "1.00"));
+        },
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 36,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Inquiry {
+                    evpd: false,
+                    page_code: 0,
+                    alloc_len: 36,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(0x05, bus.read_data(36)[0]);
+            bus.read_cs().unwrap();
+
+            let cbw = Cbw {
+                data_transfer_len: 8,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::ReadCapacity10 { lba: 0, pmi: false }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            // 2048 byte blocks unless set otherwise
+            assert_eq!(
+                [0x00, 0x00, 0x00, 0x63, 0x00, 0x00, 0x08, 0x00],
+                bus.read_data(8).as_slice()
+            );
+            bus.read_cs().unwrap();
+        }),
+    ] }
+}
+
+#[test]
+fn should_send_status_of_no_data_command_right_away() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            scsi.transport_mut()
+                .send_status(TransportCommandStatus::Passed)
+                .unwrap();
+        }),
+        // no poll in between
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_reject_buffer_smaller_than_subclass_response() {
+    let usb_bus = UsbBusAllocator::new(DummyUsbBus::new());
+    let mut io_buf = [0u8; 64];
+    assert!(matches!(
+        Scsi::new(&usb_bus, 8, 0, io_buf.as_mut_slice()),
+        Err(BulkOnlyError::BufferTooSmall)
+    ));
+}
+
+// sizable at compile time
+const _: () = assert!(required_buffer_len(64, BlockSize::B512) == 512);
+const _: () = assert!(required_buffer_len(8, BlockSize::B4096) == 4096);
+
+#[test]
+fn should_accept_buffers_of_required_len() {
+    for packet_size in common::PACKET_SIZE {
+        for block_size in [BlockSize::B512, BlockSize::B2048, BlockSize::B4096] {
+            for (len, ok) in [
+                (MIN_BUFFER_LEN - 1, false),
+                (MIN_BUFFER_LEN, true),
+                (required_buffer_len(packet_size, block_size), true),
+            ] {
+                let usb_bus = UsbBusAllocator::new(DummyUsbBus::new());
+                let mut io_buf = vec![0u8; len];
+                let res = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice());
+                assert_eq!(ok, res.is_ok(), "{packet_size} {} {len}", block_size.get());
+            }
+        }
+    }
+}
+
+#[test]
+fn should_pass_unsupported_cdb_format_with_declared_length() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let mut block = vec![0u8; 16];
+            block[0] = 0x7F; // variable-length CDB
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block,
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                assert!(matches!(
+                    cmd.kind,
+                    ScsiCommand::UnsupportedCdbFormat { opcode: 0x7F, len: 16 }
+                ));
+                assert_eq!(16, cmd.command_block().len());
+                cmd.fail_with_sense(Sense::INVALID_COMMAND_OPERATION_CODE);
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_keep_stall_until_reset_recovery() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let mut poll = |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+                usb_dev.poll(&mut [scsi]);
+                for _ in 0..64 {
+                    scsi.poll(|cmd| cmd.pass()).unwrap();
+                }
+            };
+
+            bus.write_data([0u8; 31].as_slice()); // invalid signature
+            poll(&mut scsi);
+            assert!(bus.is_in_stalled() && bus.is_out_stalled());
+            assert!(scsi.transport().in_reset_recovery());
+
+            // clearing halt before the reset is ignored
+            bus.clear_feature_halt(false);
+            poll(&mut scsi);
+            assert!(!bus.is_control_rejected());
+            assert!(bus.is_out_stalled());
+
+            bus.bulk_only_reset();
+            poll(&mut scsi);
+            assert_eq!(Some(Reset::Class), scsi.take_reset());
+            assert!(bus.is_in_stalled() && bus.is_out_stalled());
+
+            bus.clear_feature_halt(true);
+            poll(&mut scsi);
+            assert!(!bus.is_in_stalled() && bus.is_out_stalled());
+            assert!(scsi.transport().in_reset_recovery());
+
+            bus.clear_feature_halt(false);
+            poll(&mut scsi);
+            assert!(!bus.is_out_stalled());
+            assert!(!scsi.transport().in_reset_recovery());
+            let stats = scsi.transport().stats();
+            assert_eq!((1, 1), (stats.in_stalls, stats.out_stalls));
+            assert_eq!((1, 1), (stats.class_resets, stats.reset_recoveries));
+
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+            };
+            bus.write_cbw(cbw);
+            poll(&mut scsi);
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }
+    });
+}
+
+#[test]
+fn should_unstall_endpoints_per_policy() {
+    common::timeout(TIMEOUT, || {
+        for (policy, unstalled_by_reset) in [(Unstall::ClearFeature, false), (Unstall::Reset, true)]
+        {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+            let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let mut quirks = Quirks::default();
+            quirks.unstall = policy;
+            scsi.set_quirks(quirks);
+            let mut poll = |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+                usb_dev.poll(&mut [scsi]);
+                for _ in 0..64 {
+                    scsi.poll(|cmd| cmd.fail()).unwrap();
+                }
+            };
+
+            // Reset Recovery
+            bus.write_data([0u8; 31].as_slice()); // invalid signature
+            poll(&mut scsi);
+            bus.bulk_only_reset();
+            poll(&mut scsi);
+            assert_eq!(
+                !unstalled_by_reset,
+                bus.is_in_stalled() && bus.is_out_stalled(),
+                "{policy:?}"
+            );
+            assert_eq!(!unstalled_by_reset, scsi.transport().in_reset_recovery());
+            bus.clear_feature_halt(true);
+            poll(&mut scsi);
+            bus.clear_feature_halt(false);
+            poll(&mut scsi);
+            assert!(!bus.is_in_stalled() && !bus.is_out_stalled());
+            assert!(!scsi.transport().in_reset_recovery());
+
+            // IN data ended by a stall, cleared by the host alone
+            let cbw = Cbw {
+                data_transfer_len: 100,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Unknown),
+            };
+            bus.write_cbw(cbw);
+            poll(&mut scsi);
+            assert!(bus.is_in_stalled(), "{policy:?}");
+            bus.clear_feature_halt(true);
+            poll(&mut scsi);
+            let expected_csw = Csw {
+                data_transfer_len: 100,
+                status: CommandStatus::Failed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            assert!(!bus.is_in_stalled());
+        }
+    });
+}
+
+#[test]
+fn should_drop_partial_cbw_on_reset() {
+    common::timeout(TIMEOUT, || {
+        // a CBW spans multiple full packets
+        for (packet_size, bus_reset) in [(8, true), (8, false), (16, true), (16, false)] {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let mut poll = |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+                usb_dev.poll(&mut [scsi]);
+                for _ in 0..64 {
+                    scsi.poll(|cmd| cmd.pass()).unwrap();
+                }
+            };
+
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+            };
+            bus.write_data(&cbw.into_bytes()[..16]);
+            poll(&mut scsi);
+
+            if bus_reset {
+                UsbClass::reset(&mut scsi);
+                assert_eq!(Some(Reset::Bus), scsi.take_reset());
+            } else {
+                bus.bulk_only_reset();
+                poll(&mut scsi);
+                assert_eq!(Some(Reset::Class), scsi.take_reset());
+                bus.clear_feature_halt(true);
+                poll(&mut scsi);
+                bus.clear_feature_halt(false);
+                poll(&mut scsi);
+            }
+            assert!(!scsi.transport().in_reset_recovery());
+
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+            };
+            bus.write_cbw(cbw);
+            poll(&mut scsi);
+            assert!(scsi.take_aborted().is_none());
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            assert!(!bus.is_in_stalled() && !bus.is_out_stalled());
+        }
+    });
+}
+
+#[test]
+fn should_reject_cbw_ended_by_short_packet() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+            };
+            // the last packet is short for all the packet sizes
+            bus.write_data(&cbw.into_bytes()[..20]);
+            for _ in 0..64 {
+                scsi.poll(|cmd| cmd.pass()).unwrap();
+            }
+            assert!(bus.is_in_stalled() && bus.is_out_stalled());
+            assert!(scsi.transport().in_reset_recovery());
+            assert!(bus.read_packet().is_none());
+        }
+    });
+}
+
+#[test]
+fn should_drive_transport_from_usb_device_poll() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            scsi.set_auto_drive(true);
+
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+            while !scsi.has_command() {
+                usb_dev.poll(&mut [&mut scsi]);
+            }
+            scsi.handle_command(|mut cmd| {
+                cmd.try_write_data_all([0xAA; 512].as_slice()).unwrap();
+                cmd.pass();
+            })
+            .unwrap();
+
+            // the data followed by the CSW
+            let mut received = vec![];
+            while received.len() < 512 + 13 {
+                usb_dev.poll(&mut [&mut scsi]);
+                received.extend(bus.read_packet().unwrap_or_default());
+            }
+            assert_eq!([0xAA; 512], received[..512]);
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, Csw::from_bytes(&received[512..]));
+        }
+    });
+}
+
+#[test]
+fn should_wait_for_pending_in_data() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+            while !scsi.drive_transport().unwrap() {}
+
+            // the media is busy for a few polls
+            let mut calls = 0;
+            for _ in 0..4 {
+                scsi.poll(|cmd| {
+                    calls += 1;
+                    cmd.pending();
+                })
+                .unwrap();
+            }
+            assert_eq!(4, calls);
+            assert!(bus.read_packet().is_none());
+
+            for _ in 0..128 {
+                scsi.poll(|mut cmd| {
+                    cmd.try_write_data_all([0xAA; 512].as_slice()).unwrap();
+                    cmd.pass();
+                })
+                .unwrap();
+            }
+            assert_eq!(vec![0xAA; 512], bus.read_data(512));
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }
+    });
+}
+
+#[test]
+fn should_fill_in_data_of_passed_command() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let mut quirks = Quirks::default();
+            quirks.fill_short_in = Some(0xF6);
+            scsi.set_quirks(quirks);
+
+            let cbw = Cbw {
+                data_transfer_len: 2048,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 4 }),
+            };
+            bus.write_cbw(cbw);
+            for _ in 0..512 {
+                scsi.poll(|mut cmd| {
+                    cmd.try_write_data_all([0xAA; 100].as_slice()).unwrap();
+                    cmd.pass();
+                })
+                .unwrap();
+            }
+
+            let data = bus.read_data(2048);
+            assert_eq!([0xAA; 100], data[..100]);
+            assert_eq!([0xF6; 1948], data[100..]);
+            assert!(!bus.is_in_stalled());
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }
+    });
+}
+
+#[test]
+fn should_drop_in_data_abandoned_by_host() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let mut poll = |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+                usb_dev.poll(&mut [scsi]);
+                for _ in 0..4 {
+                    // never done with the data
+                    scsi.poll(|mut cmd| {
+                        let _ = cmd.write_data([0xAA; 512].as_slice());
+                    })
+                    .unwrap();
+                }
+            };
+
+            let cbw = Cbw {
+                data_transfer_len: 4096,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 8 }),
+            };
+            bus.write_cbw(cbw);
+            poll(&mut scsi);
+            let mut received = 0;
+            while let Some(packet) = bus.read_packet() {
+                received += packet.len();
+            }
+            assert!(received > 0 && received < 4096);
+
+            bus.clear_feature_halt(true);
+            poll(&mut scsi);
+            assert!(matches!(
+                scsi.take_aborted().map(|aborted| aborted.kind),
+                Some(ScsiCommand::Read { lba: 0, len: 8 })
+            ));
+            // the data left in the IO buffer is not sent
+            let expected_csw = Csw {
+                data_transfer_len: 4096 - received as u32,
+                status: CommandStatus::PhaseError,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            assert!(bus.read_packet().is_none());
+        }
+    });
+}
+
+#[test]
+fn should_wait_for_clear_halt_after_class_reset() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+        bus.bulk_only_reset();
+        usb_dev.poll(&mut [&mut scsi]);
+        assert!(scsi.transport().in_reset_recovery());
+
+        // no CBW is accepted until the recovery completes
+        let cbw = Cbw {
+            data_transfer_len: 0,
+            direction: DataDirection::NotExpected,
+            block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+        };
+        bus.write_cbw(cbw);
+        for _ in 0..64 {
+            scsi.poll(|_| panic!("unexpected command")).unwrap();
+        }
+        assert!(bus.read_cs().is_none());
+
+        // a bus reset completes the recovery
+        UsbClass::reset(&mut scsi);
+        assert!(!scsi.transport().in_reset_recovery());
+        for _ in 0..64 {
+            scsi.poll(|cmd| cmd.pass()).unwrap();
+        }
+        assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+    });
+}
+
+#[test]
+fn should_respond_to_get_max_lun_per_policy() {
+    common::timeout(TIMEOUT, || {
+        for (max_lun, policy, expected) in [
+            (0, GetMaxLun::Respond, Some(vec![0])),
+            (3, GetMaxLun::Respond, Some(vec![3])),
+            (3, GetMaxLun::RespondZero, Some(vec![0])),
+            (0, GetMaxLun::StallSingleLun, None),
+            (3, GetMaxLun::StallSingleLun, Some(vec![3])),
+        ] {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, 64, max_lun, io_buf.as_mut_slice()).unwrap();
+            let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let mut quirks = Quirks::default();
+            quirks.get_max_lun = policy;
+            scsi.set_quirks(quirks);
+
+            bus.control_in(0b0010_0001, 0xFE, 0, 0, 1);
+            usb_dev.poll(&mut [&mut scsi]);
+            assert_eq!(
+                expected,
+                bus.control_in_data(),
+                "{policy:?}, max lun {max_lun}"
+            );
+
+            // malformed requests are stalled
+            bus.control_in(0b0010_0001, 0xFE, 1, 0, 1);
+            usb_dev.poll(&mut [&mut scsi]);
+            assert_eq!(None, bus.control_in_data());
+
+            // the response is truncated to wLength
+            bus.control_in(0b0010_0001, 0xFE, 0, 0, 2);
+            usb_dev.poll(&mut [&mut scsi]);
+            assert_eq!(expected, bus.control_in_data(), "wLength 2");
+            bus.control_in(0b0010_0001, 0xFE, 0, 0, 0);
+            usb_dev.poll(&mut [&mut scsi]);
+            let empty = expected.as_ref().map(|_| vec![]);
+            assert_eq!(empty, bus.control_in_data(), "wLength 0");
+        }
+    });
+}
+
+#[test]
+fn should_send_csw_in_separate_packet_after_staged_data() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 100,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Unknown),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                // the status is set while the data is still in the IO buffer
+                assert_eq!(100, cmd.write_data([0xAAu8; 100].as_slice()).unwrap());
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!([0xAAu8; 100].as_slice(), bus.read_data(100).as_slice());
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            assert!(bus.read_packet().is_none());
+        }),
+    ] }
+}
+
+#[test]
+fn should_detect_reads_continuing_previous() {
+    fn read(bus: &DummyUsbBus, lba: u64, len: u64) {
+        bus.clear_halt(); // no data is read
+        let cbw = Cbw {
+            data_transfer_len: len as u32 * 512,
+            direction: DataDirection::In,
+            block: cmd_into_bytes(ScsiCommand::Read { lba, len }),
+        };
+        bus.write_cbw(cbw);
+    }
+
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| read(bus, 0, 2)),
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                assert!(!cmd.continues_previous());
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.read_cs().unwrap();
+            read(bus, 2, 1);
+        }),
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                assert!(cmd.continues_previous());
+                cmd.fail();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.read_cs().unwrap();
+            read(bus, 3, 1); // the previous one has failed
+        }),
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                assert!(!cmd.continues_previous());
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.read_cs().unwrap();
+            read(bus, 4, 1);
+        }),
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                assert!(cmd.continues_previous());
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.read_cs().unwrap();
+            read(bus, 0, 1); // backwards
+        }),
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                assert!(!cmd.continues_previous());
+                cmd.pass();
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+        }),
+    ] }
+}
+
+static PULL_UP: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+#[test]
+fn should_stop_answering_while_detached() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            PULL_UP.lock().unwrap().clear();
+            scsi.set_pull_up(Some(|connected| PULL_UP.lock().unwrap().push(connected)));
+        },
+        [
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| scsi.detach()),
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 0,
+                direction: DataDirection::NotExpected,
+                block: cmd_into_bytes(ScsiCommand::TestUnitReady),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert!(bus.read_packet().is_none());
+            assert_eq!(vec![false], *PULL_UP.lock().unwrap());
+        }),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            assert!(!scsi.has_command());
+            scsi.attach();
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(vec![false, true], *PULL_UP.lock().unwrap());
+            // the command sent while detached is answered with the unit attention
+            assert_eq!(CommandStatus::Failed, bus.read_cs().unwrap().status);
+
+            let cbw = Cbw {
+                data_transfer_len: 18,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::RequestSense { desc: false, alloc_len: 18 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let sense = bus.read_data(18);
+            assert_eq!([0x06, 0x28, 0x00], [sense[2], sense[12], sense[13]]);
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+        }),
+    ] }
+}
+
+#[test]
+fn should_detach_for_reenumeration_if_unsupported_by_bus() {
+    let bus = DummyUsbBus::new();
+    let usb_bus = UsbBusAllocator::new(bus.clone());
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+    let mut detached = false;
+    force_reenumeration(&mut usb_dev, || detached = true).unwrap();
+    assert!(detached);
+}
+
+#[test]
+fn should_track_host_activity() {
+    fn no_data(bus: &DummyUsbBus, cmd: ScsiCommand) {
+        let cbw = Cbw {
+            data_transfer_len: 0,
+            direction: DataDirection::NotExpected,
+            block: cmd_into_bytes(cmd),
+        };
+        bus.write_cbw(cbw);
+    }
+
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            assert!(!scsi.host_activity(0).is_active());
+        }),
+        Step::HostIo(|bus: &DummyUsbBus| {
+            no_data(bus, ScsiCommand::PreventAllowMediumRemoval { prevent: true });
+        }),
+        Step::DevIo,
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| cmd.pass(),
+        ),
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.read_cs().unwrap();
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                cmd.try_write_data_all(&[0u8; 512]).unwrap();
+                cmd.pass();
+            },
+        ),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            let activity = scsi.host_activity(0);
+            assert!(activity.accessed && activity.removal_prevented && !activity.stopped);
+            assert!(!scsi.host_activity(0).accessed); // cleared
+            assert!(scsi.host_activity(0).is_active()); // the medium is held
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(512, bus.read_data(512).len());
+            bus.read_cs().unwrap();
+            no_data(bus, ScsiCommand::PreventAllowMediumRemoval { prevent: false });
+        }),
+        Step::DevIo,
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| cmd.pass(),
+        ),
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.read_cs().unwrap();
+            no_data(bus, ScsiCommand::StartStopUnit {
+                immed: false,
+                power_condition: 0,
+                load_eject: true,
+                start: false,
+            });
+        }),
+        Step::DevIo,
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| cmd.pass(),
+        ),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            let activity = scsi.host_activity(0);
+            assert!(activity.stopped && !activity.removal_prevented);
+            assert!(!activity.is_active());
+            UsbClass::reset(scsi);
+            assert!(!scsi.host_activity(0).stopped);
+        }),
+    ] }
+}
+
+#[test]
+fn should_decode_mode_select_parameters() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 24,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(ScsiCommand::ModeSelect6 {
+                    pf: true,
+                    sp: false,
+                    parameter_list_len: 24,
+                }),
+            };
+            bus.write_cbw(cbw);
+            let mut data = [0u8; 24];
+            data[4..7].copy_from_slice(&[0x08, 0x12, 0b00000100]); // Caching, WCE
+            bus.write_data(data.as_slice());
+        }),
+        Step::DevIo,
+        Step::DevCmdHandle(
+            |mut cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| {
+                let ScsiCommand::ModeSelect6 { parameter_list_len, .. } = cmd.kind else {
+                    panic!("unexpected command {:?}", cmd.kind);
+                };
+                let mut data = [0u8; 255];
+                let data = &mut data[..parameter_list_len as usize];
+                assert_eq!(24, cmd.read_data(data).unwrap());
+                let list = decode_mode_select_6(data).unwrap();
+                match list.pages().next() {
+                    Some(Ok(ModePage::Caching(page))) if page.wce && !page.rcd => cmd.pass(),
+                    page => panic!("unexpected page {page:?}"),
+                }
+            },
+        ),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let expected_csw = Csw {
+                data_transfer_len: 0,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+        }),
+    ] }
+}
+
+#[test]
+fn should_describe_bulk_endpoints_with_interval() {
+    common::timeout(TIMEOUT, || {
+        for (interval, expected) in [(None, 0), (Some(0), 0), (Some(4), 4)] {
+            let mut io_buf = [0u8; 1024];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let buf = io_buf.as_mut_slice();
+            let mut scsi = match interval {
+                Some(interval) => Scsi::new_with_interval(&usb_bus, 64, 0, buf, interval),
+                None => Scsi::new(&usb_bus, 64, 0, buf),
+            }
+            .unwrap();
+            let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd))
+                .max_packet_size_0(64)
+                .unwrap()
+                .build();
+
+            // GET_DESCRIPTOR(Configuration)
+            bus.control_in(0b0000_0000, 0x06, 0x0200, 0, 255);
+            usb_dev.poll(&mut [&mut scsi]);
+            let descriptors = bus.control_in_data().unwrap();
+
+            let mut intervals = vec![];
+            let mut rest = descriptors.as_slice();
+            while let [len, kind, ..] = *rest {
+                if kind == 0x05 {
+                    intervals.push(rest[6]);
+                }
+                rest = &rest[len as usize..];
+            }
+            assert_eq!(vec![expected, expected], intervals, "{interval:?}");
+        }
+    });
+}
+
+#[test]
+fn should_serve_class_requests_during_data_transfer() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 2, io_buf.as_mut_slice()).unwrap();
+        let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+        bus.write_cbw(Cbw {
+            data_transfer_len: 1024,
+            direction: DataDirection::In,
+            block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 2 }),
+        });
+        while !scsi.drive_transport().unwrap() {}
+        scsi.handle_command(|mut cmd| {
+            cmd.write_data([0xAAu8; 512].as_slice()).unwrap(); // the first block
+        })
+        .unwrap();
+        assert_eq!(64, bus.read_data(64).len());
+
+        // answered without disturbing the data transfer
+        bus.control_in(0b0010_0001, 0xFE, 0, 0, 1);
+        usb_dev.poll(&mut [&mut scsi]);
+        assert_eq!(Some(vec![2]), bus.control_in_data());
+        // a malformed reset and a request addressed to another interface are stalled
+        bus.control_out(0b0010_0001, 0xFF, 1, 0);
+        usb_dev.poll(&mut [&mut scsi]);
+        assert!(bus.is_control_rejected());
+        bus.control_out(0b0010_0001, 0xFF, 0, 1);
+        usb_dev.poll(&mut [&mut scsi]);
+        assert!(bus.is_control_rejected());
+        assert!(!scsi.transport().in_reset_recovery());
+        scsi.drive_transport().unwrap();
+        assert_eq!(64, bus.read_data(64).len());
+
+        // aborts the data transfer
+        bus.bulk_only_reset();
+        usb_dev.poll(&mut [&mut scsi]);
+        assert!(!bus.is_control_rejected());
+        assert!(scsi.transport().in_reset_recovery());
+        assert!(matches!(
+            scsi.take_aborted().map(|aborted| aborted.kind),
+            Some(ScsiCommand::Read { lba: 0, len: 2 })
+        ));
+        for _ in 0..16 {
+            scsi.poll(|_| panic!("unexpected command")).unwrap();
+        }
+        assert!(bus.read_data(1024).len() <= 64); // at most a packet loaded before the reset
+    });
+}
+
+#[test]
+fn should_report_data_progress() {
+    static PROGRESS: Mutex<Vec<(u8, u32, u32)>> = Mutex::new(Vec::new());
+
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 1, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        scsi.transport_mut()
+            .set_on_data_progress(Some(|lun, transferred, total| {
+                PROGRESS.lock().unwrap().push((lun, transferred, total));
+            }));
+
+        let cbw = Cbw {
+            data_transfer_len: 1024,
+            direction: DataDirection::Out,
+            block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 2 }),
+        };
+        bus.write_cbw_to_lun(cbw, 1);
+        bus.write_data([0x55u8; 1024].as_slice());
+        for _ in 0..64 {
+            scsi.poll(|mut cmd| {
+                let mut buf = [0u8; 1024];
+                cmd.read_data(buf.as_mut_slice()).unwrap();
+                if cmd.data_residue() == 0 {
+                    cmd.pass();
+                }
+            })
+            .unwrap();
+        }
+        assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+        let progress = std::mem::take(&mut *PROGRESS.lock().unwrap());
+        let expected: Vec<_> = (1..=16).map(|i| (1, i * 64, 1024)).collect();
+        assert_eq!(expected, progress);
+
+        // the host expects more than sent
+        let cbw = Cbw {
+            data_transfer_len: 512,
+            direction: DataDirection::In,
+            block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+        };
+        bus.write_cbw(cbw);
+        for _ in 0..64 {
+            scsi.poll(|mut cmd| {
+                cmd.write_data([0xAAu8; 100].as_slice()).unwrap();
+                cmd.pass();
+            })
+            .unwrap();
+        }
+        assert_eq!(100, bus.read_data(512).len());
+        let progress = std::mem::take(&mut *PROGRESS.lock().unwrap());
+        assert_eq!(vec![(0, 64, 512), (0, 100, 512)], progress);
+    });
+}
+
+#[test]
+fn should_mirror_bulk_packets_to_tap() {
+    static PACKETS: Mutex<Vec<(UsbDirection, usize, u32)>> = Mutex::new(Vec::new());
+
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        scsi.transport_mut().set_packet_tap(Some(|packet| {
+            PACKETS
+                .lock()
+                .unwrap()
+                .push((packet.direction, packet.bytes.len(), packet.seq));
+        }));
+
+        let cbw = Cbw {
+            data_transfer_len: 512,
+            direction: DataDirection::In,
+            block: cmd_into_bytes(ScsiCommand::Read { lba: 0, len: 1 }),
+        };
+        bus.write_cbw(cbw);
+        for _ in 0..64 {
+            scsi.poll(|mut cmd| {
+                cmd.try_write_data_all([0xAAu8; 512].as_slice()).unwrap();
+                cmd.pass();
+            })
+            .unwrap();
+        }
+        assert_eq!(512, bus.read_data(512).len());
+        assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+
+        let packets = std::mem::take(&mut *PACKETS.lock().unwrap());
+        let mut expected = vec![(UsbDirection::Out, 31, 0)];
+        expected.extend((1..=8).map(|seq| (UsbDirection::In, 64, seq)));
+        expected.push((UsbDirection::In, 13, 9));
+        assert_eq!(expected, packets);
+    });
+}
+
+#[test]
+fn should_notify_of_pending_commands_and_data() {
+    static NOTIFICATIONS: Mutex<Vec<Notification>> = Mutex::new(Vec::new());
+
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        scsi.transport_mut().set_notify(Some(|notification| {
+            NOTIFICATIONS.lock().unwrap().push(notification);
+        }));
+
+        // no command, no notification
+        for _ in 0..4 {
+            assert!(!scsi.drive_transport().unwrap());
+        }
+        assert!(NOTIFICATIONS.lock().unwrap().is_empty());
+
+        bus.write_cbw(Cbw {
+            data_transfer_len: 128,
+            direction: DataDirection::Out,
+            block: cmd_into_bytes(ScsiCommand::Write { lba: 0, len: 1 }),
+        });
+        assert!(scsi.drive_transport().unwrap());
+        assert_eq!(
+            vec![Notification::CommandPending],
+            std::mem::take(&mut *NOTIFICATIONS.lock().unwrap())
+        );
+        bus.write_data(&[0xAA; 128]);
+        for _ in 0..4 {
+            scsi.drive_transport().unwrap();
+        }
+        assert_eq!(
+            vec![Notification::DataReceived; 2],
+            std::mem::take(&mut *NOTIFICATIONS.lock().unwrap())
+        );
+    });
+}
+
+#[test]
+#[cfg(feature = "history")]
+fn should_record_command_history() {
+    fn no_data(bus: &DummyUsbBus, cmd: ScsiCommand) {
+        let cbw = Cbw {
+            data_transfer_len: 0,
+            direction: DataDirection::NotExpected,
+            block: cmd_into_bytes(cmd),
+        };
+        bus.write_cbw(cbw);
+    }
+
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            no_data(bus, ScsiCommand::Reserve6);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.read_cs().unwrap();
+            let cbw = Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::Read { lba: 5, len: 1 }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevCmdHandle(
+            |cmd: Command<ScsiCommand, Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>>| cmd.fail(),
+        ),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            let history: Vec<_> = scsi
+                .history()
+                .map(|entry| (entry.opcode, entry.lba, entry.status))
+                .collect();
+            assert_eq!(
+                vec![
+                    (0x16, 0, TransportCommandStatus::Passed),
+                    (0x28, 5, TransportCommandStatus::Failed),
+                ],
+                history
+            );
+            scsi.clear_history();
+            assert_eq!(0, scsi.history().count());
+        }),
+    ] }
+}
+
+#[test]
+#[cfg(feature = "metrics")]
+fn should_record_transfer_lengths_and_gaps() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+        for (len, idle_polls) in [(0, 3), (512, 5), (0, 0)] {
+            for _ in 0..idle_polls {
+                scsi.poll(|_| panic!("no command expected")).unwrap();
+            }
+            let (direction, cmd) = match len {
+                0 => (DataDirection::NotExpected, ScsiCommand::TestUnitReady),
+                _ => (DataDirection::In, ScsiCommand::Read { lba: 0, len: 1 }),
+            };
+            bus.write_cbw(Cbw {
+                data_transfer_len: len,
+                direction,
+                block: cmd_into_bytes(cmd),
+            });
+            // drain the data and the CSW as they come, so that the device goes idle right away
+            let mut received = vec![];
+            while received.len() < len as usize + 13 {
+                scsi.poll(|mut cmd| {
+                    if len > 0 {
+                        cmd.write_data(&[0u8; 512]).unwrap();
+                    }
+                    cmd.pass();
+                })
+                .unwrap();
+                while let Some(mut packet) = bus.read_packet() {
+                    received.append(&mut packet);
+                }
+            }
+            let csw = Csw::from_bytes(&received[len as usize..]);
+            assert_eq!(CommandStatus::Passed, csw.status);
+        }
+
+        let metrics = scsi.transport().metrics();
+        assert_eq!(3, metrics.transfer_len.count());
+        assert_eq!(2, metrics.transfer_len.buckets()[0]);
+        assert_eq!(1, metrics.transfer_len.buckets()[10]);
+        // the gap before the first command is not recorded
+        assert_eq!(2, metrics.command_gap.count());
+        // the idle polls are counted from the status on, which may leave a poll earlier
+        let gaps = metrics.command_gap.buckets();
+        assert_eq!((1, 1), (gaps[0] + gaps[1], gaps[3]));
+        scsi.transport_mut().reset_metrics();
+        assert_eq!(0, scsi.transport().metrics().transfer_len.count());
+    });
+}
+
+#[test]
+fn should_guess_host_os_from_early_commands() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT,
+        |scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| scsi.set_capacity(0, 100),
+        [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 192,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::ModeSense6 {
+                    dbd: false,
+                    page_control: PageControl::CurrentValues,
+                    page_code: 0x3F,
+                    subpage_code: 0,
+                    alloc_len: 192,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            bus.read_data(192);
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+        }),
+        Step::DevAction(|scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>| {
+            assert_eq!(HostOs::Linux, scsi.host_os());
+            UsbClass::reset(scsi);
+            assert_eq!(HostOs::Unknown, scsi.host_os());
+        }),
+    ] }
+}
+
+fn set_mode_pages(scsi: &mut Scsi<BulkOnly<DummyUsbBus, &mut [u8]>>) {
+    let mut pages = caching_mode_page().to_vec();
+    pages.extend_from_slice(&[0x1C, 0x0A, 0x08, 0x03, 0, 0, 0, 0, 0, 0, 0, 0]);
+    scsi.set_capacity(0, 100);
+    scsi.set_mode_pages(&pages);
+}
+
+#[test]
+fn should_answer_mode_sense_with_registered_pages() {
+    run_on_scsi_bbb_bus_timed! { TIMEOUT, set_mode_pages, [
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let cbw = Cbw {
+                data_transfer_len: 255,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::ModeSense10 {
+                    dbd: false,
+                    page_control: PageControl::CurrentValues,
+                    page_code: 0x3F,
+                    subpage_code: 0,
+                    alloc_len: 255,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            let data = bus.read_data(255);
+            assert_eq!(48, data.len());
+            // mode data length, WP unset, block descriptor length
+            assert_eq!([0x00, 0x2E, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08], data[..8]);
+            assert_eq!(caching_mode_page(), data[16..36]);
+            assert_eq!([0x1C, 0x0A, 0x08, 0x03], data[36..40]);
+            let expected_csw = Csw {
+                data_transfer_len: 255 - 48,
+                status: CommandStatus::Passed,
+            };
+            assert_eq!(expected_csw, bus.read_cs().unwrap());
+            bus.clear_halt();
+
+            let cbw = Cbw {
+                data_transfer_len: 192,
+                direction: DataDirection::In,
+                block: cmd_into_bytes(ScsiCommand::ModeSense6 {
+                    dbd: true,
+                    page_control: PageControl::CurrentValues,
+                    page_code: 0x1C,
+                    subpage_code: 0,
+                    alloc_len: 192,
+                }),
+            };
+            bus.write_cbw(cbw);
+        }),
+        Step::DevIo,
+        Step::HostIo(|bus: &DummyUsbBus| {
+            assert_eq!(
+                [0x0F, 0x00, 0x00, 0x00, 0x1C, 0x0A, 0x08, 0x03, 0, 0, 0, 0, 0, 0, 0, 0],
+                bus.read_data(192).as_slice()
+            );
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+        }),
+    ] }
+}
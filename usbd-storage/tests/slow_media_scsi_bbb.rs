@@ -0,0 +1,210 @@
+mod common;
+
+use crate::common::bbb::{CommandStatus, DataDirection, DummyUsbBus};
+use crate::common::initiator::{BlockStream, Initiator};
+use crate::common::ramdisk::RamDisk;
+use crate::common::slowdisk::SlowDisk;
+use fatfs::{FileSystem, FormatVolumeOptions, FsOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+const BLOCK_SIZE: usize = 512;
+const VOLUME_SIZE: usize = 256 * 1024;
+/// Device polls each block of the media takes
+const LATENCY: usize = 8;
+/// Idle device polls the host waits for a slow block before giving up on a command
+const IDLE_POLLS: usize = 2 * LATENCY;
+
+const FILE_CONTENTS: &[u8] = b"Hello from slow media!";
+
+fn request_sense<F: FnMut()>(initiator: &mut Initiator<F>) -> (u8, u8) {
+    let cmd = ScsiCommand::RequestSense {
+        desc: false,
+        alloc_len: 18,
+    };
+    let (data, csw) = initiator.execute(cmd, DataDirection::In, 18, &[]);
+    assert_eq!(CommandStatus::Passed, csw.status);
+    (data[2] & 0x0F, data[12])
+}
+
+#[test]
+fn should_round_trip_fat_volume_on_slow_media() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let dummy_bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(dummy_bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let mut disk = SlowDisk::new(
+                RamDisk::new(BLOCK_SIZE, VOLUME_SIZE / BLOCK_SIZE),
+                LATENCY,
+                0,
+                1,
+            );
+            scsi.set_capacity(0, disk.disk().num_blocks() as u64);
+
+            {
+                let initiator = Initiator::new(&dummy_bus, || {
+                    scsi.poll(|command| disk.handle(command)).unwrap();
+                })
+                .with_idle_polls(IDLE_POLLS);
+
+                let mut stream = BlockStream::new(initiator);
+                let options = FormatVolumeOptions::new().bytes_per_sector(BLOCK_SIZE as u16);
+                fatfs::format_volume(&mut stream, options).unwrap();
+
+                stream.seek(SeekFrom::Start(0)).unwrap();
+                {
+                    let fs = FileSystem::new(&mut stream, FsOptions::new()).unwrap();
+                    let mut file = fs.root_dir().create_file("SLOW.TXT").unwrap();
+                    file.write_all(FILE_CONTENTS).unwrap();
+                    file.flush().unwrap();
+                    drop(file);
+                    fs.unmount().unwrap();
+                }
+
+                stream.seek(SeekFrom::Start(0)).unwrap();
+                {
+                    let fs = FileSystem::new(&mut stream, FsOptions::new()).unwrap();
+                    let mut contents = vec![];
+                    fs.root_dir()
+                        .open_file("SLOW.TXT")
+                        .unwrap()
+                        .read_to_end(&mut contents)
+                        .unwrap();
+                    assert_eq!(FILE_CONTENTS, contents.as_slice());
+                }
+            }
+
+            assert_eq!([0x55, 0xAA], disk.disk().data()[510..512]);
+        }
+    });
+}
+
+#[test]
+fn should_fail_commands_on_medium_errors() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let dummy_bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(dummy_bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let mut ram = RamDisk::new(BLOCK_SIZE, 16);
+            for (i, byte) in ram.data_mut().iter_mut().enumerate() {
+                *byte = (i / BLOCK_SIZE) as u8;
+            }
+            let mut disk = SlowDisk::new(ram, 2, 4, packet_size as u32);
+            scsi.set_capacity(0, disk.disk().num_blocks() as u64);
+
+            let (mut passed, mut failed) = (0, 0);
+            {
+                let mut initiator = Initiator::new(&dummy_bus, || {
+                    scsi.poll(|command| disk.handle(command)).unwrap();
+                })
+                .with_idle_polls(IDLE_POLLS);
+
+                for lba in 0..15u64 {
+                    let cmd = ScsiCommand::Read { lba, len: 2 };
+                    let len = 2 * BLOCK_SIZE as u32;
+                    let (data, csw) = initiator.execute(cmd, DataDirection::In, len, &[]);
+                    match csw.status {
+                        CommandStatus::Passed => {
+                            assert_eq!(0, csw.data_transfer_len);
+                            assert!(data[..BLOCK_SIZE].iter().all(|b| *b == lba as u8));
+                            assert!(data[BLOCK_SIZE..].iter().all(|b| *b == lba as u8 + 1));
+                            passed += 1;
+                        }
+                        CommandStatus::Failed => {
+                            assert_eq!(len - data.len() as u32, csw.data_transfer_len);
+                            assert_eq!((0x03, 0x11), request_sense(&mut initiator));
+                            failed += 1;
+                        }
+                        status => panic!("unexpected {:?} reading {}", status, lba),
+                    }
+                }
+                // written blocks fail the same way
+                let (_, csw) = initiator.execute(
+                    ScsiCommand::Write { lba: 0, len: 16 },
+                    DataDirection::Out,
+                    16 * BLOCK_SIZE as u32,
+                    &[0u8; 16 * BLOCK_SIZE],
+                );
+                assert_eq!(CommandStatus::Failed, csw.status);
+                assert_eq!((0x03, 0x0C), request_sense(&mut initiator));
+            }
+
+            assert!(passed > 0 && failed > 0);
+            assert_eq!(failed + 1, disk.errors());
+        }
+    });
+}
+
+#[test]
+fn should_complete_commands_across_changes_of_unit() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 1024];
+            let dummy_bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(dummy_bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let mut disk = SlowDisk::new(RamDisk::new(BLOCK_SIZE, 16), LATENCY, 0, 1);
+            scsi.set_capacity(0, disk.disk().num_blocks() as u64);
+
+            let mut started = false;
+            {
+                let mut initiator = Initiator::new(&dummy_bus, || {
+                    // write protect and shrink the unit once a command is in progress
+                    if started {
+                        scsi.set_write_protected(0, true);
+                        scsi.set_capacity(0, 1);
+                    }
+                    scsi.poll(|command| {
+                        started = true;
+                        disk.handle(command)
+                    })
+                    .unwrap();
+                })
+                .with_idle_polls(IDLE_POLLS);
+
+                let data = [0xA5u8; 8 * BLOCK_SIZE];
+                let (_, csw) = initiator.execute(
+                    ScsiCommand::Write { lba: 8, len: 8 },
+                    DataDirection::Out,
+                    data.len() as u32,
+                    &data,
+                );
+                assert_eq!(CommandStatus::Passed, csw.status);
+
+                // the conditions apply to the next commands
+                let (_, csw) = initiator.execute(
+                    ScsiCommand::TestUnitReady,
+                    DataDirection::NotExpected,
+                    0,
+                    &[],
+                );
+                assert_eq!(CommandStatus::Failed, csw.status);
+                assert_eq!((0x06, 0x2A), request_sense(&mut initiator));
+                let (_, csw) = initiator.execute(
+                    ScsiCommand::Read { lba: 8, len: 1 },
+                    DataDirection::In,
+                    BLOCK_SIZE as u32,
+                    &[],
+                );
+                assert_eq!(CommandStatus::Failed, csw.status);
+                assert_eq!((0x05, 0x21), request_sense(&mut initiator));
+            }
+
+            assert!(disk.disk().data()[8 * BLOCK_SIZE..]
+                .iter()
+                .all(|b| *b == 0xA5));
+        }
+    });
+}
@@ -0,0 +1,148 @@
+mod common;
+
+use crate::common::bbb::{CommandStatus, DataDirection, DummyUsbBus};
+use crate::common::initiator::Initiator;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usb_device::UsbDirection;
+use usbd_storage::subclass::scsi::block::{BlockDevice, BlockDriver};
+use usbd_storage::subclass::scsi::capacity::BlockSize;
+use usbd_storage::subclass::scsi::sense::Sense;
+use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+const BLOCK_SIZE: usize = 512;
+/// 64 MiB medium
+const BLOCKS: u64 = 128 * 1024;
+/// Bytes read at each packet size
+const READ_PER_PACKET_SIZE: usize = 8 * 1024 * 1024;
+/// Max blocks of a single READ
+const MAX_LEN: u64 = 64;
+const CBW_LEN: usize = 31;
+const CSW_LEN: usize = 13;
+
+/// Bytes of the bulk packets tapped in both directions
+static IN_BYTES: AtomicUsize = AtomicUsize::new(0);
+static OUT_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Pseudo-random byte at a position of the medium
+fn pattern(pos: u64) -> u8 {
+    let mut x = pos.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    x ^= x >> 29;
+    (x >> 40) as u8
+}
+
+/// Read only medium of [pattern] bytes, generated on the fly
+struct PatternDisk;
+
+impl BlockDevice for PatternDisk {
+    fn block_size(&self) -> BlockSize {
+        BlockSize::B512
+    }
+
+    fn num_blocks(&self) -> u64 {
+        BLOCKS
+    }
+
+    fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), Sense> {
+        let start = lba * BLOCK_SIZE as u64;
+        for (i, byte) in block.iter_mut().enumerate() {
+            *byte = pattern(start + i as u64);
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, _lba: u64, _block: &[u8]) -> Result<(), Sense> {
+        Err(Sense::WRITE_PROTECTED)
+    }
+}
+
+/// xorshift64, so that the commands are the same on every run
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[test]
+fn should_read_tens_of_megabytes_intact() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            // not a multiple of the block size, so that the blocks wrap around the IO buffer
+            let mut io_buf = [0u8; 1000 + BLOCK_SIZE];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            let mut disk = PatternDisk;
+            scsi.set_block_device(0, &disk);
+            let mut driver = BlockDriver::new([0u8; 2 * BLOCK_SIZE]);
+            IN_BYTES.store(0, Ordering::Relaxed);
+            OUT_BYTES.store(0, Ordering::Relaxed);
+            scsi.transport_mut().set_packet_tap(Some(|packet| {
+                let bytes = match packet.direction {
+                    UsbDirection::In => &IN_BYTES,
+                    UsbDirection::Out => &OUT_BYTES,
+                };
+                bytes.fetch_add(packet.bytes.len(), Ordering::Relaxed);
+            }));
+
+            let mut commands = 0;
+            let mut read = 0;
+            {
+                let mut initiator = Initiator::new(&bus, || {
+                    scsi.poll(|cmd| {
+                        if let Some(cmd) = driver.handle(&mut disk, cmd) {
+                            panic!("unexpected {:?}", cmd.kind);
+                        }
+                    })
+                    .unwrap();
+                });
+
+                let mut rng = Rng(0x2545_F491_4F6C_DD1D ^ packet_size as u64);
+                while read < READ_PER_PACKET_SIZE {
+                    let len = rng.next() % MAX_LEN + 1;
+                    let lba = rng.next() % (BLOCKS - len + 1);
+                    let transfer_len = len as u32 * BLOCK_SIZE as u32;
+                    let (data, csw) = initiator.execute(
+                        ScsiCommand::Read { lba, len },
+                        DataDirection::In,
+                        transfer_len,
+                        &[],
+                    );
+                    commands += 1;
+                    assert_eq!(CommandStatus::Passed, csw.status);
+                    assert_eq!(0, csw.data_transfer_len, "residue");
+                    assert_eq!(transfer_len as usize, data.len());
+                    let start = lba * BLOCK_SIZE as u64;
+                    if let Some(i) =
+                        (0..data.len()).find(|i| data[*i] != pattern(start + *i as u64))
+                    {
+                        panic!(
+                            "packet size {packet_size}: byte {i} of READ lba={lba} len={len} \
+                             corrupted after {read} bytes"
+                        );
+                    }
+                    read += data.len();
+                }
+            }
+            // a single CSW per command and nothing else besides the data
+            assert_eq!(CBW_LEN * commands, OUT_BYTES.load(Ordering::Relaxed));
+            assert_eq!(
+                read + CSW_LEN * commands,
+                IN_BYTES.load(Ordering::Relaxed),
+                "packet size {packet_size}"
+            );
+            assert!(bus.read_packet().is_none());
+            assert_eq!(0, scsi.transport().stats().in_stalls);
+        }
+    });
+}
@@ -0,0 +1,182 @@
+mod common;
+
+use crate::common::bbb::{CommandStatus, DataDirection, DummyUsbBus};
+use crate::common::initiator::Initiator;
+use crate::common::ramdisk::RamDisk;
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+use embedded_io::{Error, ErrorKind, ErrorType, Read, Seek, SeekFrom, Write};
+use std::convert::Infallible;
+use std::time::Duration;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usbd_storage::subclass::scsi::block::BlockDriver;
+use usbd_storage::subclass::scsi::capacity::BlockSize;
+use usbd_storage::subclass::scsi::sense::Sense;
+use usbd_storage::subclass::scsi::stream::{BlockStream, StreamDevice};
+use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const BLOCK_SIZE: usize = 512;
+const BLOCKS: usize = 8;
+
+/// Image of a medium in memory, growing as written past its end
+struct Image {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl ErrorType for Image {
+    type Error = Infallible;
+}
+
+impl Read for Image {
+    fn read(&mut self, dst: &mut [u8]) -> Result<usize, Infallible> {
+        let src = &self.data[self.pos.min(self.data.len())..];
+        let count = src.len().min(dst.len());
+        dst[..count].copy_from_slice(&src[..count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+impl Write for Image {
+    fn write(&mut self, src: &[u8]) -> Result<usize, Infallible> {
+        let end = self.pos + src.len();
+        if end > self.data.len() {
+            self.data.resize(end, 0);
+        }
+        self.data[self.pos..end].copy_from_slice(src);
+        self.pos = end;
+        Ok(src.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+impl Seek for Image {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Infallible> {
+        let SeekFrom::Start(pos) = pos else {
+            unimplemented!("the blocks are sought from the start")
+        };
+        self.pos = pos as usize;
+        Ok(pos)
+    }
+}
+
+/// Polls `future` until it completes, for futures never waiting on anything
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+#[test]
+fn should_stream_block_device() {
+    let mut disk = RamDisk::new(BLOCK_SIZE, BLOCKS);
+    let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    let len = (BLOCK_SIZE * BLOCKS) as u64;
+
+    let mut stream = BlockStream::new(&mut disk, [0u8; BLOCK_SIZE]);
+    // the head and the tail are parts of blocks
+    assert_eq!(100, stream.seek(SeekFrom::Start(100)).unwrap());
+    stream.write_all(&data).unwrap();
+    assert_eq!(
+        100,
+        stream
+            .seek(SeekFrom::Current(-(data.len() as i64)))
+            .unwrap()
+    );
+    let mut read = vec![0u8; data.len()];
+    stream.read_exact(&mut read).unwrap();
+    assert_eq!(data, read);
+
+    // the stream ends at the end of the medium
+    assert_eq!(len, stream.seek(SeekFrom::End(0)).unwrap());
+    assert_eq!(0, stream.read(&mut read).unwrap());
+    assert_eq!(Err(Sense::LBA_OUT_OF_RANGE), stream.write(&data));
+    let error = stream.seek(SeekFrom::End(1)).unwrap_err();
+    assert_eq!(ErrorKind::InvalidInput, error.kind());
+    assert!(stream.seek(SeekFrom::Current(-(len as i64) - 1)).is_err());
+    assert_eq!(len - 10, stream.seek(SeekFrom::End(-10)).unwrap());
+    assert_eq!(10, stream.read(&mut read).unwrap());
+
+    let written = &disk.data()[100..100 + data.len()];
+    assert_eq!(data.as_slice(), written);
+    assert!(disk.data()[..100].iter().all(|b| *b == 0));
+    assert!(disk.data()[100 + data.len()..].iter().all(|b| *b == 0));
+}
+
+#[test]
+fn should_stream_block_device_async() {
+    let mut disk = RamDisk::new(BLOCK_SIZE, BLOCKS);
+    let data = [0xAAu8; BLOCK_SIZE + 1];
+
+    let mut stream = BlockStream::new(&mut disk, [0u8; BLOCK_SIZE]);
+    block_on(async {
+        embedded_io_async::Seek::seek(&mut stream, SeekFrom::Start(BLOCK_SIZE as u64 - 1)).await?;
+        embedded_io_async::Write::write_all(&mut stream, &data).await?;
+        embedded_io_async::Write::flush(&mut stream).await
+    })
+    .unwrap();
+
+    let written = &disk.data()[BLOCK_SIZE - 1..];
+    assert_eq!(data.as_slice(), &written[..data.len()]);
+    assert_eq!(0, written[data.len()]);
+}
+
+#[test]
+fn should_serve_stream_device() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 1024];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut scsi = Scsi::new(&usb_bus, 64, 0, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+        // the image is a block short of the medium
+        let image = Image {
+            data: vec![0u8; BLOCK_SIZE * (BLOCKS - 1)],
+            pos: 0,
+        };
+        let mut device = StreamDevice::new(image, BlockSize::B512, BLOCKS as u64);
+        scsi.set_block_device(0, &device);
+        let mut driver = BlockDriver::new([0u8; 2 * BLOCK_SIZE]);
+
+        {
+            let mut initiator = Initiator::new(&bus, || {
+                scsi.poll(|cmd| {
+                    if driver.handle(&mut device, cmd).is_some() {
+                        panic!("unexpected command");
+                    }
+                })
+                .unwrap();
+            });
+
+            let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+            initiator.write_10(2, &data, BLOCK_SIZE);
+            assert_eq!(data, initiator.read_10(2, 3, BLOCK_SIZE));
+
+            let cmd = ScsiCommand::Read {
+                lba: BLOCKS as u64 - 1,
+                len: 1,
+            };
+            let len = BLOCK_SIZE as u32;
+            let (_, csw) = initiator.execute(cmd, DataDirection::In, len, &[]);
+            assert_eq!(CommandStatus::Failed, csw.status);
+        }
+        let image = device.into_inner().data;
+        let written = &image[2 * BLOCK_SIZE..5 * BLOCK_SIZE];
+        assert!(written
+            .iter()
+            .enumerate()
+            .all(|(i, b)| *b == (i % 251) as u8));
+    });
+}
@@ -0,0 +1,189 @@
+//! Compliance of the Bulk Only Transport with the Thirteen Cases (BOT 6.7)
+//!
+//! Each case is a combination of what the host expects (`Hn`, `Hi`, `Ho`) and what the device
+//! intends (`Dn`, `Di`, `Do`). The device side is a well-behaved handler using only the public API.
+//! The outcome of every case is collected into a report printed on failure.
+
+mod common;
+
+use crate::common::bbb::{Cbw, CommandStatus, Csw, DataDirection, DummyUsbBus};
+use crate::common::scsi::cmd_into_bytes;
+use std::fmt::Write;
+use std::time::Duration;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usbd_storage::subclass::scsi::{Scsi, ScsiCommand};
+use usbd_storage::subclass::Command;
+use usbd_storage::transport::bbb::BulkOnly;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of device polls after which a case is considered done
+const POLLS: usize = 1024;
+
+type Storage<'a> = Scsi<BulkOnly<'a, DummyUsbBus, &'a mut [u8]>>;
+
+#[derive(Copy, Clone, Debug)]
+enum Host {
+    N,
+    I(u32),
+    O(u32),
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Device {
+    N,
+    I(usize),
+    O(usize),
+}
+
+/// What the host observes
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    /// Number of data bytes received or accepted by the device
+    data_len: usize,
+    /// Whether the device has stalled the OUT endpoint to end the data transfer
+    out_stalled: bool,
+    csw: Csw,
+}
+
+struct Case {
+    number: u8,
+    host: Host,
+    device: Device,
+    expected: Outcome,
+}
+
+const fn case(
+    number: u8,
+    host: Host,
+    device: Device,
+    data_len: usize,
+    out_stalled: bool,
+    residue: u32,
+    status: CommandStatus,
+) -> Case {
+    Case {
+        number,
+        host,
+        device,
+        expected: Outcome {
+            data_len,
+            out_stalled,
+            csw: Csw {
+                data_transfer_len: residue,
+                status,
+            },
+        },
+    }
+}
+
+#[rustfmt::skip]
+const CASES: [Case; 13] = [
+    case(1, Host::N, Device::N, 0, false, 0, CommandStatus::Passed),
+    case(2, Host::N, Device::I(512), 0, false, 0, CommandStatus::PhaseError),
+    case(3, Host::N, Device::O(512), 0, false, 0, CommandStatus::PhaseError),
+    case(4, Host::I(512), Device::N, 0, false, 512, CommandStatus::Passed),
+    case(5, Host::I(512), Device::I(200), 200, false, 312, CommandStatus::Passed),
+    case(6, Host::I(512), Device::I(512), 512, false, 0, CommandStatus::Passed),
+    case(7, Host::I(256), Device::I(512), 256, false, 0, CommandStatus::PhaseError),
+    case(8, Host::I(512), Device::O(512), 0, false, 512, CommandStatus::PhaseError),
+    case(9, Host::O(512), Device::N, 0, true, 512, CommandStatus::Passed),
+    case(10, Host::O(512), Device::I(512), 0, true, 512, CommandStatus::PhaseError),
+    case(11, Host::O(512), Device::O(256), 256, true, 256, CommandStatus::Passed),
+    case(12, Host::O(512), Device::O(512), 512, false, 0, CommandStatus::Passed),
+    case(13, Host::O(256), Device::O(512), 256, false, 0, CommandStatus::PhaseError),
+];
+
+/// Serves a command as a device intending `device` would. `done` is the number of data bytes
+/// transferred so far
+fn handle(mut cmd: Command<ScsiCommand, Storage>, device: Device, done: &mut usize) {
+    match device {
+        Device::N => cmd.pass(),
+        Device::I(len) => match cmd.write_data_hinted(&vec![0xAA; len - *done]) {
+            Ok(hint) => {
+                *done += hint.written;
+                if *done == len {
+                    cmd.pass();
+                } else if hint.expected == 0 {
+                    cmd.fail_phase(); // the host expects less
+                }
+            }
+            Err(_) => cmd.fail_phase(), // the host doesn't expect data from the device
+        },
+        Device::O(len) => match cmd.read_data(&mut vec![0; len - *done]) {
+            Ok(count) => {
+                *done += count;
+                if *done == len {
+                    cmd.pass();
+                } else if cmd.data_residue() == 0 {
+                    cmd.fail_phase(); // the host sends less
+                }
+            }
+            Err(_) => cmd.fail_phase(), // the host doesn't send data to the device
+        },
+    }
+}
+
+fn run(case: &Case, packet_size: u16) -> Outcome {
+    let mut io_buf = [0u8; 1024];
+    let bus = DummyUsbBus::new();
+    let usb_bus = UsbBusAllocator::new(bus.clone());
+    let mut scsi = Scsi::new(&usb_bus, packet_size, 0, io_buf.as_mut_slice()).unwrap();
+    let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+    let (direction, data_transfer_len) = match case.host {
+        Host::N => (DataDirection::NotExpected, 0),
+        Host::I(len) => (DataDirection::In, len),
+        Host::O(len) => (DataDirection::Out, len),
+    };
+    bus.write_cbw(Cbw {
+        data_transfer_len,
+        direction,
+        block: cmd_into_bytes(ScsiCommand::Unknown),
+    });
+    if let Host::O(len) = case.host {
+        bus.write_data(&vec![0x55; len as usize]);
+    }
+
+    let mut done = 0;
+    for _ in 0..POLLS {
+        scsi.poll(|cmd| handle(cmd, case.device, &mut done))
+            .unwrap();
+    }
+
+    let data_len = match case.host {
+        Host::I(len) => bus.read_data(len as usize).len(),
+        _ => done,
+    };
+    Outcome {
+        data_len,
+        out_stalled: bus.is_out_stalled(),
+        csw: bus.read_cs().expect("no CSW"),
+    }
+}
+
+#[test]
+fn should_comply_with_thirteen_cases() {
+    common::timeout(TIMEOUT, || {
+        let mut report = String::new();
+        let mut failed = 0;
+        for packet_size in common::PACKET_SIZE {
+            for case in &CASES {
+                let outcome = run(case, packet_size);
+                let verdict = if outcome == case.expected {
+                    "ok"
+                } else {
+                    failed += 1;
+                    "FAILED"
+                };
+                writeln!(
+                    report,
+                    "[{packet_size:>2}] case {:>2} {:?} / {:?}: {verdict}, expected {:?}, got {:?}",
+                    case.number, case.host, case.device, case.expected, outcome
+                )
+                .unwrap();
+            }
+        }
+        assert_eq!(0, failed, "\n{report}");
+    });
+}
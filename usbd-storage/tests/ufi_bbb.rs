@@ -0,0 +1,312 @@
+mod common;
+
+use crate::common::bbb::{Cbw, CommandStatus, Csw, DataDirection, DummyUsbBus};
+use crate::common::ufi::cmd_into_bytes;
+use std::time::Duration;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usbd_storage::subclass::ufi::{lba_to_head, lba_to_sector, lba_to_track, Ufi, UfiCommand};
+use usbd_storage::subclass::Command;
+use usbd_storage::transport::bbb::BulkOnly;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// Number of device polls after which a command is considered done
+const POLLS: usize = 256;
+
+/// 1.44 MB floppy: 80 tracks, 2 heads, 18 sectors per track
+const SEC_TRK: u8 = 18;
+const HEAD_TRK: u8 = 2;
+const BLOCKS: u32 = 2880;
+
+type Floppy<'a> = Ufi<BulkOnly<'a, DummyUsbBus, &'a mut [u8]>>;
+
+/// Sends a command along with OUT `data` and serves it with `handler` expecting the command
+/// to end with `status` at every packet size
+fn run(
+    cbw: Cbw,
+    data: &[u8],
+    status: CommandStatus,
+    mut handler: impl FnMut(Command<UfiCommand, Floppy>),
+) {
+    for packet_size in common::PACKET_SIZE {
+        let mut io_buf = [0u8; 512];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut ufi = Ufi::new(&usb_bus, packet_size, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+        bus.write_cbw(cbw.clone());
+        bus.write_data(data);
+        for _ in 0..POLLS {
+            ufi.poll(&mut handler).unwrap();
+        }
+
+        let expected_csw = Csw {
+            data_transfer_len: 0,
+            status,
+        };
+        assert_eq!(
+            Some(expected_csw),
+            bus.read_cs(),
+            "packet size {packet_size}"
+        );
+    }
+}
+
+fn chs(lba: u32) -> Option<(u32, u32, u32)> {
+    Some((
+        lba_to_track(lba, SEC_TRK, HEAD_TRK)?,
+        lba_to_head(lba, SEC_TRK, HEAD_TRK)?,
+        lba_to_sector(lba, SEC_TRK)?,
+    ))
+}
+
+#[test]
+fn should_format_track_with_parameter_list() {
+    common::timeout(TIMEOUT, || {
+        // defect list header with FmtData and a single format descriptor
+        let mut params = [0u8; 12];
+        params[1] = 0b1011_0000; // FOV, DCRT, SingleTrack
+        params[3] = 8; // defect list length
+        params[4..8].copy_from_slice(&(BLOCKS).to_be_bytes());
+        params[9..12].copy_from_slice(&[0x00, 0x02, 0x00]); // 512 bytes per block
+
+        let cbw = Cbw {
+            data_transfer_len: 12,
+            direction: DataDirection::Out,
+            block: cmd_into_bytes(UfiCommand::FormatUnit {
+                track: 79,
+                parameter_list_len: 12,
+            }),
+        };
+        let mut buf = [0u8; 12];
+        let mut offset = 0;
+        run(cbw, &params, CommandStatus::Passed, |mut cmd| {
+            match cmd.kind {
+                UfiCommand::FormatUnit {
+                    track: 79,
+                    parameter_list_len: 12,
+                } => {
+                    offset += cmd.read_data(&mut buf[offset..]).unwrap();
+                    if offset == buf.len() {
+                        assert_eq!(params, buf);
+                        offset = 0;
+                        cmd.pass();
+                    }
+                }
+                kind => panic!("unexpected command: {kind:?}"),
+            }
+        });
+    });
+}
+
+#[test]
+fn should_seek_to_last_block() {
+    common::timeout(TIMEOUT, || {
+        let cbw = Cbw {
+            data_transfer_len: 0,
+            direction: DataDirection::NotExpected,
+            block: cmd_into_bytes(UfiCommand::Seek { lba: BLOCKS - 1 }),
+        };
+        run(cbw, &[], CommandStatus::Passed, |cmd| match cmd.kind {
+            UfiCommand::Seek { lba } => {
+                assert_eq!(Some((79, 1, 18)), chs(lba));
+                cmd.pass();
+            }
+            kind => panic!("unexpected command: {kind:?}"),
+        });
+    });
+}
+
+#[test]
+fn should_fail_seek_beyond_geometry() {
+    common::timeout(TIMEOUT, || {
+        let cbw = Cbw {
+            data_transfer_len: 0,
+            direction: DataDirection::NotExpected,
+            block: cmd_into_bytes(UfiCommand::Seek { lba: BLOCKS }),
+        };
+        run(cbw, &[], CommandStatus::Failed, |cmd| match cmd.kind {
+            UfiCommand::Seek { lba } => match chs(lba) {
+                Some((track, ..)) if track < 80 => cmd.pass(),
+                _ => cmd.fail(),
+            },
+            kind => panic!("unexpected command: {kind:?}"),
+        });
+    });
+}
+
+#[test]
+fn should_verify_blocks_across_tracks() {
+    common::timeout(TIMEOUT, || {
+        let cbw = Cbw {
+            data_transfer_len: 0,
+            direction: DataDirection::NotExpected,
+            block: cmd_into_bytes(UfiCommand::Verify { lba: 30, len: 10 }),
+        };
+        run(cbw, &[], CommandStatus::Passed, |cmd| match cmd.kind {
+            UfiCommand::Verify { lba: 30, len: 10 } => {
+                assert_eq!(Some((0, 1, 13)), chs(30));
+                assert_eq!(Some((1, 0, 4)), chs(30 + 10 - 1));
+                cmd.pass();
+            }
+            kind => panic!("unexpected command: {kind:?}"),
+        });
+    });
+}
+
+#[test]
+fn should_fail_command_on_zero_geometry() {
+    common::timeout(TIMEOUT, || {
+        let cbw = Cbw {
+            data_transfer_len: 0,
+            direction: DataDirection::NotExpected,
+            block: cmd_into_bytes(UfiCommand::Verify { lba: 0, len: 1 }),
+        };
+        // the medium geometry is unknown yet
+        run(cbw, &[], CommandStatus::Failed, |cmd| {
+            match lba_to_track(0, 0, 0) {
+                Some(_) => cmd.pass(),
+                None => cmd.fail(),
+            }
+        });
+    });
+}
+
+#[test]
+fn should_route_commands_to_second_drive() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 512];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut ufi = Ufi::new_with_max_lun(&usb_bus, 64, 1, io_buf.as_mut_slice()).unwrap();
+        let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+        bus.control_in(0b0010_0001, 0xFE, 0, 0, 1);
+        usb_dev.poll(&mut [&mut ufi]);
+        assert_eq!(Some(vec![1]), bus.control_in_data());
+
+        let cbw = Cbw {
+            data_transfer_len: 0,
+            direction: DataDirection::NotExpected,
+            block: cmd_into_bytes(UfiCommand::TestUnitReady),
+        };
+        bus.write_cbw_to_lun(cbw.clone(), 1);
+        let mut lun = None;
+        for _ in 0..POLLS {
+            ufi.poll(|cmd| {
+                lun = Some(cmd.lun);
+                cmd.pass();
+            })
+            .unwrap();
+        }
+        assert_eq!(Some(1), lun);
+        assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+
+        // beyond max LUN
+        bus.write_cbw_to_lun(cbw, 2);
+        for _ in 0..POLLS {
+            ufi.poll(|_| panic!("unexpected command")).unwrap();
+        }
+        assert!(ufi.transport().in_reset_recovery());
+        assert!(bus.is_in_stalled());
+    });
+}
+
+#[test]
+fn should_follow_write_protection() {
+    common::timeout(TIMEOUT, || {
+        for packet_size in common::PACKET_SIZE {
+            let mut io_buf = [0u8; 512];
+            let bus = DummyUsbBus::new();
+            let usb_bus = UsbBusAllocator::new(bus.clone());
+            let mut ufi = Ufi::new(&usb_bus, packet_size, io_buf.as_mut_slice()).unwrap();
+            let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+            ufi.set_write_protected(true);
+
+            let data_in = |ufi: &mut Floppy, cmd: UfiCommand, len: u32| {
+                bus.write_cbw(Cbw {
+                    data_transfer_len: len,
+                    direction: DataDirection::In,
+                    block: cmd_into_bytes(cmd),
+                });
+                for _ in 0..POLLS {
+                    ufi.poll(|_| panic!("unexpected command")).unwrap();
+                }
+                let data = bus.read_data(len as usize);
+                let csw = bus.read_cs().unwrap();
+                bus.clear_halt();
+                (data, csw)
+            };
+            let mode_sense = |page_code| UfiCommand::ModeSense {
+                page_control: 0,
+                page_code,
+                param_list_len: 0xFF,
+            };
+
+            let (data, csw) = data_in(&mut ufi, mode_sense(0x3F), 0xFF);
+            assert_eq!(CommandStatus::Passed, csw.status);
+            assert_eq!(0xFF - 16, csw.data_transfer_len);
+            assert_eq!([0x00, 0x0E, 0x00, 0x80], data[..4]);
+            assert_eq!([0x1C, 0x06, 0x00, 0x00, 0x01], data[8..13]);
+
+            bus.write_cbw(Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(UfiCommand::Write {
+                    lba: 0,
+                    len: 1,
+                    verify: false,
+                }),
+            });
+            bus.write_data(&[0xAA; 512]);
+            for _ in 0..POLLS {
+                ufi.poll(|_| panic!("unexpected command")).unwrap();
+            }
+            assert_eq!(CommandStatus::Failed, bus.read_cs().unwrap().status);
+            bus.clear_halt();
+
+            let (data, csw) = data_in(&mut ufi, UfiCommand::RequestSense { alloc_len: 18 }, 18);
+            assert_eq!(CommandStatus::Passed, csw.status);
+            assert_eq!(
+                (0x07, 0x27),
+                (data[2], data[12]),
+                "DATA PROTECT, WRITE PROTECTED"
+            );
+
+            ufi.set_write_protected(false);
+            assert!(!ufi.is_write_protected());
+            let (data, _) = data_in(&mut ufi, mode_sense(0x1C), 0xFF);
+            assert_eq!((0x00, 0x00), (data[3], data[12]));
+
+            // protecting the medium doesn't fail a Write in progress
+            bus.write_cbw(Cbw {
+                data_transfer_len: 512,
+                direction: DataDirection::Out,
+                block: cmd_into_bytes(UfiCommand::Write {
+                    lba: 0,
+                    len: 1,
+                    verify: false,
+                }),
+            });
+            let mut dispatched = false;
+            for _ in 0..POLLS {
+                ufi.poll(|_| dispatched = true).unwrap();
+            }
+            assert!(dispatched);
+            ufi.set_write_protected(true);
+            bus.write_data(&[0xAA; 512]);
+            let mut received = 0;
+            for _ in 0..POLLS {
+                ufi.poll(|mut cmd| {
+                    received += cmd.read_data(&mut [0u8; 512]).unwrap();
+                    if received == 512 {
+                        cmd.pass();
+                    }
+                })
+                .unwrap();
+            }
+            assert_eq!(CommandStatus::Passed, bus.read_cs().unwrap().status);
+        }
+    });
+}
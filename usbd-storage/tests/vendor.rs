@@ -0,0 +1,131 @@
+mod common;
+
+use crate::common::bbb::DummyUsbBus;
+use std::time::Duration;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usbd_storage::subclass::vendor::Vendor;
+use usbd_storage::transport::crc::Crc32;
+use usbd_storage::transport::vendor::{SimpleVendorError, SimpleVendorTransport};
+use usbd_storage::transport::{Transport, TransportError};
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// Number of device polls after which a request is considered served
+const POLLS: usize = 256;
+
+type Device<'a> = Vendor<SimpleVendorTransport<'a, DummyUsbBus, &'a mut [u8]>>;
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut bytes = (payload.len() as u32).to_le_bytes().to_vec();
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Reads packets until a short one terminates the response
+fn read_response(bus: &DummyUsbBus) -> Vec<u8> {
+    let mut bytes = vec![];
+    while let Some(mut packet) = bus.read_packet() {
+        let short = packet.len() < 8;
+        bytes.append(&mut packet);
+        if short {
+            break;
+        }
+    }
+    let len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+    assert_eq!(4 + len, bytes.len());
+    bytes.split_off(4)
+}
+
+/// Serves `requests` one by one responding with each payload reversed
+fn run(requests: &[&[u8]]) -> Vec<Vec<u8>> {
+    let mut io_buf = [0u8; 64];
+    let bus = DummyUsbBus::new();
+    let usb_bus = UsbBusAllocator::new(bus.clone());
+    let transport = SimpleVendorTransport::new(&usb_bus, 8, io_buf.as_mut_slice()).unwrap();
+    let mut vendor: Device = Vendor::new(&usb_bus, transport);
+    let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+    let mut responses = vec![];
+    for request in requests {
+        bus.write_data(&frame(request));
+        for _ in 0..POLLS {
+            vendor
+                .poll(|transport| {
+                    let mut response = transport.request().unwrap().to_vec();
+                    let mut crc = Crc32::new();
+                    crc.update(&response);
+                    assert_eq!(crc, transport.request_crc());
+                    response.reverse();
+                    transport.respond(&response).unwrap();
+                })
+                .unwrap();
+        }
+        responses.push(read_response(&bus));
+    }
+    responses
+}
+
+#[test]
+fn should_respond_to_requests() {
+    common::timeout(TIMEOUT, || {
+        let requests: [&[u8]; 4] = [&[], &[1, 2, 3], &[1, 2, 3, 4], &[7; 30]];
+        let responses = run(&requests);
+        for (request, response) in requests.iter().zip(responses) {
+            let mut expected = request.to_vec();
+            expected.reverse();
+            assert_eq!(expected, response);
+        }
+    });
+}
+
+#[test]
+fn should_drop_request_too_large() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 16];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let mut transport = SimpleVendorTransport::new(&usb_bus, 8, io_buf.as_mut_slice()).unwrap();
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+        bus.write_data(&frame(&[0; 13]));
+        assert!(matches!(
+            transport.read(),
+            Err(TransportError::Error(SimpleVendorError::RequestTooLarge))
+        ));
+        assert!(matches!(
+            transport.respond(&[]),
+            Err(SimpleVendorError::InvalidState)
+        ));
+        transport.reset();
+        assert!(transport.request().is_none());
+    });
+}
+
+#[test]
+fn should_drop_response_on_reset() {
+    common::timeout(TIMEOUT, || {
+        let mut io_buf = [0u8; 64];
+        let bus = DummyUsbBus::new();
+        let usb_bus = UsbBusAllocator::new(bus.clone());
+        let transport = SimpleVendorTransport::new(&usb_bus, 8, io_buf.as_mut_slice()).unwrap();
+        let mut vendor: Device = Vendor::new(&usb_bus, transport);
+        let _ = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0xabcd, 0xabcd)).build();
+
+        bus.write_data(&frame(&[1]));
+        vendor
+            .poll(|transport| transport.respond(&[0; 20]).unwrap())
+            .unwrap();
+        assert_eq!(8, bus.read_packet().unwrap().len());
+        UsbClass::reset(&mut vendor);
+        vendor.poll(|_| unreachable!()).unwrap();
+        assert!(bus.read_packet().is_none());
+
+        bus.write_data(&frame(&[2]));
+        vendor
+            .poll(|transport| transport.respond(&[3]).unwrap())
+            .unwrap();
+        vendor.poll(|_| unreachable!()).unwrap();
+        assert_eq!(vec![3], read_response(&bus));
+    });
+}